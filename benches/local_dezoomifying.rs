@@ -3,6 +3,7 @@ use tokio::runtime;
 use std::time::Duration;
 
 #[path = "../tests/local_dezoomifying.rs"]
+#[allow(dead_code, unused_imports)]
 mod tests;
 
 fn criterion_benchmark(c: &mut Criterion) {
@@ -0,0 +1,41 @@
+//! Records information about the build, displayed by `dezoomify-rs --version --verbose`.
+//! See src/build_info.rs
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs/heads");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!("cargo:rerun-if-env-changed=DEZOOMIFY_GIT_COMMIT");
+
+    // Packagers building from a source archive can provide the commit themselves
+    let commit = env::var("DEZOOMIFY_GIT_COMMIT").ok()
+        .or_else(|| git(&["rev-parse", "--short=12", "HEAD"]))
+        .unwrap_or_default();
+    println!("cargo:rustc-env=DEZOOMIFY_GIT_COMMIT={}", commit);
+
+    // Use the date of the commit rather than the current time, so that builds are reproducible
+    let timestamp = env::var("SOURCE_DATE_EPOCH").ok()
+        .or_else(|| git(&["log", "-1", "--format=%ct"]))
+        .unwrap_or_default();
+    println!("cargo:rustc-env=DEZOOMIFY_BUILD_TIMESTAMP={}", timestamp);
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| name.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=DEZOOMIFY_FEATURES={}", features.join(","));
+
+    for name in &["TARGET", "PROFILE"] {
+        println!("cargo:rustc-env=DEZOOMIFY_{}={}", name, env::var(name).unwrap_or_default());
+    }
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let output = Command::new("git").args(args).output().ok()?;
+    if !output.status.success() { return None; }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
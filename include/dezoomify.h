@@ -0,0 +1,34 @@
+/*
+ * C interface to dezoomify-rs. The shared library that implements it is built with:
+ *     cargo rustc --release --lib --crate-type cdylib --features ffi
+ * The functions are documented in src/ffi.rs.
+ */
+#ifndef DEZOOMIFY_H
+#define DEZOOMIFY_H
+
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define DEZOOMIFY_RUNNING 0
+#define DEZOOMIFY_SUCCESS 1
+#define DEZOOMIFY_FAILED 2
+#define DEZOOMIFY_CANCELLED 3
+
+typedef struct DezoomifyJob DezoomifyJob;
+
+DezoomifyJob *dezoomify_start(const char *options_json);
+int dezoomify_poll(const DezoomifyJob *job);
+void dezoomify_progress(const DezoomifyJob *job, uint64_t *tiles_done, uint64_t *tiles_total);
+void dezoomify_cancel(DezoomifyJob *job);
+char *dezoomify_result(const DezoomifyJob *job);
+void dezoomify_string_free(char *s);
+void dezoomify_job_free(DezoomifyJob *job);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
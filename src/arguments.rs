@@ -1,99 +1,124 @@
-use structopt::StructOpt;
+use crate::bulk::LevelSelection;
+use crate::bulk::archive::EntryTemplate;
+use crate::crop::{Region, TileCrop};
+use crate::downscale::FitSize;
+use crate::dezoomer::{Dezoomer, PhysicalSize};
+use crate::encoder::ExportFormat;
+use crate::encoder::split_encoder::SplitOptions;
+use crate::output_file::OutputTemplate;
+use crate::progress_sink::SharedProgressSink;
+use crate::zoomify::ZoomifyDezoomer;
+use crate::http_client::SharedHttpClient;
+use crate::network::HttpVersion;
+use tokio_util::sync::CancellationToken;
 
-use crate::dezoomer::Dezoomer;
-
-use super::{auto, stdin_line, Vec2d, ZoomError};
+use super::{auto, MissingTilesPolicy, OversizePolicy, PlaceholderPolicy, stdin_line, Vec2d, ZoomError};
 use std::time::Duration;
 use std::path::PathBuf;
-use regex::Regex;
 
-#[derive(StructOpt, Debug)]
-#[structopt(author, about)]
+/// The options of a download. Each field holds the value of the command-line option with the same name,
+/// described in `dezoomify-rs --help`.
+#[derive(Debug, Clone)]
 pub struct Arguments {
-    /// Input URL or local file name
     pub input_uri: Option<String>,
-
-    /// File to which the resulting image should be saved
-    #[structopt(parse(from_os_str))]
     pub outfile: Option<PathBuf>,
-
-    /// Name of the dezoomer to use
-    #[structopt(short, long, default_value = "auto")]
-    dezoomer: String,
-
-    /// If several zoom levels are available, then select the largest one
-    #[structopt(short, long)]
+    pub bulk: Option<PathBuf>,
+    pub range: Option<String>,
+    pub archive: Option<PathBuf>,
+    pub archive_names: EntryTemplate,
+    pub reading_copy: Option<PathBuf>,
+    pub output_template: Option<OutputTemplate>,
+    pub out_dir: Option<PathBuf>,
+    pub(crate) dezoomer: String,
+    pub disabled_dezoomers: Vec<String>,
+    pub list_dezoomers: bool,
     pub largest: bool,
-
-    /// If several zoom levels are available, then select the one with the largest width that
-    /// is inferior to max-width.
-    #[structopt(short = "w", long = "max-width")]
-    max_width: Option<u32>,
-
-    /// If several zoom levels are available, then select the one with the largest height that
-    /// is inferior to max-height.
-    #[structopt(short = "h", long = "max-height")]
-    max_height: Option<u32>,
-
-    /// Degree of parallelism to use. At most this number of
-    /// tiles will be downloaded at the same time.
-    #[structopt(short = "n", long = "parallelism", default_value = "16")]
+    pub levels_below_max: Option<usize>,
+    pub(crate) max_width: Option<u32>,
+    pub(crate) max_height: Option<u32>,
+    pub zoom_size: Option<Vec2d>,
+    pub target_dpi: Option<f64>,
+    pub if_larger_than: Option<Vec2d>,
+    pub crop: Option<Region>,
+    pub tile_crop: Option<TileCrop>,
+    pub max_pixels: Option<u64>,
+    pub oversize_policy: OversizePolicy,
+    pub scale: Option<f64>,
+    pub resize: Option<FitSize>,
+    pub strip_size: Option<Vec2d>,
+    pub tile_columns: Option<u32>,
+    pub tile_rows: Option<u32>,
+    pub non_interactive: bool,
+    pub dry_run: bool,
+    pub json: bool,
+    pub validate: Option<PathBuf>,
+    pub wizard: bool,
     pub parallelism: usize,
-
-    /// Number of new attempts to make when a tile load fails
-    /// before giving up. Setting this to 0 is useful to speed up the
-    /// generic dezoomer, which relies on failed tile loads to detect the
-    /// dimensions of the image. On the contrary, if a server is not reliable,
-    /// set this value to a higher number.
-    #[structopt(short = "r", long = "retries", default_value = "1")]
+    pub batch_tiles: usize,
+    pub warmup: bool,
     pub retries: usize,
-
-    /// Amount of time to wait before retrying a request that failed.
-    /// Applies only to the first retry. Subsequent retries follow an
-    /// exponential backoff strategy: each one is twice as long as
-    /// the previous one.
-    #[structopt(long, default_value = "2s", parse(try_from_str = parse_duration))]
     pub retry_delay: Duration,
-
-    /// A number between 0 and 100 expressing how much to compress the output image.
-    /// For lossy output formats such as jpeg, this affects the quality of the resulting image.
-    /// 0 means less compression, 100 means more compression.
-    /// Currently affects only the JPEG and PNG encoders.
-    #[structopt(long, default_value = "20")]
+    pub iiif_token: Option<String>,
+    pub min_interval: Duration,
+    pub auto_throttle: bool,
+    pub salvage_partial_tiles: bool,
+    pub tile_filter: Option<String>,
+    pub embed_metadata: bool,
+    pub refetch_low_resolution: bool,
+    pub missing_tiles: MissingTilesPolicy,
+    pub placeholder_tiles: PlaceholderPolicy,
+    pub placeholder_pause: Duration,
     pub compression: u8,
-
-    /// Sets an HTTP header to use on requests.
-    /// This option can be repeated in order to set multiple headers.
-    /// You can use `-H "Referer: URL"` where URL is the URL of the website's
-    /// viewer page in order to let the site think you come from the legitimate viewer.
-    #[structopt(
-    short = "H",
-    long = "header",
-    parse(try_from_str = parse_header),
-    number_of_values = 1
-    )]
+    pub strict_format: bool,
+    pub split_oversize: bool,
+    pub no_contact_sheet: bool,
+    pub export_format: Option<ExportFormat>,
+    pub max_memory: u64,
     pub headers: Vec<(String, String)>,
-
-    /// Maximum number of idle connections per host allowed at the same time
-    #[structopt(long, default_value = "32")]
+    pub default_headers: Option<PathBuf>,
+    pub no_auto_referer: bool,
+    pub dezoomers_dir: Option<PathBuf>,
     pub max_idle_per_host: usize,
-
-    /// Whether to accept connecting to insecure HTTPS servers
-    #[structopt(long)]
+    pub http_version: HttpVersion,
+    pub http3: bool,
+    pub tcp_keepalive: Option<Duration>,
+    pub cookies_file: Option<PathBuf>,
+    pub proxy: Option<String>,
     pub accept_invalid_certs: bool,
-
-    /// Maximum time between the beginning of a request and the end of a response before
-    ///the request should be interrupted and considered failed
-    #[structopt(long, default_value = "30s", parse(try_from_str = parse_duration))]
     pub timeout: Duration,
-
-    /// Time after which we should give up when trying to connect to a server
-    #[structopt(long = "connect-timeout", default_value = "6s", parse(try_from_str = parse_duration))]
+    pub metadata_timeout: Option<Duration>,
+    pub tile_timeout: Option<Duration>,
+    pub read_timeout: Option<Duration>,
+    pub stall_timeout: Option<Duration>,
     pub connect_timeout: Duration,
-
-    /// Level of logging verbosity. Set it to "debug" to get all logging messages.
-    #[structopt(long, default_value="warn")]
+    pub detect_timeout: Option<Duration>,
+    pub audit_log: Option<PathBuf>,
+    pub failed_tiles_log: Option<PathBuf>,
+    pub retry_failed: Option<PathBuf>,
+    pub composite: Option<PathBuf>,
+    pub verify_with_preview: Option<String>,
+    pub record_http: Option<PathBuf>,
+    pub replay_http: Option<PathBuf>,
+    pub tile_cache: Option<PathBuf>,
+    pub tile_cache_size: u64,
+    pub cache_ignore_param: Vec<String>,
+    pub from_tile_cache: Option<PathBuf>,
     pub logging: String,
+    pub quiet: bool,
+    pub verbose: bool,
+
+    /// Receives the progress of the download, instead of the progress bar.
+    /// Only available to programs that use dezoomify-rs as a library.
+    pub progress: Option<SharedProgressSink>,
+
+    /// Stops the download when cancelled. The tiles that were already requested are still received,
+    /// and the image is saved with them.
+    /// Only available to programs that use dezoomify-rs as a library.
+    pub cancel: Option<CancellationToken>,
+
+    /// Makes the http requests, instead of the built-in client.
+    /// Only available to programs that use dezoomify-rs as a library.
+    pub http_client: Option<SharedHttpClient>,
 }
 
 impl Default for Arguments {
@@ -101,20 +126,93 @@ impl Default for Arguments {
         Arguments {
             input_uri: None,
             outfile: None,
+            bulk: None,
+            range: None,
+            archive: None,
+            archive_names: EntryTemplate::default(),
+            reading_copy: None,
+            output_template: None,
+            out_dir: None,
             dezoomer: "auto".to_string(),
+            disabled_dezoomers: vec![],
+            list_dezoomers: false,
             largest: false,
+            levels_below_max: None,
             max_width: None,
             max_height: None,
+            zoom_size: None,
+            target_dpi: None,
+            if_larger_than: None,
+            crop: None,
+            tile_crop: None,
+            scale: None,
+            resize: None,
+            max_pixels: None,
+            oversize_policy: OversizePolicy::Error,
+            strip_size: None,
+            tile_columns: None,
+            tile_rows: None,
+            non_interactive: false,
+            dry_run: false,
+            json: false,
+            validate: None,
+            wizard: false,
             parallelism: 16,
+            batch_tiles: 1,
+            warmup: false,
             retries: 1,
             compression: 20,
+            strict_format: false,
+            split_oversize: false,
+            no_contact_sheet: false,
+            export_format: None,
+            max_memory: 2 << 30,
             retry_delay: Duration::from_secs(2),
+            iiif_token: None,
+            min_interval: Duration::default(),
+            auto_throttle: false,
+            salvage_partial_tiles: false,
+            tile_filter: None,
+            embed_metadata: false,
+            refetch_low_resolution: false,
+            missing_tiles: MissingTilesPolicy::Blank,
+            placeholder_tiles: PlaceholderPolicy::Pause,
+            placeholder_pause: Duration::from_secs(30),
             headers: vec![],
+            default_headers: None,
+            no_auto_referer: false,
+            dezoomers_dir: None,
             max_idle_per_host: 32,
+            http_version: HttpVersion::Http1,
+            http3: false,
+            tcp_keepalive: None,
+            cookies_file: None,
+            proxy: None,
             accept_invalid_certs: false,
             timeout: Duration::from_secs(30),
+            metadata_timeout: None,
+            tile_timeout: None,
+            read_timeout: None,
+            stall_timeout: None,
             connect_timeout: Duration::from_secs(6),
+            detect_timeout: None,
+            audit_log: None,
+            failed_tiles_log: None,
+            retry_failed: None,
+            composite: None,
+            verify_with_preview: None,
+            record_http: None,
+            replay_http: None,
+            tile_cache: None,
+            tile_cache_size: 1 << 30,
+            cache_ignore_param: vec![],
+            from_tile_cache: None,
             logging: "warn".to_string(),
+            quiet: false,
+            verbose: false,
+            progress: None,
+            cancel: None,
+            http_client: None,
         }
     }
 }
@@ -123,13 +221,51 @@ impl Arguments {
     pub fn choose_input_uri(&self) -> Result<String, ZoomError> {
         match &self.input_uri {
             Some(uri) => Ok(uri.clone()),
+            None if self.non_interactive => Err(ZoomError::NoInputUri),
             None => {
-                println!("Enter an URL or a path to a tiles.yaml file: ");
+                eprintln!("Enter an URL or a path to a tiles.yaml file: ");
                 stdin_line()
             }
         }
     }
+    /// Whether an image is downloaded, as opposed to only describing or checking it
+    pub fn saves_image(&self) -> bool {
+        !(self.dry_run || self.validate.is_some() || self.wizard || self.list_dezoomers)
+    }
+
+    /// The level of the logging messages that are displayed
+    pub fn log_level(&self) -> &str {
+        match (self.quiet, self.verbose, self.logging.as_str()) {
+            (true, _, _) => "error",
+            (false, true, "warn") => "info",
+            (_, _, logging) => logging,
+        }
+    }
+
+    /// Whether we can ask the user to make choices on the standard input
+    pub fn is_interactive(&self) -> bool {
+        !self.non_interactive && atty::is(atty::Stream::Stdin)
+    }
+    /// How images that are too large for the format of the output file are split, if they are
+    pub fn split_options(&self) -> Option<SplitOptions> {
+        Some(SplitOptions { contact_sheet: !self.no_contact_sheet }).filter(|_| self.split_oversize)
+    }
+
     pub fn find_dezoomer(&self) -> Result<Box<dyn Dezoomer>, ZoomError> {
+        if let Some(name) = self.disabled_dezoomers.iter()
+            .find(|&name| auto::all_dezoomers(false).iter().all(|d| d.name() != name)) {
+            return Err(ZoomError::NoSuchDezoomer { name: name.clone() });
+        }
+        if self.dezoomer == "auto" {
+            let dezoomer = auto::AutoDezoomer::default()
+                .with_detect_timeout(self.detect_timeout)
+                .without_dezoomers(&self.disabled_dezoomers);
+            return Ok(Box::new(dezoomer));
+        }
+        // Only the chosen zoomify dezoomer takes the URLs of folders for zoomify images
+        if self.dezoomer == "zoomify" {
+            return Ok(Box::new(ZoomifyDezoomer::chosen()));
+        }
         auto::all_dezoomers(true)
             .into_iter()
             .find(|d| d.name() == self.dezoomer)
@@ -137,19 +273,61 @@ impl Arguments {
                 name: self.dezoomer.clone(),
             })
     }
+    /// Selects the zoom level in the same way as the corresponding command-line options
+    pub fn select_level(&mut self, selection: LevelSelection) {
+        self.largest = false;
+        self.max_width = None;
+        self.max_height = None;
+        self.levels_below_max = None;
+        self.zoom_size = None;
+        self.target_dpi = None;
+        match selection {
+            LevelSelection::Largest => self.largest = true,
+            LevelSelection::MaxSize(Vec2d { x, y }) => {
+                self.max_width = Some(x);
+                self.max_height = Some(y);
+            }
+            LevelSelection::BelowMax(n) => self.levels_below_max = Some(n),
+            LevelSelection::ClosestTo(size) => self.zoom_size = Some(size),
+        }
+    }
+
     pub fn best_size<I: Iterator<Item = Vec2d>>(&self, sizes: I) -> Option<Vec2d> {
-        if self.largest {
-            sizes.max_by_key(|s| s.area())
-        } else if self.max_width.is_some() || self.max_height.is_some() {
-            sizes
-                .filter(|s| {
-                    self.max_width.map(|w| s.x <= w).unwrap_or(true)
-                        && self.max_height.map(|h| s.y <= h).unwrap_or(true)
-                })
-                .max_by_key(|s| s.area())
-        } else {
-            None
+        if let Some(target) = self.zoom_size {
+            return closest_size(sizes, target);
+        }
+        let has_max_size = self.max_width.is_some() || self.max_height.is_some();
+        if !self.largest && !has_max_size && self.levels_below_max.is_none() {
+            return None;
         }
+        let mut sizes: Vec<Vec2d> = sizes
+            .filter(|s| {
+                self.largest || (self.max_width.map(|w| s.x <= w).unwrap_or(true)
+                    && self.max_height.map(|h| s.y <= h).unwrap_or(true))
+            })
+            .collect();
+        sizes.sort_by_key(|s| std::cmp::Reverse(s.area()));
+        sizes.dedup();
+        let below_max = self.levels_below_max.unwrap_or(0);
+        sizes.get(below_max).or_else(|| sizes.last()).copied()
+    }
+
+    /// The size to download with --target-dpi: the smallest one that reaches the resolution
+    /// for the given physical size, or the largest one
+    pub fn best_size_for_dpi<I: Iterator<Item = Vec2d>>(&self, sizes: I, physical: Option<PhysicalSize>) -> Option<Vec2d> {
+        let dpi = self.target_dpi?;
+        let sizes: Vec<Vec2d> = sizes.collect();
+        let largest = sizes.iter().max_by_key(|s| s.area()).copied();
+        let physical = match physical {
+            Some(physical) => physical,
+            None => return largest,
+        };
+        // Tolerate rounding errors in the physical dimensions
+        let reaches = |pixels: u32, inches: f64| f64::from(pixels) + 0.5 >= inches * dpi;
+        sizes.into_iter()
+            .filter(|s| reaches(s.x, physical.width_inches) && reaches(s.y, physical.height_inches))
+            .min_by_key(|s| s.area())
+            .or(largest)
     }
 
     pub fn headers(&self) -> impl Iterator<Item = (&String, &String)> {
@@ -157,67 +335,55 @@ impl Arguments {
     }
 }
 
-fn parse_header(s: &str) -> Result<(String, String), &'static str> {
-    let vals: Vec<&str> = s.splitn(2, ':').map(str::trim).collect();
-    if let [key, value] = vals[..] {
-        Ok((key.into(), value.into()))
-    } else {
-        Err("Invalid header format. Expected 'Name: Value'")
-    }
+/// The size that is the closest to the target. Sizes are compared by their ratio to the target,
+/// so that a level twice as large and a level half as large are as close.
+fn closest_size<I: Iterator<Item = Vec2d>>(sizes: I, target: Vec2d) -> Option<Vec2d> {
+    let distance = |size: Vec2d| {
+        let ratio = |actual: u32, wanted: u32| (f64::from(actual.max(1)) / f64::from(wanted.max(1))).ln().abs();
+        ratio(size.x, target.x) + ratio(size.y, target.y)
+    };
+    sizes.min_by(|&a, &b| {
+        let (distance_a, distance_b) = (distance(a), distance(b));
+        if (distance_a - distance_b).abs() < 1e-9 {
+            b.area().cmp(&a.area())
+        } else {
+            distance_a.partial_cmp(&distance_b).unwrap_or(std::cmp::Ordering::Equal)
+        }
+    })
 }
 
-fn parse_duration(s: &str) -> Result<Duration, &'static str> {
-    let err_msg = "Invalid duration. \
-                        A duration is a number followed by a unit, such as '10ms' or '5s'";
-    let re = Regex::new(r"^(\d+)\s*(min|s|ms|ns)$").unwrap();
-    let caps = re.captures(s).ok_or(err_msg)?;
-    let val: u64 = caps[1].parse().map_err(|_| err_msg)?;
-    match &caps[2] {
-        "min" => Ok(Duration::from_secs(60 * val)),
-        "s" => Ok(Duration::from_secs(val)),
-        "ms" => Ok(Duration::from_millis(val)),
-        "ns" => Ok(Duration::from_nanos(val)),
-        _ => Err(err_msg)
-    }
+#[test]
+fn test_levels_below_max() {
+    let sizes = || [100, 1000, 500, 1000].iter().map(|&s| Vec2d::square(s));
+    let mut args = Arguments { levels_below_max: Some(1), ..Arguments::default() };
+    assert_eq!(args.best_size(sizes()), Some(Vec2d::square(500)));
+    args.levels_below_max = Some(10);
+    assert_eq!(args.best_size(sizes()), Some(Vec2d::square(100)));
+    args.max_width = Some(600);
+    args.levels_below_max = Some(1);
+    assert_eq!(args.best_size(sizes()), Some(Vec2d::square(100)));
 }
 
-
 #[test]
-fn test_headers_and_input() -> Result<(), structopt::clap::Error> {
-    let args: Arguments = StructOpt::from_iter_safe(
-        [
-            "dezoomify-rs",
-            "--header",
-            "Referer: http://test.com",
-            "--header",
-            "User-Agent: custom",
-            "--header",
-            "A:B",
-            "input-url",
-        ]
-        .iter(),
-    )?;
-    assert_eq!(args.input_uri, Some("input-url".into()));
-    assert_eq!(
-        args.headers,
-        vec![
-            ("Referer".into(), "http://test.com".into()),
-            ("User-Agent".into(), "custom".into()),
-            ("A".into(), "B".into()),
-        ]
-    );
-    Ok(())
+fn test_zoom_size() {
+    let sizes = || [250, 500, 1000, 2000].iter().map(|&s| Vec2d::square(s));
+    let args = Arguments { zoom_size: Some(Vec2d { x: 900, y: 700 }), ..Arguments::default() };
+    assert_eq!(args.best_size(sizes()), Some(Vec2d::square(1000)));
+    // 1000 is as close to 500 as to 2000, and the larger level wins
+    let args = Arguments { zoom_size: Some(Vec2d::square(1000)), ..Arguments::default() };
+    assert_eq!(args.best_size(sizes().filter(|s| s.x != 1000)), Some(Vec2d::square(2000)));
 }
 
 #[test]
-fn test_parse_duration() {
-    assert_eq!(parse_duration("2s"), Ok(Duration::from_secs(2)));
-    assert_eq!(parse_duration("29 s"), Ok(Duration::from_secs(29)));
-    assert_eq!(parse_duration("2min"), Ok(Duration::from_secs(120)));
-    assert_eq!(parse_duration("1000 ms"), Ok(Duration::from_secs(1)));
-    assert!(parse_duration("1 2 ms").is_err());
-    assert!(parse_duration("1 s s").is_err());
-    assert!(parse_duration("ms").is_err());
-    assert!(parse_duration("1j").is_err());
-    assert!(parse_duration("").is_err());
+fn test_target_dpi() {
+    let sizes = || [750, 1500, 3000, 6000].iter().map(|&s| Vec2d::square(s));
+    let args = Arguments { target_dpi: Some(300.), ..Arguments::default() };
+    let ten_inches = PhysicalSize { width_inches: 10., height_inches: 10. };
+    assert_eq!(args.best_size_for_dpi(sizes(), Some(ten_inches)), Some(Vec2d::square(3000)));
+    let four_inches = PhysicalSize { width_inches: 4., height_inches: 4. };
+    assert_eq!(args.best_size_for_dpi(sizes(), Some(four_inches)), Some(Vec2d::square(1500)));
+    let huge = PhysicalSize { width_inches: 100., height_inches: 100. };
+    assert_eq!(args.best_size_for_dpi(sizes(), Some(huge)), Some(Vec2d::square(6000)));
+    assert_eq!(args.best_size_for_dpi(sizes(), None), Some(Vec2d::square(6000)));
+    assert_eq!(Arguments::default().best_size_for_dpi(sizes(), Some(ten_inches)), None);
 }
@@ -0,0 +1,143 @@
+//! An append-only record of every request made and every image saved,
+//! written as one JSON object per line to the file given with `--audit-log`.
+
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+use lazy_static::lazy_static;
+use log::warn;
+use reqwest::header::HeaderMap;
+use serde::Serialize;
+
+use crate::time_utils::rfc3339;
+use crate::ZoomError;
+
+lazy_static! {
+    static ref AUDIT_LOG: RwLock<Option<Arc<AuditLog>>> = RwLock::new(None);
+}
+
+pub struct AuditLog {
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Open the given file for appending, creating it if it does not exist
+    pub fn open(path: &Path) -> Result<Self, ZoomError> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { file: Mutex::new(file) })
+    }
+
+    fn write(&self, entry: &AuditEntry) -> std::io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        // A single write per entry, so that concurrent entries are never interleaved
+        self.file.lock().unwrap().write_all(&line)
+    }
+}
+
+/// Start recording entries to the given audit log, or stop recording if it is `None`
+pub fn set_audit_log(log: Option<AuditLog>) {
+    *AUDIT_LOG.write().unwrap() = log.map(Arc::new);
+}
+
+/// A line of the audit log
+#[derive(Serialize, Debug, Default)]
+pub struct AuditEntry {
+    pub time: String,
+    pub tool: &'static str,
+    pub version: &'static str,
+    /// "request" for an HTTP request, "image" for a finished download
+    pub event: &'static str,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The exit code corresponding to the error, for failed image downloads
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+}
+
+impl AuditEntry {
+    fn new(event: &'static str, url: &str) -> Self {
+        AuditEntry {
+            time: rfc3339(SystemTime::now()),
+            tool: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            event,
+            url: url.to_string(),
+            ..AuditEntry::default()
+        }
+    }
+
+    pub fn request(url: &str, status: Option<u16>, headers: Option<&HeaderMap>) -> Self {
+        let headers = headers.into_iter().flatten()
+            .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(value.as_bytes()).into_owned()))
+            .collect();
+        AuditEntry { status, headers, ..AuditEntry::new("request", url) }
+    }
+
+    pub fn image(url: &str, result: &Result<std::path::PathBuf, ZoomError>) -> Self {
+        let entry = AuditEntry::new("image", url);
+        match result {
+            Ok(path) => AuditEntry {
+                bytes: path.metadata().ok().map(|m| m.len()),
+                output: Some(path.to_string_lossy().into_owned()),
+                ..entry
+            },
+            Err(e) => AuditEntry { error: Some(e.to_string()), exit_code: Some(e.exit_code()), ..entry },
+        }
+    }
+
+    pub fn with_bytes(self, bytes: u64) -> Self {
+        AuditEntry { bytes: Some(bytes), ..self }
+    }
+
+    pub fn with_error<E: ToString>(self, error: &E) -> Self {
+        AuditEntry { error: Some(error.to_string()), ..self }
+    }
+}
+
+/// Append an entry to the audit log, if one is enabled
+pub fn record(entry: impl FnOnce() -> AuditEntry) {
+    let log = AUDIT_LOG.read().unwrap().clone();
+    if let Some(log) = log {
+        if let Err(e) = log.write(&entry()) {
+            warn!("Unable to write to the audit log: {}", e);
+        }
+    }
+}
+
+#[test]
+fn test_audit_log() {
+    let dir = tempdir::TempDir::new("dezoomify-rs-audit").unwrap();
+    let path = dir.path().join("audit.jsonl");
+    let log = AuditLog::open(&path).unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert("x-license", "CC0".parse().unwrap());
+    log.write(&AuditEntry::request("http://example.com/a", Some(200), Some(&headers)).with_bytes(42)).unwrap();
+    drop(log);
+    let log = AuditLog::open(&path).unwrap();
+    log.write(&AuditEntry::request("http://example.com/b", None, None).with_error(&"timeout")).unwrap();
+    let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path).unwrap()
+        .lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0]["status"], 200);
+    assert_eq!(lines[0]["headers"]["x-license"], "CC0");
+    assert_eq!(lines[0]["bytes"], 42);
+    assert_eq!(lines[0]["tool"], "dezoomify-rs");
+    assert_eq!(lines[1]["error"], "timeout");
+    assert!(lines[1].get("status").is_none());
+    let failed = AuditEntry::image("http://example.com/c", &Err(ZoomError::NoTile));
+    assert_eq!(failed.exit_code, Some(6));
+}
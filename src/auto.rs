@@ -1,11 +1,17 @@
-use log::{debug, info};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use custom_error::custom_error;
+use log::{debug, info, warn};
 
 use crate::dezoomer::{Dezoomer, DezoomerError, DezoomerInput, ZoomLevel, ZoomLevels};
 use crate::errors::DezoomerError::NeedsData;
 
 pub fn all_dezoomers(include_generic: bool) -> Vec<Box<dyn Dezoomer>> {
-    let mut dezoomers: Vec<Box<dyn Dezoomer>> = vec![
-        Box::new(crate::custom_yaml::CustomDezoomer::default()),
+    // The dezoomers described by the user come first, so that they can replace the built-in ones
+    let mut dezoomers = crate::custom_yaml::descriptor::descriptor_dezoomers();
+    dezoomers.extend(vec![
+        Box::new(crate::custom_yaml::CustomDezoomer) as Box<dyn Dezoomer>,
         Box::new(crate::google_arts_and_culture::GAPDezoomer::default()),
         Box::new(crate::zoomify::ZoomifyDezoomer::default()),
         Box::new(crate::iiif::IIIF::default()),
@@ -15,7 +21,13 @@ pub fn all_dezoomers(include_generic: bool) -> Vec<Box<dyn Dezoomer>> {
         Box::new(crate::krpano::KrpanoDezoomer::default()),
         Box::new(crate::iipimage::IIPImage::default()),
         Box::new(crate::nypl::NYPLImage::default()),
-    ];
+        Box::new(crate::ids::IdsDezoomer),
+        Box::new(crate::micrio::Micrio),
+        Box::new(crate::xyz::XyzDezoomer::default()),
+        Box::new(crate::resizable::ResizableDezoomer::default()),
+        Box::new(crate::openseadragon::OpenSeadragon),
+        Box::new(crate::html_probe::HtmlProbe::default()),
+    ]);
     if include_generic {
         dezoomers.push(Box::new(AutoDezoomer::default()))
     }
@@ -27,6 +39,11 @@ pub struct AutoDezoomer {
     errors: Vec<(&'static str, DezoomerError)>,
     successes: Vec<ZoomLevel>,
     needs_uris: Vec<String>,
+    /// Time spent by each dezoomer, including the time spent downloading the data it requested
+    timings: HashMap<&'static str, Duration>,
+    requested_by: HashMap<String, Vec<&'static str>>,
+    last_request: Option<(String, Instant)>,
+    detect_timeout: Option<Duration>,
 }
 
 impl Default for AutoDezoomer {
@@ -36,36 +53,90 @@ impl Default for AutoDezoomer {
             errors: vec![],
             successes: vec![],
             needs_uris: vec![],
+            timings: HashMap::new(),
+            requested_by: HashMap::new(),
+            last_request: None,
+            detect_timeout: None,
         }
     }
 }
 
+impl AutoDezoomer {
+    /// Give up on dezoomers that take longer than the given duration to detect an image
+    pub fn with_detect_timeout(mut self, detect_timeout: Option<Duration>) -> Self {
+        self.detect_timeout = detect_timeout;
+        self
+    }
+
+    /// Do not try the dezoomers with the given names
+    pub fn without_dezoomers(mut self, names: &[String]) -> Self {
+        self.dezoomers.retain(|d| !names.iter().any(|name| name == d.name()));
+        self
+    }
+
+    fn log_timings(&self) {
+        let mut timings: Vec<_> = self.timings.iter().collect();
+        timings.sort_by_key(|&(_, duration)| std::cmp::Reverse(*duration));
+        for (name, duration) in timings {
+            debug!("dezoomer '{}' spent {:?} trying to detect the image", name, duration);
+        }
+    }
+}
+
+custom_error! {pub DetectTimeoutError
+    Timeout{elapsed: String} = "Gave up after {elapsed}, because of --detect-timeout"
+}
+
 impl Dezoomer for AutoDezoomer {
     fn name(&self) -> &'static str {
         "auto"
     }
 
+    fn description(&self) -> &str {
+        "Tries all the other dezoomers, and uses the ones that recognize the input. This is the default."
+    }
+
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        // The time spent downloading a file is attributed to the dezoomers that requested it
+        if let Some((uri, start)) = self.last_request.take() {
+            let elapsed = start.elapsed();
+            for name in self.requested_by.remove(&uri).unwrap_or_default() {
+                *self.timings.entry(name).or_default() += elapsed;
+            }
+        }
         // TO DO: Use drain_filter when it is stabilized
         let mut i = 0;
         while i != self.dezoomers.len() {
             let dezoomer = &mut self.dezoomers[i];
-            let keep = match dezoomer.zoom_levels(data) {
+            let start = Instant::now();
+            let result = dezoomer.zoom_levels(data);
+            let name = dezoomer.name();
+            let spent = self.timings.entry(name).or_default();
+            *spent += start.elapsed();
+            let timed_out = self.detect_timeout.filter(|timeout| *spent > *timeout);
+            let keep = match result {
                 Ok(mut levels) => {
-                    info!("dezoomer '{}' found {} zoom levels", dezoomer.name(), levels.len());
+                    info!("dezoomer '{}' found {} zoom levels", name, levels.len());
                     self.successes.append(&mut levels);
                     false
                 }
+                Err(DezoomerError::NeedsData { .. }) if timed_out.is_some() => {
+                    let elapsed = format!("{:?}", spent);
+                    warn!("dezoomer '{}' took too long to detect the image, giving up after {}", name, elapsed);
+                    self.errors.push((name, DezoomerError::wrap(DetectTimeoutError::Timeout { elapsed })));
+                    false
+                }
                 Err(DezoomerError::NeedsData { uri }) => {
-                    info!("dezoomer '{}' requested to load {}", dezoomer.name(), &uri);
+                    info!("dezoomer '{}' requested to load {}", name, &uri);
+                    self.requested_by.entry(uri.clone()).or_default().push(name);
                     if !self.needs_uris.contains(&uri) {
                         self.needs_uris.push(uri);
                     }
                     true
                 }
                 Err(e) => {
-                    debug!("{} cannot process this image: {}", dezoomer.name(), e);
-                    self.errors.push((dezoomer.name(), e));
+                    debug!("{} cannot process this image: {}", name, e);
+                    self.errors.push((name, e));
                     false
                 }
             };
@@ -76,12 +147,15 @@ impl Dezoomer for AutoDezoomer {
             }
         }
         if let Some(uri) = self.needs_uris.pop() {
+            self.last_request = Some((uri.clone(), Instant::now()));
             Err(NeedsData { uri })
         } else if self.successes.is_empty() {
             info!("No dezoomer can dezoom {:?}", data.uri);
+            self.log_timings();
             let errs = std::mem::take(&mut self.errors);
             Err(DezoomerError::wrap(AutoDezoomerError(errs)))
         } else {
+            self.log_timings();
             let successes = std::mem::take(&mut self.successes);
             Ok(successes)
         }
@@ -93,6 +167,17 @@ pub struct AutoDezoomerError(Vec<(&'static str, DezoomerError)>);
 
 impl std::error::Error for AutoDezoomerError {}
 
+impl AutoDezoomerError {
+    /// The network error that made all the dezoomers that recognized the input fail, if there is one
+    pub fn network_error(&self) -> Option<&crate::ZoomError> {
+        let mut errors = self.0.iter()
+            .map(|(_, err)| err)
+            .filter(|err| !matches!(err, DezoomerError::WrongDezoomer { .. }));
+        let first = errors.next()?.network_error()?;
+        if errors.all(|err| err.network_error().is_some()) { Some(first) } else { None }
+    }
+}
+
 impl std::fmt::Display for AutoDezoomerError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         if self.0.is_empty() {
@@ -114,3 +199,34 @@ impl std::fmt::Display for AutoDezoomerError {
         https://github.com/lovasoa/dezoomify-rs/issues")
     }
 }
+
+#[test]
+fn test_detect_timeout() {
+    use crate::dezoomer::PageContents;
+    struct EndlessDezoomer;
+    impl Dezoomer for EndlessDezoomer {
+        fn name(&self) -> &'static str { "endless" }
+        fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+            Err(NeedsData { uri: format!("{}/next", data.uri) })
+        }
+    }
+    let mut auto = AutoDezoomer {
+        dezoomers: vec![Box::new(EndlessDezoomer)],
+        ..AutoDezoomer::default()
+    }.with_detect_timeout(Some(Duration::from_secs(0)));
+    let input = DezoomerInput { uri: "test".into(), contents: PageContents::Unknown };
+    let err = auto.zoom_levels(&input).expect_err("the dezoomer should time out");
+    assert!(err.to_string().contains("--detect-timeout"), "unexpected error: {}", err);
+}
+
+#[test]
+fn test_network_error_exit_code() {
+    use std::rc::Rc;
+    let login = crate::ZoomError::LoginRedirect { url: "a".into(), location: "b".into() };
+    let network = DezoomerError::Network { error: Rc::new(login) };
+    let wrong = DezoomerError::WrongDezoomer { name: "wrong" };
+    let err = DezoomerError::wrap(AutoDezoomerError(vec![("wrong", wrong), ("network", network)]));
+    assert_eq!(crate::ZoomError::Dezoomer { source: err }.exit_code(), 4);
+    let invalid = DezoomerError::wrap(AutoDezoomerError(vec![("other", DezoomerError::wrap(crate::ZoomError::NoTile))]));
+    assert_eq!(crate::ZoomError::Dezoomer { source: invalid }.exit_code(), 2);
+}
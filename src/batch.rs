@@ -0,0 +1,132 @@
+//! Downloading several tiles with a single request, with `--batch-tiles`, for the servers that can
+//! send them together, such as some Zoomify PFF servers. The dezoomer gives the requests to make,
+//! and where each tile is in their responses (see [TileProvider::fetch_plan](crate::dezoomer::TileProvider::fetch_plan)).
+//! The tiles received this way are decoded like the other ones. When the server does not send
+//! what was expected, the tiles of the batch are requested one by one instead.
+
+use std::collections::HashMap;
+
+use log::{debug, info, warn};
+
+use crate::dezoomer::{TileBatch, TileReference};
+use crate::network::{fetch_uri, Fetcher};
+
+/// Tiles to download together
+pub enum Job {
+    Tile(TileReference),
+    Batch {
+        batch: TileBatch,
+        tiles: Vec<TileReference>,
+        /// The response to the request of the batch, when it was already received
+        response: Option<Vec<u8>>,
+    },
+}
+
+/// Groups the tiles into the batches of the plan, and the tiles that are requested one by one.
+/// The first batch is requested immediately, to check that the server supports the requests of the plan.
+/// If it does not, all the tiles are requested one by one.
+pub async fn jobs(tiles: Vec<TileReference>, plan: Vec<TileBatch>, http: &Fetcher) -> Vec<Job> {
+    let first = match plan.first() {
+        Some(first) => first,
+        None => return tiles.into_iter().map(Job::Tile).collect(),
+    };
+    let first_response = match fetch(first, http).await {
+        Some(response) => response,
+        None => {
+            warn!("The server did not send the {} tiles requested with {} as expected. \
+                   Requesting the tiles one by one.", first.tiles.len(), first.url);
+            return tiles.into_iter().map(Job::Tile).collect();
+        }
+    };
+    let batched_tiles: usize = plan.iter().map(|batch| batch.tiles.len()).sum();
+    info!("Requesting {} tiles with {} requests", tiles.len(), tiles.len() - batched_tiles + plan.len());
+    group(tiles, plan, first_response)
+}
+
+/// Places each tile in its batch. Each batch comes at the place of its first tile.
+fn group(tiles: Vec<TileReference>, plan: Vec<TileBatch>, first_response: Vec<u8>) -> Vec<Job> {
+    enum Slot { Tile(TileReference), Batch(usize) }
+    let batch_of: HashMap<String, usize> = plan.iter().enumerate()
+        .flat_map(|(i, batch)| batch.tiles.iter().map(move |(key, _)| (key.clone(), i)))
+        .collect();
+    let mut members: Vec<Vec<TileReference>> = plan.iter().map(|_| vec![]).collect();
+    let mut slots = vec![];
+    for tile in tiles {
+        match batch_of.get(&tile.key()) {
+            Some(&i) => {
+                if members[i].is_empty() { slots.push(Slot::Batch(i)); }
+                members[i].push(tile);
+            }
+            None => slots.push(Slot::Tile(tile)),
+        }
+    }
+    let mut first_response = Some(first_response);
+    let mut plan: Vec<Option<TileBatch>> = plan.into_iter().map(Some).collect();
+    slots.into_iter().map(|slot| match slot {
+        Slot::Tile(tile) => Job::Tile(tile),
+        Slot::Batch(i) => Job::Batch {
+            batch: plan[i].take().expect("each batch has a single slot"),
+            tiles: std::mem::take(&mut members[i]),
+            response: if i == 0 { first_response.take() } else { None },
+        },
+    }).collect()
+}
+
+/// Requests the tiles of a job. Returns them with the bytes of the ones that were received in a batch.
+/// The others have to be requested one by one.
+pub async fn receive(job: Job, http: &Fetcher) -> (Vec<TileReference>, HashMap<String, Vec<u8>>) {
+    match job {
+        Job::Tile(tile) => (vec![tile], HashMap::new()),
+        Job::Batch { batch, tiles, response } => {
+            let response = match response {
+                Some(response) => Some(response),
+                None => fetch(&batch, http).await,
+            };
+            let parts = response.and_then(|response| split(&batch, response)).unwrap_or_else(|| {
+                warn!("Unable to receive the {} tiles requested with {}. Requesting them one by one.",
+                      batch.tiles.len(), batch.url);
+                HashMap::new()
+            });
+            (tiles, parts)
+        }
+    }
+}
+
+/// Makes the request of a batch. Returns its response if it contains all the tiles.
+async fn fetch(batch: &TileBatch, http: &Fetcher) -> Option<Vec<u8>> {
+    let response = fetch_uri(&batch.url, http).await
+        .map_err(|e| debug!("Unable to request the batch {}: {}", batch.url, e)).ok()?;
+    let expected = batch.tiles.iter().map(|(_, range)| range.end).max().unwrap_or(0);
+    if response.len() == expected {
+        Some(response)
+    } else {
+        debug!("The response to {} has {} bytes instead of {}", batch.url, response.len(), expected);
+        None
+    }
+}
+
+/// The bytes of each tile of a batch, by key
+fn split(batch: &TileBatch, response: Vec<u8>) -> Option<HashMap<String, Vec<u8>>> {
+    batch.tiles.iter()
+        .map(|(key, range)| Some((key.clone(), response.get(range.clone())?.to_vec())))
+        .collect()
+}
+
+#[test]
+fn test_group() {
+    let tile = |x: u32| TileReference { url: format!("tile{}", x), position: crate::Vec2d { x, y: 0 }, size: None, request: None };
+    let plan = vec![
+        TileBatch { url: "batch0".into(), tiles: vec![("tile1".into(), 0..2), ("tile2".into(), 2..5)] },
+        TileBatch { url: "batch1".into(), tiles: vec![("tile4".into(), 0..1), ("tile5".into(), 1..2)] },
+    ];
+    let jobs = group((0..6).map(tile).collect(), plan.clone(), b"abcde".to_vec());
+    let summary: Vec<String> = jobs.iter().map(|job| match job {
+        Job::Tile(tile) => tile.url.clone(),
+        Job::Batch { batch, tiles, response } => format!("{} ({} tiles, received: {})", batch.url, tiles.len(), response.is_some()),
+    }).collect();
+    assert_eq!(summary, vec!["tile0", "batch0 (2 tiles, received: true)", "tile3", "batch1 (2 tiles, received: false)"]);
+
+    let parts = split(&plan[0], b"abcde".to_vec()).unwrap();
+    assert_eq!(parts["tile2"], b"cde");
+    assert!(split(&plan[0], b"abc".to_vec()).is_none());
+}
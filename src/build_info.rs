@@ -0,0 +1,93 @@
+//! Information about how dezoomify-rs was built, and what it can do at runtime.
+//! It is displayed by `dezoomify-rs --version --verbose` and included in the json reports,
+//! so that bug reports contain what is needed to reproduce them.
+//! The values are set by build.rs
+
+use std::time::{Duration, UNIX_EPOCH};
+
+use image::{ImageError, ImageFormat};
+use serde::Serialize;
+
+use crate::time_utils::rfc3339;
+
+/// The image formats that tiles can be found in
+const TILE_FORMATS: &[ImageFormat] = &[
+    ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP, ImageFormat::Gif,
+    ImageFormat::Tiff, ImageFormat::Bmp, ImageFormat::Avif,
+];
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BuildInfo {
+    pub version: &'static str,
+    pub git_commit: Option<&'static str>,
+    pub build_date: Option<String>,
+    pub target: &'static str,
+    pub profile: &'static str,
+    pub features: Vec<&'static str>,
+    pub tls_backend: &'static str,
+    /// Image formats for which a decoder is available
+    pub image_decoders: Vec<String>,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        BuildInfo {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: non_empty(env!("DEZOOMIFY_GIT_COMMIT")),
+            build_date: non_empty(env!("DEZOOMIFY_BUILD_TIMESTAMP"))
+                .and_then(|t| t.parse().ok())
+                .map(|secs| rfc3339(UNIX_EPOCH + Duration::from_secs(secs))),
+            target: env!("DEZOOMIFY_TARGET"),
+            profile: env!("DEZOOMIFY_PROFILE"),
+            features: env!("DEZOOMIFY_FEATURES").split(',').filter(|f| !f.is_empty()).collect(),
+            tls_backend: TLS_BACKEND,
+            image_decoders: TILE_FORMATS.iter().copied()
+                .filter(|&format| can_decode(format))
+                .map(|format| format!("{:?}", format).to_lowercase())
+                .collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for BuildInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "dezoomify-rs {}", self.version)?;
+        writeln!(f, "commit: {}", self.git_commit.unwrap_or("unknown"))?;
+        writeln!(f, "build date: {}", self.build_date.as_deref().unwrap_or("unknown"))?;
+        writeln!(f, "target: {} ({})", self.target, self.profile)?;
+        let features = if self.features.is_empty() { "none".to_string() } else { self.features.join(", ") };
+        writeln!(f, "features: {}", features)?;
+        writeln!(f, "tls: {}", self.tls_backend)?;
+        writeln!(f, "image decoders: {}", self.image_decoders.join(", "))
+    }
+}
+
+/// reqwest uses the default native-tls backend, which relies on the TLS library of the system
+#[cfg(target_os = "windows")]
+const TLS_BACKEND: &str = "native-tls (schannel)";
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+const TLS_BACKEND: &str = "native-tls (security-framework)";
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "ios")))]
+const TLS_BACKEND: &str = "native-tls (openssl)";
+
+fn non_empty(s: &'static str) -> Option<&'static str> {
+    Some(s).filter(|s| !s.is_empty())
+}
+
+/// The image crate reports formats whose codec was not compiled in as unsupported,
+/// and fails with a decoding error when it is available but the image is invalid
+fn can_decode(format: ImageFormat) -> bool {
+    !matches!(image::load_from_memory_with_format(&[], format), Err(ImageError::Unsupported(_)))
+}
+
+#[test]
+fn test_build_info() {
+    let info = BuildInfo::current();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(!info.target.is_empty());
+    for format in &["jpeg", "png"] {
+        assert!(info.image_decoders.iter().any(|d| d == format), "{} is not available", format);
+    }
+    assert!(!info.image_decoders.iter().any(|d| d == "avif"), "avif support is not compiled in");
+    assert!(info.to_string().starts_with("dezoomify-rs "));
+}
@@ -0,0 +1,168 @@
+//! Saving the images of a bulk download in a single zip archive, such as a `.cbz` comic book,
+//! instead of leaving them as separate files.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use crate::ZoomError;
+
+/// A template for the names of the images in the archive, such as `page_{page}`.
+/// The extension of the image is added to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EntryTemplate(String);
+
+impl EntryTemplate {
+    /// The variables that can be used in templates: the number of the image in the bulk file,
+    /// with leading zeros so that the images are sorted in order, and the name of the image file without its extension
+    pub const VARIABLES: [&'static str; 2] = ["page", "name"];
+
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}')
+                .ok_or_else(|| format!("Unclosed '{{' in the archive name template '{}'", template))?;
+            let variable = &rest[start + 1..start + end];
+            if !Self::VARIABLES.contains(&variable) {
+                return Err(format!(
+                    "Unknown variable '{{{}}}' in the archive name template. Available variables: {}",
+                    variable, Self::VARIABLES.iter().map(|v| format!("{{{}}}", v)).collect::<Vec<_>>().join(", ")
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("Unmatched '}}' in the archive name template '{}'", template));
+        }
+        Ok(EntryTemplate(template.to_string()))
+    }
+
+    /// The name in the archive of the image saved to `saved`, which is the `number`th image out of `count`
+    fn render(&self, number: usize, count: usize, saved: &Path) -> String {
+        let digits = count.to_string().len();
+        let stem = saved.file_stem().unwrap_or_default().to_string_lossy();
+        let name = self.0
+            .replace("{page}", &format!("{:0width$}", number, width = digits))
+            .replace("{name}", &stem);
+        match saved.extension() {
+            Some(extension) => format!("{}.{}", name, extension.to_string_lossy()),
+            None => name,
+        }
+    }
+}
+
+impl Default for EntryTemplate {
+    fn default() -> Self { EntryTemplate("{page}".to_string()) }
+}
+
+impl std::str::FromStr for EntryTemplate {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> { EntryTemplate::parse(s) }
+}
+
+/// A zip archive to which the images of a bulk download are moved as soon as they are saved
+pub struct BulkArchive {
+    path: PathBuf,
+    writer: ZipWriter<File>,
+    template: EntryTemplate,
+    count: usize,
+    names: HashSet<String>,
+}
+
+impl BulkArchive {
+    /// Creates the archive that will contain `count` images
+    pub fn create(path: &Path, template: &EntryTemplate, count: usize) -> Result<Self, ZoomError> {
+        let file = File::create(path)?;
+        Ok(BulkArchive {
+            path: path.to_path_buf(),
+            writer: ZipWriter::new(file),
+            template: template.clone(),
+            count,
+            names: HashSet::new(),
+        })
+    }
+
+    /// Moves the image saved to `saved`, which is the `number`th image of the bulk file, to the archive.
+    /// Returns its path inside the archive.
+    pub fn add(&mut self, number: usize, saved: &Path) -> Result<PathBuf, ZoomError> {
+        let mut name = self.template.render(number, self.count, saved);
+        // Two images can have the same name when the template does not contain {page}
+        let mut duplicate = 1;
+        while self.names.contains(&name) {
+            duplicate += 1;
+            name = self.template.render(number, self.count, &numbered(saved, duplicate));
+        }
+        // The images are already compressed
+        let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+        self.writer.start_file(name.as_str(), options).map_err(archive_error)?;
+        std::io::copy(&mut File::open(saved)?, &mut self.writer)?;
+        info!("Added {:?} to the archive {:?} as {}", saved, self.path, name);
+        if let Err(e) = std::fs::remove_file(saved) {
+            warn!("Unable to remove {:?} after adding it to the archive: {}", saved, e);
+        }
+        let entry = self.path.join(&name);
+        self.names.insert(name);
+        Ok(entry)
+    }
+
+    /// Writes the end of the archive, and returns its path
+    pub fn finish(mut self) -> Result<PathBuf, ZoomError> {
+        self.writer.finish().map_err(archive_error)?;
+        Ok(self.path)
+    }
+}
+
+/// The path with a number added to its file stem: `page.jpg` becomes `page_2.jpg`
+fn numbered(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => path.with_file_name(format!("{}_{}.{}", stem, number, extension.to_string_lossy())),
+        None => path.with_file_name(format!("{}_{}", stem, number)),
+    }
+}
+
+fn archive_error(error: zip::result::ZipError) -> ZoomError {
+    match error {
+        zip::result::ZipError::Io(source) => ZoomError::Io { source },
+        error => ZoomError::Io { source: std::io::Error::other(error.to_string()) },
+    }
+}
+
+#[test]
+fn test_entry_template() {
+    let template = EntryTemplate::default();
+    assert_eq!(template.render(7, 120, Path::new("out/Folio 7r.jpg")), "007.jpg");
+    let template = EntryTemplate::parse("{page} - {name}").unwrap();
+    assert_eq!(template.render(7, 9, Path::new("out/Folio 7r.png")), "7 - Folio 7r.png");
+    assert!(EntryTemplate::parse("{title}").is_err());
+    assert!(EntryTemplate::parse("{page").is_err());
+}
+
+#[test]
+fn test_bulk_archive() {
+    use std::io::Read;
+
+    let dir = tempdir::TempDir::new("dezoomify-rs-archive").unwrap();
+    let archive_path = dir.path().join("book.cbz");
+    let template = EntryTemplate::parse("{name}").unwrap();
+    let mut archive = BulkArchive::create(&archive_path, &template, 2).unwrap();
+    for (number, contents) in [(1, "first"), (2, "second")].iter() {
+        let page = dir.path().join("page.jpg");
+        std::fs::write(&page, contents).unwrap();
+        archive.add(*number, &page).unwrap();
+        assert!(!page.exists());
+    }
+    assert_eq!(archive.finish().unwrap(), archive_path);
+
+    let mut zip = zip::ZipArchive::new(File::open(&archive_path).unwrap()).unwrap();
+    let mut names: Vec<String> = zip.file_names().map(String::from).collect();
+    names.sort();
+    assert_eq!(names, vec!["page.jpg", "page_2.jpg"]);
+    let mut contents = String::new();
+    zip.by_name("page_2.jpg").unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "second");
+}
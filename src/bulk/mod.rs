@@ -0,0 +1,169 @@
+//! Downloading several images listed in a file given with `--bulk`,
+//! with options that can be changed for each image.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::{Arguments, Region, Vec2d, ZoomError};
+
+use self::archive::BulkArchive;
+use self::reading_copy::ReadingCopy;
+
+pub mod archive;
+pub mod parsers;
+pub mod reading_copy;
+
+/// An image to download, with the options that override the command-line arguments for it
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BulkItem {
+    pub url: String,
+    pub output: Option<PathBuf>,
+    pub headers: BTreeMap<String, String>,
+    pub zoom_level: Option<LevelSelection>,
+    pub crop: Option<Region>,
+}
+
+/// How to select the zoom level of a single image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LevelSelection {
+    Largest,
+    /// The largest level that fits in the given size
+    MaxSize(Vec2d),
+    /// The level that is this number of levels smaller than the largest one
+    BelowMax(usize),
+    /// The level whose size is the closest to the given one
+    ClosestTo(Vec2d),
+}
+
+impl BulkItem {
+    /// The arguments to use for this item, which is the `number`th of the list, counting from 1
+    pub fn arguments(&self, args: &Arguments, number: usize) -> Arguments {
+        let mut item_args = args.clone();
+        item_args.input_uri = Some(self.url.clone());
+        item_args.bulk = None;
+        if let Some(output) = &self.output { item_args.outfile = Some(output.clone()); }
+        item_args.headers.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+        if let Some(selection) = self.zoom_level { item_args.select_level(selection); }
+        if let Some(crop) = self.crop { item_args.crop = Some(crop); }
+        // Each image has its own list of failed tiles, that can be retried separately
+        item_args.failed_tiles_log = args.failed_tiles_log.as_deref().map(|path| numbered_path(path, number));
+        item_args
+    }
+}
+
+/// The path with the number added to the file name: `failed.json` becomes `failed_3.json`
+fn numbered_path(path: &Path, number: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}_{}.{}", stem, number, extension.to_string_lossy()),
+        None => format!("{}_{}", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+/// The number of images of a bulk download that were saved, skipped because of `--if-larger-than`, or failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BulkStats {
+    pub saved: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl BulkStats {
+    pub fn record<T>(&mut self, result: &Result<T, ZoomError>) {
+        match result {
+            Ok(_) => self.saved += 1,
+            Err(ZoomError::ImageTooSmall { .. }) => self.skipped += 1,
+            Err(_) => self.failed += 1,
+        }
+    }
+}
+
+impl fmt::Display for BulkStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} images saved, {} skipped, {} failed", self.saved, self.skipped, self.failed)
+    }
+}
+
+/// The files in which the images of a bulk download are gathered as soon as they are saved:
+/// a PDF reading copy, given with `--reading-copy`, and a zip archive, given with `--archive`
+pub struct BulkOutputs {
+    reading_copy: Option<ReadingCopy>,
+    archive: Option<BulkArchive>,
+}
+
+impl BulkOutputs {
+    /// Creates the outputs requested in the arguments, for a bulk download of `count` images
+    pub fn create(args: &Arguments, count: usize) -> Result<Self, ZoomError> {
+        Ok(BulkOutputs {
+            reading_copy: args.reading_copy.as_deref().map(ReadingCopy::create).transpose()?,
+            archive: args.archive.as_deref()
+                .map(|path| BulkArchive::create(path, &args.archive_names, count))
+                .transpose()?,
+        })
+    }
+
+    /// Adds the image saved to `saved`, which is the `number`th image of the bulk file.
+    /// Returns the path of the image, which is inside the archive if there is one.
+    pub fn add(&mut self, number: usize, saved: PathBuf) -> Result<PathBuf, ZoomError> {
+        if let Some(reading_copy) = &mut self.reading_copy {
+            reading_copy.add(&saved)?;
+        }
+        match &mut self.archive {
+            Some(archive) => archive.add(number, &saved),
+            None => Ok(saved),
+        }
+    }
+
+    /// Writes the end of the reading copy and of the archive, and returns the paths of those that were written
+    pub fn finish(self) -> Vec<Result<PathBuf, ZoomError>> {
+        self.reading_copy.map(ReadingCopy::finish).into_iter()
+            .chain(self.archive.map(BulkArchive::finish))
+            .collect()
+    }
+}
+
+/// Reads the list of images to download from a file. The format is chosen from the file extension:
+/// JSON files can be either a list of images or a IIIF manifest,
+/// and HTML files are Google Arts & Culture pages that list several artworks.
+/// Only the canvases of the given range are read from manifests.
+pub fn read_items(path: &Path, range: Option<&str>) -> Result<Vec<BulkItem>, ZoomError> {
+    let contents = std::fs::read(path)?;
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") if parsers::iiif_manifest::is_manifest(&contents) => {
+            parsers::iiif_manifest::parse(&contents, range)
+        }
+        Some("json") => {
+            if range.is_some() { warn!("--range is ignored, because {:?} is not a IIIF manifest", path); }
+            parsers::json_list::parse(&contents)
+        }
+        Some("html") | Some("htm") => {
+            if range.is_some() { warn!("--range is ignored, because {:?} is not a IIIF manifest", path); }
+            parsers::google_arts_and_culture::parse(&contents)
+        }
+        _ => Err(ZoomError::BulkFormat {
+            reason: format!("unsupported bulk file {:?}. Expected a .json or .html file", path),
+        }),
+    }
+}
+
+#[test]
+fn test_item_failed_tiles_log() {
+    let args = Arguments { failed_tiles_log: Some(PathBuf::from("logs/failed.json")), ..Arguments::default() };
+    let item = BulkItem { url: "http://example.com/info.json".into(), ..BulkItem::default() };
+    assert_eq!(item.arguments(&args, 3).failed_tiles_log, Some(PathBuf::from("logs/failed_3.json")));
+    assert_eq!(item.arguments(&Arguments::default(), 3).failed_tiles_log, None);
+}
+
+#[test]
+fn test_bulk_stats() {
+    let mut stats = BulkStats::default();
+    stats.record(&Ok(()));
+    stats.record::<()>(&Err(ZoomError::ImageTooSmall { width: 100, height: 100, min_width: 2000, min_height: 2000 }));
+    stats.record::<()>(&Err(ZoomError::NoTile));
+    assert_eq!(stats, BulkStats { saved: 1, skipped: 1, failed: 1 });
+    assert_eq!(stats.to_string(), "1 images saved, 1 skipped, 1 failed");
+}
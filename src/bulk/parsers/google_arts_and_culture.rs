@@ -0,0 +1,35 @@
+//! A Google Arts & Culture page that lists several artworks, such as the page of a collection,
+//! of a partner institution, or of an exhibit, saved as an HTML file.
+//! Every artwork linked from the page is downloaded.
+
+use crate::ZoomError;
+use crate::bulk::BulkItem;
+use crate::google_arts_and_culture::asset_urls;
+
+pub fn parse(contents: &[u8]) -> Result<Vec<BulkItem>, ZoomError> {
+    let page = String::from_utf8_lossy(contents);
+    let items: Vec<BulkItem> = asset_urls(&page).into_iter()
+        .map(|url| BulkItem { url, ..BulkItem::default() })
+        .collect();
+    if items.is_empty() {
+        return Err(ZoomError::BulkFormat {
+            reason: "the page does not link to any Google Arts & Culture artwork".into()
+        });
+    }
+    Ok(items)
+}
+
+#[test]
+fn test_parse_collection_page() {
+    let page = br#"<html><body>
+        <a href="/asset/the-starry-night/bgEuwDxel93-Pg">The Starry Night</a>
+        <a href="https://artsandculture.google.com/asset/the-starry-night/bgEuwDxel93-Pg?hl=en">Again</a>
+        <script>window.INIT_data = ["https:\/\/artsandculture.google.com\/asset\/irises\/YAHz2B4_fjDyxQ"];</script>
+    </body></html>"#;
+    let urls: Vec<String> = parse(page).unwrap().into_iter().map(|item| item.url).collect();
+    assert_eq!(urls, vec![
+        "https://artsandculture.google.com/asset/the-starry-night/bgEuwDxel93-Pg",
+        "https://artsandculture.google.com/asset/irises/YAHz2B4_fjDyxQ",
+    ]);
+    assert!(parse(b"<html></html>").is_err());
+}
@@ -0,0 +1,82 @@
+//! A IIIF presentation manifest, such as the ones of digitized books: each of its canvases
+//! is downloaded as a separate image. The images are named after the position and the label of their canvas.
+//! A range of the table of contents of the manifest can be selected with `--range`,
+//! to download only the canvases of a chapter.
+
+use std::path::PathBuf;
+
+use log::warn;
+use sanitize_filename_reader_friendly::sanitize;
+
+use crate::ZoomError;
+use crate::bulk::BulkItem;
+use crate::iiif::manifest_types::{Canvas, Manifest};
+use crate::iiif::tile_info::text_of;
+
+/// Whether a JSON file contains a single object, as manifests do, instead of a list of images
+pub fn is_manifest(contents: &[u8]) -> bool {
+    contents.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{')
+}
+
+fn output_name(position: usize, canvas: &Canvas) -> PathBuf {
+    let name = match text_of(&canvas.label) {
+        // Dots would be taken for the start of a file extension
+        Some(label) => format!("{:04} {}", position, sanitize(&label.replace('.', " "))),
+        None => format!("{:04}", position),
+    };
+    PathBuf::from(name.trim())
+}
+
+pub fn parse(contents: &[u8], range: Option<&str>) -> Result<Vec<BulkItem>, ZoomError> {
+    let manifest: Manifest = serde_json::from_slice(contents)
+        .map_err(|e| ZoomError::BulkFormat { reason: format!("invalid IIIF manifest: {}", e) })?;
+    let range_canvases = match range {
+        Some(name) => {
+            let range = manifest.find_range(name).ok_or_else(|| ZoomError::BulkFormat {
+                reason: format!("the manifest has no range named '{}'. Its ranges are: {}",
+                                name, manifest.range_labels().collect::<Vec<_>>().join(", ")),
+            })?;
+            Some(manifest.range_canvases(range))
+        }
+        None => None,
+    };
+    let items: Vec<BulkItem> = manifest.canvases().enumerate()
+        .filter(|(_, canvas)| range_canvases.as_ref().is_none_or(|ids| ids.contains(&canvas.id)))
+        .filter_map(|(i, canvas)| match canvas.image_info_url() {
+            Some(url) => Some(BulkItem { url, output: Some(output_name(i + 1, canvas)), ..BulkItem::default() }),
+            None => {
+                warn!("The canvas '{}' has no IIIF image service, and cannot be downloaded", canvas.id);
+                None
+            }
+        })
+        .collect();
+    if items.is_empty() {
+        return Err(ZoomError::BulkFormat { reason: "the manifest does not contain any IIIF image".into() });
+    }
+    Ok(items)
+}
+
+#[test]
+fn test_parse_manifest() {
+    let manifest = br#"
+    {
+        "@context": "http://iiif.io/api/presentation/2/context.json",
+        "sequences": [{"canvases": [
+            {"@id": "c1", "label": "Cover", "images": [{"resource": {"service": {"@id": "https://example.com/1"}}}]},
+            {"@id": "c2", "label": "fol. 1r", "images": [{"resource": {"service": {"@id": "https://example.com/2"}}}]},
+            {"@id": "c3", "images": [{"resource": {"service": {"@id": "https://example.com/3"}}}]}
+        ]}],
+        "structures": [{"@id": "r1", "label": "Chapter 1", "canvases": ["c2", "c3"]}]
+    }"#;
+    assert!(is_manifest(manifest));
+    assert!(!is_manifest(b" [{\"url\": \"a\"}]"));
+    let items = parse(manifest, None).unwrap();
+    assert_eq!(items.len(), 3);
+    assert_eq!(items[0].url, "https://example.com/1/info.json");
+    assert_eq!(items[0].output, Some(PathBuf::from("0001 Cover")));
+    let items = parse(manifest, Some("Chapter 1")).unwrap();
+    let outputs: Vec<_> = items.iter().filter_map(|i| i.output.clone()).collect();
+    assert_eq!(outputs, vec![PathBuf::from("0002 fol 1r"), PathBuf::from("0003")]);
+    let err = parse(manifest, Some("Chapter 3")).unwrap_err();
+    assert!(err.to_string().contains("no range named 'Chapter 3'. Its ranges are: Chapter 1"), "{}", err);
+}
@@ -0,0 +1,93 @@
+//! A JSON array of images to download, with options for each of them:
+//!
+//! ```json
+//! [
+//!   {"url": "https://example.com/a/info.json", "output": "a.jpg", "zoom_level": "largest"},
+//!   {"url": "https://example.com/b.dzi", "headers": {"Referer": "https://example.com/"},
+//!    "zoom_level": "4000x4000", "crop": "0,0,2000,1000"}
+//! ]
+//! ```
+//!
+//! `zoom_level` is either "largest", a maximum size such as "4000x4000",
+//! or a number of levels below the largest one.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::{Region, Vec2d, ZoomError};
+use crate::bulk::{BulkItem, LevelSelection};
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct JsonItem {
+    url: String,
+    output: Option<PathBuf>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    zoom_level: Option<JsonLevel>,
+    crop: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum JsonLevel {
+    BelowMax(usize),
+    Name(String),
+}
+
+fn level_selection(level: JsonLevel) -> Result<LevelSelection, String> {
+    match level {
+        JsonLevel::BelowMax(n) => Ok(LevelSelection::BelowMax(n)),
+        JsonLevel::Name(name) if name == "largest" => Ok(LevelSelection::Largest),
+        JsonLevel::Name(size) => {
+            let (x, y) = size.split_once('x')
+                .and_then(|(x, y)| Some((x.trim().parse().ok()?, y.trim().parse().ok()?)))
+                .ok_or_else(|| format!(
+                    "invalid zoom_level '{}'. Expected 'largest', a size such as '4000x4000', or a number", size
+                ))?;
+            Ok(LevelSelection::MaxSize(Vec2d { x, y }))
+        }
+    }
+}
+
+fn bulk_item(item: JsonItem) -> Result<BulkItem, String> {
+    Ok(BulkItem {
+        url: item.url,
+        output: item.output,
+        headers: item.headers,
+        zoom_level: item.zoom_level.map(level_selection).transpose()?,
+        crop: item.crop.as_deref().map(Region::parse).transpose()?,
+    })
+}
+
+pub fn parse(contents: &[u8]) -> Result<Vec<BulkItem>, ZoomError> {
+    let items: Vec<JsonItem> = serde_json::from_slice(contents)
+        .map_err(|e| ZoomError::BulkFormat { reason: e.to_string() })?;
+    items.into_iter().enumerate()
+        .map(|(i, item)| bulk_item(item).map_err(|reason| ZoomError::BulkFormat {
+            reason: format!("item {}: {}", i + 1, reason),
+        }))
+        .collect()
+}
+
+#[test]
+fn test_parse_json_list() {
+    let items = parse(br#"[
+        {"url": "https://example.com/a/info.json", "output": "a.jpg", "zoom_level": "largest"},
+        {"url": "https://example.com/b.dzi", "headers": {"Referer": "https://example.com/"},
+         "zoom_level": "4000x3000", "crop": "0,0,2000,1000"},
+        {"url": "https://example.com/c.dzi", "zoom_level": 1}
+    ]"#).unwrap();
+    assert_eq!(items[0].output, Some(PathBuf::from("a.jpg")));
+    assert_eq!(items[0].zoom_level, Some(LevelSelection::Largest));
+    assert_eq!(items[1].headers.get("Referer").map(String::as_str), Some("https://example.com/"));
+    assert_eq!(items[1].zoom_level, Some(LevelSelection::MaxSize(Vec2d { x: 4000, y: 3000 })));
+    assert_eq!(items[1].crop, Some(Region { position: Vec2d::default(), size: Vec2d { x: 2000, y: 1000 } }));
+    assert_eq!(items[2].zoom_level, Some(LevelSelection::BelowMax(1)));
+
+    let err = parse(br#"[{"url": "a"}, {"url": "b", "zoom_level": "huge"}]"#).unwrap_err();
+    assert!(err.to_string().contains("item 2: invalid zoom_level 'huge'"), "{}", err);
+    assert!(parse(br#"[{"url": "a", "outfile": "a.png"}]"#).is_err());
+}
@@ -0,0 +1,5 @@
+//! Parsers for the files that list the images to download in bulk mode
+
+pub mod json_list;
+pub mod iiif_manifest;
+pub mod google_arts_and_culture;
@@ -0,0 +1,125 @@
+//! A lightweight PDF "reading copy" of a bulk download, such as a book from a IIIF manifest,
+//! to which a downscaled version of each image is added as soon as it is saved.
+
+use std::path::{Path, PathBuf};
+
+use image::GenericImageView;
+use log::info;
+use pdf_writer::{Content, Filter, Finish, Name, Pdf, Rect, Ref};
+
+use crate::ZoomError;
+
+/// The largest width and height of the pages of the reading copy, in pixels
+const MAX_PAGE_SIZE: u32 = 1600;
+/// The JPEG quality of the pages of the reading copy
+const PAGE_QUALITY: u8 = 75;
+/// The resolution at which the pages are displayed, in pixels per inch
+const PAGE_DPI: f32 = 150.;
+
+/// A PDF document with one page per image of a bulk download
+pub struct ReadingCopy {
+    path: PathBuf,
+    pdf: Pdf,
+    catalog_id: Ref,
+    pages_id: Ref,
+    pages: Vec<Ref>,
+    next_id: Ref,
+}
+
+impl ReadingCopy {
+    /// Checks that the document can be written to `path`, and starts it
+    pub fn create(path: &Path) -> Result<Self, ZoomError> {
+        std::fs::File::create(path)?;
+        let mut ids = (1..).map(Ref::new);
+        Ok(ReadingCopy {
+            path: path.to_path_buf(),
+            pdf: Pdf::new(),
+            catalog_id: ids.next().unwrap(),
+            pages_id: ids.next().unwrap(),
+            pages: vec![],
+            next_id: ids.next().unwrap(),
+        })
+    }
+
+    fn new_id(&mut self) -> Ref {
+        let id = self.next_id;
+        self.next_id = Ref::new(id.get() + 1);
+        id
+    }
+
+    /// Adds a page with a downscaled version of the image saved to `saved`
+    pub fn add(&mut self, saved: &Path) -> Result<(), ZoomError> {
+        let image = image::open(saved)?;
+        let image = if image.width() > MAX_PAGE_SIZE || image.height() > MAX_PAGE_SIZE {
+            image.resize(MAX_PAGE_SIZE, MAX_PAGE_SIZE, image::imageops::FilterType::Triangle)
+        } else {
+            image
+        };
+        let image = image.into_rgb8();
+        let (width, height) = image.dimensions();
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, PAGE_QUALITY)
+            .encode(&image, width, height, image::ColorType::Rgb8)?;
+
+        let (page_id, image_id, content_id) = (self.new_id(), self.new_id(), self.new_id());
+        let image_name = Name(b"Page");
+        let (page_width, page_height) = (width as f32 * 72. / PAGE_DPI, height as f32 * 72. / PAGE_DPI);
+        let mut page = self.pdf.page(page_id);
+        page.media_box(Rect::new(0., 0., page_width, page_height));
+        page.parent(self.pages_id);
+        page.contents(content_id);
+        page.resources().x_objects().pair(image_name, image_id);
+        page.finish();
+
+        let mut xobject = self.pdf.image_xobject(image_id, &jpeg);
+        xobject.filter(Filter::DctDecode);
+        xobject.width(width as i32);
+        xobject.height(height as i32);
+        xobject.color_space().device_rgb();
+        xobject.bits_per_component(8);
+        xobject.finish();
+
+        let mut content = Content::new();
+        content.save_state();
+        content.transform([page_width, 0., 0., page_height, 0., 0.]);
+        content.x_object(image_name);
+        content.restore_state();
+        self.pdf.stream(content_id, &content.finish());
+
+        self.pages.push(page_id);
+        info!("Added {:?} to the reading copy {:?} as page {}", saved, self.path, self.pages.len());
+        Ok(())
+    }
+
+    /// Writes the document, and returns its path
+    pub fn finish(mut self) -> Result<PathBuf, ZoomError> {
+        self.pdf.catalog(self.catalog_id).pages(self.pages_id);
+        let count = self.pages.len() as i32;
+        self.pdf.pages(self.pages_id).kids(self.pages).count(count);
+        std::fs::write(&self.path, self.pdf.finish())?;
+        Ok(self.path)
+    }
+}
+
+#[test]
+fn test_reading_copy() {
+    let dir = tempdir::TempDir::new("dezoomify-rs-reading-copy").unwrap();
+    let pdf_path = dir.path().join("book.pdf");
+    let mut reading_copy = ReadingCopy::create(&pdf_path).unwrap();
+    for (name, size) in [("small.png", 100), ("large.jpg", 2000)].iter() {
+        let page = dir.path().join(name);
+        image::RgbImage::new(*size, *size / 2).save(&page).unwrap();
+        reading_copy.add(&page).unwrap();
+        assert!(page.exists(), "the saved images are kept");
+    }
+    assert_eq!(reading_copy.finish().unwrap(), pdf_path);
+
+    let pdf = std::fs::read(&pdf_path).unwrap();
+    let pdf = String::from_utf8_lossy(&pdf);
+    assert!(pdf.starts_with("%PDF-"));
+    assert!(pdf.contains("/Count 2"));
+    // The large image is downscaled, and the small one is left as it is
+    assert!(pdf.contains("/Width 1600"));
+    assert!(pdf.contains("/Height 800"));
+    assert!(pdf.contains("/Width 100"));
+}
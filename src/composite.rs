@@ -0,0 +1,191 @@
+//! Assembling a single image from several zoomable images, with `--composite`.
+//! Some maps are published as separate zoomable images, such as one for each quadrant.
+//! A YAML file lists them with their position in the final image.
+//! Each one is downloaded to a temporary file, and copied into the final image,
+//! which is assembled in memory.
+
+use std::collections::BTreeMap;
+use std::env::current_dir;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::{DynamicImage, GenericImageView};
+use log::{info, warn};
+use serde::Deserialize;
+
+use crate::{Arguments, Vec2d, ZoomError};
+use crate::output_file::get_outname;
+use crate::retry_failed::patch;
+
+/// The contents of a composite file
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct Composite {
+    /// The name of the final image, when no output file is given
+    #[serde(default)]
+    pub title: Option<String>,
+    pub parts: Vec<Part>,
+}
+
+/// One of the images that make up a composite image
+#[derive(Deserialize, Debug, PartialEq)]
+pub struct Part {
+    /// The URL of the zoomable image, as given on the command line
+    pub url: String,
+    /// Position of the top left corner of the image in the final image, in pixels
+    #[serde(default)]
+    pub x: u32,
+    #[serde(default)]
+    pub y: u32,
+    /// The dezoomer to use for this image, instead of the one given on the command line
+    #[serde(default)]
+    pub dezoomer: Option<String>,
+    /// Headers to add to the requests made for this image
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Composite {
+    pub fn load(path: &Path) -> Result<Self, ZoomError> {
+        let invalid = |reason: String| ZoomError::Composite { path: path.display().to_string(), reason };
+        let composite: Composite = serde_yaml::from_slice(&fs::read(path)?)
+            .map_err(|e| invalid(e.to_string()))?;
+        if composite.parts.is_empty() {
+            return Err(invalid("no image is listed in 'parts'".into()));
+        }
+        Ok(composite)
+    }
+}
+
+impl Part {
+    /// The arguments to download this image with
+    fn arguments(&self, args: &Arguments, outfile: PathBuf) -> Arguments {
+        let mut part_args = args.clone();
+        part_args.composite = None;
+        part_args.input_uri = Some(self.url.clone());
+        part_args.outfile = Some(outfile);
+        part_args.out_dir = None;
+        part_args.output_template = None;
+        part_args.export_format = None;
+        if let Some(dezoomer) = &self.dezoomer { part_args.dezoomer = dezoomer.clone(); }
+        part_args.headers.extend(self.headers.iter().map(|(k, v)| (k.clone(), v.clone())));
+        part_args
+    }
+}
+
+/// Downloads all the images listed in a composite file, and saves them as a single image
+pub async fn composite(path: &Path, args: &Arguments) -> Result<PathBuf, ZoomError> {
+    let composite = Composite::load(path)?;
+    let tmp_path = |i: usize| std::env::temp_dir()
+        .join(format!("dezoomify-rs-composite-{}-{}.png", std::process::id(), i));
+    let mut images = Vec::with_capacity(composite.parts.len());
+    let mut partial = None;
+    let mut result = Ok(());
+    for (i, part) in composite.parts.iter().enumerate() {
+        info!("Downloading image {} of {}: {}", i + 1, composite.parts.len(), part.url);
+        let part_args = part.arguments(args, tmp_path(i));
+        // Boxed, because downloading an image may itself require downloading a composite image
+        let saved = match Box::pin(crate::download::dezoomify_image(&part_args)).await {
+            Ok(saved) => saved,
+            Err(err @ ZoomError::PartialDownload { .. }) => {
+                warn!("Some tiles of {} are missing: {}", part.url, err);
+                partial = Some(err);
+                tmp_path(i)
+            }
+            Err(err) => {
+                result = Err(err);
+                break;
+            }
+        };
+        match image::open(&saved) {
+            Ok(image) => images.push((Vec2d { x: part.x, y: part.y }, image)),
+            Err(err) => {
+                result = Err(err.into());
+                break;
+            }
+        }
+    }
+    for i in 0..composite.parts.len() {
+        let _ = fs::remove_file(tmp_path(i));
+    }
+    result?;
+
+    let image = stitch(&images);
+    let base_dir = match &args.out_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            dir.clone()
+        }
+        None => current_dir()?,
+    };
+    // No input URL is needed, so a single positional argument is the output file
+    let outfile = args.outfile.clone().or_else(|| args.input_uri.as_ref().map(PathBuf::from));
+    let outfile = match &args.out_dir {
+        Some(dir) => outfile.map(|path| dir.join(path)),
+        None => outfile,
+    };
+    let size = Vec2d { x: image.width(), y: image.height() };
+    let title = composite.title.or_else(|| Some("composite".into()));
+    let outname = get_outname(&outfile, &title, &base_dir, Some(size), None);
+    info!("Saving the {} images as a single image of size {} to {:?}", images.len(), size, outname);
+    image.save(&outname)?;
+    match partial {
+        Some(err) => Err(err),
+        None => Ok(outname),
+    }
+}
+
+/// Copies the images to their position in a single image, just large enough to contain all of them.
+/// The image has an alpha channel only if one of the images has one.
+fn stitch(images: &[(Vec2d, DynamicImage)]) -> DynamicImage {
+    let size = images.iter()
+        .map(|(position, image)| *position + Vec2d { x: image.width(), y: image.height() })
+        .fold(Vec2d::default(), Vec2d::max);
+    let mut result = if images.iter().any(|(_, image)| image.color().has_alpha()) {
+        DynamicImage::new_rgba8(size.x, size.y)
+    } else {
+        DynamicImage::new_rgb8(size.x, size.y)
+    };
+    for (position, image) in images {
+        patch(&mut result, image, *position);
+    }
+    result
+}
+
+#[test]
+fn test_composite_file() {
+    let yaml = "
+title: Map of the world
+parts:
+  - url: http://example.com/west/info.json
+  - url: http://example.com/east/info.json
+    x: 1000
+    dezoomer: iiif
+    headers:
+      Referer: http://example.com/
+";
+    let composite: Composite = serde_yaml::from_str(yaml).unwrap();
+    assert_eq!(composite.title.as_deref(), Some("Map of the world"));
+    assert_eq!(composite.parts[0].x, 0);
+    assert_eq!(composite.parts[1], Part {
+        url: "http://example.com/east/info.json".into(),
+        x: 1000,
+        y: 0,
+        dezoomer: Some("iiif".into()),
+        headers: vec![("Referer".to_string(), "http://example.com/".to_string())].into_iter().collect(),
+    });
+    let args = composite.parts[1].arguments(&Arguments::default(), "part.png".into());
+    assert_eq!(args.input_uri.as_deref(), Some("http://example.com/east/info.json"));
+    assert_eq!(args.dezoomer, "iiif");
+}
+
+#[test]
+fn test_stitch() {
+    let red = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(2, 3, image::Rgb([255, 0, 0])));
+    let blue = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(2, 2, image::Rgb([0, 0, 255])));
+    let image = stitch(&[(Vec2d { x: 0, y: 0 }, red), (Vec2d { x: 2, y: 1 }, blue)]);
+    assert!(matches!(image, DynamicImage::ImageRgb8(_)));
+    assert_eq!((image.width(), image.height()), (4, 3));
+    assert_eq!(image.get_pixel(1, 2), image::Rgba([255, 0, 0, 255]));
+    assert_eq!(image.get_pixel(3, 2), image::Rgba([0, 0, 255, 255]));
+    assert_eq!(image.get_pixel(3, 0), image::Rgba([0, 0, 0, 255]));
+}
@@ -0,0 +1,246 @@
+//! A cookie store shared by all the HTTP clients used while dezoomifying an image,
+//! that can be loaded from and saved to a Netscape-format cookie file (as used by curl and wget).
+
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use log::debug;
+use reqwest::cookie::CookieStore;
+use reqwest::header::HeaderValue;
+use url::Url;
+
+use crate::ZoomError;
+
+lazy_static! {
+    static ref COOKIE_JAR: RwLock<Arc<CookieJar>> = RwLock::new(Arc::new(CookieJar::default()));
+}
+
+/// Replace the cookie jar used by new HTTP clients, and return it
+pub fn set_cookie_jar(jar: CookieJar) -> Arc<CookieJar> {
+    let jar = Arc::new(jar);
+    *COOKIE_JAR.write().unwrap() = Arc::clone(&jar);
+    jar
+}
+
+/// The cookie jar to use for new HTTP clients
+pub fn cookie_jar() -> Arc<CookieJar> {
+    Arc::clone(&COOKIE_JAR.read().unwrap())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct StoredCookie {
+    domain: String,
+    include_subdomains: bool,
+    path: String,
+    secure: bool,
+    /// Expiration date as a unix timestamp, or 0 for session cookies
+    expires: i64,
+    name: String,
+    value: String,
+}
+
+impl StoredCookie {
+    fn matches(&self, url: &Url, now: i64) -> bool {
+        let host = url.host_str().unwrap_or_default();
+        let domain_matches = host == self.domain || (self.include_subdomains &&
+            host.ends_with(&self.domain) &&
+            host[..host.len() - self.domain.len()].ends_with('.'));
+        let path = url.path();
+        let path_matches = path.starts_with(&self.path) && (
+            self.path.ends_with('/') || path.len() == self.path.len() ||
+                path[self.path.len()..].starts_with('/'));
+        domain_matches && path_matches &&
+            (!self.secure || url.scheme() == "https") &&
+            !self.is_expired(now)
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+
+    fn same_key(&self, other: &StoredCookie) -> bool {
+        self.domain == other.domain && self.path == other.path && self.name == other.name
+    }
+
+    /// Parse a Set-Cookie header received from the given URL
+    fn from_set_cookie(header: &str, url: &Url, now: i64) -> Option<StoredCookie> {
+        let cookie = cookie::Cookie::parse(header).ok()?;
+        let host = url.host_str()?;
+        let (domain, include_subdomains) = match cookie.domain() {
+            Some(domain) => (domain.trim_start_matches('.').to_ascii_lowercase(), true),
+            None => (host.to_string(), false),
+        };
+        // A cookie that expires in the past replaces the stored one, which deletes it
+        let expires = match (cookie.max_age(), cookie.expires()) {
+            (Some(max_age), _) => now + max_age.whole_seconds(),
+            (None, Some(date)) => date.unix_timestamp(),
+            (None, None) => 0,
+        };
+        let path = cookie.path().filter(|p| p.starts_with('/')).map(String::from)
+            .unwrap_or_else(|| default_path(url));
+        Some(StoredCookie {
+            domain,
+            include_subdomains,
+            path,
+            secure: cookie.secure().unwrap_or(false),
+            expires,
+            name: cookie.name().to_string(),
+            value: cookie.value().to_string(),
+        })
+    }
+
+    fn from_netscape_line(line: &str) -> Option<StoredCookie> {
+        // curl marks HttpOnly cookies with a prefix instead of a comment
+        let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+        if line.starts_with('#') || line.trim().is_empty() { return None; }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if let [domain, include_subdomains, path, secure, expires, name, value] = fields[..] {
+            Some(StoredCookie {
+                domain: domain.trim_start_matches('.').to_string(),
+                include_subdomains: include_subdomains.eq_ignore_ascii_case("TRUE"),
+                path: path.to_string(),
+                secure: secure.eq_ignore_ascii_case("TRUE"),
+                expires: expires.parse().ok()?,
+                name: name.to_string(),
+                value: value.trim_end_matches('\r').to_string(),
+            })
+        } else {
+            None
+        }
+    }
+
+    fn to_netscape_line(&self) -> String {
+        let bool_str = |b: bool| if b { "TRUE" } else { "FALSE" };
+        let domain_prefix = if self.include_subdomains { "." } else { "" };
+        format!("{}{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                domain_prefix, self.domain, bool_str(self.include_subdomains),
+                self.path, bool_str(self.secure), self.expires, self.name, self.value)
+    }
+}
+
+/// The directory of the path of an URL, used when a cookie does not specify a path
+fn default_path(url: &Url) -> String {
+    let path = url.path();
+    match path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(i) => path[..i].to_string(),
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or_default()
+}
+
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: RwLock<Vec<StoredCookie>>,
+}
+
+impl CookieJar {
+    /// Read a Netscape-format cookie file
+    pub fn load(path: &Path) -> Result<Self, ZoomError> {
+        let contents = std::fs::read_to_string(path)?;
+        let cookies: Vec<StoredCookie> = contents.lines()
+            .filter_map(StoredCookie::from_netscape_line)
+            .collect();
+        debug!("Loaded {} cookies from {:?}", cookies.len(), path);
+        Ok(CookieJar { cookies: RwLock::new(cookies) })
+    }
+
+    /// Write all the cookies that have not expired to a Netscape-format cookie file
+    pub fn save(&self, path: &Path) -> Result<(), ZoomError> {
+        let now = now();
+        let mut contents = String::from("# Netscape HTTP Cookie File\n");
+        for cookie in self.cookies.read().unwrap().iter().filter(|c| !c.is_expired(now)) {
+            writeln!(contents, "{}", cookie.to_netscape_line()).unwrap();
+        }
+        std::fs::write(path, contents)?;
+        Ok(())
+    }
+
+    fn add(&self, cookie: StoredCookie) {
+        let mut cookies = self.cookies.write().unwrap();
+        cookies.retain(|c| !c.same_key(&cookie));
+        cookies.push(cookie);
+    }
+
+    fn header_value(&self, url: &Url, now: i64) -> Option<HeaderValue> {
+        let cookies = self.cookies.read().unwrap();
+        let header = cookies.iter()
+            .filter(|c| c.matches(url, now))
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if header.is_empty() { None } else { HeaderValue::from_str(&header).ok() }
+    }
+}
+
+impl CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item=&HeaderValue>, url: &Url) {
+        let now = now();
+        for header in cookie_headers {
+            let parsed = header.to_str().ok()
+                .and_then(|h| StoredCookie::from_set_cookie(h, url, now));
+            if let Some(cookie) = parsed {
+                debug!("Storing cookie {}={} for {}", cookie.name, cookie.value, cookie.domain);
+                self.add(cookie);
+            }
+        }
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        self.header_value(url, now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(s: &str) -> Url { Url::parse(s).unwrap() }
+
+    #[test]
+    fn test_set_cookie() {
+        let jar = CookieJar::default();
+        let origin = url("https://www.example.com/viewer/page.html");
+        let headers = [
+            HeaderValue::from_static("session=abc; Path=/; Domain=example.com"),
+            HeaderValue::from_static("token=xyz"),
+            HeaderValue::from_static("old=1; Max-Age=0"),
+        ];
+        jar.set_cookies(&mut headers.iter(), &origin);
+        let now = now();
+        let header = |u: &str| jar.header_value(&url(u), now).map(|h| h.to_str().unwrap().to_string());
+        assert_eq!(header("https://tiles.example.com/0/0.jpg").as_deref(), Some("session=abc"));
+        assert_eq!(header("https://www.example.com/viewer/tile.jpg").as_deref(), Some("session=abc; token=xyz"));
+        assert_eq!(header("https://www.example.com/other").as_deref(), Some("session=abc"));
+        assert_eq!(header("https://notexample.com/"), None);
+    }
+
+    #[test]
+    fn test_netscape_format() {
+        let line = ".example.com\tTRUE\t/\tFALSE\t2000000000\tsession\tabc";
+        let cookie = StoredCookie::from_netscape_line(line).unwrap();
+        assert_eq!(cookie.domain, "example.com");
+        assert!(cookie.include_subdomains);
+        assert_eq!(cookie.expires, 2000000000);
+        assert_eq!(cookie.to_netscape_line(), line);
+        assert!(StoredCookie::from_netscape_line("# a comment").is_none());
+        let http_only = "#HttpOnly_example.com\tFALSE\t/a\tTRUE\t0\tx\ty";
+        assert_eq!(StoredCookie::from_netscape_line(http_only).unwrap().path, "/a");
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempdir::TempDir::new("dezoomify-rs-cookies").unwrap();
+        let path = dir.path().join("cookies.txt");
+        let jar = CookieJar::default();
+        jar.set_cookies(&mut std::iter::once(&HeaderValue::from_static("a=b")), &url("http://x.com/"));
+        jar.save(&path).unwrap();
+        let loaded = CookieJar::load(&path).unwrap();
+        assert_eq!(*loaded.cookies.read().unwrap(), *jar.cookies.read().unwrap());
+    }
+}
@@ -0,0 +1,191 @@
+//! Downloading only a part of an image, with `--crop x,y,width,height`.
+//! Only the tiles that intersect the region are requested, and they are cut to fit inside it.
+//! Also removing the borders of the tiles, with `--tile-crop top,right,bottom,left`.
+
+use std::collections::BTreeSet;
+
+use crate::dezoomer::TileReference;
+use crate::tile::Tile;
+use crate::Vec2d;
+
+/// A rectangular region of an image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Region {
+    pub position: Vec2d,
+    pub size: Vec2d,
+}
+
+impl Region {
+    /// Parses a region given as `x,y,width,height`
+    pub fn parse(s: &str) -> Result<Region, &'static str> {
+        let err_msg = "Invalid region. A region is given as x,y,width,height, such as '1000,2000,4096,4096'";
+        let values = s.split(',')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| err_msg)?;
+        match values[..] {
+            [x, y, w, h] if w > 0 && h > 0 => Ok(Region { position: Vec2d { x, y }, size: Vec2d { x: w, y: h } }),
+            _ => Err(err_msg),
+        }
+    }
+
+    pub fn bottom_right(&self) -> Vec2d {
+        self.position + self.size
+    }
+
+    /// The part of this region that is inside the given rectangle, if any
+    pub fn intersection(&self, position: Vec2d, size: Vec2d) -> Option<Region> {
+        let start = self.position.max(position);
+        let end = self.bottom_right().min(position + size);
+        if start.x < end.x && start.y < end.y {
+            Some(Region { position: start, size: end - start })
+        } else {
+            None
+        }
+    }
+
+    /// Keeps only the tiles that intersect the region. The extent of the tiles whose size
+    /// is unknown goes until the next tile in the grid, or the edge of the image.
+    pub fn tiles_inside(&self, tile_refs: Vec<TileReference>, image_size: Vec2d) -> Vec<TileReference> {
+        let xs: BTreeSet<u32> = tile_refs.iter().map(|t| t.position.x).collect();
+        let ys: BTreeSet<u32> = tile_refs.iter().map(|t| t.position.y).collect();
+        let next = |starts: &BTreeSet<u32>, start: u32, edge: u32| {
+            starts.range(start + 1..).next().copied().unwrap_or(edge)
+        };
+        tile_refs.into_iter().filter(|t| {
+            let Vec2d { x, y } = t.position;
+            let size = t.size.unwrap_or_else(|| {
+                Vec2d { x: next(&xs, x, image_size.x), y: next(&ys, y, image_size.y) } - t.position
+            });
+            self.intersection(t.position, size).is_some()
+        }).collect()
+    }
+
+    /// Cuts the part of the tile that is inside the region,
+    /// and moves it relatively to the top left corner of the region
+    pub fn crop_tile(&self, tile: Tile) -> Option<Tile> {
+        let Region { position, size } = self.intersection(tile.position, tile.size())?;
+        let Vec2d { x, y } = position - tile.position;
+        let position = position - self.position;
+        if size == tile.size() {
+            return Some(Tile { position, ..tile });
+        }
+        Some(Tile { image: tile.image.crop_imm(x, y, size.x, size.y), position, encoded: None })
+    }
+}
+
+impl std::str::FromStr for Region {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> { Region::parse(s) }
+}
+
+/// A number of pixels to remove from each side of the tiles, such as a border that overlaps their neighbors
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TileCrop {
+    pub top: u32,
+    pub right: u32,
+    pub bottom: u32,
+    pub left: u32,
+}
+
+impl TileCrop {
+    pub fn uniform(pixels: u32) -> TileCrop {
+        TileCrop { top: pixels, right: pixels, bottom: pixels, left: pixels }
+    }
+
+    /// Parses a crop given as `top,right,bottom,left`, or as a single value for all the sides
+    pub fn parse(s: &str) -> Result<TileCrop, &'static str> {
+        let err_msg = "Invalid tile crop. It is given as top,right,bottom,left, such as '0,0,20,0', \
+                       or as a single number of pixels for all the sides";
+        let values = s.split(',')
+            .map(|v| v.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| err_msg)?;
+        match values[..] {
+            [pixels] => Ok(TileCrop::uniform(pixels)),
+            [top, right, bottom, left] => Ok(TileCrop { top, right, bottom, left }),
+            _ => Err(err_msg),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == TileCrop::default()
+    }
+
+    /// Removes the sides of the tile that are inside the image, and moves what remains of the tile
+    /// so that it stays at the same place in the image. The sides on the edges of the image are kept,
+    /// because no other tile covers them. When the size of the image is unknown,
+    /// the right and bottom sides are always removed.
+    pub fn crop_tile(&self, tile: Tile, image_size: Option<Vec2d>) -> Tile {
+        let size = tile.size();
+        let end = tile.position + size;
+        let on_edge = |end: u32, edge: Option<u32>| edge.is_some_and(|edge| end >= edge);
+        let left = if tile.position.x == 0 { 0 } else { self.left };
+        let top = if tile.position.y == 0 { 0 } else { self.top };
+        let right = if on_edge(end.x, image_size.map(|s| s.x)) { 0 } else { self.right };
+        let bottom = if on_edge(end.y, image_size.map(|s| s.y)) { 0 } else { self.bottom };
+        if (left == 0 && top == 0 && right == 0 && bottom == 0) || left + right >= size.x || top + bottom >= size.y {
+            return tile;
+        }
+        let cropped = Vec2d { x: size.x - left - right, y: size.y - top - bottom };
+        Tile {
+            image: tile.image.crop_imm(left, top, cropped.x, cropped.y),
+            position: tile.position + Vec2d { x: left, y: top },
+            encoded: None,
+        }
+    }
+}
+
+impl std::str::FromStr for TileCrop {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> { TileCrop::parse(s) }
+}
+
+#[test]
+fn test_parse_region() {
+    assert_eq!(Region::parse("1000,2000, 4096,4096"), Ok(Region {
+        position: Vec2d { x: 1000, y: 2000 },
+        size: Vec2d::square(4096),
+    }));
+    assert!(Region::parse("1000,2000,4096").is_err());
+    assert!(Region::parse("0,0,0,10").is_err());
+}
+
+#[test]
+fn test_crop_tiles() {
+    let region = Region { position: Vec2d { x: 300, y: 100 }, size: Vec2d { x: 300, y: 100 } };
+    let tile_refs: Vec<TileReference> = (0..3).flat_map(|y| (0..3).map(move |x| TileReference {
+        url: format!("{}_{}", x, y),
+        position: Vec2d { x, y } * 256,
+        size: None,
+        request: None,
+    })).collect();
+    let urls: Vec<String> = region.tiles_inside(tile_refs, Vec2d::square(700))
+        .into_iter().map(|t| t.url).collect();
+    assert_eq!(urls, vec!["1_0", "2_0"]);
+
+    let tile = region.crop_tile(Tile::empty(Vec2d { x: 512, y: 0 }, Vec2d { x: 188, y: 256 })).unwrap();
+    assert_eq!(tile.position, Vec2d { x: 212, y: 0 });
+    assert_eq!(tile.size(), Vec2d { x: 88, y: 100 });
+    assert!(region.crop_tile(Tile::empty(Vec2d::default(), Vec2d::square(256))).is_none());
+}
+
+#[test]
+fn test_tile_crop() {
+    assert_eq!(TileCrop::parse("1, 2,3,4"), Ok(TileCrop { top: 1, right: 2, bottom: 3, left: 4 }));
+    assert_eq!(TileCrop::parse("2"), Ok(TileCrop::uniform(2)));
+    assert!(TileCrop::parse("1,2").is_err());
+
+    // A tile in the middle of an image, with a border of 2 pixels that overlaps its neighbors
+    let crop = TileCrop::uniform(2);
+    let image_size = Vec2d::square(1000);
+    let tile = crop.crop_tile(Tile::empty(Vec2d { x: 254, y: 254 }, Vec2d::square(260)), Some(image_size));
+    assert_eq!((tile.position, tile.size()), (Vec2d::square(256), Vec2d::square(256)));
+    // The sides on the edges of the image are kept
+    let tile = crop.crop_tile(Tile::empty(Vec2d { x: 0, y: 766 }, Vec2d { x: 258, y: 234 }), Some(image_size));
+    assert_eq!((tile.position, tile.size()), (Vec2d { x: 0, y: 768 }, Vec2d { x: 256, y: 232 }));
+    let tile = crop.crop_tile(Tile::empty(Vec2d::square(10), Vec2d::square(3)), Some(image_size));
+    assert_eq!(tile.size(), Vec2d::square(3), "tiles smaller than the crop are kept");
+}
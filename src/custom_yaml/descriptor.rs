@@ -0,0 +1,304 @@
+//! Dezoomer descriptors: YAML or JSON files, dropped in a configuration directory,
+//! that teach dezoomify-rs how to download the images of a simple site without recompiling it.
+//!
+//! ```yaml
+//! name: example-museum
+//! # Displayed by --list-dezoomers
+//! description: 'Paintings of the Example Museum'
+//! # The addresses of the pages this descriptor applies to. Named groups become variables.
+//! url_pattern: 'https://museum\.example/objects/(?P<id>\d+)'
+//! # The file to read more variables from. By default, they are read from the page itself.
+//! metadata_url: 'https://museum.example/api/objects/${id}.json'
+//! extract:
+//!   - { name: width, json: image.width }
+//!   - { name: height, regex: '"height":\s*(\d+)' }
+//! # A tiles.yaml document, in which ${variables} are replaced
+//! tiles:
+//!   url_template: 'https://museum.example/tiles/${id}/{{x}}_{{y}}.jpg'
+//!   ...
+//! ```
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use regex::Regex;
+use serde::Deserialize;
+use serde_yaml::Value;
+
+use custom_error::custom_error;
+
+use crate::dezoomer::*;
+
+use super::validate;
+
+#[derive(Deserialize, Debug)]
+pub struct Descriptor {
+    name: String,
+    #[serde(default)]
+    description: String,
+    #[serde(with = "serde_regex")]
+    url_pattern: Regex,
+    metadata_url: Option<String>,
+    #[serde(default)]
+    extract: Vec<Extraction>,
+    tiles: Value,
+}
+
+/// A variable read from the metadata file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Extraction {
+    name: String,
+    /// The first group of this regular expression, or the whole match if it has no group
+    #[serde(default, with = "serde_regex")]
+    regex: Option<Regex>,
+    /// A path in a JSON metadata file, with dot-separated keys and array indices
+    json: Option<String>,
+}
+
+/// The contents of a descriptor file: one descriptor, or a pack of several
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DescriptorFile {
+    Pack { dezoomers: Vec<Descriptor> },
+    Single(Box<Descriptor>),
+}
+
+custom_error! {pub DescriptorError
+    MissingVariable{name: String, uri: String} = "Unable to extract '{name}' from {uri}",
+    Yaml{source: serde_yaml::Error} = "Invalid tiles in the descriptor: {source}",
+    InvalidTiles{source: super::InvalidYaml} = "Invalid tiles in the descriptor:\n{source}",
+}
+
+mod serde_regex {
+    use regex::Regex;
+    use serde::{Deserialize, Deserializer};
+
+    pub fn deserialize<'d, D: Deserializer<'d>, T: From<Regex>>(de: D) -> Result<T, D::Error> {
+        let source = String::deserialize(de)?;
+        Regex::new(&source).map(T::from).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Extraction {
+    fn extract(&self, contents: &str) -> Option<String> {
+        if let Some(re) = &self.regex {
+            let captures = re.captures(contents)?;
+            return captures.get(1).or_else(|| captures.get(0)).map(|m| m.as_str().to_string());
+        }
+        let path = self.json.as_deref()?;
+        let json: serde_json::Value = serde_json::from_str(contents).ok()?;
+        let value = path.split('.').filter(|key| !key.is_empty()).try_fold(&json, |value, key| {
+            match key.parse::<usize>() {
+                Ok(index) if value.is_array() => value.get(index),
+                _ => value.get(key),
+            }
+        })?;
+        match value {
+            serde_json::Value::String(s) => Some(s.clone()),
+            serde_json::Value::Null => None,
+            other => Some(other.to_string()),
+        }
+    }
+}
+
+/// Replaces the `${variables}` in all the strings of a YAML document.
+/// A string that only contains a numeric variable becomes a number.
+fn substitute(value: &Value, variables: &HashMap<String, String>) -> Value {
+    match value {
+        Value::String(s) => {
+            let replaced = variables.iter()
+                .fold(s.clone(), |s, (name, v)| s.replace(&format!("${{{}}}", name), v));
+            let whole_variable = s.starts_with("${") && s.ends_with('}') && s.matches("${").count() == 1;
+            match replaced.parse::<u64>() {
+                Ok(n) if whole_variable => Value::Number(n.into()),
+                _ => Value::String(replaced),
+            }
+        }
+        Value::Sequence(items) => Value::Sequence(items.iter().map(|v| substitute(v, variables)).collect()),
+        Value::Mapping(map) => Value::Mapping(
+            map.iter().map(|(k, v)| (k.clone(), substitute(v, variables))).collect()
+        ),
+        other => other.clone(),
+    }
+}
+
+impl Descriptor {
+    /// The address of the file from which variables are extracted, if any
+    fn metadata_template(&self) -> Option<String> {
+        self.metadata_url.clone()
+            .or_else(|| if self.extract.is_empty() { None } else { Some("${url}".into()) })
+    }
+
+    fn levels(&self, variables: &HashMap<String, String>) -> Result<ZoomLevels, DescriptorError> {
+        let source = serde_yaml::to_string(&substitute(&self.tiles, variables))?;
+        let (levels, unknown_keys) = validate::parse(&source)?;
+        for problem in unknown_keys {
+            warn!("In the dezoomer descriptor '{}', {}", self.name, problem);
+        }
+        Ok(levels.into_iter().into_zoom_levels())
+    }
+}
+
+/// A dezoomer that follows the rules of a descriptor
+pub struct DescriptorDezoomer {
+    name: &'static str,
+    descriptor: Arc<Descriptor>,
+    /// The variables read from the input URL, while the metadata file is being downloaded
+    variables: Option<HashMap<String, String>>,
+}
+
+impl Dezoomer for DescriptorDezoomer {
+    fn name(&self) -> &'static str { self.name }
+
+    fn description(&self) -> &str { &self.descriptor.description }
+
+    fn url_patterns(&self) -> Vec<&str> { vec![self.descriptor.url_pattern.as_str()] }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let descriptor = Arc::clone(&self.descriptor);
+        let mut variables = match self.variables.take() {
+            Some(variables) => variables,
+            None => {
+                let captures = descriptor.url_pattern.captures(&data.uri).ok_or_else(|| self.wrong_dezoomer())?;
+                let mut variables: HashMap<String, String> = descriptor.url_pattern.capture_names()
+                    .flatten()
+                    .filter_map(|name| Some((name.to_string(), captures.name(name)?.as_str().to_string())))
+                    .collect();
+                variables.insert("url".into(), data.uri.clone());
+                if let Some(template) = descriptor.metadata_template() {
+                    let uri = substitute(&Value::String(template.clone()), &variables);
+                    let uri = uri.as_str().map(String::from).unwrap_or(template);
+                    self.variables = Some(variables);
+                    return Err(DezoomerError::NeedsData { uri });
+                }
+                variables
+            }
+        };
+        if descriptor.metadata_template().is_some() {
+            let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
+            let contents = String::from_utf8_lossy(contents);
+            for extraction in &descriptor.extract {
+                let value = extraction.extract(&contents).ok_or_else(|| DescriptorError::MissingVariable {
+                    name: extraction.name.clone(),
+                    uri: uri.to_string(),
+                }).map_err(DezoomerError::wrap)?;
+                variables.insert(extraction.name.clone(), value);
+            }
+        }
+        descriptor.levels(&variables).map_err(DezoomerError::wrap)
+    }
+}
+
+/// The directory of the descriptors, and the descriptors loaded from it with their names
+type LoadedDescriptors = (Option<PathBuf>, Vec<(&'static str, Arc<Descriptor>)>);
+
+lazy_static! {
+    static ref DESCRIPTORS: RwLock<LoadedDescriptors> =
+        RwLock::new((None, vec![]));
+}
+
+/// The directory in which descriptors are looked for when --dezoomers-dir is not given
+pub fn default_descriptor_dir() -> Option<PathBuf> {
+    let config = std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|| std::env::var_os("APPDATA").map(PathBuf::from))
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config.join("dezoomify-rs").join("dezoomers"))
+}
+
+/// Loads the descriptors of a directory. Invalid files are skipped with a warning.
+/// The names of the descriptors are leaked, because the names of dezoomers are static.
+fn load_dir(dir: &Path) -> Vec<(&'static str, Arc<Descriptor>)> {
+    let mut paths: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| Some(e.ok()?.path())).collect(),
+        Err(e) => {
+            debug!("No dezoomer descriptors loaded from {:?}: {}", dir, e);
+            return vec![];
+        }
+    };
+    paths.retain(|p| matches!(p.extension().and_then(|e| e.to_str()), Some("yaml") | Some("yml") | Some("json")));
+    paths.sort();
+    let mut descriptors = vec![];
+    for path in paths {
+        let parsed = std::fs::read_to_string(&path).map_err(|e| e.to_string())
+            .and_then(|source| serde_yaml::from_str::<DescriptorFile>(&source).map_err(|e| e.to_string()));
+        match parsed {
+            Ok(DescriptorFile::Single(descriptor)) => descriptors.push(Arc::from(descriptor)),
+            Ok(DescriptorFile::Pack { dezoomers }) => descriptors.extend(dezoomers.into_iter().map(Arc::new)),
+            Err(e) => warn!("Ignoring the invalid dezoomer descriptor {:?}: {}", path, e),
+        }
+    }
+    debug!("Loaded {} dezoomer descriptors from {:?}", descriptors.len(), dir);
+    descriptors.into_iter()
+        .map(|d: Arc<Descriptor>| (&*Box::leak(d.name.clone().into_boxed_str()), d))
+        .collect()
+}
+
+/// Sets the directory from which dezoomer descriptors are loaded.
+/// Without a directory, the default one is used, if it exists.
+pub fn set_descriptor_dir(dir: Option<&Path>) {
+    let dir = dir.map(Path::to_path_buf).or_else(default_descriptor_dir);
+    let mut loaded = DESCRIPTORS.write().unwrap();
+    if loaded.0 != dir {
+        let descriptors = dir.as_deref().map(load_dir).unwrap_or_default();
+        *loaded = (dir, descriptors);
+    }
+}
+
+/// A dezoomer for each loaded descriptor
+pub fn descriptor_dezoomers() -> Vec<Box<dyn Dezoomer>> {
+    DESCRIPTORS.read().unwrap().1.iter()
+        .map(|(name, descriptor)| Box::new(DescriptorDezoomer {
+            name,
+            descriptor: Arc::clone(descriptor),
+            variables: None,
+        }) as Box<dyn Dezoomer>)
+        .collect()
+}
+
+#[test]
+fn test_descriptor() {
+    let source = r#"
+name: example-museum
+url_pattern: 'https://museum\.example/objects/(?P<id>\d+)'
+metadata_url: 'https://museum.example/api/objects/${id}.json'
+extract:
+  - { name: width, json: image.sizes.0.width }
+  - { name: height, regex: '"height":\s*(\d+)' }
+tiles:
+  url_template: 'https://museum.example/tiles/${id}/{{x}}_{{y}}.jpg'
+  x_template: 'x * 256'
+  y_template: 'y * 256'
+  width: '${width}'
+  height: '${height}'
+  variables:
+    - { name: x, from: 0, to: 1 }
+    - { name: y, from: 0, to: 0 }
+"#;
+    let descriptor = match serde_yaml::from_str::<DescriptorFile>(source).unwrap() {
+        DescriptorFile::Single(d) => *d,
+        DescriptorFile::Pack { .. } => panic!("expected a single descriptor"),
+    };
+    let mut dezoomer = DescriptorDezoomer { name: "example-museum", descriptor: Arc::new(descriptor), variables: None };
+    assert_eq!(dezoomer.descriptor.name, "example-museum");
+    let other = DezoomerInput { uri: "https://example.com/".into(), contents: PageContents::Unknown };
+    assert!(matches!(dezoomer.zoom_levels(&other), Err(DezoomerError::WrongDezoomer { .. })));
+
+    let page = DezoomerInput { uri: "https://museum.example/objects/42".into(), contents: PageContents::Unknown };
+    match dezoomer.zoom_levels(&page) {
+        Err(DezoomerError::NeedsData { uri }) => assert_eq!(uri, "https://museum.example/api/objects/42.json"),
+        _ => panic!("the metadata should be requested"),
+    }
+    let metadata = br#"{"image": {"sizes": [{"width": 400, "height": 200}]}}"#;
+    let data = DezoomerInput {
+        uri: "https://museum.example/api/objects/42.json".into(),
+        contents: PageContents::Success(metadata.to_vec()),
+    };
+    let mut levels = dezoomer.zoom_levels(&data).unwrap();
+    assert_eq!(levels[0].size_hint(), Some(Vec2d { x: 400, y: 200 }));
+    let tiles = levels[0].next_tiles(None);
+    assert_eq!(tiles[1].url, "https://museum.example/tiles/42/1_0.jpg");
+}
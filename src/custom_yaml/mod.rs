@@ -1,12 +1,21 @@
 use std::collections::HashMap;
+use std::path::Path;
 
 use serde::Deserialize;
 
-use crate::network::default_headers;
+use crate::default_headers::default_headers;
 use crate::dezoomer::*;
-use crate::TileReference;
+use crate::{TileReference, ZoomError};
+use post_process::PostProcessStep;
 
+pub use validate::InvalidYaml;
+#[cfg(test)]
+pub(crate) use validate::validate as validate_yaml;
+
+pub mod descriptor;
+mod post_process;
 mod tile_set;
+mod validate;
 mod variable;
 
 /// A dezoomer that takes a yaml file indicating the tile layout
@@ -18,27 +27,129 @@ impl Dezoomer for CustomDezoomer {
         "custom"
     }
 
+    fn description(&self) -> &str {
+        "A tiles.yaml file that describes the URLs of the tiles of any image"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["*/tiles.yaml"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec!["tiles.yaml", "https://example.com/image/tiles.yaml"]
+    }
+
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
         self.assert(data.uri.ends_with("tiles.yaml"))?;
         let contents = data.with_contents()?.contents;
-        let dezoomer: CustomYamlTiles =
-            serde_yaml::from_slice(&contents).map_err(DezoomerError::wrap)?;
-        single_level(dezoomer)
+        let source = std::str::from_utf8(contents).map_err(DezoomerError::wrap)?;
+        let (levels, unknown_keys) = validate::parse(source).map_err(DezoomerError::wrap)?;
+        for problem in unknown_keys {
+            log::warn!("In {}, {}", data.uri, problem);
+        }
+        // A variable with an infinite range of values would never let the download end
+        for level in &levels {
+            level.tile_set.check().map_err(DezoomerError::wrap)?;
+        }
+        Ok(levels.into_iter().into_zoom_levels())
     }
 }
 
+/// Checks the tiles.yaml file at the given path, and prints the first tiles of each of its levels
+pub fn print_validation(path: &Path) -> Result<(), ZoomError> {
+    let source = std::fs::read_to_string(path)?;
+    let samples = validate::validate(&source)?;
+    println!("{} is valid, and contains {} zoom levels", path.display(), samples.len());
+    for sample in samples { print!("{}", sample) }
+    Ok(())
+}
+
+/// Optional information about a zoom level
+#[derive(Deserialize, Default)]
+struct LevelInfo {
+    name: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    /// Whether the positions of the tiles are counted from the bottom of the image
+    #[serde(default)]
+    invert_y: bool,
+}
 
 #[derive(Deserialize)]
 struct CustomYamlTiles {
     #[serde(flatten)]
     tile_set: tile_set::TileSet,
+    #[serde(flatten)]
+    info: LevelInfo,
+    #[serde(default = "default_headers")]
+    headers: HashMap<String, String>,
+    /// Transformations to apply to each tile after it is downloaded
+    #[serde(default)]
+    post_process: Vec<PostProcessStep>,
+    /// A request to make before downloading the tiles, to open a session on the server
+    bootstrap: Option<BootstrapRequest>,
+}
+
+/// A tiles.yaml file that describes the same image at several resolutions
+#[derive(Deserialize)]
+struct CustomYamlLevels {
+    levels: Vec<CustomYamlLevel>,
     #[serde(default = "default_headers")]
     headers: HashMap<String, String>,
+    bootstrap: Option<BootstrapRequest>,
+}
+
+#[derive(Deserialize)]
+struct CustomYamlLevel {
+    #[serde(flatten)]
+    tile_set: tile_set::TileSet,
+    #[serde(flatten)]
+    info: LevelInfo,
+    /// Headers specific to this level, that override the ones defined for all levels
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    #[serde(default)]
+    post_process: Vec<PostProcessStep>,
+    /// Overrides the bootstrap request defined for all levels
+    bootstrap: Option<BootstrapRequest>,
+}
+
+impl CustomYamlLevels {
+    fn into_tiles(self) -> impl Iterator<Item=CustomYamlTiles> {
+        let headers = self.headers;
+        let bootstrap = self.bootstrap;
+        self.levels.into_iter().map(move |level| {
+            let mut level_headers = headers.clone();
+            level_headers.extend(level.headers);
+            CustomYamlTiles {
+                tile_set: level.tile_set,
+                info: level.info,
+                headers: level_headers,
+                post_process: level.post_process,
+                bootstrap: level.bootstrap.or_else(|| bootstrap.clone()),
+            }
+        })
+    }
+}
+
+impl CustomYamlTiles {
+    /// Evaluates the tiles, with `level_width` and `level_height` available in the templates
+    /// when the size of the level is given
+    fn tiles(&self) -> impl Iterator<Item=Result<TileReference, tile_set::UrlTemplateError>> + '_ {
+        let level_values = [("level_width", self.info.width), ("level_height", self.info.height)]
+            .iter()
+            .filter_map(|&(name, value)| Some((name, i64::from(value?))))
+            .collect();
+        self.tile_set.tiles(level_values)
+    }
 }
 
 impl std::fmt::Debug for CustomYamlTiles {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(f, "Custom tiles")
+        match &self.info.name {
+            Some(name) => write!(f, "Custom tiles ({})", name),
+            None => write!(f, "Custom tiles"),
+        }
     }
 }
 
@@ -47,9 +158,12 @@ impl TileProvider for CustomYamlTiles {
         if previous.is_some() {
             return vec![];
         }
-        let tiles_result:Result<Vec<_>, _> = self.tile_set.into_iter().collect();
+        let tiles_result:Result<Vec<_>, _> = self.tiles().collect();
         match tiles_result{
-            Ok(tiles) => tiles,
+            Ok(mut tiles) => {
+                if self.info.invert_y { flip_tiles_y(&mut tiles, self.info.height); }
+                tiles
+            }
             Err(err) => {
                 log::error!("Invalid tiles.yaml file: {}\n", err);
                 vec![]
@@ -57,9 +171,24 @@ impl TileProvider for CustomYamlTiles {
         }
     }
 
+    fn size_hint(&self) -> Option<Vec2d> {
+        match self.info {
+            LevelInfo { width: Some(x), height: Some(y), .. } => Some(Vec2d { x, y }),
+            _ => None,
+        }
+    }
+
     fn http_headers(&self) -> HashMap<String, String> {
         self.headers.clone()
     }
+
+    fn bootstrap_request(&self) -> Option<BootstrapRequest> {
+        self.bootstrap.clone()
+    }
+
+    fn post_process_fn(&self) -> PostProcessFn {
+        post_process::post_process_fn(&self.post_process)
+    }
 }
 
 #[test]
@@ -84,3 +213,118 @@ fn test_has_default_user_agent() {
         "There should be a user agent"
     );
 }
+
+#[test]
+fn test_multiple_levels() {
+    let conf: CustomYamlLevels = serde_yaml::from_str(r#"
+headers:
+  Referer: "http://example.com"
+levels:
+  - name: small
+    width: 200
+    height: 100
+    url_template: "small/{{x}}_{{y}}.jpg"
+    x_template: "x * 100"
+    y_template: "y * 100"
+    variables:
+      - { name: x, from: 0, to: 1 }
+      - { name: y, from: 0, to: 0 }
+  - name: large
+    width: 400
+    height: 200
+    url_template: "large/{{x}}_{{y}}.jpg"
+    x_template: "x * 100"
+    y_template: "y * 100"
+    variables:
+      - { name: x, from: 0, to: 3 }
+      - { name: y, from: 0, to: 1 }
+    headers:
+      Referer: "http://example.com/large"
+"#).unwrap();
+    let mut levels: Vec<CustomYamlTiles> = conf.into_tiles().collect();
+    assert_eq!(levels.len(), 2);
+    assert_eq!(levels[0].size_hint(), Some(Vec2d { x: 200, y: 100 }));
+    assert_eq!(levels[0].http_headers()["Referer"], "http://example.com");
+    assert_eq!(levels[1].http_headers()["Referer"], "http://example.com/large");
+    assert_eq!(levels[1].next_tiles(None).len(), 8);
+    assert_eq!(format!("{:?}", levels[1]), "Custom tiles (large)");
+}
+
+#[test]
+fn test_invert_y() {
+    let mut conf: CustomYamlTiles = serde_yaml::from_str(r#"
+url_template: "{{y}}.jpg"
+y_template: "y * 100"
+height: 250
+invert_y: true
+variables:
+  - { name: x, from: 0, to: 0 }
+  - { name: y, from: 0, to: 2 }
+"#).unwrap();
+    let tiles = conf.next_tiles(None);
+    let positions: Vec<(&str, u32)> = tiles.iter().map(|t| (t.url.as_str(), t.position.y)).collect();
+    assert_eq!(positions, vec![("0.jpg", 150), ("1.jpg", 50), ("2.jpg", 0)]);
+}
+
+#[test]
+fn test_level_values() {
+    let mut conf: CustomYamlTiles = serde_yaml::from_str(r#"
+url_template: "{{tiles_y - y - 1}}/{{x}}.jpg"
+x_template: "x * level_width / tiles_x"
+y_template: "y * 100"
+width: 400
+variables:
+  - { name: x, from: 0, to: 1 }
+  - { name: y, from: 0, to: 2 }
+"#).unwrap();
+    let tiles = conf.next_tiles(None);
+    let tiles: Vec<(&str, u32)> = tiles.iter().map(|t| (t.url.as_str(), t.position.x)).collect();
+    assert_eq!(tiles[..4], [("2/0.jpg", 0), ("1/0.jpg", 0), ("0/0.jpg", 0), ("2/1.jpg", 200)]);
+}
+
+#[test]
+fn test_post_process_declaration() {
+    let conf: CustomYamlTiles = serde_yaml::from_str(r#"
+url_template: "{{x}}.jpg"
+variables: [{ name: x, from: 0, to: 1 }]
+headers:
+  Cookie: "session=1234"
+post_process:
+  - xor: [42, 13]
+  - crop: 1
+"#).unwrap();
+    assert_eq!(conf.http_headers()["Cookie"], "session=1234");
+    assert_eq!(conf.post_process, vec![PostProcessStep::Xor(vec![42, 13]), PostProcessStep::Crop(1)]);
+    assert!(matches!(conf.post_process_fn(), PostProcessFn::Closure(_)));
+}
+
+#[test]
+fn test_bootstrap_declaration() {
+    let conf: CustomYamlLevels = serde_yaml::from_str(r#"
+bootstrap:
+  url: "http://example.com/viewer"
+  token: { pattern: 'token = "(\w+)"', header: Authorization, prefix: "Bearer " }
+levels:
+  - url_template: "small/{{x}}.jpg"
+    variables: [{ name: x, from: 0, to: 1 }]
+  - url_template: "large/{{x}}.jpg"
+    variables: [{ name: x, from: 0, to: 3 }]
+    bootstrap:
+      url: "http://example.com/api/session"
+      forward_headers: [X-Session]
+"#).unwrap();
+    let levels: Vec<CustomYamlTiles> = conf.into_tiles().collect();
+    let small = levels[0].bootstrap_request().unwrap();
+    assert_eq!(small.url, "http://example.com/viewer");
+    assert_eq!(small.token.unwrap().prefix, "Bearer ");
+    let large = levels[1].bootstrap_request().unwrap();
+    assert_eq!(large.url, "http://example.com/api/session");
+    assert_eq!(large.forward_headers, vec!["X-Session"]);
+}
+
+#[test]
+fn test_rejects_zero_step() {
+    let contents = b"url_template: '{{x}}.jpg'\nvariables: [{ name: x, from: 0, to: 10, step: 0 }]";
+    let input = DezoomerInput { uri: "tiles.yaml".into(), contents: PageContents::Success(contents.to_vec()) };
+    assert!(CustomDezoomer.zoom_levels(&input).is_err());
+}
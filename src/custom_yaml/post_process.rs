@@ -0,0 +1,76 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use image::{GenericImageView, ImageOutputFormat};
+use serde::Deserialize;
+
+use custom_error::custom_error;
+
+use crate::dezoomer::{PostProcessFn, TileReference};
+
+/// A transformation to apply to the bytes of each tile after it is downloaded
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PostProcessStep {
+    /// Apply a XOR between the bytes of the tile and the given key, repeated as needed
+    Xor(Vec<u8>),
+    /// Remove the given number of pixels from each side of the tile
+    Crop(u32),
+}
+
+custom_error! {pub PostProcessError
+    EmptyKey = "The XOR key cannot be empty",
+    TileTooSmall{width: u32, height: u32, crop: u32} =
+        "Cannot remove {crop} pixels from each side of a {width}x{height} tile",
+    Image{source: image::ImageError} = "Unable to decode the tile: {source}",
+}
+
+impl PostProcessStep {
+    fn apply(&self, data: Vec<u8>) -> Result<Vec<u8>, PostProcessError> {
+        match self {
+            PostProcessStep::Xor(key) => {
+                if key.is_empty() { return Err(PostProcessError::EmptyKey); }
+                Ok(data.into_iter().zip(key.iter().cycle()).map(|(b, k)| b ^ k).collect())
+            }
+            &PostProcessStep::Crop(crop) => {
+                let image = image::load_from_memory(&data)?;
+                let (width, height) = image.dimensions();
+                if 2 * crop >= width || 2 * crop >= height {
+                    return Err(PostProcessError::TileTooSmall { width, height, crop });
+                }
+                let cropped = image.crop_imm(crop, crop, width - 2 * crop, height - 2 * crop);
+                let mut out = Vec::new();
+                cropped.write_to(&mut out, ImageOutputFormat::Png)?;
+                Ok(out)
+            }
+        }
+    }
+}
+
+/// Create a function that applies all the given post-processing steps in order
+pub fn post_process_fn(steps: &[PostProcessStep]) -> PostProcessFn {
+    if steps.is_empty() {
+        return PostProcessFn::None;
+    }
+    let steps = steps.to_vec();
+    PostProcessFn::Closure(Arc::new(move |_tile: &TileReference, data: Vec<u8>| {
+        steps.iter()
+            .try_fold(data, |data, step| step.apply(data))
+            .map_err(|e| Box::new(e) as Box<dyn Error + Send>)
+    }))
+}
+
+#[test]
+fn test_xor() {
+    let step = PostProcessStep::Xor(vec![1, 2]);
+    assert_eq!(step.apply(vec![0, 0, 0, 3]).unwrap(), vec![1, 2, 1, 1]);
+}
+
+#[test]
+fn test_crop() {
+    let mut png = Vec::new();
+    image::DynamicImage::new_rgb8(10, 8).write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    let cropped = PostProcessStep::Crop(1).apply(png.clone()).unwrap();
+    assert_eq!(image::load_from_memory(&cropped).unwrap().dimensions(), (8, 6));
+    assert!(PostProcessStep::Crop(4).apply(png).is_err());
+}
@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::str::FromStr;
 
@@ -8,6 +9,7 @@ use custom_error::custom_error;
 use lazy_static::lazy_static;
 
 use crate::{TileReference, Vec2d};
+use crate::dezoomer::TileRequest;
 
 use super::variable::{BadVariableError, Variables};
 use evalexpr::{Context, IntType, HashMapContext};
@@ -25,6 +27,15 @@ pub struct TileSet {
     w_template: IntTemplate,
     #[serde(default = "default_h_template")]
     h_template: IntTemplate,
+
+    /// The http method of the tile requests, such as POST
+    method: Option<String>,
+    /// The body of the tile requests, with the same expressions as the URL.
+    /// Tiles with a body are requested with POST, unless another method is given.
+    body_template: Option<UrlTemplate>,
+    /// Headers whose values depend on the tile
+    #[serde(default)]
+    tile_headers: BTreeMap<String, UrlTemplate>,
 }
 
 fn default_x_template() -> IntTemplate {
@@ -43,13 +54,33 @@ fn default_h_template() -> IntTemplate {
     "h".parse().unwrap()
 }
 
-impl<'a> IntoIterator for &'a TileSet {
-    type Item = Result<TileReference, UrlTemplateError>;
-    type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
+impl TileSet {
+    /// Checks that the variables have valid names and finite ranges of values
+    pub fn check(&self) -> Result<(), BadVariableError> {
+        self.variables.check()
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        Box::new(self.variables.iter_contexts().map(move |ctx| {
+    pub fn tile_count(&self) -> u64 {
+        self.variables.combinations()
+    }
+
+    /// Evaluates the templates for all the values of the variables.
+    /// The given level values, as well as `tiles_x` and `tiles_y` (the number of values
+    /// of the `x` and `y` variables), can be used in all the templates.
+    pub fn tiles(&self, mut level_values: Vec<(&'static str, i64)>) -> impl Iterator<Item=Result<TileReference, UrlTemplateError>> + '_ {
+        for (name, var) in &[("tiles_x", "x"), ("tiles_y", "y")] {
+            if let Some(count) = self.variables.value_count(var) {
+                level_values.push((name, count as i64));
+            }
+        }
+        self.variables.iter_contexts().map(move |ctx| {
             let mut ctx:HashMapContext = ctx?;
+            // Variables declared in the file take precedence over the level values
+            for &(name, value) in &level_values {
+                if ctx.get_value(name).is_none() {
+                    ctx.set_value(name.into(), value.into())?;
+                }
+            }
             if ctx.get_value("w") != None {
                 ctx.set_value("w".into(), (self.w_template.eval(&ctx)? as IntType).into())?
             }
@@ -62,11 +93,39 @@ impl<'a> IntoIterator for &'a TileSet {
                     x: self.x_template.eval(&ctx)?,
                     y: self.y_template.eval(&ctx)?,
                 },
+                size: None,
+                request: self.request(&ctx)?.map(Box::new),
             })
+        })
+    }
+
+    /// The request of a tile, when its tiles are not requested with a simple GET
+    fn request<C: evalexpr::Context>(&self, ctx: &C) -> Result<Option<TileRequest>, UrlTemplateError> {
+        if self.method.is_none() && self.body_template.is_none() && self.tile_headers.is_empty() {
+            return Ok(None);
+        }
+        let body = self.body_template.as_ref().map(|template| template.eval(ctx)).transpose()?;
+        let default_method = if body.is_some() { "POST" } else { "GET" };
+        let headers = self.tile_headers.iter()
+            .map(|(name, template)| Ok((name.clone(), template.eval(ctx)?)))
+            .collect::<Result<_, UrlTemplateError>>()?;
+        Ok(Some(TileRequest {
+            method: self.method.as_deref().unwrap_or(default_method).to_uppercase(),
+            body,
+            headers,
         }))
     }
 }
 
+impl<'a> IntoIterator for &'a TileSet {
+    type Item = Result<TileReference, UrlTemplateError>;
+    type IntoIter = Box<dyn Iterator<Item = Self::Item> + 'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Box::new(self.tiles(vec![]))
+    }
+}
+
 #[derive(Debug)]
 struct IntTemplate(String);
 
@@ -209,6 +268,9 @@ mod tests {
             y_template: IntTemplate::from_str("y").unwrap(),
             w_template: IntTemplate::from_str("w").unwrap(),
             h_template: IntTemplate::from_str("h").unwrap(),
+            method: None,
+            body_template: None,
+            tile_headers: Default::default(),
         };
         let tile_refs: Vec<_> = ts.into_iter().collect::<Result<_, _>>().unwrap();
         let expected: Vec<_> = vec!["0 0 0/0/1/1", "0 1 0/1/1/1", "1 0 1/0/1/1", "1 1 1/1/1/1"]
@@ -242,4 +304,27 @@ url_template: "{{x*tile_size}}/{{y*tile_size}}"
             .unwrap();
         assert_eq!(expected, tile_refs);
     }
+
+    #[test]
+    fn tileset_with_post_requests() {
+        let serialized = r#"
+variables:
+    - name: x
+      from: 0
+      to: 1
+    - name: y
+      value: 0
+url_template: "https://example.com/tiles"
+body_template: '{"column": {{x}}, "row": {{y}}}'
+tile_headers:
+    X-Tile: "{{x}}-{{y}}"
+        "#;
+        let ts: TileSet = serde_yaml::from_str(serialized).unwrap();
+        let tile_refs: Vec<_> = ts.into_iter().collect::<Result<_, _>>().unwrap();
+        let request = tile_refs[1].request.as_deref().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.body.as_deref(), Some(r#"{"column": 1, "row": 0}"#));
+        assert_eq!(request.headers, vec![("X-Tile".to_string(), "1-0".to_string())]);
+        assert_ne!(tile_refs[0].key(), tile_refs[1].key());
+    }
 }
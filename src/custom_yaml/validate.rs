@@ -0,0 +1,278 @@
+//! Checks of tiles.yaml files. Unknown keys are reported with their position in the file
+//! and the closest known key, because a typo in an optional key would otherwise be silently ignored.
+//! `dezoomify-rs validate` additionally checks the ranges of the variables and evaluates the URLs
+//! of a few tiles of each level, without downloading anything.
+
+use std::fmt;
+
+use regex::Regex;
+use serde_yaml::Value;
+
+use crate::dezoomer::TileProvider;
+use crate::Vec2d;
+
+use super::{CustomYamlLevels, CustomYamlTiles};
+
+/// Number of tile URLs evaluated for each level
+const SAMPLE_TILES: usize = 3;
+
+const LEVEL_KEYS: &[&str] = &[
+    "url_template", "variables", "x_template", "y_template", "w_template", "h_template",
+    "name", "width", "height", "invert_y", "headers", "post_process", "bootstrap",
+    "method", "body_template", "tile_headers",
+];
+const LEVELS_KEYS: &[&str] = &["levels", "headers", "bootstrap"];
+const VARIABLE_KEYS: &[&str] = &["name", "from", "to", "step", "value"];
+const POST_PROCESS_KEYS: &[&str] = &["xor", "crop"];
+const BOOTSTRAP_KEYS: &[&str] = &["url", "forward_headers", "token"];
+const TOKEN_KEYS: &[&str] = &["pattern", "header", "prefix"];
+
+/// An error in a tiles.yaml file, with its line and column when they are known
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(f, "line {}, column {}: {}", line, column, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for Problem {
+    fn from(err: serde_yaml::Error) -> Self {
+        // The message of the error already contains its position
+        let message = err.to_string().replace(
+            "data did not match any variant of untagged enum VarOrConst",
+            "invalid variable: a variable needs a name, and either 'from' and 'to', or a 'value'",
+        );
+        Problem { position: None, message }
+    }
+}
+
+/// All the problems found in a tiles.yaml file
+#[derive(Debug)]
+pub struct InvalidYaml {
+    pub problems: Vec<Problem>,
+}
+
+impl fmt::Display for InvalidYaml {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, problem) in self.problems.iter().enumerate() {
+            if i > 0 { writeln!(f)?; }
+            write!(f, "  {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for InvalidYaml {}
+
+/// Parses a tiles.yaml file into its levels.
+/// The unknown keys are returned separately, because they do not prevent the file from being used.
+pub(super) fn parse(source: &str) -> Result<(Vec<CustomYamlTiles>, Vec<Problem>), InvalidYaml> {
+    let value: Value = serde_yaml::from_str(source)
+        .map_err(|e| InvalidYaml { problems: vec![e.into()] })?;
+    let unknown = unknown_keys(source, &value);
+    let levels = if value.get("levels").is_some() {
+        serde_yaml::from_str::<CustomYamlLevels>(source).map(|l| l.into_tiles().collect())
+    } else {
+        serde_yaml::from_str::<CustomYamlTiles>(source).map(|t| vec![t])
+    };
+    match levels {
+        Ok(levels) => Ok((levels, unknown)),
+        Err(err) => {
+            // An unknown key is often the cause of the error, such as a misspelled required key
+            let mut problems = unknown;
+            problems.push(err.into());
+            Err(InvalidYaml { problems })
+        }
+    }
+}
+
+/// The first tiles of a level, as evaluated from the tiles.yaml file
+#[derive(Debug, PartialEq)]
+pub struct LevelSample {
+    pub name: Option<String>,
+    pub size: Option<Vec2d>,
+    pub tile_count: u64,
+    pub first_tiles: Vec<(Vec2d, String)>,
+}
+
+impl fmt::Display for LevelSample {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name.as_deref().unwrap_or("level"))?;
+        if let Some(Vec2d { x, y }) = self.size { write!(f, " ({} x {} pixels)", x, y)?; }
+        writeln!(f, ": {} tiles", self.tile_count)?;
+        for (position, url) in &self.first_tiles {
+            writeln!(f, "    {},{}: {}", position.x, position.y, url)?;
+        }
+        if (self.first_tiles.len() as u64) < self.tile_count { writeln!(f, "    ...")?; }
+        Ok(())
+    }
+}
+
+/// Checks a whole tiles.yaml file, and evaluates the first tiles of each of its levels
+pub fn validate(source: &str) -> Result<Vec<LevelSample>, InvalidYaml> {
+    let (levels, mut problems) = parse(source)?;
+    let mut samples = Vec::with_capacity(levels.len());
+    for (i, level) in levels.iter().enumerate() {
+        let name = level.info.name.clone();
+        let level_name = match &name {
+            Some(name) => format!("level '{}'", name),
+            None => format!("level {}", i + 1),
+        };
+        let level_problem = |message: String| Problem {
+            position: None,
+            message: format!("in {}: {}", level_name, message),
+        };
+        if let Err(err) = level.tile_set.check() {
+            problems.push(level_problem(format!("invalid variable: {}", err)));
+            continue;
+        }
+        let mut first_tiles = Vec::with_capacity(SAMPLE_TILES);
+        for tile in level.tiles().take(SAMPLE_TILES) {
+            match tile {
+                Ok(tile) => first_tiles.push((tile.position, tile.url)),
+                Err(err) => problems.push(level_problem(format!("unable to evaluate a tile URL: {}", err))),
+            }
+        }
+        samples.push(LevelSample { name, size: level.size_hint(), tile_count: level.tile_set.tile_count(), first_tiles });
+    }
+    if problems.is_empty() { Ok(samples) } else { Err(InvalidYaml { problems }) }
+}
+
+/// Finds the keys that are not used by any part of a tiles.yaml file
+fn unknown_keys(source: &str, value: &Value) -> Vec<Problem> {
+    let mut finder = KeyFinder { source, offset: 0, problems: vec![] };
+    if value.get("levels").is_some() {
+        finder.check(value, LEVELS_KEYS);
+        finder.check(&value["bootstrap"], BOOTSTRAP_KEYS);
+        finder.check(&value["bootstrap"]["token"], TOKEN_KEYS);
+        for level in value["levels"].as_sequence().into_iter().flatten() {
+            finder.check_level(level);
+        }
+    } else {
+        finder.check_level(value);
+    }
+    finder.problems
+}
+
+/// Walks the yaml document in order, and looks for the position of each unknown key in the source
+struct KeyFinder<'a> {
+    source: &'a str,
+    /// Position in the source after the previous unknown key
+    offset: usize,
+    problems: Vec<Problem>,
+}
+
+impl KeyFinder<'_> {
+    fn check_level(&mut self, level: &Value) {
+        self.check(level, LEVEL_KEYS);
+        for variable in level["variables"].as_sequence().into_iter().flatten() {
+            self.check(variable, VARIABLE_KEYS);
+        }
+        for step in level["post_process"].as_sequence().into_iter().flatten() {
+            self.check(step, POST_PROCESS_KEYS);
+        }
+        self.check(&level["bootstrap"], BOOTSTRAP_KEYS);
+        self.check(&level["bootstrap"]["token"], TOKEN_KEYS);
+    }
+
+    fn check(&mut self, value: &Value, known: &[&str]) {
+        let keys = value.as_mapping().into_iter().flat_map(|m| m.iter()).filter_map(|(k, _)| k.as_str());
+        for key in keys.filter(|k| !known.contains(k)) {
+            let mut message = format!("unknown key '{}'", key);
+            if let Some(suggestion) = closest(key, known) {
+                message.push_str(&format!(", did you mean '{}'?", suggestion));
+            }
+            let position = self.find(key);
+            self.problems.push(Problem { position, message });
+        }
+    }
+
+    /// Line and column of the next occurrence of the key in the source
+    fn find(&mut self, key: &str) -> Option<(usize, usize)> {
+        let re = Regex::new(&format!(r#"(?:^|[\s{{,\-])["']?({})["']?\s*:"#, regex::escape(key))).ok()?;
+        // Keys are usually found in the order of the document, but not always
+        let start = match re.captures(&self.source[self.offset..]) {
+            Some(captures) => captures.get(1)?.start() + self.offset,
+            None => re.captures(self.source)?.get(1)?.start(),
+        };
+        self.offset = start + key.len();
+        let before = &self.source[..start];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        Some((line, column))
+    }
+}
+
+/// The known key that is the most similar to the given unknown one, if any is similar enough
+fn closest<'a>(key: &str, known: &[&'a str]) -> Option<&'a str> {
+    known.iter()
+        .map(|&k| (edit_distance(key, k), k))
+        .filter(|&(distance, k)| distance <= 2.max(k.len() / 4))
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, k)| k)
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[test]
+fn test_edit_distance() {
+    assert_eq!(edit_distance("url_templat", "url_template"), 1);
+    assert_eq!(edit_distance("hieght", "height"), 2);
+    assert_eq!(closest("urltemplate", LEVEL_KEYS), Some("url_template"));
+    assert_eq!(closest("color", LEVEL_KEYS), None);
+}
+
+#[test]
+fn test_unknown_keys() {
+    let source = "url_templat: \"{{x}}.jpg\"\nvariables:\n  - { name: x, from: 0, too: 2 }\n";
+    let problems = parse(source).unwrap_err().problems;
+    assert_eq!(problems[0], Problem {
+        position: Some((1, 1)),
+        message: "unknown key 'url_templat', did you mean 'url_template'?".into(),
+    });
+    assert_eq!(problems[1], Problem {
+        position: Some((3, 25)),
+        message: "unknown key 'too', did you mean 'to'?".into(),
+    });
+    assert!(problems[2].message.starts_with("invalid variable: a variable needs a name"), "{}", problems[2]);
+}
+
+#[test]
+fn test_validate() {
+    let samples = validate(r#"
+levels:
+  - name: small
+    url_template: "small/{{x}}.jpg"
+    x_template: "x * 100"
+    variables: [{ name: x, from: 0, to: 9 }, { name: y, value: 0 }]
+"#).unwrap();
+    assert_eq!(samples[0].tile_count, 10);
+    assert_eq!(samples[0].first_tiles[1], (Vec2d { x: 100, y: 0 }, "small/1.jpg".into()));
+
+    let err = validate(r#"
+url_template: "{{x / 0}}.jpg"
+variables: [{ name: x, from: 2, to: 0 }, { name: y, from: 0, to: 1, step: 0 }]
+"#).unwrap_err();
+    assert!(err.to_string().contains("in level 1: invalid variable"), "{}", err);
+}
@@ -32,6 +32,11 @@ impl Variable {
                 name: self.name.clone(),
             });
         }
+        if self.step == 0 {
+            return Err(BadVariableError::Infinite {
+                name: self.name.clone(),
+            });
+        }
         let steps = (self.to - self.from) / self.step;
         if steps < 0 {
             return Err(BadVariableError::Infinite {
@@ -143,6 +148,21 @@ impl VarOrConst {
         };
         orconst.check().and(Ok(Const(orconst)))
     }
+    pub fn check(&self) -> Result<(), BadVariableError> {
+        match self {
+            VarOrConst::Var(v) => v.check(),
+            VarOrConst::Const(c) => c.check().map_err(|_| BadVariableError::BadName {
+                name: c.name.clone(),
+            }),
+        }
+    }
+    /// Number of values the variable takes
+    pub fn value_count(&self) -> u64 {
+        match self {
+            VarOrConst::Var(v) => (v.to - v.from).checked_div(v.step).map_or(0, |steps| (steps + 1).max(0) as u64),
+            VarOrConst::Const(_) => 1,
+        }
+    }
     pub fn name(&self) -> &str {
         match self {
             VarOrConst::Var(v) => v.name(),
@@ -176,6 +196,17 @@ impl Variables {
     pub fn new(vars: Vec<VarOrConst>) -> Variables {
         Variables(vars)
     }
+    pub fn check(&self) -> Result<(), BadVariableError> {
+        self.0.iter().try_for_each(VarOrConst::check)
+    }
+    /// Number of values of the variable with the given name
+    pub fn value_count(&self, name: &str) -> Option<u64> {
+        self.0.iter().find(|v| v.name() == name).map(VarOrConst::value_count)
+    }
+    /// Number of combinations of the values of the variables, which is the number of tiles
+    pub fn combinations(&self) -> u64 {
+        self.0.iter().map(VarOrConst::value_count).fold(1, u64::saturating_mul)
+    }
     pub fn iter_contexts(
         &self,
     ) -> impl Iterator<Item = Result<HashMapContext, BadVariableError>> + '_ {
@@ -240,6 +271,14 @@ mod tests {
             .contains("invalid variable name"))
     }
 
+    #[test]
+    fn variable_zero_step() {
+        let var = VarOrConst::Var(Variable { name: "x".into(), from: 0, to: 10, step: 0 });
+        assert_eq!(var.value_count(), 0);
+        assert!(var.check().is_err());
+        assert_eq!(VarOrConst::var("x", 0, 10, 5).unwrap().value_count(), 3);
+    }
+
     #[test]
     fn iter_contexts() {
         let vars = Variables(vec![
@@ -0,0 +1,104 @@
+//! A cache of decoded tiles, keyed by the hash of their encoded contents.
+//!
+//! Many images contain large areas of identical tiles (a plain background, for instance).
+//! Keeping the decoded pixels avoids decoding the same data again for each of them.
+//! The least recently used tiles are evicted when the cache grows over its memory budget.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use image::{DynamicImage, GenericImageView, ImageResult};
+use lazy_static::lazy_static;
+use sha1::{Digest, Sha1};
+
+/// Maximum amount of memory used by the decoded tiles in the cache, in bytes
+const MEMORY_BUDGET: usize = 64 * 1024 * 1024;
+
+lazy_static! {
+    static ref DECODE_CACHE: Mutex<DecodeCache> = Mutex::new(DecodeCache::new(MEMORY_BUDGET));
+}
+
+type Key = [u8; 20];
+
+struct Entry {
+    image: DynamicImage,
+    last_used: u64,
+}
+
+pub struct DecodeCache {
+    budget: usize,
+    used: usize,
+    clock: u64,
+    entries: HashMap<Key, Entry>,
+}
+
+fn memory_size(image: &DynamicImage) -> usize {
+    let (width, height) = image.dimensions();
+    width as usize * height as usize * usize::from(image.color().bytes_per_pixel())
+}
+
+impl DecodeCache {
+    pub fn new(budget: usize) -> Self {
+        DecodeCache { budget, used: 0, clock: 0, entries: HashMap::new() }
+    }
+
+    fn get(&mut self, key: &Key) -> Option<DynamicImage> {
+        self.clock += 1;
+        let clock = self.clock;
+        self.entries.get_mut(key).map(|entry| {
+            entry.last_used = clock;
+            entry.image.clone()
+        })
+    }
+
+    fn insert(&mut self, key: Key, image: DynamicImage) {
+        let size = memory_size(&image);
+        // Very large tiles would evict everything else
+        if size > self.budget / 2 { return; }
+        while self.used + size > self.budget {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| *key);
+            match oldest.and_then(|key| self.entries.remove(&key)) {
+                Some(entry) => self.used -= memory_size(&entry.image),
+                None => break,
+            }
+        }
+        self.clock += 1;
+        self.used += size;
+        if let Some(old) = self.entries.insert(key, Entry { image, last_used: self.clock }) {
+            self.used -= memory_size(&old.image);
+        }
+    }
+}
+
+/// Decode an image, or return a copy of the already decoded image if the same data was seen recently
+pub fn decode(data: &[u8]) -> ImageResult<DynamicImage> {
+    let key: Key = Sha1::digest(data).into();
+    if let Some(image) = DECODE_CACHE.lock().unwrap().get(&key) {
+        return Ok(image);
+    }
+    // Decode without holding the lock, so that several tiles can be decoded in parallel
+    let image = image::load_from_memory(data)?;
+    DECODE_CACHE.lock().unwrap().insert(key, image.clone());
+    Ok(image)
+}
+
+#[test]
+fn test_decode_cache() {
+    let mut cache = DecodeCache::new(3 * 10 * 10 * 4);
+    let tile = || DynamicImage::new_rgba8(10, 10);
+    for key in &[[1; 20], [2; 20], [3; 20]] {
+        cache.insert(*key, tile());
+    }
+    assert!(cache.get(&[1; 20]).is_some());
+    // The least recently used tile is evicted
+    cache.insert([4; 20], tile());
+    assert!(cache.get(&[2; 20]).is_none());
+    assert!(cache.get(&[1; 20]).is_some());
+    assert!(cache.get(&[3; 20]).is_some());
+    assert_eq!(cache.used, 3 * 10 * 10 * 4);
+    // Tiles that are too large are not cached
+    cache.insert([5; 20], DynamicImage::new_rgba8(20, 20));
+    assert!(cache.get(&[5; 20]).is_none());
+}
@@ -0,0 +1,60 @@
+//! The HTTP headers sent with every request.
+//! They are needed both by the http client and by the dezoomers that describe requests,
+//! so this module does not depend on the network stack.
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::RwLock;
+
+use lazy_static::lazy_static;
+
+use crate::ZoomError;
+
+lazy_static! {
+    static ref DEFAULT_HEADER_OVERRIDES: RwLock<HashMap<String, Option<String>>> = RwLock::new(HashMap::new());
+}
+
+/// The headers sent with all requests, with the lowest precedence:
+/// the built-in ones, changed by the file given with `--default-headers`
+pub fn default_headers() -> HashMap<String, String> {
+    let headers = serde_yaml::from_str(include_str!("default_headers.yaml")).unwrap();
+    override_headers(headers, &DEFAULT_HEADER_OVERRIDES.read().unwrap())
+}
+
+fn override_headers(
+    mut headers: HashMap<String, String>,
+    overrides: &HashMap<String, Option<String>>,
+) -> HashMap<String, String> {
+    for (name, value) in overrides {
+        // Header names are case-insensitive
+        headers.retain(|existing, _| !existing.eq_ignore_ascii_case(name));
+        if let Some(value) = value.as_ref().filter(|v| !v.is_empty()) {
+            headers.insert(name.clone(), value.clone());
+        }
+    }
+    headers
+}
+
+/// Reads a YAML file in the format of `default_headers.yaml`, whose headers replace or extend
+/// the built-in default headers. A header without a value is removed.
+pub fn set_default_headers_file(path: Option<&Path>) -> Result<(), ZoomError> {
+    let overrides = match path {
+        Some(path) => serde_yaml::from_str(&std::fs::read_to_string(path)?)?,
+        None => HashMap::new(),
+    };
+    *DEFAULT_HEADER_OVERRIDES.write().unwrap() = overrides;
+    Ok(())
+}
+
+#[test]
+fn test_override_headers() {
+    let overrides: HashMap<String, Option<String>> = serde_yaml::from_str(
+        "user-agent: my-packaged-build/1.0\nAccept-Language:\nDNT: \"1\"\n"
+    ).unwrap();
+    let headers = override_headers(default_headers(), &overrides);
+    assert_eq!(headers.get("user-agent").map(String::as_str), Some("my-packaged-build/1.0"));
+    assert!(!headers.contains_key("User-Agent"));
+    assert!(!headers.contains_key("Accept-Language"));
+    assert_eq!(headers.get("DNT").map(String::as_str), Some("1"));
+    assert_eq!(headers.get("Accept").map(String::as_str), Some("*/*"));
+}
@@ -2,11 +2,17 @@ use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Debug;
+use std::ops::Range;
+use std::rc::Rc;
 use std::str::FromStr;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
 
 pub use crate::errors::DezoomerError;
 
 pub use super::Vec2d;
+pub use crate::crop::TileCrop;
 use super::ZoomError;
 use std::fmt;
 use crate::dezoomer::PageContents::Success;
@@ -14,12 +20,13 @@ use crate::dezoomer::PageContents::Success;
 pub enum PageContents {
     Unknown,
     Success(Vec<u8>),
-    Error(ZoomError),
+    /// The error that prevented the download. It is shared by all the dezoomers that receive the input.
+    Error(Rc<ZoomError>),
 }
 
 impl From<Result<Vec<u8>, ZoomError>> for PageContents {
     fn from(res: Result<Vec<u8>, ZoomError>) -> Self {
-        res.map(Self::Success).unwrap_or_else(Self::Error)
+        res.map(Self::Success).unwrap_or_else(|e| Self::Error(Rc::new(e)))
     }
 }
 
@@ -54,7 +61,7 @@ impl DezoomerInput {
                 contents,
             }),
             PageContents::Error(e) => {
-                Err(DezoomerError::DownloadError{msg: e.to_string()})
+                Err(DezoomerError::Network { error: Rc::clone(e) })
             }
         }
     }
@@ -85,6 +92,15 @@ pub trait Dezoomer {
     /// The name of the image format. Used for dezoomer selection
     fn name(&self) -> &'static str;
 
+    /// A one-line description of the images this dezoomer supports, displayed by --list-dezoomers
+    fn description(&self) -> &str { "" }
+
+    /// The forms of the inputs this dezoomer accepts, such as the name of a metadata file
+    fn url_patterns(&self) -> Vec<&str> { vec![] }
+
+    /// Inputs this dezoomer accepts, as examples for --list-dezoomers
+    fn examples(&self) -> Vec<&str> { vec![] }
+
     /// List of the various sizes at which an image is available
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError>;
     fn assert(&self, c: bool) -> Result<(), DezoomerError> {
@@ -99,11 +115,13 @@ pub trait Dezoomer {
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Default)]
 pub struct TileFetchResult {
     pub count: u64,
     pub successes: u64,
     pub tile_size: Option<Vec2d>,
+    /// The URLs of the tiles that were received, for the dezoomers that probe several tiles at once
+    pub successful_urls: Vec<String>,
 }
 
 impl TileFetchResult {
@@ -116,14 +134,33 @@ impl TileFetchResult {
 }
 
 type PostProcessResult = Result<Vec<u8>, Box<dyn Error + Send>>;
+pub type PostProcessClosure = dyn Fn(&TileReference, Vec<u8>) -> PostProcessResult + Send + Sync;
 // TODO : fix
 // see: https://github.com/rust-lang/rust/issues/63033
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub enum PostProcessFn {
     Fn(fn(&TileReference, Vec<u8>) -> PostProcessResult),
+    /// A post-processing function that depends on some configuration, such as a decryption key
+    Closure(Arc<PostProcessClosure>),
     None,
 }
 
+/// Statements about the reuse of an image, as published by the server that hosts it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Rights {
+    /// The URL of the license of the image, such as a Creative Commons license
+    pub license: Option<String>,
+    /// A text that has to be displayed with the image, such as the name of the institution that holds it
+    pub attribution: Option<String>,
+}
+
+/// The dimensions of the original of an image, such as a painting or a page of a book
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PhysicalSize {
+    pub width_inches: f64,
+    pub height_inches: f64,
+}
+
 /// A single tiled image
 pub trait TileProvider: Debug {
     /// Provide a list of image tiles. Should be called repetitively until it returns
@@ -135,6 +172,12 @@ pub trait TileProvider: Debug {
         PostProcessFn::None
     }
 
+    /// Pixels to remove from the sides of the tiles that are inside the image,
+    /// such as the overlap of the tiles of DeepZoom images. Replaced by --tile-crop.
+    fn tile_crop(&self) -> TileCrop {
+        TileCrop::default()
+    }
+
     /// The name of the format
     fn name(&self) -> String {
         format!("{:?}", self)
@@ -143,15 +186,78 @@ pub trait TileProvider: Debug {
     /// The title of the image
     fn title(&self) -> Option<String> { None }
 
+    /// The license and attribution statements published with the image
+    fn rights(&self) -> Option<Rights> { None }
+
+    /// The dimensions of what the image represents, when the server publishes them
+    fn physical_size(&self) -> Option<PhysicalSize> { None }
+
     /// The width and height of the image. Can be unknown when dezooming starts
     fn size_hint(&self) -> Option<Vec2d> {
         None
     }
 
-    /// A collection of http headers to use when requesting the tiles
+    /// A collection of http headers to use when requesting the tiles, such as the Referer or the User-Agent
+    /// that the server of a dezoomer expects. They override the default headers and the automatic Referer,
+    /// which is the input URL, or the address of the tile server when the input is not a web address.
     fn http_headers(&self) -> HashMap<String, String> {
         HashMap::new()
     }
+
+    /// A request to make once before downloading the tiles, for servers that require a session
+    fn bootstrap_request(&self) -> Option<BootstrapRequest> {
+        None
+    }
+
+    /// A single tile that can be downloaded to check that the level really exists on the server
+    /// before requesting all of its tiles
+    fn probe_tile(&self) -> Option<TileReference> {
+        None
+    }
+
+    /// Groups of at most `max_tiles` of the given tiles that can be downloaded with a single request,
+    /// for servers that can send several tiles at once. The other tiles are requested one by one.
+    fn fetch_plan(&self, _tiles: &[TileReference], _max_tiles: usize) -> Vec<TileBatch> {
+        vec![]
+    }
+}
+
+/// Tiles that the server sends one after the other in the response to a single request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileBatch {
+    pub url: String,
+    /// The keys of the tiles (see [TileReference::key]), with the range of their bytes in the response
+    pub tiles: Vec<(String, Range<usize>)>,
+}
+
+/// A request made before downloading the tiles of an image, for servers that only serve tiles
+/// to clients that first visited a page or called an API.
+/// The cookies set by the response are sent with the tile requests.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BootstrapRequest {
+    pub url: String,
+    /// Names of response headers whose values are sent back with the tile requests
+    #[serde(default)]
+    pub forward_headers: Vec<String>,
+    /// A token to extract from the response body and send as a header with the tile requests
+    pub token: Option<TokenCapture>,
+}
+
+impl BootstrapRequest {
+    pub fn new(url: String) -> Self {
+        BootstrapRequest { url, forward_headers: vec![], token: None }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TokenCapture {
+    /// A regular expression whose first group matches the token in the response body
+    pub pattern: String,
+    /// The header in which the token is sent
+    pub header: String,
+    /// A string to put before the token in the header value, such as "Bearer "
+    #[serde(default)]
+    pub prefix: String,
 }
 
 /// Used to iterate over all the batches of tiles in a zoom level
@@ -168,7 +274,7 @@ impl<'a> ZoomLevelIter<'a> {
     pub fn next_tile_references(&mut self) -> Option<Vec<TileReference>> {
         assert!(!self.waiting_results);
         self.waiting_results = true;
-        let tiles = self.zoom_level.next_tiles(self.previous);
+        let tiles = self.zoom_level.next_tiles(self.previous.take());
         if tiles.is_empty() { None } else { Some(tiles) }
     }
     pub fn set_fetch_result(&mut self, result: TileFetchResult) {
@@ -179,6 +285,9 @@ impl<'a> ZoomLevelIter<'a> {
     pub fn size_hint(&self) -> Option<Vec2d> {
         self.zoom_level.size_hint()
     }
+    pub fn fetch_plan(&self, tiles: &[TileReference], max_tiles: usize) -> Vec<TileBatch> {
+        self.zoom_level.fetch_plan(tiles, max_tiles)
+    }
 }
 
 /// Shortcut to return a single zoom level from a dezoomer
@@ -188,20 +297,71 @@ pub fn single_level<T: TileProvider + Sync + 'static>(
     Ok(vec![Box::new(level)])
 }
 
+/// Give a default title to the levels that do not have one,
+/// for instance the title of the web page in which they were found
+pub fn with_default_title(levels: ZoomLevels, title: Option<String>) -> ZoomLevels {
+    let title = match title { Some(title) => title, None => return levels };
+    levels.into_iter().map(|level| {
+        if level.title().is_some() { return level; }
+        Box::new(TitledLevel { level, title: title.clone() }) as ZoomLevel
+    }).collect()
+}
+
+struct TitledLevel {
+    level: ZoomLevel,
+    title: String,
+}
+
+impl TileProvider for TitledLevel {
+    fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
+        self.level.next_tiles(previous)
+    }
+    fn post_process_fn(&self) -> PostProcessFn { self.level.post_process_fn() }
+    fn tile_crop(&self) -> TileCrop { self.level.tile_crop() }
+    fn name(&self) -> String { self.level.name() }
+    fn title(&self) -> Option<String> { Some(self.title.clone()) }
+    fn rights(&self) -> Option<Rights> { self.level.rights() }
+    fn physical_size(&self) -> Option<PhysicalSize> { self.level.physical_size() }
+    fn size_hint(&self) -> Option<Vec2d> { self.level.size_hint() }
+    fn http_headers(&self) -> HashMap<String, String> { self.level.http_headers() }
+    fn bootstrap_request(&self) -> Option<BootstrapRequest> { self.level.bootstrap_request() }
+    fn probe_tile(&self) -> Option<TileReference> { self.level.probe_tile() }
+    fn fetch_plan(&self, tiles: &[TileReference], max_tiles: usize) -> Vec<TileBatch> { self.level.fetch_plan(tiles, max_tiles) }
+}
+
+impl Debug for TitledLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.level.fmt(f)
+    }
+}
+
 pub trait TilesRect: Debug {
     fn size(&self) -> Vec2d;
     fn tile_size(&self) -> Vec2d;
     fn tile_url(&self, pos: Vec2d) -> String;
     fn title(&self) -> Option<String> { None }
+    fn rights(&self) -> Option<Rights> { None }
+    fn physical_size(&self) -> Option<PhysicalSize> { None }
     fn tile_ref(&self, pos: Vec2d) -> TileReference {
         TileReference {
             url: self.tile_url(pos),
             position: self.tile_size() * pos,
+            size: None,
+            request: None,
         }
     }
     fn post_process_fn(&self) -> PostProcessFn {
         PostProcessFn::None
     }
+    fn tile_crop(&self) -> TileCrop { TileCrop::default() }
+    fn bootstrap_request(&self) -> Option<BootstrapRequest> { None }
+    fn probe_tile(&self) -> Option<TileReference> { None }
+    fn fetch_plan(&self, _tiles: &[TileReference], _max_tiles: usize) -> Vec<TileBatch> { vec![] }
+
+    /// The reference of the tile at the bottom right corner of the image
+    fn last_tile_ref(&self) -> TileReference {
+        self.tile_ref(self.size().ceil_div(self.tile_size()) - Vec2d { x: 1, y: 1 })
+    }
 
     fn tile_count(&self) -> u32 {
         let Vec2d { x, y } = self.size().ceil_div(self.tile_size());
@@ -229,6 +389,8 @@ impl<T: TilesRect> TileProvider for T {
         TilesRect::post_process_fn(self)
     }
 
+    fn tile_crop(&self) -> TileCrop { TilesRect::tile_crop(self) }
+
     fn name(&self) -> String {
         let Vec2d { x, y } = self.size();
         format!(
@@ -242,15 +404,44 @@ impl<T: TilesRect> TileProvider for T {
 
     fn title(&self) -> Option<String> { TilesRect::title(self) }
 
+    fn rights(&self) -> Option<Rights> { TilesRect::rights(self) }
+
+    fn physical_size(&self) -> Option<PhysicalSize> { TilesRect::physical_size(self) }
+
+    fn bootstrap_request(&self) -> Option<BootstrapRequest> { TilesRect::bootstrap_request(self) }
+
+    fn probe_tile(&self) -> Option<TileReference> { TilesRect::probe_tile(self) }
+
+    fn fetch_plan(&self, tiles: &[TileReference], max_tiles: usize) -> Vec<TileBatch> {
+        TilesRect::fetch_plan(self, tiles, max_tiles)
+    }
+
     fn size_hint(&self) -> Option<Vec2d> {
         Some(self.size())
     }
+}
 
-    fn http_headers(&self) -> HashMap<String, String> {
-        let mut headers = HashMap::new();
-        // By default, use the first tile as the referer, so that it is on the same domain
-        headers.insert("Referer".into(), self.tile_url(Vec2d::default()));
-        headers
+/// Returns the distance to the top of the image of a tile whose bottom is `bottom_y` pixels
+/// above the bottom of the image. Used for sources that number their tiles
+/// starting from the bottom-left corner, such as TMS.
+pub fn flip_y(bottom_y: u32, tile_height: u32, image_height: u32) -> u32 {
+    image_height.saturating_sub(bottom_y + tile_height)
+}
+
+/// Flip the positions of tiles that were counted from the bottom of the image.
+/// The tile height is deduced from the space between rows, and the image height
+/// defaults to the top of the highest row.
+pub fn flip_tiles_y(tiles: &mut [TileReference], image_height: Option<u32>) {
+    let mut rows: Vec<u32> = tiles.iter().map(|t| t.position.y).collect();
+    rows.sort_unstable();
+    rows.dedup();
+    let max_y = rows.last().copied().unwrap_or(0);
+    let tile_height = rows.windows(2).map(|w| w[1] - w[0]).min()
+        .or(image_height)
+        .unwrap_or(0);
+    let image_height = image_height.unwrap_or(max_y + tile_height);
+    for tile in tiles {
+        tile.position.y = flip_y(tile.position.y, tile_height, image_height);
     }
 }
 
@@ -258,6 +449,32 @@ impl<T: TilesRect> TileProvider for T {
 pub struct TileReference {
     pub url: String,
     pub position: Vec2d,
+    /// The size the tile occupies in the image, when the dezoomer knows it.
+    /// Tiles that are received with another size are scaled, or cropped and padded, to fit it.
+    pub size: Option<Vec2d>,
+    /// How to request the tile, when it is not with a simple GET request
+    pub request: Option<Box<TileRequest>>,
+}
+
+impl TileReference {
+    /// Identifies the tile: its URL, and the method and the body of its request if it has one,
+    /// because tiles requested with POST can all share the same URL
+    pub fn key(&self) -> String {
+        match &self.request {
+            Some(request) => format!("{}#{} {}", self.url, request.method, request.body.as_deref().unwrap_or("")),
+            None => self.url.clone(),
+        }
+    }
+}
+
+/// A tile request with another method than GET, a body, or headers specific to the tile
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
+pub struct TileRequest {
+    /// The http method, such as POST
+    pub method: String,
+    pub body: Option<String>,
+    /// Headers sent with this tile only, in addition to the ones of its zoom level
+    pub headers: Vec<(String, String)>,
 }
 
 impl FromStr for TileReference {
@@ -275,6 +492,8 @@ impl FromStr for TileReference {
             Ok(TileReference {
                 url: String::from(url),
                 position: Vec2d { x, y },
+                size: None,
+                request: None,
             })
         } else {
             Err(make_error())
@@ -320,6 +539,7 @@ mod tests {
                 count: 0,
                 successes: 0,
                 tile_size: None,
+                successful_urls: vec![],
             });
         };
         assert_eq!(
@@ -328,20 +548,41 @@ mod tests {
                 TileReference {
                     url: "0,0".into(),
                     position: Vec2d { x: 0, y: 0 },
+                    size: None,
+                    request: None,
                 },
                 TileReference {
                     url: "1,0".into(),
                     position: Vec2d { x: 60, y: 0 },
+                    size: None,
+                    request: None,
                 },
                 TileReference {
                     url: "0,1".into(),
                     position: Vec2d { x: 0, y: 60 },
+                    size: None,
+                    request: None,
                 },
                 TileReference {
                     url: "1,1".into(),
                     position: Vec2d { x: 60, y: 60 },
+                    size: None,
+                    request: None,
                 }
             ]
         );
     }
+
+    #[test]
+    fn test_flip_tiles_y() {
+        let mut tiles: Vec<TileReference> = vec!["0 0 a", "0 256 b", "0 512 c"]
+            .into_iter().map(|s| s.parse().unwrap()).collect();
+        flip_tiles_y(&mut tiles, Some(600));
+        let ys: Vec<u32> = tiles.iter().map(|t| t.position.y).collect();
+        // The top row is only 88 pixels high
+        assert_eq!(ys, vec![344, 88, 0]);
+        flip_tiles_y(&mut tiles[..2], None);
+        assert_eq!(tiles[0].position.y, 0);
+        assert_eq!(tiles[1].position.y, 256);
+    }
 }
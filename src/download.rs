@@ -0,0 +1,978 @@
+//! Downloading an image: finding its zoom levels, choosing one, downloading its tiles and assembling them.
+
+use std::{fs, fmt};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::FutureExt;
+use futures::stream::StreamExt;
+use indicatif::ProgressBar;
+use itertools::Itertools;
+use lazy_static::lazy_static;
+use log::{debug, info, warn};
+
+use crate::{audit_log, batch, composite, custom_yaml, dry_run, generic, iiif, list_dezoomers, resizable, retry_failed};
+use crate::{tile, tile_cache, tile_filter, warmup, wizard};
+use crate::{Arguments, max_size_in_rect, stdin_line, Vec2d, ZoomError};
+use crate::dezoomer::{PostProcessFn, TileFetchResult, ZoomLevel, ZoomLevelIter};
+use crate::dezoomer::{Dezoomer, DezoomerError, DezoomerInput, ZoomLevels};
+use crate::dezoomer::TileReference;
+use crate::missing_tiles::MissingTilesPolicy;
+use crate::placeholder::PlaceholderDetector;
+use crate::missing_tiles::{fallback_level, fill_holes, Hole};
+use crate::network::{bootstrap, client, fetch_uri, set_read_timeout, tile_client, Fetcher};
+use crate::default_headers::set_default_headers_file;
+use crate::output_file::get_outname;
+use crate::downscale::{Downscale, OversizePolicy};
+use crate::encoder::ExportFormat;
+use crate::tile::Tile;
+use crate::progress_sink::{Phase, ProgressDisplay, ProgressSink, SharedProgressSink};
+use crate::encoder::tile_buffer::TileBuffer;
+use crate::output_file::{remove_reserved_file, reserve_output_file};
+use crate::dezoomer::PageContents;
+use crate::audit_log::{AuditEntry, AuditLog, set_audit_log};
+use crate::exif::ImageMetadata;
+use crate::throttler::{set_throttler, Throttler};
+use crate::cookies::{CookieJar, set_cookie_jar};
+use crate::tile_cache::{CachedImage, set_tile_cache, TileCache};
+use crate::failure_report::{FailedTile, FailureReport};
+use crate::preview::PreviewCheck;
+use std::error::Error;
+use std::env::current_dir;
+
+pub(crate) async fn list_tiles(
+    dezoomer: &mut dyn Dezoomer,
+    http: &Fetcher,
+    uri: &str,
+    args: &Arguments,
+) -> Result<ZoomLevels, ZoomError> {
+    let mut i = DezoomerInput {
+        uri: String::from(uri),
+        contents: PageContents::Unknown,
+    };
+    loop {
+        match dezoomer.zoom_levels(&i) {
+            Ok(levels) => return Ok(levels),
+            Err(DezoomerError::NeedsData { uri }) => {
+                let mut contents = fetch_uri(&uri, http).await;
+                if let Some(body) = contents.as_ref().err().and_then(ZoomError::unauthorized_contents) {
+                    if iiif::auth::authenticate(&uri, body, args)? {
+                        contents = fetch_uri(&uri, http).await;
+                    }
+                }
+                let contents = contents.into();
+                debug!("Response for metadata file '{}': {:?}", uri, &contents);
+                i.uri = uri;
+                i.contents = contents;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// An interactive level picker
+fn level_picker(levels: &mut Vec<ZoomLevel>) -> Result<ZoomLevel, ZoomError> {
+    eprintln!("Found the following zoom levels:");
+    for (i, level) in levels.iter().enumerate() {
+        eprintln!("{: >2}. {}", i, level.name());
+    }
+    loop {
+        eprintln!("Which level do you want to download? ");
+        let line = stdin_line()?;
+        if let Ok(idx) = line.parse::<usize>() {
+            if levels.get(idx).is_some() {
+                return Ok(levels.swap_remove(idx));
+            }
+        }
+        eprintln!("'{}' is not a valid level number", line);
+    }
+}
+
+fn choose_level(levels: &mut Vec<ZoomLevel>, args: &Arguments) -> Result<ZoomLevel, ZoomError> {
+    match levels.len() {
+        0 => Err(ZoomError::NoLevels),
+        1 => Ok(levels.swap_remove(0)),
+        _ => {
+            let sizes = levels.iter().filter_map(|l| l.size_hint());
+            let best_size = match args.target_dpi {
+                Some(dpi) => {
+                    let physical = levels.iter().find_map(|l| l.physical_size());
+                    match physical {
+                        Some(p) => info!("The original is {:.1} x {:.1} inches. Looking for a level of at least {} dpi",
+                                         p.width_inches, p.height_inches, dpi),
+                        None => warn!("The physical size of the image is unknown, so --target-dpi cannot be applied. \
+                                       Selecting the largest level."),
+                    }
+                    args.best_size_for_dpi(sizes, physical)
+                }
+                None => args.best_size(sizes),
+            };
+            let pos = best_size
+                .and_then(|best_size| {
+                    levels
+                        .iter()
+                        .find_position(|&l| l.size_hint() == Some(best_size))
+                });
+            if let Some((i, _)) = pos {
+                Ok(levels.swap_remove(i))
+            } else if args.is_interactive() {
+                level_picker(levels)
+            } else {
+                let levels = levels.iter()
+                    .map(|level| format!(" - {}", level.name()))
+                    .join("\n");
+                Err(ZoomError::LevelSelectionRequired { levels })
+            }
+        }
+    }
+}
+
+/// Returns the zoom level to download, and the level to use to fill missing tiles, if any
+async fn find_zoomlevel(args: &Arguments) -> Result<(ZoomLevel, Option<ZoomLevel>), ZoomError> {
+    if let Some(dir) = &args.from_tile_cache {
+        info!("Assembling the image from the tiles in {:?}", dir);
+        return Ok((Box::new(CachedImage::load(dir)?), None));
+    }
+    let mut dezoomer = args.find_dezoomer()?;
+    let uri = args.choose_input_uri()?;
+    let http_client = client(args.headers(), args, Some(&uri))?;
+    info!("Trying to locate a zoomable image...");
+    let mut zoom_levels: Vec<ZoomLevel> = list_tiles(dezoomer.as_mut(), &http_client, &uri, args).await?;
+    info!("Found {} zoom levels", zoom_levels.len());
+    check_min_size(&zoom_levels, args)?;
+    let zoom_level = choose_level(&mut zoom_levels, args)?;
+    let zoom_level = check_max_pixels(zoom_level, &mut zoom_levels, args)?;
+    let zoom_level = check_level_exists(zoom_level, &mut zoom_levels, args).await;
+    let fallback = if args.missing_tiles == MissingTilesPolicy::Interpolate {
+        fallback_level(zoom_levels, zoom_level.size_hint())
+    } else {
+        None
+    };
+    Ok((zoom_level, fallback))
+}
+
+/// Checks that the chosen level exists on the server by downloading a single one of its tiles,
+/// because some image descriptors announce levels that the server does not have.
+/// If it is missing, the smaller levels are probed from the largest to the smallest,
+/// and the first one that exists replaces it, instead of requesting thousands of missing tiles.
+async fn check_level_exists(zoom_level: ZoomLevel, levels: &mut Vec<ZoomLevel>, args: &Arguments) -> ZoomLevel {
+    let size = match (zoom_level.probe_tile(), zoom_level.size_hint()) {
+        (Some(_), Some(size)) => size,
+        _ => return zoom_level,
+    };
+    if level_exists(&zoom_level, args).await { return zoom_level; }
+    levels.sort_by_key(|l| std::cmp::Reverse(l.size_hint().map(|s| s.area())));
+    let smaller = (0..levels.len())
+        .filter(|&i| levels[i].size_hint().is_some_and(|s| s.area() < size.area()));
+    for i in smaller {
+        if level_exists(&levels[i], args).await {
+            let level = levels.remove(i);
+            warn!("The tiles of {} do not exist on the server. Downloading {} instead.",
+                  zoom_level.name(), level.name());
+            return level;
+        }
+    }
+    warn!("The tiles of {} may not exist on the server", zoom_level.name());
+    zoom_level
+}
+
+/// Whether the probe tile of a level exists. Only an explicit refusal from the server
+/// (a client error other than 401) counts as a missing level.
+async fn level_exists(level: &ZoomLevel, args: &Arguments) -> bool {
+    let tile = match level.probe_tile() {
+        Some(tile) => tile,
+        None => return true,
+    };
+    let http_client = match tile_client(level.http_headers().iter().chain(args.headers()), args) {
+        Ok(http_client) => http_client,
+        Err(_) => return true,
+    };
+    match fetch_uri(&tile.url, &http_client).await {
+        Err(ZoomError::Networking { source }) => {
+            let missing = source.status().is_some_and(|s| s.is_client_error());
+            if missing { debug!("Probe tile {} is missing: {}", tile.url, source) }
+            !missing
+        }
+        _ => true,
+    }
+}
+
+/// The size of the image that will be saved for a level of the given size, before downscaling
+fn output_size(size: Vec2d, args: &Arguments) -> Vec2d {
+    args.crop
+        .and_then(|region| region.intersection(Vec2d::default(), size))
+        .map_or(size, |region| region.size)
+}
+
+/// Applies the --oversize-policy if the chosen level has more pixels than allowed by --max-pixels
+fn check_max_pixels(zoom_level: ZoomLevel, levels: &mut Vec<ZoomLevel>, args: &Arguments) -> Result<ZoomLevel, ZoomError> {
+    let fits = |level: &ZoomLevel, max_pixels: u64| {
+        level.size_hint().is_none_or(|size| output_size(size, args).area() <= max_pixels)
+    };
+    let max_pixels = match args.max_pixels {
+        Some(max_pixels) if !fits(&zoom_level, max_pixels) => max_pixels,
+        _ => return Ok(zoom_level),
+    };
+    let Vec2d { x: width, y: height } = zoom_level.size_hint().map(|s| output_size(s, args)).unwrap_or_default();
+    let too_large = ZoomError::ImageTooLarge { width, height, max_pixels };
+    match args.oversize_policy {
+        OversizePolicy::Error => Err(too_large),
+        OversizePolicy::Downscale => Ok(zoom_level),
+        OversizePolicy::SmallerLevel => {
+            let smaller = levels.iter()
+                .positions(|level| level.size_hint().is_some() && fits(level, max_pixels))
+                .max_by_key(|&i| levels[i].size_hint().map(|s| s.area()))
+                .ok_or(too_large)?;
+            let level = levels.swap_remove(smaller);
+            warn!("{} is larger than --max-pixels. Downloading {} instead.", zoom_level.name(), level.name());
+            levels.push(zoom_level);
+            Ok(level)
+        }
+    }
+}
+
+/// Returns an error if the image is smaller than the size requested with --if-larger-than
+fn check_min_size(levels: &[ZoomLevel], args: &Arguments) -> Result<(), ZoomError> {
+    let min_size = if let Some(min_size) = args.if_larger_than { min_size } else { return Ok(()) };
+    let largest = levels.iter().filter_map(|l| l.size_hint()).max_by_key(|s| s.area());
+    match largest {
+        Some(size) if !min_size.fits_inside(size) => Err(ZoomError::ImageTooSmall {
+            width: size.x,
+            height: size.y,
+            min_width: min_size.x,
+            min_height: min_size.y,
+        }),
+        Some(_) => Ok(()),
+        None => {
+            warn!("The size of the image is unknown, so --if-larger-than cannot be applied");
+            Ok(())
+        }
+    }
+}
+
+lazy_static! {
+    /// Held during the downloads started with [`dezoomify_exclusive`]
+    pub(crate) static ref RUN_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+/// Downloads the image described by the arguments, and returns the path where it was saved.
+/// With --dry-run, nothing is saved, and the returned path is empty.
+///
+/// The throttler, the cookies, the tile cache, the audit log and the other settings of a download
+/// are shared by the whole process, so only one download can run at a time.
+/// [`DezoomRequest::run`] waits for the previous requests to end.
+pub async fn dezoomify(args: &Arguments) -> Result<PathBuf, ZoomError> {
+    // The log of a previous run in the same process must not receive the entries of this one
+    let audit_log = args.audit_log.as_deref().map(AuditLog::open).transpose()?;
+    set_audit_log(audit_log);
+    set_throttler(Some(Throttler::new(args.min_interval, args.auto_throttle)));
+    set_read_timeout(args.read_timeout);
+    set_default_headers_file(args.default_headers.as_deref())?;
+    if let Some(dir) = &args.record_http {
+        fs::create_dir_all(dir)?;
+    }
+    custom_yaml::descriptor::set_descriptor_dir(args.dezoomers_dir.as_deref());
+    resizable::set_strip_size(args.strip_size);
+    generic::set_tile_grid(args.tile_columns, args.tile_rows);
+    let cookies = match &args.cookies_file {
+        Some(path) if path.exists() => CookieJar::load(path)?,
+        _ => CookieJar::default(),
+    };
+    let cookies = set_cookie_jar(cookies);
+    // The cache of a previous run in the same process must not be used by this one
+    let tile_cache = match (&args.tile_cache, &args.from_tile_cache) {
+        (Some(_), Some(_)) => {
+            warn!("--tile-cache is ignored when assembling an image --from-tile-cache");
+            None
+        }
+        (Some(dir), None) => {
+            Some(TileCache::open(dir, args.tile_cache_size)?.ignoring_params(args.cache_ignore_param.clone()))
+        }
+        (None, _) => {
+            if !args.cache_ignore_param.is_empty() {
+                warn!("--cache-ignore-param is ignored without --tile-cache");
+            }
+            None
+        }
+    };
+    set_tile_cache(tile_cache);
+    let result = dezoomify_image(args).await;
+    if let Some(path) = &args.cookies_file {
+        if let Err(e) = cookies.save(path) {
+            warn!("Unable to save the cookies to {:?}: {}", path, e);
+        }
+    }
+    if let (Some(uri), true) = (&args.input_uri, args.saves_image()) {
+        audit_log::record(|| AuditEntry::image(uri, &result));
+    }
+    result
+}
+
+/// Downloads an image once the other downloads started this way have ended,
+/// or stops waiting if the download is cancelled
+pub(crate) async fn dezoomify_exclusive(args: &Arguments) -> Result<PathBuf, ZoomError> {
+    let cancelled = async {
+        match &args.cancel {
+            Some(token) => token.cancelled().await,
+            None => futures::future::pending().await,
+        }
+    };
+    let _running = tokio::select! {
+        guard = RUN_LOCK.lock() => guard,
+        _ = cancelled => return Err(ZoomError::Interrupted),
+    };
+    dezoomify(args).await
+}
+
+pub(crate) async fn dezoomify_image(args: &Arguments) -> Result<PathBuf, ZoomError> {
+    if args.list_dezoomers {
+        list_dezoomers::print_dezoomers(args)?;
+        return Ok(PathBuf::new());
+    }
+    if let Some(path) = &args.validate {
+        custom_yaml::print_validation(path)?;
+        return Ok(PathBuf::new());
+    }
+    if args.wizard {
+        wizard::wizard(args).await?;
+        return Ok(PathBuf::new());
+    }
+    if args.dry_run {
+        dry_run::print_report(args).await?;
+        return Ok(PathBuf::new());
+    }
+    if let Some(path) = &args.retry_failed {
+        return retry_failed::retry_failed(path, args).await;
+    }
+    if let Some(path) = &args.composite {
+        return composite::composite(path, args).await;
+    }
+    let (zoom_level, fallback) = find_zoomlevel(args).await?;
+    // Do not create the output file if the user pressed Ctrl-C while the image was being found
+    if is_cancelled(args) { return Err(ZoomError::Interrupted); }
+    let base_dir = match &args.out_dir {
+        Some(dir) => {
+            fs::create_dir_all(dir)?;
+            dir.clone()
+        }
+        None => current_dir()?,
+    };
+    let outfile = match (&args.from_tile_cache, &args.outfile, &args.input_uri) {
+        // No input URL is needed in this case, so a single positional argument is the output file
+        (Some(_), None, Some(uri)) => Some(PathBuf::from(uri)),
+        _ => args.outfile.clone(),
+    };
+    // A relative output file is relative to the output directory
+    let outfile = match &args.out_dir {
+        Some(dir) => outfile.map(|path| dir.join(path)),
+        None => outfile,
+    };
+    let name = match &args.output_template {
+        Some(template) => {
+            let title = zoom_level.title().unwrap_or_else(|| "dezoomified".into());
+            Some(template.render(Some(&title), zoom_level.size_hint(), args.input_uri.as_deref()))
+        }
+        None => zoom_level.title(),
+    };
+    let export_extension = args.export_format.map(ExportFormat::extension);
+    let outname = get_outname(&outfile, &name, &base_dir, zoom_level.size_hint(), export_extension);
+    let save_as = fs::canonicalize(outname.as_path()).unwrap_or_else(|_e| outname.clone());
+    reserve_output_file(&save_as)?;
+    let mut metadata = ImageMetadata::new(args.input_uri.clone().filter(|_| args.from_tile_cache.is_none()));
+    metadata.title = zoom_level.title();
+    metadata.rights = zoom_level.rights();
+    metadata.embed_xmp = args.embed_metadata;
+    let mut tile_buffer: TileBuffer =
+        match TileBuffer::new(save_as.clone(), args.compression, args.strict_format, args.max_memory, metadata, args.split_options()).await {
+            Ok(tile_buffer) => tile_buffer,
+            Err(e) => {
+                remove_reserved_file(&save_as);
+                return Err(e);
+            }
+        };
+    info!("Dezooming {}", zoom_level.name());
+    if let Err(e) = dezoomify_level(args, zoom_level, fallback, &mut tile_buffer).await {
+        // Do not leave an empty placeholder behind if nothing could be written
+        remove_reserved_file(tile_buffer.destination());
+        return Err(e);
+    }
+    Ok(tile_buffer.destination().to_path_buf())
+}
+
+pub async fn dezoomify_level(
+    args: &Arguments,
+    mut zoom_level: ZoomLevel,
+    fallback: Option<ZoomLevel>,
+    tile_buffer: &mut TileBuffer,
+) -> Result<(), ZoomError> {
+    let mut level_headers = zoom_level.http_headers();
+    if let Some(request) = zoom_level.bootstrap_request() {
+        let bootstrap_client = client(level_headers.iter().chain(args.headers()), args, None)?;
+        match bootstrap(&request, &bootstrap_client).await {
+            Ok(headers) => level_headers.extend(headers),
+            Err(e) => warn!("{}. Trying to download the tiles anyway.", e),
+        }
+    }
+    let mut http_client = tile_client(level_headers.iter().chain(args.headers()), args)?;
+    let mut preview_check = match &args.verify_with_preview {
+        Some(_) if args.crop.is_some() => {
+            warn!("The cropped image cannot be compared with the preview given with --verify-with-preview");
+            None
+        }
+        Some(url) => {
+            let preview_client = client(level_headers.iter().chain(args.headers()), args, None)?;
+            PreviewCheck::download(url, &preview_client).await
+                .map_err(|e| warn!("Unable to download the preview at {}: {}", url, e))
+                .ok()
+        }
+        None => None,
+    };
+
+    info!("Creating canvas");
+    let canvas = tile_buffer;
+
+    let progress: Arc<dyn ProgressSink> = match &args.progress {
+        Some(SharedProgressSink(sink)) => Arc::clone(sink),
+        None if args.quiet => Arc::new(ProgressBar::hidden()),
+        None => Arc::new(ProgressDisplay::new(args.stall_timeout)),
+    };
+    let mut total_tiles = 0u64;
+    let mut successful_tiles = 0u64;
+    // The tiles that are requested again, in addition to the tiles of the image
+    let mut retried_tiles = 0u64;
+
+    let crop = match (args.crop, zoom_level.size_hint()) {
+        (Some(region), Some(size)) => {
+            let Vec2d { x: width, y: height } = size;
+            Some(region.intersection(Vec2d::default(), size).ok_or(ZoomError::CropOutsideImage { width, height })?)
+        }
+        (Some(_), None) => {
+            warn!("The size of the image is not known in advance, so --crop cannot be applied");
+            None
+        }
+        (None, _) => None,
+    };
+    let downscale = zoom_level.size_hint()
+        .and_then(|size| Downscale::for_args(crop.map_or(size, |region| region.size), args));
+    if downscale.is_none() && zoom_level.size_hint().is_none() && (args.scale.is_some() || args.resize.is_some()) {
+        warn!("The size of the image is not known in advance, so it cannot be scaled down");
+    }
+    let tile_crop = args.tile_crop.unwrap_or_else(|| zoom_level.tile_crop());
+    let image_size = zoom_level.size_hint();
+    // Tiles are cut to the cropped region and scaled down just before they are added to the canvas
+    let prepare_tile = |tile: Tile| {
+        let tile = if tile_crop.is_empty() { tile } else { tile_crop.crop_tile(tile, image_size) };
+        let tile = match crop {
+            Some(region) => region.crop_tile(tile)?,
+            None => tile,
+        };
+        match downscale {
+            Some(downscale) => downscale.scale_tile(tile),
+            None => Some(tile),
+        }
+    };
+
+    let post_process_fn = zoom_level.post_process_fn();
+    tile_cache::record_image(zoom_level.size_hint(), zoom_level.title());
+    let title = zoom_level.title();
+    // What the pixels of the image depend on, to find the checkpoint left by a previous attempt
+    let image_description = format!("{:?} {} {:?} {:?} {:?}",
+                                    args.input_uri, zoom_level.name(), crop, tile_crop, args.tile_filter);
+
+    progress.set_phase(Phase::ListingTiles);
+
+    let mut zoom_level_iter = ZoomLevelIter::new(&mut zoom_level);
+    let mut last_count = 0;
+    let mut last_successes = 0;
+    let mut holes: Vec<Hole> = vec![];
+    let mut missing_tile: Option<String> = None;
+    // An error that makes the download of the other tiles pointless
+    let mut fatal_error: Option<ZoomError> = None;
+    let mut low_resolution_tiles: Vec<String> = vec![];
+    // The tiles of the last batch that could not be downloaded
+    let mut failed_tiles: Vec<FailedTile> = vec![];
+    let mut placeholders = PlaceholderDetector::default();
+    while let Some(tile_refs) = zoom_level_iter.next_tile_references() {
+        let tile_refs = match (crop, zoom_level_iter.size_hint()) {
+            (Some(region), Some(size)) => region.tiles_inside(tile_refs, size),
+            _ => tile_refs,
+        };
+        last_count = tile_refs.len() as u64;
+        total_tiles += last_count;
+        progress.set_total_tiles(total_tiles + retried_tiles);
+
+        if args.warmup && total_tiles == last_count {
+            progress.set_phase(Phase::WarmingUp);
+            if let Some(plan) = warmup::warmup(&tile_refs, &http_client, args.parallelism).await {
+                progress.println(&plan.to_string());
+            }
+        }
+
+        progress.set_phase(Phase::DownloadingTiles);
+
+        last_successes = 0;
+        let mut successful_urls = vec![];
+        let mut tile_size = None;
+
+        let canvas_size = zoom_level_iter.size_hint();
+        // The tiles that are already in the image, assembled by a previous attempt
+        let mut resumed = HashSet::new();
+        if let Some(size) = canvas_size {
+            let size = crop.map_or(size, |region| region.size);
+            let output_size = downscale.map_or(size, |downscale| downscale.size());
+            let checkpoint = tile_cache::checkpoint(&format!("{} {}", image_description, output_size));
+            resumed = canvas.set_size(output_size, checkpoint).await?;
+            if let Some(check) = &mut preview_check { check.set_image_size(output_size); }
+            tile_cache::record_image(Some(size), title.clone());
+        }
+        // Missing tiles policies only apply once the size of the image is known:
+        // before that, failed requests may just be the dezoomer probing for the image edges.
+        let policy = if canvas_size.is_some() { args.missing_tiles } else { MissingTilesPolicy::Blank };
+        let mut hole_positions = vec![];
+        let grid_step = tile::grid_step(&tile_refs);
+        let (tile_refs, skipped): (Vec<_>, Vec<_>) = tile_refs.into_iter()
+            .partition(|tile_ref| !resumed.contains(&tile_ref.position));
+        if !skipped.is_empty() {
+            info!("Resuming the assembly of the image: {} tiles are already in its checkpoint", skipped.len());
+            skipped.iter().for_each(|_| progress.tile_completed(Some(0)));
+            last_successes += skipped.len() as u64;
+            successful_urls.extend(skipped.into_iter().map(|tile_ref| tile_ref.url));
+            if preview_check.take().is_some() {
+                warn!("The image resumed from a checkpoint cannot be compared with the preview given with --verify-with-preview");
+            }
+        }
+
+        let mut pending = tile_refs;
+        let mut stall_restarts = 0;
+        let mut placeholder_retries = 0;
+        loop {
+            let plan = if args.batch_tiles > 1 { zoom_level_iter.fetch_plan(&pending, args.batch_tiles) } else { vec![] };
+            let jobs = batch::jobs(pending.clone(), plan, &http_client).await;
+            let http = &http_client;
+            let download = |tile_ref: TileReference, batched: Option<Vec<u8>>| {
+                let reference = tile_ref.clone();
+                let expected_size = tile::expected_size(&tile_ref, grid_step, canvas_size);
+                download_tile(post_process_fn.clone(), tile_ref, expected_size, batched, http, args)
+                    .map(|result| (reference, result))
+            };
+            let mut stream = futures::stream::iter(jobs)
+                // After a cancellation, no new tile is requested, but the requests in flight are received
+                .take_while(|_| futures::future::ready(!is_cancelled(args)))
+                .map(|job| async move {
+                    let (tile_refs, mut batched) = batch::receive(job, http).await;
+                    futures::future::join_all(tile_refs.into_iter().map(|tile_ref| {
+                        let bytes = batched.remove(&tile_ref.key());
+                        download(tile_ref, bytes)
+                    })).await
+                })
+                .buffer_unordered(args.parallelism)
+                .flat_map(futures::stream::iter);
+            let stall_timeout = args.stall_timeout.filter(|_| stall_restarts < MAX_STALL_RESTARTS);
+            let mut completed = HashSet::new();
+            let mut stalled = false;
+            loop {
+                let next = match stall_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, stream.next()).await.unwrap_or_else(|_| {
+                        stalled = true;
+                        None
+                    }),
+                    None => stream.next().await,
+                };
+                let last = next.is_none();
+                if last && stalled { break; }
+                let mut received = vec![];
+                let tile = match next {
+                    // The tiles that were held back are not placeholders
+                    None => {
+                        received = placeholders.flush();
+                        None
+                    }
+                    Some((reference, tile_result)) => {
+                        completed.insert(reference.key());
+                        debug!("Received tile result: {:?}", tile_result);
+                        progress.tile_completed(tile_result.as_ref().ok().map(|(tile, _)| {
+                            tile.encoded.as_ref().map_or(0, |bytes| bytes.len() as u64)
+                        }));
+                        match tile_result {
+                            Ok((tile, low_resolution)) => {
+                                progress.message(&format!("Downloaded tile at {}", tile.position()));
+                                let tile = ReceivedTile { reference, tile, low_resolution };
+                                match placeholders.receive(tile, args.placeholder_tiles, args.placeholder_pause).await {
+                                    Ok(tiles) => received = tiles,
+                                    Err(error) => {
+                                        fatal_error = Some(error);
+                                        break;
+                                    }
+                                }
+                                None
+                            }
+                            Err(TileDownloadError { tile_reference, cause: cause @ ZoomError::LoginRedirect { .. } }) => {
+                                failed_tiles.push(FailedTile::new(&tile_reference, &cause));
+                                fatal_error = Some(cause);
+                                break;
+                            }
+                            Err(err) => {
+                                progress.message(&err.to_string());
+                                // Before the size of the image is known, failed requests are not missing tiles
+                                if canvas_size.is_some() {
+                                    failed_tiles.push(FailedTile::new(&err.tile_reference, &err.cause));
+                                }
+                                let position = err.tile_reference.position;
+                                match policy {
+                                    MissingTilesPolicy::Abort => {
+                                        missing_tile = Some(err.tile_reference.url);
+                                        break;
+                                    }
+                                    MissingTilesPolicy::Interpolate if fallback.is_some() => {
+                                        hole_positions.push(position);
+                                        None
+                                    }
+                                    // If a tile download fails, we replace it with an empty tile
+                                    _ => tile_size.and_then(|tile_size| {
+                                        canvas_size.map(|canvas_size| {
+                                            let size = max_size_in_rect(position, tile_size, canvas_size);
+                                            Tile::empty(position, size)
+                                        })
+                                    })
+                                }
+                            }
+                        }
+                    }
+                };
+                let mut composited = vec![];
+                let received = received.into_iter().map(|ReceivedTile { reference, tile, low_resolution }| {
+                    if low_resolution { low_resolution_tiles.push(reference.url.clone()); }
+                    tile_cache::record_tile(&reference.key(), tile.position());
+                    composited.push(reference.position);
+                    tile_size.replace(tile.size());
+                    last_successes += 1;
+                    successful_urls.push(reference.url);
+                    tile
+                }).collect::<Vec<_>>();
+                for tile in received.into_iter().chain(tile).filter_map(prepare_tile) {
+                    if let Some(check) = &mut preview_check { check.add_tile(&tile); }
+                    canvas.add_tile(tile).await;
+                }
+                if !composited.is_empty() { canvas.checkpoint(composited).await; }
+                if last { break; }
+            }
+            if !stalled {
+                let replaced = placeholders.take_replaced();
+                if replaced.is_empty() {
+                    break;
+                }
+                let stopped = missing_tile.is_some() || fatal_error.is_some() || is_cancelled(args);
+                if stopped || placeholder_retries >= MAX_PLACEHOLDER_RETRIES {
+                    let error = placeholders.error().expect("placeholders were received");
+                    warn!("{} tiles are placeholders, and are not saved.", replaced.len());
+                    failed_tiles.extend(replaced.iter().map(|tile_ref| FailedTile::new(tile_ref, &error)));
+                    break;
+                }
+                // The placeholders are not saved, and the tiles are requested again after the pause
+                placeholder_retries += 1;
+                pending = replaced;
+                pending.iter().for_each(|tile_ref| tile_cache::forget(&tile_ref.key()));
+                warn!("Requesting the {} tiles that were replaced by placeholders again.", pending.len());
+                retried_tiles += pending.len() as u64;
+                progress.set_total_tiles(total_tiles + retried_tiles);
+                continue;
+            }
+            // Dropping the stream aborts the requests in flight, and a new client opens new connections
+            drop(stream);
+            pending.retain(|tile_ref| !completed.contains(&tile_ref.key()));
+            stall_restarts += 1;
+            warn!("No tile was received for {:?}. Restarting the download of the {} remaining tiles.",
+                  args.stall_timeout.unwrap_or_default(), pending.len());
+            http_client = tile_client(level_headers.iter().chain(args.headers()), args)?;
+        }
+        if missing_tile.is_some() || fatal_error.is_some() { break; }
+        if let (Some(tile_size), Some(canvas_size)) = (tile_size, canvas_size) {
+            holes.extend(hole_positions.into_iter().map(|position| {
+                Hole { position, size: max_size_in_rect(position, tile_size, canvas_size) }
+            }));
+        }
+        successful_tiles += last_successes;
+        if is_cancelled(args) {
+            warn!("The download was cancelled. Saving the {} tiles that were received.", successful_tiles);
+            break;
+        }
+        zoom_level_iter.set_fetch_result(TileFetchResult {
+            count: last_count,
+            successes: last_successes,
+            tile_size,
+            successful_urls,
+        });
+    }
+
+    let canvas_size = zoom_level_iter.size_hint();
+    let aborted = missing_tile.is_some() || fatal_error.is_some();
+    let fallback = fallback.filter(|_| !is_cancelled(args) && !aborted);
+    if let (Some(fallback), Some(canvas_size), false) = (fallback, canvas_size, holes.is_empty()) {
+        progress.set_phase(Phase::Interpolating(holes.len()));
+        let tiles = fill_holes(fallback, &holes, canvas_size, args).await.unwrap_or_else(|e| {
+            warn!("Unable to interpolate the missing tiles: {}", e);
+            holes.iter().map(|h| Tile::empty(h.position, h.size)).collect()
+        });
+        for tile in tiles.into_iter().filter_map(prepare_tile) {
+            if let Some(check) = &mut preview_check { check.add_tile(&tile); }
+            canvas.add_tile(tile).await;
+        }
+    }
+
+    if !low_resolution_tiles.is_empty() {
+        warn!("{} tiles were received at a lower resolution than the rest of the image, \
+               and will look blurry: {}{}",
+              low_resolution_tiles.len(),
+              low_resolution_tiles.iter().take(5).join(", "),
+              if args.refetch_low_resolution { "" } else { ". Use --refetch-low-resolution to request them again." });
+    }
+
+    // The list is written even if the image cannot be saved, so that the tiles can be requested again
+    let sized = canvas.fix_size().await;
+    if let Some(path) = &args.failed_tiles_log {
+        let report = FailureReport {
+            input_uri: args.input_uri.clone(),
+            output: canvas.destination().to_path_buf(),
+            failed_tiles,
+        };
+        if let Err(e) = report.save(path) {
+            warn!("Unable to write the list of failed tiles to {:?}: {}", path, e);
+        }
+    }
+
+    // A blank image is not saved when the download is cancelled before any tile is received
+    let interrupted = Some(ZoomError::Interrupted).filter(|_| successful_tiles == 0 && is_cancelled(args));
+    if let Some(err) = missing_tile.map(|url| ZoomError::MissingTile { url }).or(fatal_error).or(interrupted) {
+        progress.finish("Aborted");
+        canvas.abort().await?;
+        return Err(err);
+    }
+    sized?;
+    progress.set_phase(Phase::Finalizing);
+    canvas.finalize().await?;
+
+    progress.finish("Finished tile download");
+    if successful_tiles == 0 {
+        return Err(ZoomError::NoTile);
+    }
+
+    let verified = preview_check.map_or(Ok(()), |check| check.verify());
+    if last_successes < last_count || (is_cancelled(args) && successful_tiles < total_tiles) {
+        Err(ZoomError::PartialDownload { successful_tiles, total_tiles })
+    } else {
+        verified
+    }
+}
+
+fn is_cancelled(args: &Arguments) -> bool {
+    args.cancel.as_ref().is_some_and(|token| token.is_cancelled())
+}
+
+/// Number of times the download of a batch of tiles is restarted when it stalls
+const MAX_STALL_RESTARTS: u32 = 3;
+
+/// Number of times the tiles that were replaced by placeholders are requested again
+const MAX_PLACEHOLDER_RETRIES: u32 = 1;
+
+/// A tile that was downloaded, and that may be held back while it is checked for placeholders
+struct ReceivedTile {
+    reference: TileReference,
+    tile: Tile,
+    low_resolution: bool,
+}
+
+impl AsRef<Tile> for ReceivedTile {
+    fn as_ref(&self) -> &Tile { &self.tile }
+}
+
+impl AsRef<TileReference> for ReceivedTile {
+    fn as_ref(&self) -> &TileReference { &self.reference }
+}
+
+/// Downloads a tile, retrying when it fails.
+/// The `batched` bytes of a tile that was received with other tiles are decoded instead,
+/// and the tile is requested alone if they are not valid.
+/// Also returns whether the tile is smaller than the area it should cover.
+pub(crate) async fn download_tile(
+    post_process_fn: PostProcessFn,
+    tile_reference: TileReference,
+    expected_size: Option<Vec2d>,
+    batched: Option<Vec<u8>>,
+    client: &Fetcher,
+    args: &Arguments,
+) -> Result<(Tile, bool), TileDownloadError> {
+    let post_process_fn = tile_filter::with_tile_filter(post_process_fn, args.tile_filter.as_deref());
+    let batched = match batched {
+        Some(bytes) => Tile::decode(post_process_fn.clone(), &tile_reference, bytes, Some(Default::default())).await
+            .map_err(|e| debug!("The tile '{}' received in a batch is invalid: {}", tile_reference.url, e))
+            .ok(),
+        None => None,
+    };
+    let mut res = match batched {
+        Some(tile) => Ok(tile),
+        None => Tile::download(post_process_fn.clone(), &tile_reference, client).await,
+    };
+    for attempt in 0..args.retries {
+        let err = match &res {
+            Ok(_) => { break; },
+            Err(e) => e,
+        };
+        // Logging in is required: retrying would only be redirected again
+        if let ZoomError::LoginRedirect { .. } = err { break; }
+        let retry_after = err.retry_after();
+        let wait_time = retry_wait_time(args.retry_delay, attempt as u32, retry_after, rand::random());
+        warn!("{}. Retrying tile download in {:?}.", err, wait_time);
+        tokio::time::sleep(wait_time).await;
+        res = Tile::download(post_process_fn.clone(), &tile_reference, client).await;
+    }
+    if let (true, PostProcessFn::None, Err(ZoomError::PartialBody { contents, .. })) = (args.salvage_partial_tiles, &post_process_fn, &res) {
+        if let Some(image) = tile::salvage_jpeg(&contents.0) {
+            warn!("Only part of the tile '{}' could be recovered", tile_reference.url);
+            return Ok((Tile { image, position: tile_reference.position, encoded: None }, false));
+        }
+    }
+    let (tile, received) = res.map_err(|cause| TileDownloadError { tile_reference: tile_reference.clone(), cause })?;
+    let expected = match expected_size {
+        Some(expected) if tile::is_low_resolution(received, expected) => expected,
+        _ => return Ok((tile, false)),
+    };
+    warn!("The tile '{}' has a size of {}, but it should cover an area of {}. \
+           The server may have sent a downscaled version of it.", tile_reference.url, received, expected);
+    // Local files are not behind a cache
+    if args.refetch_low_resolution && tile_reference.url.starts_with("http") {
+        let fresh_reference = TileReference { url: cache_busting_url(&tile_reference.url), ..tile_reference };
+        match Tile::download(post_process_fn, &fresh_reference, client).await {
+            Ok((fresh_tile, received)) if !tile::is_low_resolution(received, expected) => {
+                info!("Received the tile '{}' in full resolution", fresh_reference.url);
+                return Ok((fresh_tile, false));
+            }
+            Ok(_) => warn!("The tile '{}' is still downscaled", fresh_reference.url),
+            Err(err) => warn!("Unable to request the tile '{}' again: {}", fresh_reference.url, err),
+        }
+    }
+    Ok((tile, true))
+}
+
+/// Adds a unique query parameter to a URL, so that the caches between dezoomify-rs and the server
+/// cannot answer with the response they stored for it
+fn cache_busting_url(url: &str) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}dezoomify-rs-nocache={}", url, separator, rand::random::<u32>())
+}
+
+/// Time to wait before a new attempt to download a tile.
+/// The maximal wait time doubles at each attempt, and the actual wait time is a random fraction of it
+/// ("full jitter"), so that the tiles that failed together are not all retried at the same time.
+/// A delay requested by the server with a Retry-After header is always respected.
+fn retry_wait_time(retry_delay: Duration, attempt: u32, retry_after: Option<Duration>, random: f64) -> Duration {
+    let max_wait = retry_delay.saturating_mul(1 << attempt.min(16));
+    let wait = max_wait.mul_f64(random.clamp(0., 1.));
+    retry_after.map_or(wait, |delay| delay.max(wait))
+}
+
+#[derive(Debug)]
+pub(crate) struct TileDownloadError {
+    pub(crate) tile_reference: TileReference,
+    pub(crate) cause: ZoomError,
+}
+
+impl fmt::Display for TileDownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unable to download tile '{}'. Cause: {}", self.tile_reference.url, self.cause)
+    }
+}
+
+impl Error for TileDownloadError {}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::dezoomer::TilesRect;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct FakeLvl(u32);
+
+    impl TilesRect for FakeLvl {
+        fn size(&self) -> Vec2d { Vec2d::square(self.0) }
+        fn tile_size(&self) -> Vec2d { Vec2d::square(256) }
+        fn tile_url(&self, _pos: Vec2d) -> String { String::new() }
+    }
+
+    fn fake_levels() -> Vec<ZoomLevel> {
+        vec![Box::new(FakeLvl(100)), Box::new(FakeLvl(1000))]
+    }
+
+    #[allow(clippy::field_reassign_with_default)]
+    #[test]
+    fn test_non_interactive_level_choice() {
+        let mut args = Arguments::default();
+        args.non_interactive = true;
+        match choose_level(&mut fake_levels(), &args) {
+            Err(ZoomError::LevelSelectionRequired { levels }) => {
+                assert!(levels.contains("100 x   100"), "unexpected level list: {}", levels);
+                assert!(levels.contains("1000 x  1000"), "unexpected level list: {}", levels);
+            }
+            other => panic!("Unexpected result: {:?}", other.map(|l| l.name())),
+        }
+        args.largest = true;
+        let level = choose_level(&mut fake_levels(), &args).unwrap();
+        assert_eq!(level.size_hint(), Some(Vec2d::square(1000)));
+    }
+
+    #[allow(clippy::field_reassign_with_default)]
+    #[test]
+    fn test_max_pixels() {
+        let mut args = Arguments::default();
+        args.max_pixels = Some(500_000);
+        let mut levels = fake_levels();
+        let level = levels.pop().unwrap();
+        assert!(matches!(
+            check_max_pixels(level, &mut levels, &args),
+            Err(ZoomError::ImageTooLarge { width: 1000, height: 1000, max_pixels: 500_000 })
+        ));
+        args.oversize_policy = OversizePolicy::SmallerLevel;
+        let mut levels = fake_levels();
+        let level = levels.pop().unwrap();
+        let level = check_max_pixels(level, &mut levels, &args).unwrap();
+        assert_eq!(level.size_hint(), Some(Vec2d::square(100)));
+    }
+
+    #[allow(clippy::field_reassign_with_default)]
+    #[test]
+    fn test_if_larger_than() {
+        let mut args = Arguments::default();
+        assert!(check_min_size(&fake_levels(), &args).is_ok());
+        args.if_larger_than = Some(Vec2d { x: 1000, y: 500 });
+        assert!(check_min_size(&fake_levels(), &args).is_ok());
+        args.if_larger_than = Some(Vec2d { x: 1001, y: 500 });
+        assert!(matches!(
+            check_min_size(&fake_levels(), &args),
+            Err(ZoomError::ImageTooSmall { width: 1000, height: 1000, .. })
+        ));
+    }
+
+    #[allow(clippy::field_reassign_with_default)]
+    #[tokio::test]
+    async fn test_exclusive_cancelled_while_waiting() {
+        let _running = RUN_LOCK.lock().await;
+        let mut args = Arguments::default();
+        let token = tokio_util::sync::CancellationToken::new();
+        args.cancel = Some(token.clone());
+        token.cancel();
+        assert!(matches!(dezoomify_exclusive(&args).await, Err(ZoomError::Interrupted)));
+    }
+
+    #[test]
+    fn test_retry_wait_time() {
+        let delay = Duration::from_secs(2);
+        assert_eq!(retry_wait_time(delay, 0, None, 1.), Duration::from_secs(2));
+        assert_eq!(retry_wait_time(delay, 2, None, 0.5), Duration::from_secs(4));
+        assert_eq!(retry_wait_time(delay, 3, None, 0.), Duration::from_secs(0));
+        let retry_after = Some(Duration::from_secs(30));
+        assert_eq!(retry_wait_time(delay, 0, retry_after, 1.), Duration::from_secs(30));
+    }
+}
@@ -0,0 +1,166 @@
+//! Scaling the image down while it is being stitched, with `--scale` and `--resize`,
+//! or when it is larger than the limit set with `--max-pixels`
+
+use std::str::FromStr;
+
+use image::imageops::FilterType;
+#[cfg(feature = "cli")]
+use log::{info, warn};
+
+#[cfg(feature = "cli")]
+use crate::Arguments;
+use crate::{Tile, Vec2d};
+
+/// What to do when the selected level has more pixels than allowed by --max-pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Stop before downloading anything
+    Error,
+    /// Download the largest level that is under the limit instead
+    SmallerLevel,
+    /// Download the selected level, but scale the tiles down while stitching them
+    Downscale,
+}
+
+impl FromStr for OversizePolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(OversizePolicy::Error),
+            "smaller-level" => Ok(OversizePolicy::SmallerLevel),
+            "downscale" => Ok(OversizePolicy::Downscale),
+            _ => Err("Invalid oversize policy. Expected 'error', 'smaller-level' or 'downscale'"),
+        }
+    }
+}
+
+/// The maximal dimensions of the image given with --resize. A missing dimension is not limited.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FitSize {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+}
+
+impl FitSize {
+    /// Parses a size such as 4000x3000, 4000x (only the width is limited) or x3000
+    pub fn parse(s: &str) -> Result<FitSize, &'static str> {
+        let err_msg = "Invalid size. Expected a width and a height separated by 'x', \
+                       such as '4000x3000', '4000x' or 'x3000'";
+        let (width, height) = s.split_once('x').ok_or(err_msg)?;
+        let dimension = |d: &str| match d.trim() {
+            "" => Ok(None),
+            d => d.parse().ok().filter(|&d| d > 0).map(Some).ok_or(err_msg),
+        };
+        match (dimension(width)?, dimension(height)?) {
+            (None, None) => Err(err_msg),
+            (width, height) => Ok(FitSize { width, height }),
+        }
+    }
+
+    fn factor(&self, size: Vec2d) -> f64 {
+        let ratio = |max: Option<u32>, actual: u32| max.map_or(1., |max| f64::from(max) / f64::from(actual));
+        ratio(self.width, size.x).min(ratio(self.height, size.y))
+    }
+}
+
+/// Parses a scale given as a percentage, such as 50%, or as a factor, such as 0.5
+pub fn parse_scale(s: &str) -> Result<f64, &'static str> {
+    let err_msg = "Invalid scale. Expected a percentage between 0% and 100%, such as '50%'";
+    let s = s.trim();
+    let factor = match s.strip_suffix('%') {
+        Some(percent) => percent.trim().parse::<f64>().map(|p| p / 100.),
+        None => s.parse::<f64>(),
+    }.map_err(|_| err_msg)?;
+    if factor > 0. && factor <= 1. { Ok(factor) } else { Err(err_msg) }
+}
+
+/// Scales the tiles of an image down, so that the whole image is smaller by a constant factor
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Downscale {
+    original_size: Vec2d,
+    factor: f64,
+}
+
+impl Downscale {
+    pub fn new(original_size: Vec2d, factor: f64) -> Self {
+        Downscale { original_size, factor: factor.min(1.) }
+    }
+
+    /// The scaling requested by the arguments for an image of the given size, if any
+    #[cfg(feature = "cli")]
+    pub fn for_args(original_size: Vec2d, args: &Arguments) -> Option<Self> {
+        let requested = args.scale.unwrap_or(1.)
+            .min(args.resize.map_or(1., |fit| fit.factor(original_size)));
+        let max_pixels = match (args.max_pixels, args.oversize_policy) {
+            (Some(max_pixels), OversizePolicy::Downscale) if original_size.area() > max_pixels => max_pixels,
+            _ => u64::MAX,
+        };
+        let max_pixels_factor = (max_pixels as f64 / original_size.area() as f64).sqrt();
+        let downscale = Downscale::new(original_size, requested.min(max_pixels_factor));
+        if downscale.factor >= 1. { return None; }
+        let Vec2d { x, y } = downscale.size();
+        if max_pixels_factor < requested {
+            warn!("The image is larger than --max-pixels. It will be scaled down to {}x{} pixels.", x, y);
+        } else {
+            info!("The image will be scaled down to {}x{} pixels", x, y);
+        }
+        Some(downscale)
+    }
+
+    fn scale(&self, v: Vec2d, round: fn(f64) -> f64) -> Vec2d {
+        let scale = |x: u32| round(f64::from(x) * self.factor) as u32;
+        Vec2d { x: scale(v.x), y: scale(v.y) }
+    }
+
+    /// The size of the scaled image
+    pub fn size(&self) -> Vec2d {
+        self.scale(self.original_size, f64::floor).max(Vec2d::square(1))
+    }
+
+    /// Scales a tile down, and moves it to its place in the scaled image.
+    /// The edges of the tile are rounded outwards, so that no gap is left between neighbouring tiles.
+    pub fn scale_tile(&self, tile: Tile) -> Option<Tile> {
+        let position = self.scale(tile.position, f64::floor);
+        let end = self.scale(tile.bottom_right(), f64::ceil).min(self.size());
+        let size = end - position;
+        if size.x == 0 || size.y == 0 { return None; }
+        Some(Tile {
+            image: tile.image.resize_exact(size.x, size.y, FilterType::Triangle),
+            position,
+            encoded: None,
+        })
+    }
+}
+
+#[test]
+fn test_downscale() {
+    let downscale = Downscale::new(Vec2d { x: 1000, y: 500 }, 0.5);
+    assert_eq!(downscale.size(), Vec2d { x: 500, y: 250 });
+    let tile = downscale.scale_tile(Tile::empty(Vec2d { x: 255, y: 0 }, Vec2d::square(255))).unwrap();
+    assert_eq!(tile.position, Vec2d { x: 127, y: 0 });
+    assert_eq!(tile.size(), Vec2d { x: 128, y: 128 });
+    assert_eq!(Downscale::new(Vec2d::square(10), 2.).size(), Vec2d::square(10));
+}
+
+#[allow(clippy::field_reassign_with_default)]
+#[cfg(feature = "cli")]
+#[test]
+fn test_downscale_arguments() {
+    let size = Vec2d { x: 1000, y: 500 };
+    let mut args = Arguments::default();
+    args.scale = Some(parse_scale("50%").unwrap());
+    assert_eq!(Downscale::for_args(size, &args).map(|d| d.size()), Some(Vec2d { x: 500, y: 250 }));
+    args.resize = Some(FitSize::parse("x100").unwrap());
+    assert_eq!(Downscale::for_args(size, &args).map(|d| d.size()), Some(Vec2d { x: 200, y: 100 }));
+    args = Arguments::default();
+    args.max_pixels = Some(125_000);
+    assert_eq!(Downscale::for_args(size, &args), None, "the default oversize policy is an error");
+    args.oversize_policy = OversizePolicy::Downscale;
+    assert_eq!(Downscale::for_args(size, &args).map(|d| d.size()), Some(Vec2d { x: 500, y: 250 }));
+
+    assert_eq!(parse_scale("0.25"), Ok(0.25));
+    assert!(parse_scale("150%").is_err());
+    assert_eq!(FitSize::parse("4000x"), Ok(FitSize { width: Some(4000), height: None }));
+    assert!(FitSize::parse("x").is_err());
+}
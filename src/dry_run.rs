@@ -0,0 +1,120 @@
+//! The `--dry-run` mode: find the image and describe its zoom levels,
+//! without downloading any tile.
+
+use serde::Serialize;
+
+use crate::arguments::Arguments;
+use crate::build_info::BuildInfo;
+use crate::dezoomer::{TileFetchResult, ZoomLevel};
+use crate::network::client;
+use crate::{Vec2d, ZoomError};
+use crate::download::list_tiles;
+
+/// Number of tile URLs listed for each level
+const LISTED_TILES: usize = 3;
+
+#[derive(Serialize, Debug)]
+pub struct DryRunReport {
+    pub url: String,
+    pub levels: Vec<LevelReport>,
+    pub build: BuildInfo,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct LevelReport {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    pub tile_count: usize,
+    /// False when the dezoomer discovers the tiles while downloading them,
+    /// in which case tile_count is only the number of tiles requested first
+    pub tile_count_exact: bool,
+    pub first_tiles: Vec<String>,
+}
+
+impl LevelReport {
+    /// Describes a level by generating its tile references, which consumes it
+    pub fn new(mut level: ZoomLevel) -> Self {
+        let size = level.size_hint();
+        let tiles = level.next_tiles(None);
+        let tile_count_exact = size.is_some() && !tiles.is_empty() && {
+            // Pretend the first batch was successfully downloaded, and see whether more tiles follow
+            let tile_size = tiles[0].size.unwrap_or_else(|| Vec2d::square(1));
+            let count = tiles.len() as u64;
+            let successful_urls = tiles.iter().map(|t| t.url.clone()).collect();
+            level.next_tiles(Some(TileFetchResult { count, successes: count, tile_size: Some(tile_size), successful_urls }))
+                .is_empty()
+        };
+        LevelReport {
+            name: level.name(),
+            title: level.title(),
+            width: size.map(|s| s.x),
+            height: size.map(|s| s.y),
+            tile_count: tiles.len(),
+            tile_count_exact,
+            first_tiles: tiles.into_iter().take(LISTED_TILES).map(|t| t.url).collect(),
+        }
+    }
+}
+
+impl std::fmt::Display for LevelReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.name)?;
+        if let Some(title) = &self.title { writeln!(f, "  title: {}", title)?; }
+        if let (Some(width), Some(height)) = (self.width, self.height) {
+            let megapixels = f64::from(width) * f64::from(height) / 1e6;
+            writeln!(f, "  size: {} x {} pixels ({:.1} megapixels)", width, height, megapixels)?;
+        }
+        let at_least = if self.tile_count_exact { "" } else { "at least " };
+        writeln!(f, "  tiles: {}{}", at_least, self.tile_count)?;
+        for url in &self.first_tiles { writeln!(f, "    {}", url)?; }
+        if self.first_tiles.len() < self.tile_count { writeln!(f, "    ...")?; }
+        Ok(())
+    }
+}
+
+/// Fetches the metadata of the image, and prints a description of all its zoom levels
+pub async fn print_report(args: &Arguments) -> Result<(), ZoomError> {
+    let mut dezoomer = args.find_dezoomer()?;
+    let url = args.choose_input_uri()?;
+    let http_client = client(args.headers(), args, Some(&url))?;
+    let mut levels = list_tiles(dezoomer.as_mut(), &http_client, &url, args).await?;
+    levels.sort_by_key(|l| l.size_hint().map(|s| s.area()));
+    let report = DryRunReport {
+        url,
+        levels: levels.into_iter().map(LevelReport::new).collect(),
+        build: BuildInfo::current(),
+    };
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report).map_err(std::io::Error::from)?);
+    } else {
+        println!("Found {} zoom levels in {}", report.levels.len(), report.url);
+        for level in &report.levels { print!("{}", level) }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_level_report() {
+    use crate::dezoomer::TilesRect;
+
+    #[derive(Debug)]
+    struct Level;
+    impl TilesRect for Level {
+        fn size(&self) -> Vec2d { Vec2d { x: 600, y: 300 } }
+        fn tile_size(&self) -> Vec2d { Vec2d::square(256) }
+        fn tile_url(&self, pos: Vec2d) -> String { format!("{}_{}.jpg", pos.x, pos.y) }
+    }
+    let report = LevelReport::new(Box::new(Level));
+    assert_eq!(report.width, Some(600));
+    assert_eq!(report.tile_count, 6);
+    assert!(report.tile_count_exact);
+    assert_eq!(report.first_tiles, vec!["0_0.jpg", "1_0.jpg", "2_0.jpg"]);
+    let text = report.to_string();
+    assert!(text.contains("600 x 300 pixels (0.2 megapixels)"), "{}", text);
+    assert!(text.contains("tiles: 6\n"), "{}", text);
+}
@@ -3,25 +3,29 @@ use std::fmt::Debug;
 use serde::Deserialize;
 
 use crate::json_utils::number_or_string;
-use crate::network::resolve_relative;
+use crate::url_utils::resolve_relative;
 use crate::Vec2d;
 
 use super::DziError;
 
+// The attributes are also accepted in lower camel case, as some viewers write them in their configuration
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct DziFile {
-    #[serde(rename = "Overlap", deserialize_with = "number_or_string", default)]
+    #[serde(rename = "Overlap", alias = "overlap", deserialize_with = "number_or_string", default)]
     pub overlap: u32,
-    #[serde(rename = "TileSize", deserialize_with = "number_or_string")]
+    #[serde(rename = "TileSize", alias = "tileSize", deserialize_with = "number_or_string")]
     pub tile_size: u32,
-    #[serde(rename = "Format")]
+    /// The extension of the tile files
+    #[serde(rename = "Format", alias = "format", default = "default_format")]
     pub format: String,
-    #[serde(rename = "Size")]
+    #[serde(rename = "Size", alias = "size")]
     pub size: Size,
-    #[serde(rename = "Url")]
+    #[serde(rename = "Url", alias = "url")]
     pub base_url: Option<String>,
 }
 
+fn default_format() -> String { "jpg".into() }
+
 impl DziFile {
     pub fn get_size(&self) -> Result<Vec2d, DziError> {
         Ok(Vec2d { x: self.size.width, y: self.size.height })
@@ -29,6 +33,11 @@ impl DziFile {
     pub fn get_tile_size(&self) -> Vec2d {
         Vec2d::square(self.tile_size)
     }
+    /// The extension of the tile files, without the dot that some files include
+    pub fn extension(&self) -> &str {
+        let extension = self.format.trim().trim_start_matches('.');
+        if extension.is_empty() { "jpg" } else { extension }
+    }
     pub fn max_level(&self) -> u32 {
         let size = self.get_size().unwrap();
         log2(size.x.max(size.y))
@@ -52,9 +61,9 @@ fn log2(n: u32) -> u32 {
 
 #[derive(Debug, Deserialize, PartialEq, Default)]
 pub struct Size {
-    #[serde(rename = "Width", deserialize_with = "number_or_string", default)]
+    #[serde(rename = "Width", alias = "width", deserialize_with = "number_or_string", default)]
     pub width: u32,
-    #[serde(rename = "Height", deserialize_with = "number_or_string", default)]
+    #[serde(rename = "Height", alias = "height", deserialize_with = "number_or_string", default)]
     pub height: u32,
 }
 
@@ -90,3 +99,15 @@ fn test_dzi_json() {
     assert_eq!(dzi.get_tile_size(), Vec2d { x: 254, y: 254 });
     assert_eq!(dzi.max_level(), 13);
 }
+
+#[test]
+fn test_dzi_lower_case_attributes() {
+    let dzi: DziFile = serde_json::from_str(
+        r#"{ "tileSize": 510, "overlap": 1, "format": ".png", "size": { "width": 1000, "height": 800 } }"#,
+    ).unwrap();
+    assert_eq!(dzi.overlap, 1);
+    assert_eq!(dzi.extension(), "png");
+    assert_eq!(dzi.get_size().unwrap(), Vec2d { x: 1000, y: 800 });
+    let dzi: DziFile = serde_xml_rs::from_str(r#"<Image TileSize="256"><Size Width="10" Height="10"/></Image>"#).unwrap();
+    assert_eq!((dzi.overlap, dzi.extension()), (0, "jpg"));
+}
@@ -7,7 +7,7 @@ use dzi_file::DziFile;
 
 use crate::dezoomer::*;
 use crate::json_utils::all_json;
-use crate::network::remove_bom;
+use crate::url_utils::remove_bom;
 
 mod dzi_file;
 
@@ -21,6 +21,18 @@ impl Dezoomer for DziDezoomer {
         "deepzoom"
     }
 
+    fn description(&self) -> &str {
+        "DeepZoom images, from their dzi file"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["*.dzi", "*.xml"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec!["http://test.com/y/xy.dzi"]
+    }
+
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
         let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
         let levels = load_from_properties(uri, contents)?;
@@ -79,7 +91,7 @@ fn load_from_dzi(url: &str, image_properties: DziFile) -> Result<ZoomLevels, Dzi
         base_url: Arc::clone(base_url),
         size,
         tile_size: image_properties.get_tile_size(),
-        format: image_properties.format.clone(),
+        format: image_properties.extension().to_string(),
         overlap: image_properties.overlap,
         level: max_level - level_num as u32,
     })
@@ -124,9 +136,17 @@ impl TilesRect for DziLevel {
         TileReference {
             url: self.tile_url(pos),
             position: self.tile_size() * pos - delta,
+            size: None,
+            request: None,
         }
     }
 
+    // The overlap is only on the sides of the tiles that are inside the image
+    fn tile_crop(&self) -> TileCrop { TileCrop::uniform(self.overlap) }
+
+    // The levels of a DZI are computed from the image size, but servers do not always generate all of them
+    fn probe_tile(&self) -> Option<TileReference> { Some(self.last_tile_ref()) }
+
     fn title(&self) -> Option<String> {
         let suffix = self.base_url.rsplitn(2, '/').next().unwrap_or("");
         let name = suffix.trim_end_matches("_files");
@@ -1,12 +1,15 @@
 use std::path::{PathBuf, Path};
 use std::io;
-use image::{GenericImage, ImageBuffer, Pixel, ImageResult};
-use log::debug;
+use image::{GenericImage, GenericImageView, ImageBuffer, Pixel, ImageResult};
+use log::{debug, warn};
 
 use crate::Vec2d;
-use crate::encoder::{Encoder, crop_tile};
+use crate::encoder::{Encoder, crop_tile, png_fallback, supports_alpha};
+use crate::errors::make_io_err;
 use crate::tile::Tile;
 use crate::ZoomError;
+use crate::exif::ImageMetadata;
+use tiff::tags::Tag;
 use std::io::BufWriter;
 use std::fs::File;
 
@@ -23,23 +26,55 @@ pub struct Canvas {
     image: CanvasBuffer,
     destination: PathBuf,
     image_writer: ImageWriter,
+    /// Fail instead of saving the image as PNG when its format cannot hold transparent tiles
+    strict_format: bool,
+    /// Whether a tile had transparent pixels
+    transparent: bool,
+    /// The PNG file the image was saved to instead of the destination
+    fallback: Option<PathBuf>,
+    metadata: ImageMetadata,
 }
 
 
 impl Canvas {
-    pub fn new(destination: PathBuf, size: Vec2d, image_writer: ImageWriter) -> Result<Self, ZoomError> {
+    pub fn new(destination: PathBuf, size: Vec2d, image_writer: ImageWriter, strict_format: bool, metadata: ImageMetadata) -> Result<Self, ZoomError> {
         Ok(Canvas {
             image: empty_buffer(size),
             destination,
             image_writer,
+            strict_format,
+            transparent: false,
+            fallback: None,
+            metadata,
         })
     }
+
+    /// The file in which the image can be saved with its transparent parts
+    fn destination_for_alpha(&mut self) -> io::Result<PathBuf> {
+        if !self.transparent || supports_alpha(&self.destination) {
+            return Ok(self.destination.clone());
+        }
+        let destination = self.destination.to_string_lossy().to_string();
+        if self.strict_format {
+            return Err(make_io_err(ZoomError::UnsupportedTransparency { destination }.to_string()));
+        }
+        let fallback = png_fallback(&self.destination).map_err(|e| make_io_err(e.to_string()))?;
+        warn!("The image has transparent parts, that cannot be saved to {:?}. Saving it as PNG to {:?} instead.",
+              destination, fallback);
+        self.image_writer = ImageWriter::Generic;
+        self.fallback = Some(fallback.clone());
+        Ok(fallback)
+    }
 }
 
 impl Encoder for Canvas {
     fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
         let sub_tile = crop_tile(&tile, self.size());
         let Vec2d { x, y } = tile.position();
+        if let Some(encoded) = &tile.encoded { self.metadata.merge_tile(encoded); }
+        if !self.transparent && tile.image.color().has_alpha() {
+            self.transparent = sub_tile.pixels().any(|(_, _, pixel)| pixel[3] < u8::MAX);
+        }
         debug!("Copying tile data from {:?}", tile);
         self.image.copy_from(&sub_tile, x, y).map_err(|_err| {
             io::Error::new(io::ErrorKind::InvalidData, "tile too large for image")
@@ -47,31 +82,45 @@ impl Encoder for Canvas {
     }
 
     fn finalize(&mut self) -> io::Result<()> {
-        self.image_writer.write(&self.image, &self.destination).map_err(|e| {
+        let destination = self.destination_for_alpha()?;
+        self.image_writer.write(&self.image, &destination, &self.metadata).map_err(|e| {
             match e {
                 image::ImageError::IoError(e) => e,
-                other => io::Error::new(io::ErrorKind::Other, other)
+                other => io::Error::other(other)
             }
         })?;
         Ok(())
     }
 
     fn size(&self) -> Vec2d { self.image.dimensions().into() }
+
+    fn saved_to(&self) -> Option<PathBuf> { self.fallback.clone() }
 }
 
 pub enum ImageWriter {
     Generic,
     Jpeg { quality: u8 },
+    Tiff,
 }
 
 impl ImageWriter {
-    fn write(&self, image: &CanvasBuffer, destination: &Path) -> ImageResult<()> {
+    fn write(&self, image: &CanvasBuffer, destination: &Path, metadata: &ImageMetadata) -> ImageResult<()> {
         match *self {
             ImageWriter::Jpeg { quality } => {
-                let file = File::create(destination)?;
-                let fout = &mut BufWriter::new(file);
-                let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(fout, quality);
+                // The image is encoded in memory, so that the EXIF segment can be inserted at its start
+                let mut jpeg = Vec::new();
+                let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, quality);
                 encoder.encode(image, image.width(), image.height(), Pix::COLOR_TYPE)?;
+                metadata.insert_into_jpeg(&mut jpeg);
+                if let Some(xmp) = metadata.xmp() { crate::xmp::insert_into_jpeg(&xmp, &mut jpeg); }
+                std::fs::write(destination, jpeg)?;
+            },
+            ImageWriter::Tiff => {
+                let file = BufWriter::new(File::create(destination)?);
+                write_tiff(image, file, metadata).map_err(|e| match e {
+                    tiff::TiffError::IoError(e) => image::ImageError::IoError(e),
+                    other => image::ImageError::IoError(io::Error::other(other)),
+                })?;
             },
             ImageWriter::Generic => {
                 image.save(destination)?;
@@ -80,3 +129,18 @@ impl ImageWriter {
         Ok(())
     }
 }
+
+fn write_tiff<W: io::Write + io::Seek>(image: &CanvasBuffer, writer: W, metadata: &ImageMetadata) -> tiff::TiffResult<()> {
+    let mut encoder = tiff::encoder::TiffEncoder::new(writer)?;
+    let mut tiff_image = encoder.new_image::<tiff::encoder::colortype::RGBA8>(image.width(), image.height())?;
+    for (tag, value) in metadata.text_tags() {
+        tiff_image.encoder().write_tag(Tag::from_u16_exhaustive(tag), value.as_str())?;
+    }
+    if let Some(orientation) = metadata.orientation {
+        tiff_image.encoder().write_tag(Tag::Orientation, orientation)?;
+    }
+    if let Some(xmp) = metadata.xmp() {
+        tiff_image.encoder().write_tag(Tag::Unknown(crate::xmp::TIFF_TAG), xmp.as_bytes())?;
+    }
+    tiff_image.write_data(image.as_raw())
+}
@@ -0,0 +1,182 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use image::{GenericImageView, Pixel, Rgb, RgbImage};
+use log::{debug, info, warn};
+
+use crate::{Vec2d, ZoomError};
+use crate::encoder::crop_tile;
+use crate::tile::Tile;
+use crate::tile_cache::Checkpoint;
+
+use super::Encoder;
+use super::png_encoder::png_writer;
+
+const BYTES_PER_PIXEL: u64 = Rgb::<u8>::CHANNEL_COUNT as u64;
+
+/// An image assembled in a temporary file instead of in memory, for images that are too large
+/// to fit in memory. Each tile is written directly at its place in the file, in any order,
+/// and the pixels are then streamed from the file to a PNG image.
+/// With a checkpoint, the file is kept when the image is not saved, and the assembly can resume from it.
+pub struct DiskCanvas {
+    file: File,
+    tmp_path: PathBuf,
+    destination: PathBuf,
+    size: Vec2d,
+    compression: u8,
+    xmp: Option<String>,
+    checkpoint: Option<Checkpoint>,
+}
+
+impl DiskCanvas {
+    pub fn new(destination: PathBuf, size: Vec2d, compression: u8, xmp: Option<String>, checkpoint: Option<Checkpoint>) -> Result<Self, ZoomError> {
+        let file_name = destination.file_name().unwrap_or_default().to_string_lossy();
+        let tmp_path = match &checkpoint {
+            Some(checkpoint) => checkpoint.canvas().to_path_buf(),
+            None => destination.with_file_name(format!(".{}.canvas", file_name)),
+        };
+        let resumed = checkpoint.as_ref().is_some_and(|checkpoint| !checkpoint.tiles().is_empty());
+        debug!("Assembling the image in {:?}", tmp_path);
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(!resumed).open(&tmp_path)?;
+        // The pixels that are never written are read back as zeroes, like the in-memory canvases
+        file.set_len(size.area() * BYTES_PER_PIXEL)?;
+        Ok(DiskCanvas { file, tmp_path, destination, size, compression, xmp, checkpoint })
+    }
+
+    fn offset(&self, position: Vec2d) -> u64 {
+        (u64::from(position.y) * u64::from(self.size.x) + u64::from(position.x)) * BYTES_PER_PIXEL
+    }
+
+    /// Reads back a rectangular part of the image
+    pub fn read_region(&mut self, position: Vec2d, size: Vec2d) -> io::Result<RgbImage> {
+        let row_len = (u64::from(size.x) * BYTES_PER_PIXEL) as usize;
+        let mut pixels = vec![0; row_len * size.y as usize];
+        for (y, row) in (0..size.y).zip(pixels.chunks_exact_mut(row_len.max(1))) {
+            self.file.seek(SeekFrom::Start(self.offset(position + Vec2d { x: 0, y })))?;
+            self.file.read_exact(row)?;
+        }
+        Ok(RgbImage::from_raw(size.x, size.y, pixels).expect("the buffer has the size of the region"))
+    }
+}
+
+impl Encoder for DiskCanvas {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        let sub_tile = crop_tile(&tile, self.size);
+        let mut row = Vec::with_capacity((sub_tile.width() as u64 * BYTES_PER_PIXEL) as usize);
+        for y in 0..sub_tile.height() {
+            row.clear();
+            for x in 0..sub_tile.width() {
+                row.extend_from_slice(&sub_tile.get_pixel(x, y).to_rgb().0);
+            }
+            let offset = self.offset(tile.position + Vec2d { x: 0, y });
+            self.file.seek(SeekFrom::Start(offset))?;
+            self.file.write_all(&row)?;
+        }
+        Ok(())
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        let mut writer = png_writer(&self.destination, self.size, self.compression, self.xmp.as_deref())?;
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::with_capacity(128 * 1024, &mut self.file);
+        let copied = io::copy(&mut reader, &mut writer)?;
+        writer.finish()?;
+        info!("Copied {:.1} MiB from the image assembled on disk", copied as f64 / 1024.0 / 1024.0);
+        match self.checkpoint.take() {
+            // The checkpoint contains the temporary file
+            Some(checkpoint) => {
+                checkpoint.remove();
+                Ok(())
+            }
+            None => std::fs::remove_file(&self.tmp_path),
+        }
+    }
+
+    fn abort(&mut self) -> io::Result<()> {
+        // The checkpoint is kept, so that the image can be completed later
+        match &mut self.checkpoint {
+            Some(checkpoint) => checkpoint.save(&self.file),
+            None => Ok(()),
+        }
+    }
+
+    fn checkpoint(&mut self, tiles: &[Vec2d]) -> io::Result<()> {
+        match &mut self.checkpoint {
+            Some(checkpoint) => checkpoint.record(&self.file, tiles),
+            None => Ok(()),
+        }
+    }
+
+    fn size(&self) -> Vec2d {
+        self.size
+    }
+}
+
+impl Drop for DiskCanvas {
+    fn drop(&mut self) {
+        match &mut self.checkpoint {
+            // The image was not saved: keep it to resume its assembly later
+            Some(checkpoint) => if let Err(e) = checkpoint.save(&self.file) {
+                warn!("Unable to save the checkpoint of the image: {}", e);
+            },
+            // The temporary file is already removed when the image was successfully finalized
+            None => { let _ = std::fs::remove_file(&self.tmp_path); }
+        }
+    }
+}
+
+#[test]
+fn test_disk_canvas() {
+    use image::{DynamicImage, ImageBuffer};
+
+    let dir = tempdir::TempDir::new("dezoomify-rs-disk-canvas").unwrap();
+    let destination = dir.path().join("image.png");
+    let mut canvas = DiskCanvas::new(destination.clone(), Vec2d { x: 3, y: 2 }, 20, None, None).unwrap();
+    let tile = |position, pixels: Vec<u8>| Tile {
+        position,
+        image: DynamicImage::ImageRgb8(ImageBuffer::from_raw(2, 2, pixels).unwrap()),
+        encoded: None,
+    };
+    // Tiles can be added in any order, and the first one goes beyond the right edge of the image
+    canvas.add_tile(tile(Vec2d { x: 2, y: 0 }, vec![1, 1, 1, 9, 9, 9, 2, 2, 2, 9, 9, 9])).unwrap();
+    canvas.add_tile(tile(Vec2d { x: 0, y: 1 }, vec![3, 3, 3, 4, 4, 4, 9, 9, 9, 9, 9, 9])).unwrap();
+    canvas.finalize().unwrap();
+    let result = image::open(&destination).unwrap().to_rgb8().into_raw();
+    assert_eq!(result, vec![0, 0, 0, 0, 0, 0, 1, 1, 1, 3, 3, 3, 4, 4, 4, 2, 2, 2]);
+    assert!(!dir.path().join(".image.png.canvas").exists(), "the temporary file should be removed");
+
+    let mut canvas = DiskCanvas::new(destination, Vec2d { x: 3, y: 2 }, 20, None, None).unwrap();
+    canvas.add_tile(tile(Vec2d { x: 1, y: 0 }, vec![1, 1, 1, 2, 2, 2, 3, 3, 3, 4, 4, 4])).unwrap();
+    let region = canvas.read_region(Vec2d { x: 2, y: 0 }, Vec2d { x: 1, y: 2 }).unwrap();
+    assert_eq!(region.into_raw(), vec![2, 2, 2, 4, 4, 4]);
+}
+
+#[test]
+fn test_disk_canvas_checkpoint() {
+    use image::{DynamicImage, ImageBuffer};
+
+    let dir = tempdir::TempDir::new("dezoomify-rs-disk-canvas-checkpoint").unwrap();
+    let destination = dir.path().join("image.png");
+    let checkpoint_path = dir.path().join("checkpoint");
+    let size = Vec2d { x: 2, y: 1 };
+    let tile = |x, value| Tile {
+        position: Vec2d { x, y: 0 },
+        image: DynamicImage::ImageRgb8(ImageBuffer::from_raw(1, 1, vec![value; 3]).unwrap()),
+        encoded: None,
+    };
+    let mut canvas = DiskCanvas::new(destination.clone(), size, 20, None, Some(Checkpoint::open(&checkpoint_path))).unwrap();
+    canvas.add_tile(tile(0, 7)).unwrap();
+    canvas.checkpoint(&[Vec2d { x: 0, y: 0 }]).unwrap();
+    // The image is not saved, so the tiles written to it are kept
+    drop(canvas);
+
+    let checkpoint = Checkpoint::open(&checkpoint_path);
+    assert_eq!(checkpoint.tiles().iter().collect::<Vec<_>>(), vec![&Vec2d { x: 0, y: 0 }]);
+    let mut canvas = DiskCanvas::new(destination.clone(), size, 20, None, Some(checkpoint)).unwrap();
+    canvas.add_tile(tile(1, 9)).unwrap();
+    canvas.finalize().unwrap();
+    let result = image::open(&destination).unwrap().to_rgb8().into_raw();
+    assert_eq!(result, vec![7, 7, 7, 9, 9, 9]);
+    assert!(Checkpoint::open(&checkpoint_path).tiles().is_empty(), "the checkpoint should be removed");
+}
@@ -0,0 +1,237 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use image::{ImageFormat, ImageOutputFormat};
+use log::{debug, warn};
+
+use crate::{Vec2d, ZoomError};
+use crate::errors::image_error_to_io_error;
+use crate::tile::Tile;
+
+use super::Encoder;
+
+/// Saves the tiles as they were received, without decoding and re-encoding them,
+/// in the folder structure of a DeepZoom image: `{name}_files/{level}/{column}_{row}.{format}`,
+/// next to a `{name}.dzi` descriptor.
+/// Only the level that was downloaded is saved.
+pub struct DziEncoder {
+    destination: PathBuf,
+    level_dir: PathBuf,
+    size: Vec2d,
+    /// The tiles that were written, with a temporary name, until their column and row are known
+    saved: Vec<SavedTile>,
+    /// The tiles that were not received as-is from the server (such as missing tiles),
+    /// encoded in the format of the others once it is known
+    decoded: Vec<Tile>,
+}
+
+struct SavedTile {
+    position: Vec2d,
+    size: Vec2d,
+    extension: &'static str,
+}
+
+impl SavedTile {
+    fn tmp_path(&self, dir: &Path) -> PathBuf {
+        dir.join(format!("{}-{}.{}.tmp", self.position.x, self.position.y, self.extension))
+    }
+}
+
+impl DziEncoder {
+    pub fn new(destination: PathBuf, size: Vec2d) -> Result<Self, ZoomError> {
+        let stem = destination.file_stem().unwrap_or_default().to_string_lossy();
+        let files_dir = destination.with_file_name(format!("{}_files", stem));
+        let level_dir = files_dir.join(dzi_level(size).to_string());
+        debug!("Creating the DeepZoom directory {:?}", level_dir);
+        std::fs::create_dir_all(&level_dir)?;
+        Ok(DziEncoder { destination, level_dir, size, saved: vec![], decoded: vec![] })
+    }
+
+    fn save_encoded(&mut self, tile: Tile) -> io::Result<()> {
+        let bytes = match &tile.encoded {
+            Some(bytes) => bytes,
+            None => {
+                self.decoded.push(tile);
+                return Ok(());
+            }
+        };
+        let format = image::guess_format(bytes).map_err(image_error_to_io_error)?;
+        let saved = SavedTile {
+            position: tile.position,
+            size: tile.size(),
+            extension: format.extensions_str().first().copied().unwrap_or("jpg"),
+        };
+        // Some dezoomers download the same tile more than once
+        for previous in self.saved.iter().filter(|t| t.position == saved.position) {
+            std::fs::remove_file(previous.tmp_path(&self.level_dir))?;
+        }
+        self.saved.retain(|t| t.position != saved.position);
+        std::fs::write(saved.tmp_path(&self.level_dir), bytes)?;
+        self.saved.push(saved);
+        Ok(())
+    }
+}
+
+impl Encoder for DziEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        self.save_encoded(tile)
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        let extension = self.saved.first().map(|t| t.extension).unwrap_or("jpg");
+        let output_format = match ImageFormat::from_extension(extension) {
+            Some(ImageFormat::Png) => ImageOutputFormat::Png,
+            _ => ImageOutputFormat::Jpeg(95),
+        };
+        let positions = self.saved.iter().map(|t| (t.position, t.size))
+            .chain(self.decoded.iter().map(|t| (t.position, t.size())));
+        let grid = Grid::new(positions);
+        if grid.tile_size.x != grid.tile_size.y || !grid.regular {
+            warn!("The tiles do not form a regular grid of square tiles, \
+                   so the DeepZoom image may not be displayed correctly");
+        }
+        for tile in std::mem::take(&mut self.saved) {
+            let Vec2d { x, y } = grid.cell(tile.position);
+            let path = self.level_dir.join(format!("{}_{}.{}", x, y, tile.extension));
+            std::fs::rename(tile.tmp_path(&self.level_dir), path)?;
+        }
+        for tile in std::mem::take(&mut self.decoded) {
+            let Vec2d { x, y } = grid.cell(tile.position);
+            let path = self.level_dir.join(format!("{}_{}.{}", x, y, extension));
+            let file = &mut BufWriter::new(File::create(&path)?);
+            tile.image.write_to(file, output_format.clone()).map_err(image_error_to_io_error)?;
+        }
+        let descriptor = format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+            <Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" \
+            Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\n  \
+            <Size Width=\"{}\" Height=\"{}\"/>\n</Image>\n",
+            extension, grid.overlap, grid.tile_size.x.max(grid.tile_size.y), self.size.x, self.size.y
+        );
+        debug!("Writing the DeepZoom descriptor to {:?}", self.destination);
+        std::fs::write(&self.destination, descriptor)
+    }
+
+    fn abort(&mut self) -> io::Result<()> {
+        for tile in std::mem::take(&mut self.saved) {
+            std::fs::remove_file(tile.tmp_path(&self.level_dir))?;
+        }
+        // The directories are kept if they contain files that were not written by this encoder
+        let files_dir = self.level_dir.parent().map(Path::to_path_buf);
+        for dir in std::iter::once(self.level_dir.clone()).chain(files_dir) {
+            if let Err(e) = std::fs::remove_dir(&dir) {
+                debug!("Unable to remove the directory {:?}: {}", dir, e);
+            }
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> Vec2d {
+        self.size
+    }
+}
+
+/// The DeepZoom level of the full-size image: the level at which the image is 1 pixel wide is 0,
+/// and the size doubles at each level
+fn dzi_level(size: Vec2d) -> u32 {
+    let largest = size.x.max(size.y).max(1);
+    u32::BITS - (largest - 1).leading_zeros()
+}
+
+/// The layout of the tiles in the image.
+/// In DeepZoom images, all tiles but the first of each row and column start `overlap` pixels
+/// before the end of the previous one.
+#[derive(Debug, PartialEq)]
+struct Grid {
+    xs: Vec<u32>,
+    ys: Vec<u32>,
+    tile_size: Vec2d,
+    overlap: u32,
+    regular: bool,
+}
+
+impl Grid {
+    fn new(tiles: impl Iterator<Item=(Vec2d, Vec2d)>) -> Grid {
+        let tiles: Vec<(Vec2d, Vec2d)> = tiles.collect();
+        let xs: BTreeSet<u32> = tiles.iter().map(|(p, _)| p.x).collect();
+        let ys: BTreeSet<u32> = tiles.iter().map(|(p, _)| p.y).collect();
+        let (xs, ys): (Vec<u32>, Vec<u32>) = (xs.into_iter().collect(), ys.into_iter().collect());
+        // The first tile is tile_size + overlap wide, and the second one starts at tile_size - overlap
+        let first = tiles.iter().find(|(p, _)| p.x == 0 && p.y == 0).map(|&(_, s)| s).unwrap_or_default();
+        let second = Vec2d { x: xs.get(1).copied().unwrap_or(first.x), y: ys.get(1).copied().unwrap_or(first.y) };
+        let overlap = first.x.saturating_sub(second.x).max(first.y.saturating_sub(second.y)) / 2;
+        let tile_size = Vec2d { x: second.x + overlap, y: second.y + overlap };
+        let start = |i: usize, size: u32| if i == 0 { 0 } else { size * i as u32 - overlap };
+        let regular = xs.iter().enumerate().all(|(i, &x)| x == start(i, tile_size.x)) &&
+            ys.iter().enumerate().all(|(i, &y)| y == start(i, tile_size.y));
+        Grid { xs, ys, tile_size, overlap, regular }
+    }
+
+    /// The column and row of the tile at the given position
+    fn cell(&self, position: Vec2d) -> Vec2d {
+        let index = |v: &[u32], p: u32| v.binary_search(&p).unwrap_or_else(|i| i) as u32;
+        Vec2d { x: index(&self.xs, position.x), y: index(&self.ys, position.y) }
+    }
+}
+
+#[test]
+fn test_dzi_level() {
+    assert_eq!(dzi_level(Vec2d::square(1)), 0);
+    assert_eq!(dzi_level(Vec2d { x: 256, y: 100 }), 8);
+    assert_eq!(dzi_level(Vec2d { x: 257, y: 100 }), 9);
+}
+
+#[test]
+fn test_grid() {
+    // A 600x300 image in tiles of 254 pixels, with an overlap of 1 pixel
+    let tiles = vec![
+        (Vec2d { x: 0, y: 0 }, Vec2d { x: 255, y: 255 }),
+        (Vec2d { x: 253, y: 0 }, Vec2d { x: 256, y: 255 }),
+        (Vec2d { x: 507, y: 0 }, Vec2d { x: 93, y: 255 }),
+        (Vec2d { x: 0, y: 253 }, Vec2d { x: 255, y: 47 }),
+    ];
+    let grid = Grid::new(tiles.into_iter());
+    assert_eq!(grid.overlap, 1);
+    assert_eq!(grid.tile_size, Vec2d::square(254));
+    assert!(grid.regular);
+    assert_eq!(grid.cell(Vec2d { x: 507, y: 253 }), Vec2d { x: 2, y: 1 });
+}
+
+#[test]
+fn test_dzi_encoder() {
+    use std::sync::Arc;
+
+    let dir = tempdir::TempDir::new("dezoomify-rs-dzi").unwrap();
+    let destination = dir.path().join("image.dzi");
+    let size = Vec2d { x: 3, y: 1 };
+    let mut encoder = DziEncoder::new(destination.clone(), size).unwrap();
+    let mut png = vec![];
+    image::DynamicImage::new_rgb8(2, 1).write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    let tile = Tile { image: image::DynamicImage::new_rgb8(2, 1), position: Vec2d::default(), encoded: None };
+    encoder.add_tile(Tile { encoded: Some(Arc::from(png.clone())), ..tile }).unwrap();
+    encoder.add_tile(Tile::empty(Vec2d { x: 2, y: 0 }, Vec2d { x: 1, y: 1 })).unwrap();
+    encoder.finalize().unwrap();
+    let files = dir.path().join("image_files").join("2");
+    assert_eq!(std::fs::read(files.join("0_0.png")).unwrap(), png, "tiles should be saved as-is");
+    use image::GenericImageView;
+    assert_eq!(image::open(files.join("1_0.png")).unwrap().dimensions(), (1, 1));
+    let descriptor = std::fs::read_to_string(destination).unwrap();
+    assert!(descriptor.contains(r#"Format="png" Overlap="0" TileSize="2""#), "{}", descriptor);
+    assert!(descriptor.contains(r#"<Size Width="3" Height="1"/>"#), "{}", descriptor);
+}
+
+#[test]
+fn test_dzi_encoder_abort() {
+    let dir = tempdir::TempDir::new("dezoomify-rs-dzi").unwrap();
+    let destination = dir.path().join("image.dzi");
+    let mut encoder = DziEncoder::new(destination, Vec2d { x: 2, y: 1 }).unwrap();
+    let mut png = vec![];
+    image::DynamicImage::new_rgb8(2, 1).write_to(&mut png, ImageOutputFormat::Png).unwrap();
+    let tile = Tile { image: image::DynamicImage::new_rgb8(2, 1), position: Vec2d::default(), encoded: Some(png.into()) };
+    encoder.add_tile(tile).unwrap();
+    encoder.abort().unwrap();
+    assert!(!dir.path().join("image_files").exists(), "the exported tiles should be removed");
+}
@@ -84,6 +84,11 @@ impl Encoder for IiifEncoder {
         Ok(())
     }
 
+    fn abort(&mut self) -> io::Result<()> {
+        debug!("Removing the IIIF directory at {:?}", &self.root_path);
+        std::fs::remove_dir_all(&self.root_path)
+    }
+
     fn size(&self) -> Vec2d {
         self.retiler.size()
     }
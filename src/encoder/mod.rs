@@ -1,49 +1,285 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use image::{DynamicImage, GenericImageView, SubImage};
-use log::debug;
+use indicatif::HumanBytes;
+use log::{debug, warn};
 
 use crate::{max_size_in_rect, Vec2d, ZoomError};
 use crate::tile::Tile;
 use crate::encoder::canvas::ImageWriter;
+use crate::exif::ImageMetadata;
+use crate::tile_cache::Checkpoint;
+use crate::output_file::{available_path, reserve_output_file};
 
 pub mod canvas;
+pub mod disk_canvas;
 pub mod png_encoder;
 pub mod pixel_streamer;
 pub mod tile_buffer;
 pub mod iiif_encoder;
+pub mod dzi_encoder;
+pub mod split_encoder;
 mod retiler;
 
+/// A zoomable image format in which the tiles can be saved instead of a single image, with --export-format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Dzi,
+    Iiif,
+}
+
+impl ExportFormat {
+    /// The extension of the output file that selects the encoder for this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Dzi => "dzi",
+            ExportFormat::Iiif => "iiif",
+        }
+    }
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "dzi" | "deepzoom" => Ok(ExportFormat::Dzi),
+            "iiif" => Ok(ExportFormat::Iiif),
+            _ => Err("Invalid export format. Expected 'dzi' or 'iiif'"),
+        }
+    }
+}
+
 pub trait Encoder: Send + 'static {
     /// Add a tile to the image
     fn add_tile(&mut self, tile: Tile) -> std::io::Result<()>;
     /// To be called when no more tile will be added
     fn finalize(&mut self) -> std::io::Result<()>;
+    /// To be called instead of `finalize` when the image will not be saved.
+    /// Removes the files that were written outside of the destination, such as exported tiles.
+    fn abort(&mut self) -> std::io::Result<()> { Ok(()) }
+    /// Record that the tiles with references at the given positions were added,
+    /// for encoders that can resume the assembly of the image from a checkpoint
+    fn checkpoint(&mut self, _tiles: &[Vec2d]) -> std::io::Result<()> { Ok(()) }
     /// Size of the image being encoded
     fn size(&self) -> Vec2d;
+    /// The file the image was saved to by `finalize`, if it is not the requested destination
+    fn saved_to(&self) -> Option<PathBuf> { None }
 }
 
-fn encoder_for_name(destination: PathBuf, size: Vec2d, compression: u8) -> Result<Box<dyn Encoder>, ZoomError> {
+/// Whether the image is assembled in a file instead of in memory. Only these images use checkpoints.
+fn assembled_on_disk(destination: &Path, size: Vec2d, max_memory: u64) -> bool {
+    destination.extension().unwrap_or_default() == "png" && canvas_memory(size) > max_memory
+}
+
+fn encoder_for_name(destination: PathBuf, size: Vec2d, compression: u8, strict_format: bool, max_memory: u64, metadata: ImageMetadata, checkpoint: Option<Checkpoint>) -> Result<Box<dyn Encoder>, ZoomError> {
     let extension = destination.extension().unwrap_or_default();
-    if extension == "png" {
+    if metadata.embed_xmp && !supports_xmp(&destination) {
+        warn!("Metadata can only be embedded in JPEG, PNG and TIFF files, not in {}", destination.display());
+    }
+    if assembled_on_disk(&destination, size, max_memory) {
+        debug!("Assembling the png image on disk");
+        Ok(Box::new(disk_canvas::DiskCanvas::new(destination, size, compression, metadata.xmp(), checkpoint)?))
+    } else if extension == "png" {
         debug!("Using the streaming png encoder");
-        Ok(Box::new(png_encoder::PngEncoder::new(destination, size, compression)?))
+        Ok(Box::new(png_encoder::PngEncoder::new(destination, size, compression, metadata.xmp())?))
     } else if extension == "iiif" {
         debug!("Using the iiif tiling encoder");
 	let quality = 100u8.saturating_sub(compression);
         Ok(Box::new(iiif_encoder::IiifEncoder::new(destination, size, quality)?))
+    } else if extension == "dzi" {
+        debug!("Saving the tiles as-is to a DeepZoom image");
+        Ok(Box::new(dzi_encoder::DziEncoder::new(destination, size)?))
     } else if extension == "jpeg" || extension == "jpg" {
         debug!("Using the jpeg encoder with a quality of {}", compression);
         let image_writer = ImageWriter::Jpeg { quality: 100u8.saturating_sub(compression) };
-        Ok(Box::new(canvas::Canvas::new(destination, size, image_writer)?))
+        Ok(Box::new(canvas::Canvas::new(destination, size, image_writer, strict_format, metadata)?))
+    } else if extension == "tiff" || extension == "tif" {
+        debug!("Using the tiff encoder");
+        Ok(Box::new(canvas::Canvas::new(destination, size, ImageWriter::Tiff, strict_format, metadata)?))
     } else {
         debug!("Using the generic canvas implementation {}", &destination.to_string_lossy());
-        Ok(Box::new(canvas::Canvas::new(destination, size, ImageWriter::Generic)?))
+        Ok(Box::new(canvas::Canvas::new(destination, size, ImageWriter::Generic, strict_format, metadata)?))
     }
 }
 
+/// The largest image that can be stored in a file with the given name, if the format is limited
+pub fn max_size_for_name(destination: &Path) -> Option<Vec2d> {
+    let extension = destination.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    match extension.as_str() {
+        // JPEG and GIF store dimensions as 16-bit integers
+        "jpg" | "jpeg" | "gif" => Some(Vec2d::square(u16::MAX.into())),
+        _ => None,
+    }
+}
+
+/// Whether transparent pixels can be stored in a file with the given name
+pub fn supports_alpha(destination: &Path) -> bool {
+    let extension = destination.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    !matches!(extension.as_str(), "jpg" | "jpeg")
+}
+
+/// Whether an XMP packet can be written in a file with the given name
+fn supports_xmp(destination: &Path) -> bool {
+    let extension = destination.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    matches!(extension.as_str(), "png" | "jpg" | "jpeg" | "tif" | "tiff")
+}
+
+/// Whether the whole image has to be held in memory before it is saved in the format of this file
+fn needs_canvas(destination: &Path) -> bool {
+    let extension = destination.extension().unwrap_or_default().to_string_lossy().to_lowercase();
+    !matches!(extension.as_str(), "png" | "iiif" | "dzi")
+}
+
+/// Number of bytes used by an in-memory canvas of the given size
+fn canvas_memory(size: Vec2d) -> u64 {
+    size.area() * 4
+}
+
+/// Returns the path where an image of the given size can actually be saved.
+/// If the format of the requested destination cannot hold the image, or would require more
+/// than `max_memory` bytes to assemble it, then either fall back to PNG (with a warning),
+/// or fail if `strict_format` is set.
+/// PNG images larger than `max_memory` are assembled on disk.
+pub fn destination_for_size(destination: PathBuf, size: Vec2d, strict_format: bool, max_memory: u64) -> Result<PathBuf, ZoomError> {
+    let name = destination.to_string_lossy().to_string();
+    let reason = match max_size_for_name(&destination) {
+        Some(max_size) if !size.fits_inside(max_size) => {
+            if strict_format {
+                return Err(ZoomError::UnsupportedSize {
+                    destination: name,
+                    width: size.x,
+                    height: size.y,
+                    max_width: max_size.x,
+                    max_height: max_size.y,
+                });
+            }
+            format!("whose format is limited to {}x{} pixels", max_size.x, max_size.y)
+        }
+        _ if needs_canvas(&destination) && canvas_memory(size) > max_memory => {
+            if strict_format {
+                return Err(ZoomError::CanvasTooLarge {
+                    destination: name,
+                    width: size.x,
+                    height: size.y,
+                    max_memory,
+                });
+            }
+            format!("because it would need {} of memory, more than --max-memory", HumanBytes(canvas_memory(size)))
+        }
+        _ => return Ok(destination),
+    };
+    let fallback = png_fallback(&destination)?;
+    warn!("An image of size {}x{} cannot be saved to {:?}, {}. Saving it as PNG to {:?} instead.",
+          size.x, size.y, destination, reason, fallback);
+    Ok(fallback)
+}
+
+/// Reserves the PNG file in which an image is saved instead of the given destination.
+/// An existing file, such as one left by an earlier run, is never replaced: a suffix is added to the name instead.
+pub fn png_fallback(destination: &Path) -> Result<PathBuf, ZoomError> {
+    let fallback = loop {
+        let fallback = available_path(destination.with_extension("png"));
+        match reserve_output_file(&fallback) {
+            // The file was created since it was found to be available
+            Err(ZoomError::Io { source }) if source.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            result => break result.map(|()| fallback)?,
+        }
+    };
+    // Remove the empty placeholder that was reserved for the original destination
+    if std::fs::metadata(destination).map(|m| m.len() == 0).unwrap_or(false) {
+        std::fs::remove_file(destination)?;
+    }
+    Ok(fallback)
+}
+
 /// If a tile is larger than the advertised image size, then crop it to fit in the canvas
 pub fn crop_tile(tile: &Tile, canvas_size: Vec2d) -> SubImage<&DynamicImage> {
     let Vec2d { x: xmax, y: ymax } = max_size_in_rect(tile.position, tile.size(), canvas_size);
     tile.image.view(0, 0, xmax, ymax)
 }
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn test_destination_for_size() {
+        let dir = TempDir::new("dezoomify-rs-test-fallback").unwrap();
+        let small = Vec2d::square(1000);
+        let huge = Vec2d { x: 70_000, y: 10 };
+
+        let jpg = dir.path().join("image.jpg");
+        reserve_output_file(&jpg).unwrap();
+        assert_eq!(destination_for_size(jpg.clone(), small, true, u64::MAX).unwrap(), jpg);
+        assert!(destination_for_size(jpg.clone(), huge, true, u64::MAX).is_err());
+        assert!(jpg.exists(), "the reserved file should be kept in strict mode");
+
+        let png = dir.path().join("image.png");
+        assert_eq!(destination_for_size(jpg.clone(), huge, false, u64::MAX).unwrap(), png);
+        assert!(png.exists());
+        assert!(!jpg.exists(), "the empty reserved jpg file should be removed");
+
+        let tiff = dir.path().join("image.tiff");
+        assert_eq!(destination_for_size(tiff.clone(), huge, true, u64::MAX).unwrap(), tiff);
+    }
+
+    #[test]
+    fn test_transparent_jpeg_fallback() {
+        let dir = TempDir::new("dezoomify-rs-test-alpha").unwrap();
+        let jpg = dir.path().join("image.jpg");
+        let size = Vec2d::square(2);
+        let canvas = |strict| canvas::Canvas::new(jpg.clone(), size, ImageWriter::Jpeg { quality: 90 }, strict, ImageMetadata::new(None)).unwrap();
+
+        reserve_output_file(&jpg).unwrap();
+        let mut strict = canvas(true);
+        strict.add_tile(Tile::empty(Vec2d::square(0), size)).unwrap();
+        assert!(strict.finalize().is_err());
+
+        let mut opaque = canvas(false);
+        opaque.add_tile(Tile { image: DynamicImage::new_rgb8(2, 2), position: Vec2d::square(0), encoded: None }).unwrap();
+        opaque.finalize().unwrap();
+        assert_eq!(opaque.saved_to(), None);
+
+        std::fs::write(&jpg, b"").unwrap();
+        let mut transparent = canvas(false);
+        transparent.add_tile(Tile::empty(Vec2d::square(0), size)).unwrap();
+        transparent.finalize().unwrap();
+        let png = dir.path().join("image.png");
+        assert_eq!(transparent.saved_to(), Some(png.clone()));
+        assert_eq!(image::open(&png).unwrap().color(), image::ColorType::Rgba8);
+        assert!(!jpg.exists(), "the empty reserved jpg file should be removed");
+    }
+
+    #[test]
+    fn test_transparent_jpeg_fallback_to_existing_png() {
+        let dir = TempDir::new("dezoomify-rs-test-alpha-existing").unwrap();
+        let jpg = dir.path().join("image.jpg");
+        let png = dir.path().join("image.png");
+        std::fs::write(&png, b"saved by an earlier run").unwrap();
+        reserve_output_file(&jpg).unwrap();
+        let size = Vec2d::square(2);
+        let mut transparent = canvas::Canvas::new(jpg.clone(), size, ImageWriter::Jpeg { quality: 90 }, false, ImageMetadata::new(None)).unwrap();
+        transparent.add_tile(Tile::empty(Vec2d::square(0), size)).unwrap();
+        transparent.finalize().unwrap();
+        let fallback = dir.path().join("image_0001.png");
+        assert_eq!(transparent.saved_to(), Some(fallback.clone()));
+        assert_eq!(image::open(&fallback).unwrap().color(), image::ColorType::Rgba8);
+        assert_eq!(std::fs::read(&png).unwrap(), b"saved by an earlier run");
+    }
+
+    #[test]
+    fn test_destination_for_memory() {
+        let dir = TempDir::new("dezoomify-rs-test-memory").unwrap();
+        let size = Vec2d::square(1000);
+        let tiff = dir.path().join("image.tiff");
+        reserve_output_file(&tiff).unwrap();
+        assert_eq!(destination_for_size(tiff.clone(), size, false, 4_000_000).unwrap(), tiff);
+        assert!(destination_for_size(tiff.clone(), size, true, 3_999_999).is_err());
+        let png = dir.path().join("image.png");
+        assert_eq!(destination_for_size(tiff.clone(), size, false, 3_999_999).unwrap(), png);
+        assert_eq!(destination_for_size(png.clone(), size, true, 1).unwrap(), png);
+    }
+}
@@ -156,6 +156,7 @@ mod tests {
                     /* pixel 0,0 */ 1, 2, 3, /* pixel 1,0 */ 4, 5, 6,
                     /* pixel 0,1 */ 7, 8, 9, /* pixel 1,1 */ 10, 11, 12,
                 ]).unwrap()),
+                encoded: None,
             },
             Tile {
                 position: Vec2d { x: 2, y: 0 },
@@ -165,6 +166,7 @@ mod tests {
                     /* pixel 2,2 */ 02, 02, 02, /* pixel 3,2 */ 12, 12, 12,
                     /* pixel 2,3 */ 03, 03, 03, /* pixel 3,3 */ 13, 13, 13,
                 ]).unwrap()),
+                encoded: None,
             },
             Tile {
                 position: Vec2d { x: 0, y: 2 },
@@ -172,12 +174,14 @@ mod tests {
                     /* pixel 0,2 */ 100, 100, 100, /* pixel 1,2 */ 200, 200, 200,
                     /* pixel 0,3 */ 200, 200, 200, /* pixel 1,3 */ 99, 99, 99,
                 ]).unwrap()),
+                encoded: None,
             },
             Tile {
                 position: Vec2d { x: 1, y: 0 },
                 image: DynamicImage::ImageRgb8(ImageBuffer::from_raw(2, 1, vec![
                     /* pixel 1,0 */ 4, 5, 6, /* pixel 2,0 */ 00, 00, 00,
                 ]).unwrap()),
+                encoded: None,
             }
         ][i].clone()
     }
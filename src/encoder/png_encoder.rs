@@ -1,5 +1,5 @@
 use std::fs::{File, OpenOptions};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io;
 
 use crate::{Vec2d, ZoomError};
@@ -14,25 +14,34 @@ pub struct PngEncoder {
 }
 
 impl PngEncoder {
-    pub fn new(destination: PathBuf, size: Vec2d, compression: u8) -> Result<Self, ZoomError> {
-        let file = OpenOptions::new().write(true).create(true).open(destination)?;
-        let mut encoder = png::Encoder::new(file, size.x, size.y);
-        encoder.set_color(png::ColorType::RGB);
-        encoder.set_depth(png::BitDepth::Eight);
-        encoder.set_compression(match compression {
-            0 => png::Compression::Rle,
-            1..=9 => png::Compression::Huffman,
-            10..=19 => png::Compression::Fast,
-            20..=60 => png::Compression::Default,
-            _ => png::Compression::Best,
-        });
-        let writer = encoder.write_header()?
-            .into_stream_writer_with_size(128 * 1024);
+    pub fn new(destination: PathBuf, size: Vec2d, compression: u8, xmp: Option<String>) -> Result<Self, ZoomError> {
+        let writer = png_writer(&destination, size, compression, xmp.as_deref())?;
         let pixel_streamer = Some(PixelStreamer::new(writer, size));
         Ok(PngEncoder { pixel_streamer, size })
     }
 }
 
+/// Creates a PNG file to which RGB pixels are written from top left to bottom right.
+/// The XMP packet, if any, is written before the pixels.
+pub(super) fn png_writer(destination: &Path, size: Vec2d, compression: u8, xmp: Option<&str>) -> io::Result<png::StreamWriter<'static, File>> {
+    let file = OpenOptions::new().write(true).create(true).truncate(true).open(destination)?;
+    let mut encoder = png::Encoder::new(file, size.x, size.y);
+    encoder.set_color(png::ColorType::RGB);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(match compression {
+        0 => png::Compression::Rle,
+        1..=9 => png::Compression::Huffman,
+        10..=19 => png::Compression::Fast,
+        20..=60 => png::Compression::Default,
+        _ => png::Compression::Best,
+    });
+    let mut writer = encoder.write_header()?;
+    if let Some(xmp) = xmp {
+        writer.write_chunk(*b"iTXt", &crate::xmp::png_chunk(xmp))?;
+    }
+    Ok(writer.into_stream_writer_with_size(128 * 1024))
+}
+
 impl Encoder for PngEncoder {
     fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
         self.pixel_streamer
@@ -68,13 +77,14 @@ mod tests {
     fn test_png_create() {
         let destination = temp_dir().join("dezoomify-rs-png-test.png");
         let size = Vec2d { x: 2, y: 2 };
-        let mut encoder = PngEncoder::new(destination.clone(), size, 1).unwrap();
+        let mut encoder = PngEncoder::new(destination.clone(), size, 1, None).unwrap();
 
         encoder.add_tile(Tile {
             position: Vec2d { x: 1, y: 1 },
             image: DynamicImage::ImageRgb8(
                 ImageBuffer::from_raw(1, 1, vec![1, 2, 3, ]).unwrap()
             ),
+            encoded: None,
         }).unwrap();
 
         encoder.finalize().unwrap();
@@ -99,6 +99,7 @@ impl<T: TileSaver> Retiler<T> {
             Some(Tile {
                 position: scaled_top_left,
                 image: tile.image.resize_exact(scaled_size.x, scaled_size.y, FilterType::Gaussian),
+                encoded: None,
             })
         };
         let scaled_tile = scaled_tile.as_ref().unwrap_or(tile);
@@ -162,7 +163,7 @@ impl<T: TileSaver> Retiler<T> {
     }
 
     pub fn tile_save(&self, position: Vec2d, size: Vec2d, image: DynamicImage) -> io::Result<()> {
-        self.tile_saver.save_tile(size, Tile { position, image })
+        self.tile_saver.save_tile(size, Tile { position, image, encoded: None })
     }
 
     pub fn level_count(&self) -> u32 {
@@ -296,10 +297,12 @@ mod tests {
         retiler.add_tile(&Tile {
             image: plain_image(Vec2d { x: 2, y: 1 }, 64),
             position: Vec2d { x: 0, y: 0 },
+            encoded: None,
         }).unwrap();
         retiler.add_tile(&Tile {
             image: plain_image(Vec2d { x: 2, y: 2 }, 16),
             position: Vec2d { x: 0, y: 1 },
+            encoded: None,
         }).unwrap();
         retiler.finalize();
         /* We created the following image :
@@ -322,9 +325,9 @@ mod tests {
             ]).unwrap());
         assert_eq!(tile_saver.get_added(), vec![
             //   ( covered size , Tile {position in target, size in target, pixels })
-            (Vec2d { x: 2, y: 2 }, Tile { position: Vec2d { x: 0, y: 0 }, image: expected_first_tile }),
-            (Vec2d { x: 2, y: 1 }, Tile { position: Vec2d { x: 0, y: 2 }, image: plain_image(Vec2d { x: 2, y: 1 }, 16) }),
-            (image_size, Tile { position: Vec2d { x: 0, y: 0 }, image: expected_zoomed_out_tile }),
+            (Vec2d { x: 2, y: 2 }, Tile { position: Vec2d { x: 0, y: 0 }, image: expected_first_tile, encoded: None }),
+            (Vec2d { x: 2, y: 1 }, Tile { position: Vec2d { x: 0, y: 2 }, image: plain_image(Vec2d { x: 2, y: 1 }, 16), encoded: None }),
+            (image_size, Tile { position: Vec2d { x: 0, y: 0 }, image: expected_zoomed_out_tile, encoded: None }),
         ]);
     }
 }
\ No newline at end of file
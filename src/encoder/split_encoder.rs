@@ -0,0 +1,179 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use image::ColorType;
+use log::{debug, info};
+
+use crate::{Vec2d, ZoomError};
+use crate::encoder::disk_canvas::DiskCanvas;
+use crate::encoder::Encoder;
+use crate::exif::ImageMetadata;
+use crate::tile::Tile;
+
+/// Largest dimension of a JPEG image
+const MAX_PART_SIZE: u32 = u16::MAX as u32;
+/// Number of bytes per pixel of the parts while they are encoded
+const PART_BYTES_PER_PIXEL: u64 = 3;
+
+/// How images that are too large for the format of the output file are split, with --split-oversize
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SplitOptions {
+    /// Whether an HTML page showing all the parts side by side is written
+    pub contact_sheet: bool,
+}
+
+/// Saves an image that is too large for a single JPEG file as a grid of JPEG files.
+/// The image is assembled on disk, and each part is then read back and encoded in memory.
+pub struct SplitEncoder {
+    canvas: DiskCanvas,
+    destination: PathBuf,
+    size: Vec2d,
+    /// Number of parts in each dimension
+    grid: Vec2d,
+    quality: u8,
+    metadata: ImageMetadata,
+    options: SplitOptions,
+}
+
+impl SplitEncoder {
+    pub fn new(destination: PathBuf, size: Vec2d, quality: u8, max_memory: u64, metadata: ImageMetadata, options: SplitOptions) -> Result<Self, ZoomError> {
+        let grid = part_grid(size, max_memory);
+        info!("The image of size {} will be saved as {} parts of at most {} pixels",
+              size, grid.area(), size.ceil_div(grid));
+        let canvas = DiskCanvas::new(destination.clone(), size, 0, None, None)?;
+        Ok(SplitEncoder { canvas, destination, size, grid, quality, metadata, options })
+    }
+
+    /// The file that represents the whole image: the contact sheet, or else the first part
+    pub fn main_file(destination: &Path, options: SplitOptions) -> PathBuf {
+        if options.contact_sheet {
+            destination.with_extension("html")
+        } else {
+            part_path(destination, 0, 0)
+        }
+    }
+
+    /// The position and size of the part in the given row and column
+    fn part_rect(&self, row: u32, col: u32) -> (Vec2d, Vec2d) {
+        let part_size = self.size.ceil_div(self.grid);
+        let position = part_size * Vec2d { x: col, y: row };
+        (position, part_size.min(self.size - position))
+    }
+
+    fn write_part(&mut self, row: u32, col: u32) -> io::Result<PathBuf> {
+        let (position, size) = self.part_rect(row, col);
+        let image = self.canvas.read_region(position, size)?;
+        let mut jpeg = Vec::new();
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, self.quality)
+            .encode(&image, size.x, size.y, ColorType::Rgb8)
+            .map_err(io::Error::other)?;
+        self.metadata.insert_into_jpeg(&mut jpeg);
+        if let Some(xmp) = self.metadata.xmp() { crate::xmp::insert_into_jpeg(&xmp, &mut jpeg); }
+        let path = part_path(&self.destination, row, col);
+        debug!("Saving the part at {} of size {} to {:?}", position, size, path);
+        std::fs::write(&path, jpeg)?;
+        Ok(path)
+    }
+
+    fn write_contact_sheet(&self, parts: &[PathBuf]) -> io::Result<()> {
+        let title = self.metadata.title.clone().unwrap_or_else(|| {
+            self.destination.file_stem().unwrap_or_default().to_string_lossy().to_string()
+        });
+        // The columns are as wide as the parts, so that the images keep their proportions when scaled
+        let columns: Vec<String> = (0..self.grid.x)
+            .map(|col| format!("{}fr", self.part_rect(0, col).1.x))
+            .collect();
+        let images: String = parts.iter().map(|path| {
+            let name = crate::xmp::escape(&path.file_name().unwrap_or_default().to_string_lossy());
+            format!("<a href=\"{0}\"><img src=\"{0}\" alt=\"{0}\"></a>\n", name)
+        }).collect();
+        let html = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n\
+             <style>\nbody {{ margin: 0; background: #222; }}\n\
+             .parts {{ display: grid; grid-template-columns: {columns}; }}\n\
+             img {{ display: block; width: 100%; height: auto; }}\n</style>\n</head>\n\
+             <body>\n<div class=\"parts\">\n{images}</div>\n</body>\n</html>\n",
+            title = crate::xmp::escape(&title),
+            columns = columns.join(" "),
+            images = images,
+        );
+        std::fs::write(Self::main_file(&self.destination, self.options), html)
+    }
+}
+
+impl Encoder for SplitEncoder {
+    fn add_tile(&mut self, tile: Tile) -> io::Result<()> {
+        if let Some(encoded) = &tile.encoded { self.metadata.merge_tile(encoded); }
+        self.canvas.add_tile(tile)
+    }
+
+    fn finalize(&mut self) -> io::Result<()> {
+        let mut parts = Vec::with_capacity(self.grid.area() as usize);
+        for row in 0..self.grid.y {
+            for col in 0..self.grid.x {
+                parts.push(self.write_part(row, col)?);
+            }
+        }
+        if self.options.contact_sheet { self.write_contact_sheet(&parts)?; }
+        info!("Saved the image as {} parts next to {:?}", parts.len(), self.destination);
+        Ok(())
+    }
+
+    fn size(&self) -> Vec2d {
+        self.size
+    }
+}
+
+/// The path of the part in the given row and column, numbered from 1: `image_r1_c2.jpg`
+fn part_path(destination: &Path, row: u32, col: u32) -> PathBuf {
+    let stem = destination.file_stem().unwrap_or_default().to_string_lossy();
+    destination.with_file_name(format!("{}_r{}_c{}.jpg", stem, row + 1, col + 1))
+}
+
+/// Number of parts in each dimension, so that each part fits in a JPEG file,
+/// and can be encoded with at most `max_memory` bytes
+fn part_grid(size: Vec2d, max_memory: u64) -> Vec2d {
+    let mut grid = size.ceil_div(Vec2d::square(MAX_PART_SIZE));
+    loop {
+        let part_size = size.ceil_div(grid);
+        if part_size.area() * PART_BYTES_PER_PIXEL <= max_memory || part_size.area() <= 1 {
+            return grid;
+        }
+        if part_size.x >= part_size.y { grid.x += 1 } else { grid.y += 1 }
+    }
+}
+
+#[test]
+fn test_part_grid() {
+    assert_eq!(part_grid(Vec2d { x: 70_000, y: 1000 }, u64::MAX), Vec2d { x: 2, y: 1 });
+    assert_eq!(part_grid(Vec2d { x: 131_071, y: 65_536 }, u64::MAX), Vec2d { x: 3, y: 2 });
+    assert_eq!(part_grid(Vec2d { x: 70_000, y: 1000 }, 35_000 * 1000 * 3 - 1), Vec2d { x: 3, y: 1 });
+    assert_eq!(part_grid(Vec2d { x: 10, y: 10 }, 75), Vec2d { x: 2, y: 2 });
+}
+
+#[test]
+fn test_split_encoder() {
+    use image::{DynamicImage, GenericImageView, RgbImage};
+
+    let dir = tempdir::TempDir::new("dezoomify-rs-split").unwrap();
+    let destination = dir.path().join("image.jpg");
+    let options = SplitOptions { contact_sheet: true };
+    let mut metadata = ImageMetadata::new(None);
+    metadata.title = Some("A <large> image".into());
+    // Parts of at most 25 pixels: 2 columns and 2 rows
+    let mut encoder = SplitEncoder::new(destination.clone(), Vec2d { x: 9, y: 10 }, 90, 75, metadata, options).unwrap();
+    let image = RgbImage::from_fn(9, 10, |x, _| if x < 5 { image::Rgb([255, 0, 0]) } else { image::Rgb([0, 0, 255]) });
+    encoder.add_tile(Tile { image: DynamicImage::ImageRgb8(image), position: Vec2d::default(), encoded: None }).unwrap();
+    encoder.finalize().unwrap();
+    drop(encoder);
+
+    let part = image::open(dir.path().join("image_r2_c2.jpg")).unwrap();
+    assert_eq!(part.dimensions(), (4, 5));
+    assert!(part.get_pixel(1, 1).0[2] > 200, "the second column should be blue");
+    assert_eq!(image::open(dir.path().join("image_r1_c1.jpg")).unwrap().dimensions(), (5, 5));
+    let html = std::fs::read_to_string(SplitEncoder::main_file(&destination, options)).unwrap();
+    assert!(html.contains("<title>A &lt;large&gt; image</title>"));
+    assert!(html.contains("grid-template-columns: 5fr 4fr;"));
+    assert!(html.contains("<img src=\"image_r2_c1.jpg\""));
+    assert!(!dir.path().join(".image.jpg.canvas").exists(), "the temporary file should be removed");
+}
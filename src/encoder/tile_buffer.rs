@@ -1,54 +1,114 @@
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
 
 /**
 Used to receive tiles asynchronously and provide them to the encoder
 */
 use log::debug;
-use tokio::sync::mpsc;
+#[cfg(feature = "cli")]
+use tokio::sync::{mpsc, oneshot};
 
 use crate::{Vec2d, ZoomError};
-use crate::encoder::{Encoder, encoder_for_name};
+use crate::encoder::{assembled_on_disk, destination_for_size, Encoder, encoder_for_name, max_size_for_name};
+use crate::encoder::split_encoder::{SplitEncoder, SplitOptions};
+use crate::output_file::reserve_output_file;
 use crate::tile::Tile;
+use crate::exif::ImageMetadata;
+use crate::tile_cache::Checkpoint;
 use log::warn;
 
 /// Data structure used to store tiles until the final image size is known
+#[allow(clippy::large_enum_variant)]
 pub enum TileBuffer {
     Buffering {
         destination: PathBuf,
         buffer: Vec<Tile>,
+        /// The positions of the references of the buffered tiles, to record in the checkpoint
+        buffered_references: Vec<Vec2d>,
         compression: u8,
+        strict_format: bool,
+        max_memory: u64,
+        metadata: ImageMetadata,
+        split: Option<SplitOptions>,
     },
     Writing {
-        tile_sender: mpsc::Sender<TileBufferMsg>,
-        error_receiver: mpsc::Receiver<std::io::Error>,
+        destination: PathBuf,
+        encoder: EncoderTask,
     },
 }
 
 impl TileBuffer {
     /// Create an encoder for an image of the given size at the path
     /// Errors out if the encoder cannot create files with the given extension
-    /// or at the given size
-    pub async fn new(destination: PathBuf, compression: u8) -> Result<Self, ZoomError> {
+    /// or at the given size.
+    /// If `strict_format` is not set, then images that are too large for the format of the
+    /// destination are saved as PNG instead.
+    /// Images that would use more than `max_memory` bytes to assemble are assembled on disk.
+    /// The `metadata` is saved in the formats that support it.
+    /// With `split`, images that are too large for the format of the destination are saved
+    /// as several JPEG files instead.
+    pub async fn new(destination: PathBuf, compression: u8, strict_format: bool, max_memory: u64, metadata: ImageMetadata, split: Option<SplitOptions>) -> Result<Self, ZoomError> {
         Ok(TileBuffer::Buffering {
             destination,
             buffer: vec![],
+            buffered_references: vec![],
             compression,
+            strict_format,
+            max_memory,
+            metadata,
+            split,
         })
     }
 
-    pub async fn set_size(&mut self, size: Vec2d) -> Result<(), ZoomError> {
+    /// The path of the image file. It may change when the size of the image is set.
+    pub fn destination(&self) -> &Path {
+        match self {
+            TileBuffer::Buffering { destination, .. } => destination,
+            TileBuffer::Writing { destination, .. } => destination,
+        }
+    }
+
+    /// Sets the size of the image, and creates its encoder.
+    /// An image that is assembled on disk is assembled in the `checkpoint`, if one is given:
+    /// the positions of the references of the tiles that it already contains are returned,
+    /// and these tiles do not need to be added again.
+    pub async fn set_size(&mut self, size: Vec2d, checkpoint: Option<Checkpoint>) -> Result<HashSet<Vec2d>, ZoomError> {
+        let mut resumed = HashSet::new();
         let next_state = match self {
-            TileBuffer::Buffering { buffer, destination, compression } => {
+            TileBuffer::Buffering { buffer, buffered_references, destination, compression, strict_format, max_memory, metadata, split } => {
                 debug!("Creating a tile writer for an image of size {}", size);
-                let mut e = encoder_for_name(destination.clone(), size, *compression)?;
+                let oversize = max_size_for_name(destination).is_some_and(|max_size| !size.fits_inside(max_size));
+                let (destination, mut e): (PathBuf, Box<dyn Encoder>) = match split {
+                    Some(options) if oversize => {
+                        let quality = 100u8.saturating_sub(*compression);
+                        let e = SplitEncoder::new(destination.clone(), size, quality, *max_memory, metadata.clone(), *options)?;
+                        let main_file = SplitEncoder::main_file(destination, *options);
+                        reserve_output_file(&main_file)?;
+                        // Remove the empty placeholder that was reserved for the single image
+                        if std::fs::metadata(&*destination).map(|m| m.len() == 0).unwrap_or(false) {
+                            std::fs::remove_file(&*destination)?;
+                        }
+                        (main_file, Box::new(e))
+                    }
+                    _ => {
+                        let destination = destination_for_size(destination.clone(), size, *strict_format, *max_memory)?;
+                        let checkpoint = checkpoint.filter(|_| assembled_on_disk(&destination, size, *max_memory));
+                        if let Some(checkpoint) = &checkpoint { resumed.clone_from(checkpoint.tiles()); }
+                        let e = encoder_for_name(destination.clone(), size, *compression, *strict_format, *max_memory, metadata.clone(), checkpoint)?;
+                        (destination, e)
+                    }
+                };
                 debug!("Adding buffered tiles: {:?}", buffer);
                 for tile in buffer.drain(..) { e.add_tile(tile)?; }
-                buffer_tiles(e).await
+                if let Err(err) = e.checkpoint(buffered_references) {
+                    warn!("Unable to save a checkpoint of the image: {}", err);
+                }
+                TileBuffer::Writing { destination, encoder: EncoderTask::start(e) }
             }
             TileBuffer::Writing { .. } => unreachable!("The size of the image can be set only once")
         };
         *self = next_state;
-        Ok(())
+        Ok(resumed)
     }
 
     /// Add a tile to the image
@@ -57,64 +117,187 @@ impl TileBuffer {
             TileBuffer::Buffering { buffer, .. } => {
                 buffer.push(tile)
             }
-            TileBuffer::Writing { tile_sender, .. } => {
-                tile_sender.send(TileBufferMsg::AddTile(tile))
-                    .await.expect("The tile writer ended unexpectedly");
+            TileBuffer::Writing { encoder, .. } => encoder.add_tile(tile).await,
+        }
+    }
+
+    /// Record that the tiles with references at the given positions were added to the image,
+    /// after the tiles themselves
+    pub async fn checkpoint(&mut self, tiles: Vec<Vec2d>) {
+        match self {
+            TileBuffer::Buffering { buffered_references, .. } => {
+                buffered_references.extend(tiles)
             }
+            TileBuffer::Writing { encoder, .. } => encoder.checkpoint(tiles).await,
         }
     }
 
-    /// To be called when no more tile will be added
-    pub async fn finalize(&mut self) -> Result<(), ZoomError> {
+    /// Sets the size of the image to the area covered by the tiles received so far, if it was not set.
+    /// The destination of the image is known afterwards.
+    pub async fn fix_size(&mut self) -> Result<(), ZoomError> {
         if let TileBuffer::Buffering { buffer, .. } = self {
             let size = buffer.iter().map(|t| t.position + t.size()).fold(
                 Vec2d { x: 0, y: 0 },
                 Vec2d::max,
             );
-            self.set_size(size).await?;
+            self.set_size(size, None).await?;
         }
-        let (tile_sender, error_receiver) = match self {
+        Ok(())
+    }
+
+    /// To be called when no more tile will be added
+    pub async fn finalize(&mut self) -> Result<(), ZoomError> {
+        self.fix_size().await?;
+        let (destination, encoder) = match self {
             TileBuffer::Buffering { .. } => unreachable!("Just set the size"),
-            TileBuffer::Writing { tile_sender, error_receiver } => (tile_sender, error_receiver)
+            TileBuffer::Writing { destination, encoder } => (destination, encoder),
         };
-        tile_sender.send(TileBufferMsg::Close).await?;
-        debug!("Waiting for the image encoding task to finish");
-        if let Some(err) = error_receiver.recv().await { return Err(err.into()) }
+        if let Some(path) = encoder.finalize().await? { *destination = path; }
         Ok(())
     }
+
+    /// To be called instead of `finalize` when the image will not be saved.
+    /// Removes the destination, and the other files written by the encoder.
+    pub async fn abort(&mut self) -> Result<(), ZoomError> {
+        if let TileBuffer::Writing { encoder, .. } = self {
+            encoder.abort().await?;
+        }
+        match std::fs::remove_file(self.destination()) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
 }
 
+#[cfg(feature = "cli")]
 #[derive(Debug)]
 pub enum TileBufferMsg {
     AddTile(Tile),
+    Checkpoint(Vec<Vec2d>),
     Close,
+    Abort,
 }
 
-async fn buffer_tiles(mut encoder: Box<dyn Encoder>) -> TileBuffer {
-    let (tile_sender, mut tile_receiver) = mpsc::channel(1024);
-    let (error_sender, error_receiver) = mpsc::channel(1);
-    tokio::spawn(async move {
-        while let Some(msg) = tile_receiver.recv().await {
-            match msg {
-                TileBufferMsg::AddTile(tile) => {
-                    debug!("Sending tile to encoder: {:?}", tile);
-                    let result = tokio::task::block_in_place(|| encoder.add_tile(tile));
-                    if let Err(err) = result {
-                        warn!("Error when adding tile: {}", err);
-                        error_sender.send(err).await.expect("could not send error");
+/// The encoder of an image whose size is known.
+/// With the `cli` feature, it runs in its own task, so that tiles are encoded while others are downloaded.
+#[cfg(feature = "cli")]
+pub struct EncoderTask {
+    tile_sender: mpsc::Sender<TileBufferMsg>,
+    error_receiver: mpsc::Receiver<std::io::Error>,
+    /// The file the image was saved to, if the encoder had to change it when finalizing the image
+    saved_to: oneshot::Receiver<PathBuf>,
+}
+
+#[cfg(feature = "cli")]
+impl EncoderTask {
+    fn start(mut encoder: Box<dyn Encoder>) -> Self {
+        let (tile_sender, mut tile_receiver) = mpsc::channel(1024);
+        let (error_sender, error_receiver) = mpsc::channel(1);
+        let (saved_to_sender, saved_to) = oneshot::channel();
+        tokio::spawn(async move {
+            while let Some(msg) = tile_receiver.recv().await {
+                match msg {
+                    TileBufferMsg::AddTile(tile) => {
+                        debug!("Sending tile to encoder: {:?}", tile);
+                        let result = tokio::task::block_in_place(|| encoder.add_tile(tile));
+                        if let Err(err) = result {
+                            warn!("Error when adding tile: {}", err);
+                            error_sender.send(err).await.expect("could not send error");
+                        }
+                    }
+                    TileBufferMsg::Checkpoint(tiles) => {
+                        if let Err(err) = tokio::task::block_in_place(|| encoder.checkpoint(&tiles)) {
+                            warn!("Unable to save a checkpoint of the image: {}", err);
+                        }
+                    }
+                    TileBufferMsg::Close => { break; }
+                    TileBufferMsg::Abort => {
+                        debug!("Aborting the encoder");
+                        if let Err(err) = encoder.abort() {
+                            warn!("Error when removing the image: {}", err);
+                            error_sender.send(err).await.expect("could not send error");
+                        }
+                        return;
                     }
                 }
-                TileBufferMsg::Close => { break; }
             }
+            debug!("Finalizing the encoder");
+            if let Err(err) = encoder.finalize() {
+                warn!("Error when finalizing image: {}", err);
+                error_sender.send(err).await.expect("could not send error");
+            } else if let Some(path) = encoder.saved_to() {
+                let _ = saved_to_sender.send(path);
+            }
+        });
+        EncoderTask { tile_sender, error_receiver, saved_to }
+    }
+
+    async fn add_tile(&mut self, tile: Tile) {
+        self.tile_sender.send(TileBufferMsg::AddTile(tile))
+            .await.expect("The tile writer ended unexpectedly");
+    }
+
+    async fn checkpoint(&mut self, tiles: Vec<Vec2d>) {
+        self.tile_sender.send(TileBufferMsg::Checkpoint(tiles))
+            .await.expect("The tile writer ended unexpectedly");
+    }
+
+    /// Returns the file the image was saved to, if the encoder had to change it
+    async fn finalize(&mut self) -> Result<Option<PathBuf>, ZoomError> {
+        self.tile_sender.send(TileBufferMsg::Close).await?;
+        debug!("Waiting for the image encoding task to finish");
+        if let Some(err) = self.error_receiver.recv().await { return Err(err.into()) }
+        Ok(self.saved_to.try_recv().ok())
+    }
+
+    async fn abort(&mut self) -> Result<(), ZoomError> {
+        self.tile_sender.send(TileBufferMsg::Abort).await?;
+        debug!("Waiting for the image encoding task to stop");
+        if let Some(err) = self.error_receiver.recv().await { return Err(err.into()) }
+        Ok(())
+    }
+}
+
+/// The encoder of an image whose size is known.
+/// Without the `cli` feature, there is no tokio runtime, so tiles are encoded as soon as they are added.
+#[cfg(not(feature = "cli"))]
+pub struct EncoderTask {
+    encoder: Box<dyn Encoder>,
+    /// The first error that happened when adding a tile, returned when finalizing the image
+    error: Option<std::io::Error>,
+}
+
+#[cfg(not(feature = "cli"))]
+impl EncoderTask {
+    fn start(encoder: Box<dyn Encoder>) -> Self {
+        EncoderTask { encoder, error: None }
+    }
+
+    async fn add_tile(&mut self, tile: Tile) {
+        debug!("Sending tile to encoder: {:?}", tile);
+        if let Err(err) = self.encoder.add_tile(tile) {
+            warn!("Error when adding tile: {}", err);
+            self.error.get_or_insert(err);
         }
-        debug!("Finalizing the encoder");
-        if let Err(err) = encoder.finalize() {
-            warn!("Error when finalizing image: {}", err);
-            error_sender.send(err).await.expect("could not send error");
+    }
+
+    async fn checkpoint(&mut self, tiles: Vec<Vec2d>) {
+        if let Err(err) = self.encoder.checkpoint(&tiles) {
+            warn!("Unable to save a checkpoint of the image: {}", err);
         }
-    });
-    TileBuffer::Writing {
-        tile_sender,
-        error_receiver,
     }
-}
\ No newline at end of file
+
+    /// Returns the file the image was saved to, if the encoder had to change it
+    async fn finalize(&mut self) -> Result<Option<PathBuf>, ZoomError> {
+        debug!("Finalizing the encoder");
+        let finalized = self.encoder.finalize();
+        if let Some(err) = self.error.take() { return Err(err.into()) }
+        finalized?;
+        Ok(self.encoder.saved_to())
+    }
+
+    async fn abort(&mut self) -> Result<(), ZoomError> {
+        debug!("Aborting the encoder");
+        Ok(self.encoder.abort()?)
+    }
+}
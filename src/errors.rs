@@ -1,38 +1,204 @@
 use std::error::Error;
+use std::rc::Rc;
+use std::time::Duration;
 
-use reqwest::{self, header};
+use http::header;
+#[cfg(feature = "cli")]
 use tokio::sync::mpsc::error::SendError;
+use crate::auto::AutoDezoomerError;
+#[cfg(feature = "cli")]
 use crate::encoder::tile_buffer::TileBufferMsg;
 use custom_error::custom_error;
 
+#[cfg(feature = "cli")]
+type NetworkError = reqwest::Error;
+#[cfg(feature = "cli")]
+type TlsError = native_tls::Error;
+#[cfg(feature = "cli")]
+type JoinError = tokio::task::JoinError;
+#[cfg(feature = "cli")]
+type TileSendError = SendError<TileBufferMsg>;
+
+/// Errors of the crates that are only used with the `cli` feature. Without it, they cannot happen,
+/// so they are replaced with types that have no values.
+#[cfg(not(feature = "cli"))]
+macro_rules! impossible_errors {
+    ($($name:ident),*) => {$(
+        #[derive(Debug)]
+        pub enum $name {}
+
+        impl std::fmt::Display for $name {
+            fn fmt(&self, _f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result { match *self {} }
+        }
+
+        impl Error for $name {}
+    )*};
+}
+
+#[cfg(not(feature = "cli"))]
+impossible_errors!(NetworkError, TlsError, JoinError, TileSendError);
+
+#[cfg(not(feature = "cli"))]
+impl NetworkError {
+    pub fn status(&self) -> Option<http::StatusCode> { match *self {} }
+}
+
 custom_error! {
     pub ZoomError
-    Networking{source: reqwest::Error} = "network error: {source}",
+    Networking{source: NetworkError} = "network error: {source}",
+    Tls{source: TlsError} = "unable to set up TLS: {source}",
+    RequestFailed{url: String, reason: String} = "the request for {url} failed: {reason}",
+    HttpStatus{url: String, status: u16, retry_after_secs: u64, contents: PartialData} =
+        "the server answered the request for {url} with the error status {status}",
+    LoginRedirect{url: String, location: String} =
+        "The request for {url} was redirected to the login page {location}: the server requires authentication. \
+        Log in with a browser, then export its cookies to a file and use it with --cookies-file, \
+        or copy the Cookie header of a tile request and use it with -H \"Cookie: ...\"",
+    Unauthorized{contents: PartialData, source: NetworkError} =
+        "network error: {source}. The server requires authentication",
+    RetryLater{retry_after_secs: u64, source: NetworkError} =
+        "network error: {source}. The server asked to retry after {retry_after_secs} seconds",
+    PartialBody{contents: PartialData, source: NetworkError} =
+        "the connection was interrupted after {contents}: {source}",
+    ReadTimeout{timeout: String} = "no data was received for {timeout}, because of --read-timeout",
+    Bootstrap{url: String, reason: String} = "the initial request to {url} failed: {reason}",
     Dezoomer{source: DezoomerError} = "Dezoomer error: {source}",
     NoLevels = "A zoomable image was found, but it did not contain any zoom level",
     NoTile = "Could not get any tile for the image",
     PartialDownload{successful_tiles: u64, total_tiles: u64} =
         "Only {successful_tiles} tiles out of {total_tiles} could be downloaded. \
         The resulting image was still created.",
+    Interrupted = "The download was interrupted before any tile was received",
+    MissingTile{url: String} =
+        "Unable to download the tile at {url}. \
+        No image was saved, because --missing-tiles is set to 'abort'.",
     Image{source: image::ImageError} = "invalid image error: {source}",
     PostProcessing{source: Box<dyn Error>} = "unable to process the downloaded tile: {source}",
     Io{source: std::io::Error} = "Input/Output error: {source}",
     Yaml{source: serde_yaml::Error} = "Invalid YAML configuration file: {source}",
+    CustomYaml{source: crate::custom_yaml::InvalidYaml} = "Invalid tiles.yaml file:\n{source}",
     TileCopyError{x:u32, y:u32, twidth:u32, theight:u32, width:u32, height:u32} =
                                 "Unable to copy a {twidth}x{theight} tile \
                                  at position {x},{y} \
                                  on a canvas of size {width}x{height}",
+    UnsupportedSize{destination: String, width: u32, height: u32, max_width: u32, max_height: u32} =
+        "Cannot save an image of size {width}x{height} to '{destination}': \
+        this format is limited to {max_width}x{max_height} pixels. \
+        Use another output file format, or remove --strict-format to fall back to PNG automatically.",
+    CanvasTooLarge{destination: String, width: u32, height: u32, max_memory: u64} =
+        "Cannot save an image of size {width}x{height} to '{destination}': \
+        this format requires assembling the whole image in memory, which would use more than {max_memory} bytes. \
+        Save the image as PNG, increase --max-memory, or remove --strict-format to fall back to PNG automatically.",
+    UnsupportedTransparency{destination: String} =
+        "Cannot save an image with transparent parts to '{destination}': this format has no transparency. \
+        Use another output file format, or remove --strict-format to fall back to PNG automatically.",
     MalformedTileStr{tile_str: String} = "Malformed tile string: '{tile_str}' \
                                           expected 'x y url'",
+    ImageTooSmall{width: u32, height: u32, min_width: u32, min_height: u32} =
+        "Skipped: the largest available level is {width}x{height}, \
+        which is smaller than the minimum of {min_width}x{min_height} set with --if-larger-than",
+    CropOutsideImage{width: u32, height: u32} =
+        "The region given with --crop is outside of the image, which is {width}x{height} pixels",
+    ImageTooLarge{width: u32, height: u32, max_pixels: u64} =
+        "The image is {width}x{height} pixels, which is more than the {max_pixels} pixels allowed \
+        by --max-pixels. Use --oversize-policy to download a smaller level or scale the image down instead.",
+    BulkFormat{reason: String} = "Invalid bulk file: {reason}",
+    FailureReport{path: String, reason: String} =
+        "Invalid list of failed tiles in {path}: {reason}",
+    PlaceholderTiles{url: String, count: usize} =
+        "The server sent the same tile {count} times in a row, the last one from '{url}'. \
+        It may have stopped serving the image, and sent placeholders instead of its tiles. \
+        Wait before downloading the image again, or request the tiles more slowly with --min-interval.",
+    PreviewMismatch{similarity: f64, min_similarity: f64} =
+        "The image was saved, but it does not look like the preview given with --verify-with-preview \
+        (similarity of {similarity:.2}, for a minimum of {min_similarity}). \
+        Its tiles may have been assembled in the wrong order.",
+    Composite{path: String, reason: String} = "Invalid composite file {path}: {reason}",
+    Wizard{reason: String} = "Unable to describe the image: {reason}",
     NoSuchDezoomer{name: String} = "No such dezoomer: {name}",
+    Http3Unsupported =
+        "This version of dezoomify-rs was built without HTTP/3 support. Build it with \
+        RUSTFLAGS=\"--cfg reqwest_unstable\" cargo build --release --features http3 to use --http3",
+    NoInputUri = "No input URL was given, and dezoomify-rs is running in non-interactive mode",
+    LevelSelectionRequired{levels: String} =
+        "Several zoom levels are available, but none was selected, \
+        and dezoomify-rs is running in non-interactive mode. \
+        Use --largest, --max-width or --max-height to select one of the following levels:\n{levels}",
     InvalidHeaderName{source: header::InvalidHeaderName} = "Invalid header name: {source}",
     InvalidHeaderValue{source: header::InvalidHeaderValue} = "Invalid header value: {source}",
-    AsyncError{source: tokio::task::JoinError} = "Unable get the result from a thread: {source}",
+    AsyncError{source: JoinError} = "Unable get the result from a thread: {source}",
     BufferToImage{source: BufferToImageError} = "{}",
-    WriteError{source: SendError<TileBufferMsg>} = "Unable to write tile {:?}",
+    WriteError{source: TileSendError} = "Unable to write tile {:?}",
     PngError{source: png::EncodingError} = "PNG encoding error: {}",
 }
 
+impl ZoomError {
+    /// The status of the http response that caused this error, if the server answered
+    pub fn http_status(&self) -> Option<u16> {
+        use ZoomError::*;
+        match self {
+            Networking { source } | Unauthorized { source, .. } |
+            RetryLater { source, .. } | PartialBody { source, .. } => source.status().map(|s| s.as_u16()),
+            HttpStatus { status, .. } => Some(*status),
+            _ => None,
+        }
+    }
+
+    /// The time after which the server asked to make the request again.
+    /// An [`ZoomError::HttpStatus`] error without a delay has a `retry_after_secs` of 0.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            ZoomError::RetryLater { retry_after_secs, .. } => Some(Duration::from_secs(*retry_after_secs)),
+            ZoomError::HttpStatus { retry_after_secs, .. } if *retry_after_secs > 0 => Some(Duration::from_secs(*retry_after_secs)),
+            _ => None,
+        }
+    }
+
+    /// The body of a response saying that authentication is required, which can describe how to authenticate
+    pub fn unauthorized_contents(&self) -> Option<&[u8]> {
+        match self {
+            ZoomError::Unauthorized { contents, .. } |
+            ZoomError::HttpStatus { status: 401, contents, .. } => Some(&contents.0),
+            _ => None,
+        }
+    }
+
+    /// The exit status of the command-line tool when it fails with this error.
+    /// Each category of error has its own code, documented in the README, so that scripts
+    /// can decide what to do without parsing error messages.
+    pub fn exit_code(&self) -> i32 {
+        use ZoomError::*;
+        match self {
+            // A metadata file that could not be downloaded is a network error, not a missing image
+            Dezoomer { source } => source.network_error().map_or(2, ZoomError::exit_code),
+            NoLevels | NoSuchDezoomer { .. } | CustomYaml { .. } => 2,
+            Networking { .. } | RequestFailed { .. } | RetryLater { .. } | PlaceholderTiles { .. } | PartialBody { .. } | ReadTimeout { .. } | Bootstrap { .. } => 3,
+            Unauthorized { .. } | LoginRedirect { .. } | HttpStatus { status: 401, .. } => 4,
+            HttpStatus { .. } => 3,
+            PartialDownload { .. } => 5,
+            NoTile | MissingTile { .. } => 6,
+            NoInputUri | LevelSelectionRequired { .. } => 7,
+            Io { .. } | UnsupportedSize { .. } | UnsupportedTransparency { .. } | CanvasTooLarge { .. } | WriteError { .. } | PngError { .. } => 8,
+            ImageTooSmall { .. } => 9,
+            PreviewMismatch { .. } => 10,
+            // The conventional status of programs stopped by Ctrl-C
+            Interrupted => 130,
+            _ => 1,
+        }
+    }
+}
+
+/// The body of a response that could not be used as expected,
+/// such as the beginning of a response whose download was interrupted
+#[derive(Debug)]
+pub struct PartialData(pub Vec<u8>);
+
+impl std::fmt::Display for PartialData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} bytes", self.0.len())
+    }
+}
+
 custom_error! {
     pub BufferToImageError
     Image{source: image::ImageError} = "invalid image error: {source}",
@@ -42,11 +208,20 @@ custom_error! {
 custom_error! {pub DezoomerError
     NeedsData{uri: String}           = "Need to download data from {uri}",
     WrongDezoomer{name:&'static str} = "The '{name}' dezoomer cannot handle this URI",
-    DownloadError{msg: String} = "Unable to download required data: {msg}",
+    Network{error: Rc<ZoomError>} = "Unable to download required data: {error}",
     Other{source: Box<dyn Error>}    = "Unable to create the dezoomer: {source}"
 }
 
 impl DezoomerError {
+    /// The network error that prevented the dezoomer from downloading the data it needed, if any
+    pub fn network_error(&self) -> Option<&ZoomError> {
+        match self {
+            DezoomerError::Network { error } => Some(error),
+            DezoomerError::Other { source } => source.downcast_ref::<AutoDezoomerError>()?.network_error(),
+            _ => None,
+        }
+    }
+
     pub fn wrap<E: Error + 'static>(err: E) -> DezoomerError {
         DezoomerError::Other { source: err.into() }
     }
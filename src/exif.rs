@@ -0,0 +1,235 @@
+//! EXIF metadata of the output images. The orientation and the camera or scanner information
+//! are copied from the EXIF block of the first tile that has one, and the URL of the image,
+//! the date of the download and the version of dezoomify-rs are added.
+//! The metadata is written to JPEG and TIFF files. See also the `xmp` module.
+
+use std::convert::TryFrom;
+use std::time::SystemTime;
+
+use crate::time_utils::rfc3339;
+use crate::dezoomer::Rights;
+
+const IMAGE_DESCRIPTION: u16 = 0x010E;
+const MAKE: u16 = 0x010F;
+const MODEL: u16 = 0x0110;
+const ORIENTATION: u16 = 0x0112;
+const SOFTWARE: u16 = 0x0131;
+const DATE_TIME: u16 = 0x0132;
+
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageMetadata {
+    pub orientation: Option<u16>,
+    pub make: Option<String>,
+    pub model: Option<String>,
+    /// The URL of the zoomable image
+    pub source: Option<String>,
+    /// The date of the download, in the EXIF format: `YYYY:MM:DD HH:MM:SS`
+    pub date: String,
+    /// The title of the image, as found by the dezoomer
+    pub title: Option<String>,
+    pub rights: Option<Rights>,
+    /// Whether an XMP packet is also written, with `--embed-metadata`
+    pub embed_xmp: bool,
+    /// Whether the metadata of a tile was already copied
+    tile_merged: bool,
+}
+
+impl ImageMetadata {
+    pub fn new(source: Option<String>) -> Self {
+        let date = rfc3339(SystemTime::now())[..19].replace('-', ":").replace('T', " ");
+        ImageMetadata {
+            orientation: None,
+            make: None,
+            model: None,
+            source,
+            date,
+            title: None,
+            rights: None,
+            embed_xmp: false,
+            tile_merged: false,
+        }
+    }
+
+    /// The XMP packet to write in the image, if it was requested
+    pub fn xmp(&self) -> Option<String> {
+        Some(crate::xmp::packet(self)).filter(|_| self.embed_xmp)
+    }
+
+    pub fn software() -> String {
+        format!("dezoomify-rs {}", env!("CARGO_PKG_VERSION"))
+    }
+
+    /// Copies the metadata of the first tile that has some. Only JPEG tiles have EXIF metadata.
+    pub fn merge_tile(&mut self, encoded: &[u8]) {
+        if self.tile_merged { return; }
+        if let Some(tiff) = jpeg_exif(encoded) {
+            self.tile_merged = true;
+            self.orientation = read_short(tiff, ORIENTATION);
+            self.make = read_ascii(tiff, MAKE);
+            self.model = read_ascii(tiff, MODEL);
+        }
+    }
+
+    /// The text tags, with their values, in the order of their numbers
+    pub fn text_tags(&self) -> Vec<(u16, String)> {
+        let mut tags = vec![];
+        if let Some(source) = &self.source { tags.push((IMAGE_DESCRIPTION, source.clone())) }
+        if let Some(make) = &self.make { tags.push((MAKE, make.clone())) }
+        if let Some(model) = &self.model { tags.push((MODEL, model.clone())) }
+        tags.push((SOFTWARE, Self::software()));
+        tags.push((DATE_TIME, self.date.clone()));
+        tags
+    }
+
+    /// A little-endian TIFF structure containing a single directory with all the tags
+    fn tiff(&self) -> Vec<u8> {
+        let mut entries: Vec<(u16, u16, u32, Vec<u8>)> = self.text_tags().into_iter().map(|(tag, value)| {
+            let mut bytes = value.into_bytes();
+            bytes.push(0);
+            (tag, ASCII, bytes.len() as u32, bytes)
+        }).collect();
+        if let Some(orientation) = self.orientation {
+            entries.push((ORIENTATION, SHORT, 1, orientation.to_le_bytes().to_vec()));
+        }
+        entries.sort_by_key(|e| e.0);
+        let mut tiff = b"II\x2A\x00\x08\x00\x00\x00".to_vec();
+        tiff.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        // The values that do not fit in 4 bytes are stored after the directory
+        let mut data_offset = 8 + 2 + 12 * entries.len() + 4;
+        let mut data = vec![];
+        for (tag, kind, count, mut value) in entries {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&count.to_le_bytes());
+            if value.len() <= 4 {
+                value.resize(4, 0);
+                tiff.extend_from_slice(&value);
+            } else {
+                tiff.extend_from_slice(&(data_offset as u32).to_le_bytes());
+                data_offset += value.len();
+                data.extend_from_slice(&value);
+            }
+        }
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(&data);
+        tiff
+    }
+
+    /// Inserts an APP1 EXIF segment in a JPEG file, after its JFIF segment if there is one
+    pub fn insert_into_jpeg(&self, jpeg: &mut Vec<u8>) {
+        let mut payload = b"Exif\x00\x00".to_vec();
+        payload.extend(self.tiff());
+        let length = match u16::try_from(payload.len() + 2) {
+            Ok(length) => length,
+            Err(_) => return,
+        };
+        let mut segment = vec![0xFF, 0xE1];
+        segment.extend_from_slice(&length.to_be_bytes());
+        segment.extend(payload);
+        let position = match segments(jpeg).next() {
+            Some((0xE0, _, end)) => end,
+            _ => 2,
+        };
+        if jpeg.starts_with(&[0xFF, 0xD8]) {
+            jpeg.splice(position..position, segment);
+        }
+    }
+}
+
+/// The markers of the segments of a JPEG file before the image data, with the range of their data
+pub fn segments(jpeg: &[u8]) -> impl Iterator<Item=(u8, usize, usize)> + '_ {
+    let mut position = 2;
+    std::iter::from_fn(move || {
+        let header = jpeg.get(position..position + 4)?;
+        if header[0] != 0xFF || header[1] == 0xDA { return None; }
+        let length = usize::from(u16::from_be_bytes([header[2], header[3]]));
+        let segment = (header[1], position + 4, position + 2 + length);
+        position += 2 + length;
+        Some(segment).filter(|s| s.2 <= jpeg.len())
+    })
+}
+
+/// The TIFF structure inside the EXIF segment of a JPEG file
+fn jpeg_exif(jpeg: &[u8]) -> Option<&[u8]> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) { return None; }
+    segments(jpeg)
+        .filter(|&(marker, _, _)| marker == 0xE1)
+        .map(|(_, start, end)| &jpeg[start..end])
+        .find(|data| data.starts_with(b"Exif\x00\x00"))
+        .map(|data| &data[6..])
+}
+
+/// Reads the entry of the first directory of a TIFF structure with the given tag:
+/// its type, its number of values, and its value or the offset of its value
+fn read_entry(tiff: &[u8], tag: u16) -> Option<(u16, u32, &[u8])> {
+    let little_endian = tiff.starts_with(b"II");
+    let u16_at = |i: usize| tiff.get(i..i + 2).map(|b| {
+        let b = [b[0], b[1]];
+        if little_endian { u16::from_le_bytes(b) } else { u16::from_be_bytes(b) }
+    });
+    let u32_at = |i: usize| tiff.get(i..i + 4).map(|b| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if little_endian { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) }
+    });
+    let directory = u32_at(4)? as usize;
+    let count = usize::from(u16_at(directory)?);
+    (0..count).map(|i| directory + 2 + 12 * i).find_map(|entry| {
+        if u16_at(entry)? != tag { return None; }
+        let kind = u16_at(entry + 2)?;
+        let values = u32_at(entry + 4)?;
+        let size = if kind == SHORT { 2 * values as usize } else { values as usize };
+        let value = if size <= 4 {
+            tiff.get(entry + 8..entry + 8 + size)?
+        } else {
+            let offset = u32_at(entry + 8)? as usize;
+            tiff.get(offset..offset + size)?
+        };
+        Some((kind, values, value))
+    })
+}
+
+fn read_short(tiff: &[u8], tag: u16) -> Option<u16> {
+    match read_entry(tiff, tag)? {
+        (SHORT, 1, value) if tiff.starts_with(b"II") => Some(u16::from_le_bytes([value[0], value[1]])),
+        (SHORT, 1, value) => Some(u16::from_be_bytes([value[0], value[1]])),
+        _ => None,
+    }
+}
+
+fn read_ascii(tiff: &[u8], tag: u16) -> Option<String> {
+    match read_entry(tiff, tag)? {
+        (ASCII, _, value) => {
+            let text = String::from_utf8_lossy(value);
+            Some(text.trim_end_matches('\0').trim().to_string()).filter(|s| !s.is_empty())
+        }
+        _ => None,
+    }
+}
+
+#[test]
+fn test_exif_round_trip() {
+    let mut tile_metadata = ImageMetadata::new(None);
+    tile_metadata.orientation = Some(6);
+    tile_metadata.make = Some("Scanner Inc.".into());
+    let mut tile = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x4A, 0x46, 0xFF, 0xDA];
+    tile_metadata.insert_into_jpeg(&mut tile);
+    assert_eq!(&tile[..6], &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04], "the JFIF segment should stay first");
+    assert_eq!(&tile[8..10], &[0xFF, 0xE1]);
+
+    let mut metadata = ImageMetadata::new(Some("http://example.com/image.xml".into()));
+    metadata.merge_tile(&tile);
+    assert_eq!(metadata.orientation, Some(6));
+    assert_eq!(metadata.make.as_deref(), Some("Scanner Inc."));
+    assert_eq!(metadata.model, None);
+
+    let mut output = vec![0xFF, 0xD8, 0xFF, 0xDA];
+    metadata.insert_into_jpeg(&mut output);
+    let tiff = jpeg_exif(&output).unwrap();
+    assert_eq!(read_ascii(tiff, IMAGE_DESCRIPTION).as_deref(), Some("http://example.com/image.xml"));
+    assert_eq!(read_ascii(tiff, SOFTWARE), Some(ImageMetadata::software()));
+    assert_eq!(read_ascii(tiff, DATE_TIME).map(|d| d.len()), Some(19));
+    assert_eq!(read_short(tiff, ORIENTATION), Some(6));
+}
@@ -0,0 +1,77 @@
+//! The list of the tiles that could not be downloaded, written with `--failed-tiles-log`,
+//! so that they can be requested again, or attached to a bug report.
+
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::dezoomer::{TileReference, TileRequest};
+use crate::ZoomError;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct FailureReport {
+    /// The URL of the zoomable image
+    pub input_uri: Option<String>,
+    /// The image the tiles are missing from
+    pub output: PathBuf,
+    pub failed_tiles: Vec<FailedTile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct FailedTile {
+    pub url: String,
+    /// Position of the tile in the image, in pixels
+    pub x: u32,
+    pub y: u32,
+    /// The status of the response, if the server answered
+    pub status: Option<u16>,
+    pub error: String,
+    /// How the tile was requested, when it was not with a simple GET request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request: Option<TileRequest>,
+}
+
+impl FailedTile {
+    pub fn new(tile_reference: &TileReference, error: &ZoomError) -> Self {
+        FailedTile {
+            url: tile_reference.url.clone(),
+            x: tile_reference.position.x,
+            y: tile_reference.position.y,
+            status: error.http_status(),
+            error: error.to_string(),
+            request: tile_reference.request.as_deref().cloned(),
+        }
+    }
+}
+
+impl FailureReport {
+    pub fn save(&self, path: &Path) -> Result<(), ZoomError> {
+        let json = serde_json::to_vec_pretty(self).expect("reports can be serialized");
+        std::fs::write(path, json)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, ZoomError> {
+        serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| ZoomError::FailureReport { path: path.display().to_string(), reason: e.to_string() })
+    }
+}
+
+#[test]
+fn test_failure_report() {
+    use crate::Vec2d;
+    let tile = TileReference { url: "http://example.com/0_1.jpg".into(), position: Vec2d { x: 0, y: 256 }, size: None, request: None };
+    let error = ZoomError::RequestFailed { url: tile.url.clone(), reason: "timeout".into() };
+    let report = FailureReport {
+        input_uri: Some("http://example.com/info.json".into()),
+        output: "image.png".into(),
+        failed_tiles: vec![FailedTile::new(&tile, &error)],
+    };
+    let dir = tempdir::TempDir::new("dezoomify-rs-failure-report").unwrap();
+    let path = dir.path().join("failed.json");
+    report.save(&path).unwrap();
+    let json: serde_json::Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+    assert_eq!(json["failed_tiles"][0]["y"], 256);
+    assert_eq!(json["failed_tiles"][0]["status"], serde_json::Value::Null);
+    assert_eq!(FailureReport::load(&path).unwrap(), report);
+}
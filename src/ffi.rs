@@ -0,0 +1,313 @@
+//! A minimal C interface to dezoomify-rs, available with the `ffi` feature.
+//!
+//! `cargo rustc --release --lib --crate-type cdylib --features ffi` builds it as a shared library
+//! (`libdezoomify_rs.so`, `libdezoomify_rs.dylib` or `dezoomify_rs.dll`),
+//! declared in `include/dezoomify.h`:
+//!
+//! ```c
+//! typedef struct DezoomifyJob DezoomifyJob;
+//! DezoomifyJob *dezoomify_start(const char *options_json);
+//! int dezoomify_poll(const DezoomifyJob *job);
+//! void dezoomify_progress(const DezoomifyJob *job, uint64_t *tiles_done, uint64_t *tiles_total);
+//! void dezoomify_cancel(DezoomifyJob *job);
+//! char *dezoomify_result(const DezoomifyJob *job);
+//! void dezoomify_string_free(char *s);
+//! void dezoomify_job_free(DezoomifyJob *job);
+//! ```
+//!
+//! The options are a JSON object whose keys are the names of the command-line options,
+//! for instance `{"input-uri": "http://example.com/ImageProperties.xml", "largest": true}`.
+//!
+//! Jobs run one at a time: a job started while another one is running waits for it to end.
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+
+use serde_json::Value;
+use tokio_util::sync::CancellationToken;
+
+use crate::{Arguments, CommandLine, ZoomError};
+use crate::download::dezoomify_exclusive;
+use crate::progress_sink::{ProgressSink, SharedProgressSink};
+
+pub const DEZOOMIFY_RUNNING: c_int = 0;
+pub const DEZOOMIFY_SUCCESS: c_int = 1;
+pub const DEZOOMIFY_FAILED: c_int = 2;
+pub const DEZOOMIFY_CANCELLED: c_int = 3;
+
+enum JobState {
+    Running,
+    Success(String),
+    Failed(String),
+    /// The message of the error the cancelled download ended with
+    Cancelled(String),
+}
+
+/// The number of tiles of a job, updated while it runs
+#[derive(Default)]
+struct JobProgress {
+    /// The tiles that were downloaded, or that failed
+    done: AtomicU64,
+    total: AtomicU64,
+}
+
+impl ProgressSink for JobProgress {
+    fn set_total_tiles(&self, total: u64) { self.total.store(total, Ordering::Relaxed) }
+    fn tile_completed(&self, _bytes: Option<u64>) { self.done.fetch_add(1, Ordering::Relaxed); }
+}
+
+/// A dezoomify job running in a background thread
+pub struct DezoomifyJob {
+    state: Arc<Mutex<JobState>>,
+    progress: Arc<JobProgress>,
+    cancel: CancellationToken,
+}
+
+impl DezoomifyJob {
+    fn failed(msg: String) -> Self {
+        DezoomifyJob {
+            state: Arc::new(Mutex::new(JobState::Failed(msg))),
+            progress: Arc::default(),
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    fn start(mut args: Arguments) -> Self {
+        let state = Arc::new(Mutex::new(JobState::Running));
+        let progress = Arc::new(JobProgress::default());
+        let cancel = CancellationToken::new();
+        // The download stops by itself when cancelled, so that the partial image is saved or removed
+        args.cancel = Some(cancel.clone());
+        args.progress = Some(SharedProgressSink(progress.clone()));
+        let job_state = Arc::clone(&state);
+        thread::spawn(move || {
+            let result = match tokio::runtime::Runtime::new() {
+                Ok(runtime) => match runtime.block_on(dezoomify_exclusive(&args)) {
+                    Ok(path) => JobState::Success(path.to_string_lossy().into_owned()),
+                    Err(e @ ZoomError::Interrupted) | Err(e @ ZoomError::PartialDownload { .. })
+                    if args.cancel.as_ref().is_some_and(CancellationToken::is_cancelled) => {
+                        JobState::Cancelled(e.to_string())
+                    }
+                    Err(e) => JobState::Failed(e.to_string()),
+                },
+                Err(e) => JobState::Failed(e.to_string()),
+            };
+            *job_state.lock().unwrap() = result;
+        });
+        DezoomifyJob { state, progress, cancel }
+    }
+}
+
+/// Convert a JSON object of options to a list of command-line arguments
+fn options_to_args(options: &Value) -> Result<Vec<String>, String> {
+    let options = options.as_object().ok_or("The options should be a JSON object")?;
+    let mut args = vec!["dezoomify-rs".to_string(), "--non-interactive".to_string()];
+    let mut positional = vec![];
+    for (key, value) in options {
+        let values = match value {
+            Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                Value::Bool(false) | Value::Null => continue,
+                Value::Bool(true) => None,
+                Value::String(s) => Some(s.clone()),
+                Value::Number(n) => Some(n.to_string()),
+                _ => return Err(format!("Invalid value for option '{}'", key)),
+            };
+            match (key.as_str(), value) {
+                ("input-uri", Some(v)) | ("input_uri", Some(v)) => positional.insert(0, v),
+                ("outfile", Some(v)) => positional.push(v),
+                (key, value) => {
+                    args.push(format!("--{}", key.replace('_', "-")));
+                    args.extend(value);
+                }
+            }
+        }
+    }
+    args.push("--".to_string());
+    args.extend(positional);
+    Ok(args)
+}
+
+unsafe fn parse_options(options_json: *const c_char) -> Result<Arguments, String> {
+    if options_json.is_null() {
+        return Err("No options were given".into());
+    }
+    let options = CStr::from_ptr(options_json).to_str().map_err(|e| e.to_string())?;
+    let options: Value = serde_json::from_str(options).map_err(|e| e.to_string())?;
+    let args = options_to_args(&options)?;
+    CommandLine::parse(args).map_err(|e| e.message)
+}
+
+/// Start downloading an image in the background.
+/// The returned job must be freed with `dezoomify_job_free`.
+/// If the options are invalid, the job fails immediately.
+///
+/// # Safety
+/// `options_json` must be NULL or a valid nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn dezoomify_start(options_json: *const c_char) -> *mut DezoomifyJob {
+    let job = match parse_options(options_json) {
+        Ok(args) => DezoomifyJob::start(args),
+        Err(msg) => DezoomifyJob::failed(msg),
+    };
+    Box::into_raw(Box::new(job))
+}
+
+/// Returns the current state of a job:
+/// 0 while it is running, 1 on success, 2 on failure, and 3 if it was cancelled.
+///
+/// # Safety
+/// `job` must be NULL or a job returned by `dezoomify_start` that was not freed.
+#[no_mangle]
+pub unsafe extern "C" fn dezoomify_poll(job: *const DezoomifyJob) -> c_int {
+    let job = match job.as_ref() { Some(job) => job, None => return DEZOOMIFY_FAILED };
+    match *job.state.lock().unwrap() {
+        JobState::Running => DEZOOMIFY_RUNNING,
+        JobState::Success(_) => DEZOOMIFY_SUCCESS,
+        JobState::Failed(_) => DEZOOMIFY_FAILED,
+        JobState::Cancelled(_) => DEZOOMIFY_CANCELLED,
+    }
+}
+
+/// Writes the number of tiles of a job that were downloaded or failed, and the total number of its tiles,
+/// which stays 0 until the tiles of the image are known, and can grow for some formats.
+/// NULL pointers are left alone.
+///
+/// # Safety
+/// `job` must be NULL or a job returned by `dezoomify_start` that was not freed.
+/// `tiles_done` and `tiles_total` must be NULL or valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn dezoomify_progress(job: *const DezoomifyJob, tiles_done: *mut u64, tiles_total: *mut u64) {
+    let job = match job.as_ref() { Some(job) => job, None => return };
+    if let Some(done) = tiles_done.as_mut() { *done = job.progress.done.load(Ordering::Relaxed) }
+    if let Some(total) = tiles_total.as_mut() { *total = job.progress.total.load(Ordering::Relaxed) }
+}
+
+/// Ask a running job to stop. It stops requesting tiles, saves the image with the tiles it received,
+/// and ends in the cancelled state.
+///
+/// # Safety
+/// `job` must be NULL or a job returned by `dezoomify_start` that was not freed.
+#[no_mangle]
+pub unsafe extern "C" fn dezoomify_cancel(job: *mut DezoomifyJob) {
+    if let Some(job) = job.as_ref() {
+        job.cancel.cancel();
+    }
+}
+
+/// Returns the path of the saved image for a successful job, the error message for a failed
+/// or cancelled job, and NULL while it is running.
+/// The returned string must be freed with `dezoomify_string_free`.
+///
+/// # Safety
+/// `job` must be NULL or a job returned by `dezoomify_start` that was not freed.
+#[no_mangle]
+pub unsafe extern "C" fn dezoomify_result(job: *const DezoomifyJob) -> *mut c_char {
+    let job = match job.as_ref() { Some(job) => job, None => return ptr::null_mut() };
+    match &*job.state.lock().unwrap() {
+        JobState::Success(s) | JobState::Failed(s) | JobState::Cancelled(s) => CString::new(s.replace('\0', ""))
+            .map(CString::into_raw)
+            .unwrap_or(ptr::null_mut()),
+        JobState::Running => ptr::null_mut(),
+    }
+}
+
+/// Free a string returned by `dezoomify_result`
+///
+/// # Safety
+/// `s` must be NULL or a string returned by `dezoomify_result` that was not freed.
+#[no_mangle]
+pub unsafe extern "C" fn dezoomify_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Free a job returned by `dezoomify_start`. A job that is still running is cancelled,
+/// and finishes saving its image in the background.
+///
+/// # Safety
+/// `job` must be NULL or a job returned by `dezoomify_start` that was not freed.
+#[no_mangle]
+pub unsafe extern "C" fn dezoomify_job_free(job: *mut DezoomifyJob) {
+    if !job.is_null() {
+        dezoomify_cancel(job);
+        drop(Box::from_raw(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_options_to_args() {
+        let options = serde_json::json!({
+            "input-uri": "http://example.com/",
+            "largest": true,
+            "retries": 3,
+            "header": ["A: B", "C: D"],
+            "strict_format": false,
+        });
+        let args = options_to_args(&options).unwrap();
+        let args = CommandLine::parse(args).unwrap();
+        assert_eq!(args.input_uri.as_deref(), Some("http://example.com/"));
+        assert!(args.largest);
+        assert!(args.non_interactive);
+        assert!(!args.strict_format);
+        assert_eq!(args.retries, 3);
+        assert_eq!(args.headers.len(), 2);
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        let options = CString::new("{\"no-such-option\": 1}").unwrap();
+        unsafe {
+            let job = dezoomify_start(options.as_ptr());
+            assert_eq!(dezoomify_poll(job), DEZOOMIFY_FAILED);
+            let msg = dezoomify_result(job);
+            assert!(!msg.is_null());
+            dezoomify_string_free(msg);
+            dezoomify_job_free(job);
+        }
+    }
+
+    #[test]
+    fn test_progress() {
+        let progress = JobProgress::default();
+        progress.set_total_tiles(4);
+        progress.tile_completed(Some(100));
+        progress.tile_completed(None);
+        let job = DezoomifyJob { progress: Arc::new(progress), ..DezoomifyJob::failed(String::new()) };
+        let (mut done, mut total) = (0, 0);
+        unsafe { dezoomify_progress(&job, &mut done, &mut total) };
+        assert_eq!((done, total), (2, 4));
+    }
+
+    #[test]
+    fn test_cancel_waiting_job() {
+        // Keep the job waiting for the other downloads, as if one was running
+        let _running = loop {
+            if let Ok(guard) = crate::download::RUN_LOCK.try_lock() { break guard; }
+            thread::sleep(std::time::Duration::from_millis(10));
+        };
+        let options = CString::new("{\"input-uri\": \"http://example.com/info.json\"}").unwrap();
+        unsafe {
+            let job = dezoomify_start(options.as_ptr());
+            assert_eq!(dezoomify_poll(job), DEZOOMIFY_RUNNING);
+            dezoomify_cancel(job);
+            while dezoomify_poll(job) == DEZOOMIFY_RUNNING {
+                thread::sleep(std::time::Duration::from_millis(10));
+            }
+            assert_eq!(dezoomify_poll(job), DEZOOMIFY_CANCELLED);
+            dezoomify_job_free(job);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+/// A search for the last existing tile along a row or a column.
+/// The index is doubled until a missing tile is found, then the last existing one
+/// is found by bisection, so that finding n tiles takes about 2*log2(n) requests.
+#[derive(Default, Debug, Clone, Copy)]
+pub struct AxisSearch {
+    /// Index of a tile that exists
+    pub found: u32,
+    /// Index of a tile that does not exist
+    missing: Option<u32>,
+}
+
+impl AxisSearch {
+    /// A search whose result is already known, that probes nothing
+    pub fn known(count: u32) -> Self {
+        AxisSearch { found: count.saturating_sub(1), missing: Some(count) }
+    }
+
+    /// The next index to request, or None once the last existing tile is known
+    pub fn probe(&self) -> Option<u32> {
+        let probe = match self.missing {
+            None => self.found.saturating_mul(2).saturating_add(1),
+            Some(missing) => self.found + (missing - self.found) / 2,
+        };
+        if probe > self.found { Some(probe) } else { None }
+    }
+
+    pub fn update(&mut self, probe: u32, exists: bool) {
+        if exists { self.found = probe } else { self.missing = Some(probe) }
+    }
+}
+
+#[test]
+fn test_axis_search() {
+    for last in 0..1000 {
+        let mut search = AxisSearch::default();
+        let mut tries = 0;
+        while let Some(probe) = search.probe() {
+            search.update(probe, probe <= last);
+            tries += 1;
+            assert!(tries <= 20, "probed {} on the {}th try", probe, tries);
+        }
+        assert_eq!(search.found, last, "found {} instead of {} in {} tries", search.found, last, tries);
+    }
+}
@@ -1,74 +1,284 @@
 use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
 
+use custom_error::custom_error;
+use evalexpr::{Context, HashMapContext, IntType};
 use lazy_static::lazy_static;
+use log::{debug, warn};
 use regex::Regex;
 
-use crate::dezoomer::{Dezoomer, DezoomerError, DezoomerInput, single_level, TileFetchResult, TileProvider, TileReference, ZoomLevels};
+use crate::dezoomer::{Dezoomer, DezoomerError, DezoomerInput, IntoZoomLevels, PageContents, single_level, TileFetchResult, TileProvider, TileReference, ZoomLevels};
 use crate::Vec2d;
 
-mod dichotomy_2d;
+use self::axis_search::AxisSearch;
+
+mod axis_search;
+
+/// The highest zoom level that is probed for when the template contains `{{Z}}` or `{{quadkey}}`
+const MAX_LEVEL: u32 = 24;
+
+lazy_static! {
+    static ref TILE_GRID: RwLock<TileGrid> = RwLock::new(TileGrid::default());
+}
+
+/// Set the number of columns and rows of tiles, that templates can refer to as `{{COLUMNS}}` and `{{ROWS}}`
+pub fn set_tile_grid(columns: Option<u32>, rows: Option<u32>) {
+    *TILE_GRID.write().unwrap() = TileGrid { columns, rows };
+}
+
+/// The numbers of columns and rows of tiles given by the user, that are not searched for
+#[derive(Default, Debug, Clone, Copy)]
+struct TileGrid {
+    columns: Option<u32>,
+    rows: Option<u32>,
+}
+
+impl TileGrid {
+    fn size(&self) -> Vec2d {
+        Vec2d { x: self.columns.unwrap_or(1), y: self.rows.unwrap_or(1) }
+    }
+}
 
 /// A dezoomer that takes an image tile URL template like
 /// `http://example.com/image_{{X}}_{{Y}}.jpg`
 /// and automatically figures out the dimensions of the image.
+/// The placeholders can contain arithmetic expressions, such as `{{X*256+1}}`,
+/// and can refer to the zoom level `{{Z}}` or to a Bing-style `{{quadkey}}`.
+/// Tiles numbered column by column can use the number of rows `{{X*ROWS+Y}}`
+/// (or `{{Y*COLUMNS+X}}`), given with `--tile-rows` (or `--tile-columns`).
 #[derive(Default)]
-pub struct GenericDezoomer;
+pub struct GenericDezoomer {
+    template: Option<Arc<UrlTemplate>>,
+    next_level: u32,
+    levels: Vec<u32>,
+}
+
+impl GenericDezoomer {
+    /// The zoom levels at which tiles were found, in the order of the levels that were returned
+    pub fn found_levels(&self) -> &[u32] {
+        &self.levels
+    }
+}
 
 impl Dezoomer for GenericDezoomer {
     fn name(&self) -> &'static str {
         "generic"
     }
 
+    fn description(&self) -> &str {
+        "Any tiled image, from a template of the URLs of its tiles"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["*{{X}}*{{Y}}*", "*{{Z}}*{{X}}*{{Y}}*", "*{{quadkey}}*"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec![
+            "http://example.com/my_image/image-{{X}}-{{Y}}.jpg",
+            "http://example.com/my_image/image-{{X:02}}-{{Y:02}}.jpg",
+        ]
+    }
+
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
-        self.assert(TEMPLATE_RE.is_match(&data.uri))?;
-        let dezoomer = ZoomLevel {
-            url_template: data.uri.clone(),
-            dichotomy: Default::default(),
-            last_tile: (0, 0),
-            done: HashSet::new(),
-            tile_size: None,
-            image_size: None,
+        let template = match &self.template {
+            Some(template) => Arc::clone(template),
+            None => {
+                self.assert(TEMPLATE_RE.is_match(&data.uri))?;
+                let template = Arc::new(UrlTemplate::parse(&data.uri).map_err(DezoomerError::wrap)?);
+                let grid = *TILE_GRID.read().unwrap();
+                for (re, value, option) in &[(&*COLUMNS_RE, grid.columns, "--tile-columns"),
+                                             (&*ROWS_RE, grid.rows, "--tile-rows")] {
+                    if value.is_none() && template.uses_variable(re) {
+                        return Err(DezoomerError::wrap(GenericError::MissingGrid {
+                            template: template.source.clone(),
+                            option: option.to_string(),
+                        }));
+                    }
+                }
+                self.template = Some(Arc::clone(&template));
+                template
+            }
         };
-        single_level(dezoomer)
+        let grid = *TILE_GRID.read().unwrap();
+        if !template.uses_level() {
+            return single_level(ZoomLevel::new(template, 0, grid));
+        }
+        // Find the zoom levels at which the first tile exists
+        let probed_url = template.url(0, 0, self.next_level, grid.size());
+        if data.uri != probed_url {
+            return Err(DezoomerError::NeedsData { uri: probed_url });
+        }
+        let exists = match &data.contents {
+            PageContents::Unknown => return Err(DezoomerError::NeedsData { uri: probed_url }),
+            PageContents::Success(contents) => image::guess_format(contents).is_ok(),
+            PageContents::Error(_) => false,
+        };
+        debug!("Tile {} exists: {}", probed_url, exists);
+        if exists { self.levels.push(self.next_level) }
+        // Some servers start numbering their levels at 1: keep going until the first success
+        if (exists || self.levels.is_empty()) && self.next_level < MAX_LEVEL {
+            self.next_level += 1;
+            let uri = template.url(0, 0, self.next_level, grid.size());
+            return Err(DezoomerError::NeedsData { uri });
+        }
+        if self.levels.is_empty() {
+            return Err(DezoomerError::wrap(GenericError::NoLevel { template: template.source.clone() }));
+        }
+        Ok(self.levels.iter()
+            .map(|&z| ZoomLevel::new(Arc::clone(&template), z, grid))
+            .into_zoom_levels())
     }
 }
 
+custom_error! {pub GenericError
+    BadExpression{expr: String, source: evalexpr::EvalexprError} =
+        "'{expr}' is not a valid tile URL template expression: {source}",
+    NoLevel{template: String} = "Unable to find any zoom level for the template {template}",
+    MissingGrid{template: String, option: String} =
+        "The template {template} refers to the size of the grid of tiles, which must be given with {option}",
+}
+
 lazy_static! {
     static ref TEMPLATE_RE: Regex = Regex::new(r"(?xi)
     \{\{
-        (?P<dimension>x|y)
+        (?P<expr>[^}:]+)
         (?::0(?P<zeroes>\d+))?
      \}\}
     ").unwrap();
+    static ref LEVEL_RE: Regex = Regex::new(r"(?i)\bz\b").unwrap();
+    static ref COLUMNS_RE: Regex = Regex::new(r"(?i)\bcolumns\b").unwrap();
+    static ref ROWS_RE: Regex = Regex::new(r"(?i)\brows\b").unwrap();
+}
+
+#[derive(Debug)]
+enum TemplatePart {
+    Constant(String),
+    Quadkey,
+    Expression { expr: String, padding: usize },
+}
+
+/// A tile URL template with placeholders that are evaluated for each tile
+#[derive(Debug)]
+struct UrlTemplate {
+    source: String,
+    parts: Vec<TemplatePart>,
+}
+
+impl UrlTemplate {
+    fn parse(source: &str) -> Result<UrlTemplate, GenericError> {
+        let mut parts = vec![];
+        let mut cursor = 0;
+        for caps in TEMPLATE_RE.captures_iter(source) {
+            let m = caps.get(0).expect("a match");
+            parts.push(TemplatePart::Constant(source[cursor..m.start()].to_string()));
+            let expr = caps["expr"].trim();
+            if expr.eq_ignore_ascii_case("quadkey") {
+                parts.push(TemplatePart::Quadkey);
+            } else {
+                // Check that the expression is valid and refers only to known variables
+                evalexpr::eval_int_with_context(expr, &variables(1, 1, 1, Vec2d::square(1))).map_err(|source| {
+                    GenericError::BadExpression { expr: expr.to_string(), source }
+                })?;
+                let padding = caps.name("zeroes")
+                    .and_then(|m| m.as_str().parse().ok())
+                    .unwrap_or(0);
+                parts.push(TemplatePart::Expression { expr: expr.to_string(), padding });
+            }
+            cursor = m.end();
+        }
+        parts.push(TemplatePart::Constant(source[cursor..].to_string()));
+        Ok(UrlTemplate { source: source.to_string(), parts })
+    }
+
+    /// Whether the URLs depend on the zoom level
+    fn uses_level(&self) -> bool {
+        self.parts.iter().any(|part| matches!(part, TemplatePart::Quadkey)) || self.uses_variable(&LEVEL_RE)
+    }
+
+    /// Whether an expression of the template refers to a variable matched by the given regex
+    fn uses_variable(&self, re: &Regex) -> bool {
+        self.parts.iter().any(|part| match part {
+            TemplatePart::Expression { expr, .. } => re.is_match(expr),
+            TemplatePart::Quadkey | TemplatePart::Constant(_) => false,
+        })
+    }
+
+    fn url(&self, x: u32, y: u32, z: u32, grid: Vec2d) -> String {
+        let ctx = variables(x, y, z, grid);
+        self.parts.iter().map(|part| match part {
+            TemplatePart::Constant(s) => s.clone(),
+            TemplatePart::Quadkey => quadkey(x, y, z),
+            TemplatePart::Expression { expr, padding } => {
+                let num = evalexpr::eval_int_with_context(expr, &ctx).unwrap_or_else(|e| {
+                    warn!("Unable to evaluate '{}' in the tile URL template: {}", expr, e);
+                    0
+                });
+                format!("{num:0padding$}", num = num, padding = padding)
+            }
+        }).collect()
+    }
+}
+
+/// The variables that can be used in tile URL template expressions
+fn variables(x: u32, y: u32, z: u32, grid: Vec2d) -> HashMapContext {
+    let mut ctx = HashMapContext::new();
+    for (names, value) in &[(["x", "X"], x), (["y", "Y"], y), (["z", "Z"], z),
+                            (["columns", "COLUMNS"], grid.x), (["rows", "ROWS"], grid.y)] {
+        for &name in names {
+            ctx.set_value(name.into(), IntType::from(*value).into())
+                .expect("integer variables can always be set");
+        }
+    }
+    ctx
+}
+
+/// Bing-style quadtree key of a tile.
+/// See https://docs.microsoft.com/en-us/bingmaps/articles/bing-maps-tile-system
+fn quadkey(x: u32, y: u32, z: u32) -> String {
+    (1..=z).rev().map(|i| {
+        let mask = 1 << (i - 1);
+        let digit = (x & mask != 0) as u8 + 2 * (y & mask != 0) as u8;
+        char::from(b'0' + digit)
+    }).collect()
 }
 
 struct ZoomLevel {
-    url_template: String,
-    dichotomy: dichotomy_2d::Dichotomy2d,
-    last_tile: (u32, u32),
+    template: Arc<UrlTemplate>,
+    z: u32,
+    columns: AxisSearch,
+    rows: AxisSearch,
+    /// The tiles of the last batch, on the first row and on the first column
+    probes: Vec<Probe>,
     tile_size: Option<Vec2d>,
     image_size: Option<Vec2d>,
     done: HashSet<(u32, u32)>,
 }
 
+enum Probe {
+    Column(u32, String),
+    Row(u32, String),
+}
+
 impl ZoomLevel {
+    fn new(template: Arc<UrlTemplate>, z: u32, grid: TileGrid) -> Self {
+        ZoomLevel {
+            template,
+            z,
+            columns: grid.columns.map(AxisSearch::known).unwrap_or_default(),
+            rows: grid.rows.map(AxisSearch::known).unwrap_or_default(),
+            probes: vec![],
+            done: HashSet::new(),
+            tile_size: None,
+            image_size: None,
+        }
+    }
+    /// The size of the grid of tiles, as far as it is known
+    fn grid(&self) -> Vec2d {
+        Vec2d { x: self.columns.found + 1, y: self.rows.found + 1 }
+    }
     fn tile_url_at(&self, x: u32, y: u32) -> String {
-        TEMPLATE_RE.replace_all(&self.url_template, |caps: &regex::Captures| {
-            let dimension = caps.name("dimension")
-                .expect("missing dimension")
-                .as_str()
-                .chars().next().expect("empty dim")
-                .to_ascii_lowercase();
-            let num = match dimension {
-                'x' => x,
-                'y' => y,
-                _ => unreachable!("The dimension is either x or y")
-            };
-            let padding: usize = caps.name("zeroes")
-                .and_then(|m| m.as_str().parse().ok())
-                .unwrap_or(0);
-            format!("{num:0padding$}", num = num, padding = padding)
-        }).to_string()
+        self.template.url(x, y, self.z, self.grid())
     }
     fn tile_ref_at(&self, x: u32, y: u32) -> TileReference {
         let tile_size = self.tile_size.unwrap_or(Vec2d { x: 0, y: 0 });
@@ -76,43 +286,75 @@ impl ZoomLevel {
         TileReference {
             url: self.tile_url_at(x, y),
             position,
+            size: None,
+            request: None,
+        }
+    }
+
+    /// Records which of the probed tiles exist
+    fn update_probes(&mut self, successful_urls: &[String]) {
+        for probe in std::mem::take(&mut self.probes) {
+            let (search, index, url, pos) = match &probe {
+                Probe::Column(x, url) => (&mut self.columns, *x, url, (*x, 0)),
+                Probe::Row(y, url) => (&mut self.rows, *y, url, (0, *y)),
+            };
+            let exists = successful_urls.contains(url);
+            debug!("Tile {} exists: {}", url, exists);
+            search.update(index, exists);
+            if exists { self.done.insert(pos); }
         }
     }
+
+    /// The next tiles to probe on the first row and on the first column, requested together
+    fn next_probes(&mut self) -> Vec<TileReference> {
+        let column = self.columns.probe().map(|x| Probe::Column(x, self.tile_url_at(x, 0)));
+        let row = self.rows.probe().map(|y| Probe::Row(y, self.tile_url_at(0, y)));
+        self.probes = column.into_iter().chain(row).collect();
+        self.probes.iter().map(|probe| match probe {
+            Probe::Column(x, _) => self.tile_ref_at(*x, 0),
+            Probe::Row(y, _) => self.tile_ref_at(0, *y),
+        }).collect()
+    }
+
+    fn all_tiles(&mut self) -> Vec<TileReference> {
+        let grid = self.grid();
+        self.image_size = self.tile_size.map(|s| s * grid);
+        let tiles = (0..grid.y)
+            .flat_map(|y| (0..grid.x).map(move |x| (x, y)))
+            .filter(|pos| !self.done.contains(pos))
+            .map(|(x, y)| self.tile_ref_at(x, y))
+            .collect();
+        self.done.clear();
+        tiles
+    }
 }
 
 impl TileProvider for ZoomLevel {
+    /// The first tile is requested alone, to find the size of the tiles.
+    /// Then the last column and the last row are searched for at the same time,
+    /// and finally all the remaining tiles are requested.
     fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
-        if let Some(p) = previous {
-            self.tile_size = self.tile_size.or(p.tile_size);
-            if let Some((x, y)) = self.dichotomy.next(p.is_success()) {
-                self.last_tile = (x, y);
-                self.done.insert((x, y));
-                vec![self.tile_ref_at(x, y)]
-            } else if !self.done.is_empty() {
-                let mut res = vec![];
-                let last_tile_pos = Vec2d {
-                    x: self.last_tile.0,
-                    y: self.last_tile.1,
-                };
-                self.image_size = self.tile_size.map(|s| s * last_tile_pos + s);
-                for y in 0..=last_tile_pos.y {
-                    for x in 0..=last_tile_pos.x {
-                        if !self.done.contains(&(x, y)) {
-                            res.push(self.tile_ref_at(x, y));
-                        }
-                    }
-                }
-                self.done.clear();
-                res
-            } else {
-                vec![]
-            }
+        let previous = match previous {
+            None => return vec![self.tile_ref_at(0, 0)],
+            Some(previous) => previous,
+        };
+        if self.image_size.is_some() { return vec![]; }
+        if self.tile_size.is_none() {
+            if !previous.is_success() { return vec![]; }
+            self.tile_size = previous.tile_size;
+            self.done.insert((0, 0));
         } else {
-            vec![self.tile_ref_at(self.last_tile.0, self.last_tile.1)]
+            self.update_probes(&previous.successful_urls);
         }
+        let probes = self.next_probes();
+        if probes.is_empty() { self.all_tiles() } else { probes }
     }
     fn name(&self) -> String {
-        format!("Generic image with template {}", self.url_template)
+        if self.template.uses_level() {
+            format!("Generic image with template {} at level {}", self.template.source, self.z)
+        } else {
+            format!("Generic image with template {}", self.template.source)
+        }
     }
     fn size_hint(&self) -> Option<Vec2d> {
         self.image_size
@@ -131,7 +373,7 @@ fn test_generic_dezoomer() {
     use std::collections::HashSet;
     use crate::dezoomer::PageContents;
     let uri = "{{X}},{{Y}}".to_string();
-    let mut lvl = GenericDezoomer {}
+    let mut lvl = GenericDezoomer::default()
         .zoom_levels(&DezoomerInput {
             uri,
             contents: PageContents::Unknown,
@@ -158,6 +400,7 @@ fn test_generic_dezoomer() {
             count,
             successes: successes.len() as u64,
             tile_size: Some(Vec2d { x: 4, y: 5 }),
+            successful_urls: successes.iter().map(|t| t.url.clone()).collect(),
         });
         all_tiles.extend(successes);
         tries += 1;
@@ -168,42 +411,117 @@ fn test_generic_dezoomer() {
         TileReference {
             url: "0,0".into(),
             position: Vec2d { x: 0, y: 0 },
+            size: None,
+            request: None,
         },
         TileReference {
             url: "1,0".into(),
             position: Vec2d { x: 4, y: 0 },
+            size: None,
+            request: None,
         },
         TileReference {
             url: "2,0".into(),
             position: Vec2d { x: 8, y: 0 },
+            size: None,
+            request: None,
         },
         TileReference {
             url: "0,1".into(),
             position: Vec2d { x: 0, y: 5 },
+            size: None,
+            request: None,
         },
         TileReference {
             url: "1,1".into(),
             position: Vec2d { x: 4, y: 5 },
+            size: None,
+            request: None,
         },
         TileReference {
             url: "2,1".into(),
             position: Vec2d { x: 8, y: 5 },
+            size: None,
+            request: None,
         },
     ].into_iter().collect();
     assert_eq!(all_tiles, expected);
 }
 
 #[test]
-fn test_url_templating() {
-    let url_template = "http://x.com/{{x:05}}_{{y}}".to_string();
-    let lvl: ZoomLevel = ZoomLevel {
-        url_template,
-        dichotomy: Default::default(),
-        last_tile: (0, 0),
-        tile_size: None,
-        image_size: None,
-        done: Default::default(),
+fn test_generic_probes_concurrently() {
+    let mut lvl = ZoomLevel::new(Arc::new(UrlTemplate::parse("{{X}},{{Y}}").unwrap()), 0, TileGrid::default());
+    let (columns, rows) = (700, 3);
+    let mut previous = None;
+    let mut probes = 0;
+    let mut tiles = loop {
+        let tiles = lvl.next_tiles(previous.take());
+        if lvl.size_hint().is_some() { break tiles; }
+        assert!(tiles.len() <= 2, "at most one tile per axis is probed at once");
+        probes += tiles.len();
+        let successful_urls: Vec<String> = tiles.iter().filter(|t| {
+            let (x, y) = t.url.split_once(',').unwrap();
+            x.parse::<u32>().unwrap() < columns && y.parse::<u32>().unwrap() < rows
+        }).map(|t| t.url.clone()).collect();
+        previous = Some(TileFetchResult {
+            count: tiles.len() as u64,
+            successes: successful_urls.len() as u64,
+            tile_size: Some(Vec2d::square(10)),
+            successful_urls,
+        });
     };
+    assert!(probes <= 25, "{} tiles were probed", probes);
+    assert_eq!(lvl.size_hint(), Some(Vec2d { x: 7000, y: 30 }));
+    tiles.sort_by_key(|t| (t.position.y, t.position.x));
+    assert_eq!(tiles.last().unwrap().url, "699,2");
+    // The tiles found while probing are not requested again
+    assert!(!tiles.iter().any(|t| t.url == "0,0" || t.url == "1,0" || t.url == "0,1"));
+}
+
+#[test]
+fn test_url_templating() {
+    let url_template = "http://x.com/{{x:05}}_{{y}}";
+    let lvl = ZoomLevel::new(Arc::new(UrlTemplate::parse(url_template).unwrap()), 0, TileGrid::default());
     assert_eq!(lvl.tile_url_at(10, 11), "http://x.com/00010_11");
     assert_eq!(lvl.tile_url_at(123, 1), "http://x.com/00123_1");
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_url_template_expressions() {
+    let template = UrlTemplate::parse("http://x.com/{{Z}}/{{X*256-256:04}}_{{y+1}}_{{x*10+y}}").unwrap();
+    assert!(template.uses_level());
+    assert_eq!(template.url(2, 3, 4, Vec2d::square(1)), "http://x.com/4/0256_4_23");
+    let template = UrlTemplate::parse("http://x.com/t{{quadkey}}.jpg").unwrap();
+    assert!(template.uses_level());
+    assert_eq!(template.url(3, 5, 3, Vec2d::square(1)), "http://x.com/t213.jpg");
+    assert_eq!(template.url(0, 0, 0, Vec2d::square(1)), "http://x.com/t.jpg");
+    assert!(!UrlTemplate::parse("http://x.com/{{x}}_{{y}}").unwrap().uses_level());
+    assert!(UrlTemplate::parse("http://x.com/{{x+}}").is_err());
+    assert!(UrlTemplate::parse("http://x.com/{{w}}").is_err());
+}
+
+#[test]
+fn test_column_major_template() {
+    let template = Arc::new(UrlTemplate::parse("{{X*ROWS+Y}}").unwrap());
+    assert!(!template.uses_variable(&COLUMNS_RE));
+    let mut lvl = ZoomLevel::new(template, 0, TileGrid { columns: None, rows: Some(2) });
+    // Every number below 6 exists: the grid is 3 columns of 2 tiles
+    let mut previous = None;
+    let tiles = loop {
+        let tiles = lvl.next_tiles(previous.take());
+        if lvl.size_hint().is_some() { break tiles; }
+        assert!(tiles.len() <= 1, "the number of rows is not searched for");
+        let successful_urls: Vec<String> = tiles.iter()
+            .filter(|t| t.url.parse::<u32>().unwrap() < 6)
+            .map(|t| t.url.clone()).collect();
+        previous = Some(TileFetchResult {
+            count: tiles.len() as u64,
+            successes: successful_urls.len() as u64,
+            tile_size: Some(Vec2d::square(10)),
+            successful_urls,
+        });
+    };
+    assert_eq!(lvl.size_hint(), Some(Vec2d { x: 30, y: 20 }));
+    assert_eq!(lvl.tile_url_at(2, 1), "5");
+    assert!(tiles.iter().any(|t| t.url == "3" && t.position == Vec2d { x: 10, y: 10 }));
+}
@@ -1,7 +1,9 @@
 use std::error::Error;
 use std::sync::Arc;
 
-use tile_info::{PageInfo, TileInfo};
+use regex::Regex;
+
+use tile_info::{PageInfo, PageParseError, TileInfo};
 
 use crate::dezoomer::*;
 
@@ -21,38 +23,47 @@ impl Dezoomer for GAPDezoomer {
         "google_arts_and_culture"
     }
 
+    fn description(&self) -> &str {
+        "Images from Google Arts & Culture, from their page"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["https://artsandculture.google.com/asset/*"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec!["https://artsandculture.google.com/asset/light-in-the-dark/ZQFouDGMVmsI2w"]
+    }
+
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
         self.assert(data.uri.contains("artsandculture.google.com") || self.page_info.is_some())?;
         let contents = data.with_contents()?.contents;
         match &self.page_info {
             None => {
                 let page_source = std::str::from_utf8(contents).map_err(DezoomerError::wrap)?;
-                let info: PageInfo = page_source.parse().map_err(DezoomerError::wrap)?;
+                let info: PageInfo = page_source.parse().map_err(|e| {
+                    // Collection and exhibit pages have no image of their own, but link to artworks
+                    match (e, asset_urls(page_source).len()) {
+                        (PageParseError::NoToken, count) if count > 0 =>
+                            DezoomerError::wrap(PageParseError::CollectionPage { count }),
+                        (e, _) => DezoomerError::wrap(e),
+                    }
+                })?;
                 let uri = info.tile_info_url();
                 self.page_info = Some(Arc::new(info));
                 Err(DezoomerError::NeedsData { uri })
             }
             Some(page_info) => {
-                let TileInfo {
-                    tile_width,
-                    tile_height,
-                    pyramid_level,
-                    ..
-                } = serde_xml_rs::from_reader(contents).map_err(DezoomerError::wrap)?;
-                let levels: ZoomLevels = pyramid_level
-                    .into_iter()
+                let info: TileInfo = serde_xml_rs::from_reader(contents).map_err(DezoomerError::wrap)?;
+                let levels: ZoomLevels = info.pyramid_level
+                    .iter()
                     .enumerate()
                     .map(|(z, level)| {
-                        let width = tile_width * level.num_tiles_x - level.empty_pels_x;
-                        let height = tile_height * level.num_tiles_y - level.empty_pels_y;
                         GAPZoomLevel {
-                            size: Vec2d {
-                                x: width,
-                                y: height,
-                            },
+                            size: info.level_size(level),
                             tile_size: Vec2d {
-                                x: tile_width,
-                                y: tile_height,
+                                x: info.tile_width,
+                                y: info.tile_height,
                             },
                             z,
                             page_info: Arc::clone(page_info),
@@ -95,6 +106,19 @@ impl TilesRect for GAPZoomLevel {
     }
 }
 
+/// The addresses of the artworks linked from a page, in the order in which they first appear
+pub fn asset_urls(page: &str) -> Vec<String> {
+    // Links also appear in the JSON data of the page, where slashes are escaped
+    let page = page.replace("\\/", "/");
+    let re = Regex::new(r#"(?:https://artsandculture\.google\.com)?/asset/([^"'?#\s\\<>]+)"#).unwrap();
+    let mut urls: Vec<String> = vec![];
+    for captures in re.captures_iter(&page) {
+        let url = format!("https://artsandculture.google.com/asset/{}", captures[1].trim_end_matches('/'));
+        if !urls.contains(&url) { urls.push(url) }
+    }
+    urls
+}
+
 fn post_process_tile(_tile: &TileReference, data: Vec<u8>) -> Result<Vec<u8>, Box<dyn Error + Send + 'static>> {
     decryption::decrypt(data)
         .map_err(|e| Box::new(e) as Box<(dyn Error + Send + 'static)>)
@@ -6,10 +6,19 @@ use serde::Deserialize;
 
 use custom_error::custom_error;
 
+use crate::Vec2d;
+
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct TileInfo {
     pub tile_width: u32,
     pub tile_height: u32,
+    /// The size of the full image, given by the newer versions of the tiler
+    #[serde(default)]
+    pub image_width: u32,
+    #[serde(default)]
+    pub image_height: u32,
+    #[serde(default)]
+    pub tiler_version_number: u32,
     pub pyramid_level: Vec<PyramidLevel>,
 }
 
@@ -17,8 +26,28 @@ pub struct TileInfo {
 pub struct PyramidLevel {
     pub num_tiles_x: u32,
     pub num_tiles_y: u32,
-    pub empty_pels_x: u32,
-    pub empty_pels_y: u32,
+    /// The number of unused pixels in the last column and row of tiles.
+    /// The newer versions of the tiler can omit them, and give the scale of the level instead.
+    pub empty_pels_x: Option<u32>,
+    pub empty_pels_y: Option<u32>,
+    #[serde(default)]
+    pub inverse_scale: u32,
+}
+
+impl TileInfo {
+    /// The size of the image at the given level
+    pub fn level_size(&self, level: &PyramidLevel) -> Vec2d {
+        let full_width = self.tile_width * level.num_tiles_x;
+        let full_height = self.tile_height * level.num_tiles_y;
+        match (level.empty_pels_x, level.empty_pels_y) {
+            (Some(x), Some(y)) => Vec2d { x: full_width - x, y: full_height - y },
+            _ if self.image_width > 0 && level.inverse_scale > 0 => Vec2d {
+                x: (self.image_width / level.inverse_scale).min(full_width),
+                y: (self.image_height / level.inverse_scale).min(full_height),
+            },
+            _ => Vec2d { x: full_width, y: full_height },
+        }
+    }
 }
 
 pub struct PageInfo {
@@ -41,9 +70,20 @@ impl FromStr for PageInfo {
 
     /// Parses a google arts project HTML page
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let re = Regex::new(r#"]\r?\n,"(//[^"/]+/[^"/]+)",(?:"([^"]+)"|null)"#).unwrap();
-        let mat = re.captures(s).ok_or(PageParseError::NoToken)?;
-        let base_url = format!("https:{}", &mat[1]);
+        // Newer pages give absolute image addresses, and escape the slashes in them
+        let s = &s.replace("\\/", "/");
+        let re = Regex::new(r#"]\r?\n?,"((?:https:)?//[^"/]+/[^"/]+)",(?:"([^"]+)"|null)"#).unwrap();
+        let mat = match re.captures(s) {
+            Some(mat) => mat,
+            None if Regex::new(r"youtube\.com/(?:embed|watch)").unwrap().is_match(s) => {
+                return Err(PageParseError::Video)
+            }
+            None => return Err(PageParseError::NoToken),
+        };
+        let base_url = match mat[1].strip_prefix("//") {
+            Some(url) => format!("https://{}", url),
+            None => mat[1].to_string(),
+        };
         let token = mat
             .get(2)
             .map_or_else(Default::default, |s| s.as_str().into());
@@ -67,6 +107,9 @@ custom_error! {pub PageParseError
     BadPath                     = "The path has an invalid form",
     NoToken                     = "Unable to find the token in the page",
     InvalidToken{token: String} = "Invalid token: '{token}'",
+    Video                       = "This asset is a video, not a zoomable image",
+    CollectionPage{count: usize} = "This page does not display a single artwork, but links to {count} of them. \
+        Save it as an HTML file, and download all of them with --bulk",
 }
 
 #[cfg(test)]
@@ -87,6 +130,21 @@ mod tests {
          "#).unwrap();
         assert_eq!(infos.tile_width, 512);
         assert_eq!(infos.pyramid_level[4].num_tiles_x, 11);
+        assert_eq!(infos.level_size(&infos.pyramid_level[0]), Vec2d { x: 339, y: 255 });
+        assert_eq!(infos.level_size(&infos.pyramid_level[4]), Vec2d { x: 5436, y: 4080 });
+    }
+
+    #[test]
+    fn test_xml_parse_without_empty_pels() {
+        let infos: TileInfo = serde_xml_rs::from_str(r#"
+            <TileInfo tile_width="512" tile_height="512" tiler_version_number="3" image_width="5436" image_height="4080">
+                <pyramid_level num_tiles_x="3" num_tiles_y="2" inverse_scale="4"/>
+                <pyramid_level num_tiles_x="11" num_tiles_y="8" inverse_scale="1"/>
+             </TileInfo>
+         "#).unwrap();
+        assert_eq!(infos.tiler_version_number, 3);
+        assert_eq!(infos.level_size(&infos.pyramid_level[0]), Vec2d { x: 1359, y: 1020 });
+        assert_eq!(infos.level_size(&infos.pyramid_level[1]), Vec2d { x: 5436, y: 4080 });
     }
 
     fn parse_html_file(test_file_name: &str) -> PageInfo {
@@ -128,6 +186,17 @@ mod tests {
         assert_eq!(info.token, "mcOPEQJmk1514hP_dJkpwVwIhPU");
     }
 
+    #[test]
+    fn test_parse_html_absolute_url() {
+        let html = r#"["Wildflower",[]
+,"https:\/\/lh3.googleusercontent.com\/D0sqZ0sJbzoQeYFoySoX","mcOPEQJmk1514hP_dJkpwVwIhPU",[1]]"#;
+        let info: PageInfo = html.parse().unwrap();
+        assert_eq!(info.base_url, "https://lh3.googleusercontent.com/D0sqZ0sJbzoQeYFoySoX");
+        assert_eq!(info.token, "mcOPEQJmk1514hP_dJkpwVwIhPU");
+        let video = r#"<iframe src="https://www.youtube.com/embed/dQw4w9WgXcQ"></iframe>"#;
+        assert!(matches!(video.parse::<PageInfo>(), Err(PageParseError::Video)));
+    }
+
     #[test]
     fn test_parse_html_newformat() {
         // See: https://github.com/lovasoa/dezoomify-rs/issues/11
@@ -0,0 +1,230 @@
+use custom_error::custom_error;
+use lazy_static::lazy_static;
+use log::{debug, info};
+use regex::bytes::Regex;
+
+use crate::auto::AutoDezoomer;
+use crate::dezoomer::*;
+use crate::url_utils::resolve_relative;
+use crate::openseadragon;
+
+/// A dezoomer for the web pages of image viewers.
+/// It looks for the URLs of zoomable image descriptors (IIIF info.json files,
+/// DZI files, Zoomify ImageProperties.xml files, krpano XML files, OpenSeadragon tile sources...)
+/// in the HTML and scripts of the page, and tries them one after the other
+/// with all the other dezoomers.
+#[derive(Default)]
+pub struct HtmlProbe {
+    /// Descriptor URLs that remain to be tried, the next one last
+    candidates: Vec<String>,
+    /// The dezoomer that is currently trying a candidate
+    current: Option<AutoDezoomer>,
+    /// The URL requested by the current dezoomer
+    pending: Option<String>,
+    /// The title of the page, used for the images that do not have their own
+    title: Option<String>,
+}
+
+impl Dezoomer for HtmlProbe {
+    fn name(&self) -> &'static str {
+        "html_probe"
+    }
+
+    fn description(&self) -> &str {
+        "Web pages that link to zoomable images that other dezoomers support"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["web pages"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec!["https://example.com/collection/object/123"]
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        if self.current.is_none() {
+            let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
+            self.assert(looks_like_html(contents))?;
+            let mut candidates = descriptor_urls(contents, uri);
+            self.assert(!candidates.is_empty())?;
+            info!("Found {} possible zoomable images in {}: {:?}", candidates.len(), uri, candidates);
+            candidates.reverse();
+            self.candidates = candidates;
+            self.title = page_title(contents);
+            return self.next_candidate();
+        }
+        match &self.pending {
+            Some(pending) if pending != &data.uri => Err(DezoomerError::NeedsData { uri: pending.clone() }),
+            _ => self.feed(data),
+        }
+    }
+}
+
+impl HtmlProbe {
+    fn next_candidate(&mut self) -> Result<ZoomLevels, DezoomerError> {
+        let uri = self.candidates.pop()
+            .ok_or_else(|| DezoomerError::wrap(HtmlProbeError::NoImage))?;
+        debug!("Trying {} found in the HTML page", uri);
+        self.current = Some(
+            AutoDezoomer::default().without_dezoomers(&[self.name().to_string()])
+        );
+        self.feed(&DezoomerInput { uri, contents: PageContents::Unknown })
+    }
+
+    fn feed(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let current = self.current.as_mut().expect("a candidate is being tried");
+        match current.zoom_levels(data) {
+            Ok(levels) => Ok(with_default_title(levels, self.title.clone())),
+            Err(DezoomerError::NeedsData { uri }) => {
+                self.pending = Some(uri.clone());
+                Err(DezoomerError::NeedsData { uri })
+            }
+            Err(e) if self.candidates.is_empty() => Err(e),
+            Err(e) => {
+                debug!("{} is not a zoomable image: {}", data.uri, e);
+                self.pending = None;
+                self.next_candidate()
+            }
+        }
+    }
+}
+
+custom_error! {pub HtmlProbeError
+    NoImage = "None of the image URLs found in the page could be opened",
+}
+
+fn looks_like_html(contents: &[u8]) -> bool {
+    lazy_static! {
+        static ref HTML_RE: Regex = Regex::new(r"(?i)<(!doctype html|html|head|body|script)[\s>]").unwrap();
+    }
+    let start = &contents[..contents.len().min(4096)];
+    HTML_RE.is_match(start)
+}
+
+/// The title of an HTML page, taken from its OpenGraph metadata or from its `<title>` element
+pub fn page_title(contents: &[u8]) -> Option<String> {
+    lazy_static! {
+        static ref OG_TITLE_RE: Regex = Regex::new(
+            r#"(?is)<meta\s[^>]*?property\s*=\s*["']og:title["'][^>]*?\scontent\s*=\s*(?:"([^"]*)"|'([^']*)')"#
+        ).unwrap();
+        static ref OG_TITLE_REVERSED_RE: Regex = Regex::new(
+            r#"(?is)<meta\s[^>]*?content\s*=\s*(?:"([^"]*)"|'([^']*)')[^>]*?\sproperty\s*=\s*["']og:title["']"#
+        ).unwrap();
+        static ref TITLE_RE: Regex = Regex::new(r"(?is)<title[^>]*>([^<]*)</title>").unwrap();
+    }
+    [&*OG_TITLE_RE, &*OG_TITLE_REVERSED_RE, &*TITLE_RE].iter()
+        .filter_map(|re| re.captures(contents))
+        .filter_map(|c| c.get(1).or_else(|| c.get(2)))
+        .map(|m| decode_entities(&String::from_utf8_lossy(m.as_bytes())))
+        .map(|title| title.split_whitespace().collect::<Vec<_>>().join(" "))
+        .find(|title| !title.is_empty())
+}
+
+/// Decodes the most common HTML character references
+fn decode_entities(s: &str) -> String {
+    lazy_static! {
+        static ref ENTITY_RE: regex::Regex = regex::Regex::new(r"&(#[0-9]+|#x[0-9a-fA-F]+|[a-z]+);").unwrap();
+    }
+    ENTITY_RE.replace_all(s, |caps: &regex::Captures| {
+        let entity = &caps[1];
+        let decoded = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            "nbsp" => Some(' '),
+            _ => entity.strip_prefix("#x").map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|dec| dec.parse()))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        decoded.map(String::from).unwrap_or_else(|| caps[0].to_string())
+    }).into_owned()
+}
+
+/// Finds the URLs of zoomable image descriptors in an HTML page,
+/// resolved relatively to the URL of the page
+fn descriptor_urls(contents: &[u8], page_uri: &str) -> Vec<String> {
+    lazy_static! {
+        // krpano: embedpano({ xml: "tour.xml", ... })
+        static ref KRPANO_RE: Regex = Regex::new(
+            r#"embedpano\s*\(\s*\{[^}]*?\bxml\s*:\s*["']([^"']+)["']"#
+        ).unwrap();
+        static ref QUOTED_RE: Regex = Regex::new(r#"["']([^"'\s<>]{4,})["']"#).unwrap();
+        static ref DESCRIPTOR_RE: Regex = Regex::new(
+            r"(?i)(/info\.json|\.dzi|/ImageProperties\.xml|\.pff)(\?[^#]*)?$"
+        ).unwrap();
+    }
+    // Viewer configurations give the most reliable results
+    let from_config = openseadragon::descriptor_urls(&String::from_utf8_lossy(contents))
+        .into_iter()
+        .map(String::into_bytes)
+        .chain(KRPANO_RE.captures_iter(contents).map(|c| c[1].to_vec()));
+    let from_strings = QUOTED_RE.captures_iter(contents)
+        .map(|c| c[1].to_vec())
+        .filter(|s| DESCRIPTOR_RE.is_match(&unescape(s)));
+    let mut urls: Vec<String> = vec![];
+    for url in from_config.chain(from_strings) {
+        let url = String::from_utf8_lossy(&unescape(&url)).into_owned();
+        let url = resolve_relative(page_uri, &url.replace("&amp;", "&"));
+        if !urls.contains(&url) { urls.push(url); }
+    }
+    urls
+}
+
+/// Removes the backslashes used to escape slashes in JSON strings
+fn unescape(s: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut iter = s.iter().peekable();
+    while let Some(&b) = iter.next() {
+        if b == b'\\' && iter.peek() == Some(&&b'/') { continue; }
+        out.push(b);
+    }
+    out
+}
+
+#[test]
+fn test_descriptor_urls() {
+    let html = br#"<!DOCTYPE html>
+    <html><head><script>
+        var viewer = OpenSeadragon({ id: "viewer", tileSources: ["/images/painting.dzi"] });
+        var data = {"iiif":"https:\/\/example.com\/iiif\/img1\/info.json","thumb":"thumb.jpg"};
+        embedpano({ swf: "tour.swf", xml: "panos/tour.xml", target: "pano" });
+    </script></head>
+    <body><a href='zoomify/ImageProperties.xml?v=2'>zoom</a><img src="/images/painting.dzi"></body></html>"#;
+    assert!(looks_like_html(html));
+    assert!(!looks_like_html(br#"{"@context": "http://iiif.io/api/image/2/context.json"}"#));
+    assert_eq!(descriptor_urls(html, "http://example.com/viewer/page.html"), vec![
+        "http://example.com/images/painting.dzi",
+        "http://example.com/viewer/panos/tour.xml",
+        "https://example.com/iiif/img1/info.json",
+        "http://example.com/viewer/zoomify/ImageProperties.xml?v=2",
+    ]);
+}
+
+#[test]
+fn test_tries_found_urls() {
+    let mut probe = HtmlProbe::default();
+    let page = DezoomerInput {
+        uri: "http://example.com/page.html".into(),
+        contents: PageContents::Success(br#"<html><a href="http://example.com/img/info.json">"#.to_vec()),
+    };
+    let err = probe.zoom_levels(&page).expect_err("the descriptor should be requested");
+    assert!(matches!(err, DezoomerError::NeedsData { uri } if uri == "http://example.com/img/info.json"));
+    // Data for another URL is ignored until the requested one is received
+    let err = probe.zoom_levels(&page).expect_err("the descriptor should be requested again");
+    assert!(matches!(err, DezoomerError::NeedsData { uri } if uri == "http://example.com/img/info.json"));
+}
+
+#[test]
+fn test_page_title() {
+    assert_eq!(page_title(b"<html><head><title>\n  The Night Watch &amp; other\n  works </title>").as_deref(),
+               Some("The Night Watch & other works"));
+    let og = br#"<title>Museum - Collection</title><meta property="og:title" content="The Night Watch &#8211; Rembrandt">"#;
+    assert_eq!(page_title(og).as_deref(), Some("The Night Watch \u{2013} Rembrandt"));
+    let reversed = br#"<meta content='Mona Lisa' property='og:title' />"#;
+    assert_eq!(page_title(reversed).as_deref(), Some("Mona Lisa"));
+    assert_eq!(page_title(b"<title> </title>"), None);
+}
@@ -0,0 +1,56 @@
+//! All the http requests of dezoomify-rs are made through an [`HttpClient`].
+//! The command-line interface uses reqwest, and programs that use dezoomify-rs as a library
+//! can provide their own client with [`crate::DezoomRequest::http_client`],
+//! for instance to cache, record, mock or sign requests.
+
+use std::fmt;
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+// The types of the http crate, that reqwest also uses, so that clients do not depend on reqwest
+pub use http::{HeaderMap, Method};
+
+use crate::ZoomError;
+
+/// The headers and the body of a successful response
+pub type HttpResponse = (HeaderMap, Vec<u8>);
+
+/// Makes GET requests.
+/// The requests are throttled before reaching the client, and carry all the headers they need:
+/// the default ones, the ones given by the user and by the dezoomer, and access tokens.
+/// Cookies and redirections are left to the client.
+pub trait HttpClient: Send + Sync {
+    /// Requests the given URL with the given headers, and returns the headers and the body of the response.
+    /// Responses with an error status must be returned as errors,
+    /// such as [`ZoomError::HttpStatus`] for clients other than reqwest.
+    /// The responses are reported to the throttler and to the audit log whatever the client.
+    fn get<'a>(&'a self, url: &'a str, headers: &'a HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>>;
+
+    /// Sends a request with another method, such as POST, and a body.
+    /// It is only used for the tiles that require it, so clients that only make GET requests
+    /// do not have to implement it.
+    fn send<'a>(
+        &'a self,
+        method: &'a Method,
+        url: &'a str,
+        _headers: &'a HeaderMap,
+        _body: &'a [u8],
+    ) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(async move {
+            Err(ZoomError::RequestFailed {
+                url: url.to_string(),
+                reason: format!("this http client cannot make {} requests", method),
+            })
+        })
+    }
+}
+
+/// An http client that can be stored in the arguments of a download
+#[derive(Clone)]
+pub struct SharedHttpClient(pub Arc<dyn HttpClient>);
+
+impl fmt::Debug for SharedHttpClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedHttpClient")
+    }
+}
@@ -0,0 +1,192 @@
+//! Recording of http responses with `--record-http`, and replay with `--replay-http`.
+//! A recording directory contains, for each requested URL, a JSON file with the headers
+//! of the response or the error of the request, and a file with its body, named after the hash of the URL.
+//! Replaying it does not access the network, so that a failure can be reproduced offline,
+//! and the tests of a dezoomer can use real responses.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use futures::future::BoxFuture;
+use log::{debug, warn};
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use http::Method;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::fs;
+
+use crate::http_client::{HttpClient, HttpResponse};
+use crate::ZoomError;
+
+const METADATA_EXTENSION: &str = "json";
+const BODY_EXTENSION: &str = "body";
+
+/// What is stored about a response, next to its body
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct RecordedResponse {
+    url: String,
+    headers: Vec<(String, String)>,
+    /// The error with which the request failed. The body of failed requests is not stored.
+    error: Option<String>,
+}
+
+fn file_stem(dir: &Path, url: &str) -> PathBuf {
+    dir.join(Sha1::digest(url.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+/// What identifies a request that is not a GET request, such as a POST request to the same URL as others
+fn request_key(method: &Method, url: &str, body: &[u8]) -> String {
+    format!("{}#{} {}", url, method, String::from_utf8_lossy(body))
+}
+
+/// Passes the requests to another client, and saves its responses
+pub struct Recorder {
+    client: Arc<dyn HttpClient>,
+    dir: PathBuf,
+}
+
+impl Recorder {
+    pub fn new(client: Arc<dyn HttpClient>, dir: &Path) -> Self {
+        Recorder { client, dir: dir.to_path_buf() }
+    }
+
+    fn save(&self, key: &str, url: &str, result: &Result<HttpResponse, ZoomError>) -> Result<(), ZoomError> {
+        let stem = file_stem(&self.dir, key);
+        let (headers, error) = match result {
+            Ok((headers, body)) => {
+                std::fs::write(stem.with_extension(BODY_EXTENSION), body)?;
+                let headers = headers.iter()
+                    .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+                    .collect();
+                (headers, None)
+            }
+            Err(e) => (vec![], Some(e.to_string())),
+        };
+        let recorded = RecordedResponse { url: url.to_string(), headers, error };
+        let json = serde_json::to_vec_pretty(&recorded).expect("responses can be serialized");
+        std::fs::write(stem.with_extension(METADATA_EXTENSION), json)?;
+        Ok(())
+    }
+}
+
+impl HttpClient for Recorder {
+    fn get<'a>(&'a self, url: &'a str, headers: &'a HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(async move {
+            let result = self.client.get(url, headers).await;
+            // Saved synchronously, because errors are not Send and cannot be kept across an await point
+            if let Err(e) = self.save(url, url, &result) {
+                warn!("Unable to record the response to {} in {:?}: {}", url, self.dir, e);
+            }
+            result
+        })
+    }
+
+    fn send<'a>(
+        &'a self,
+        method: &'a Method,
+        url: &'a str,
+        headers: &'a HeaderMap,
+        body: &'a [u8],
+    ) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(async move {
+            let result = self.client.send(method, url, headers, body).await;
+            if let Err(e) = self.save(&request_key(method, url, body), url, &result) {
+                warn!("Unable to record the response to {} in {:?}: {}", url, self.dir, e);
+            }
+            result
+        })
+    }
+}
+
+/// Serves the responses saved by a [`Recorder`]
+pub struct Replayer {
+    dir: PathBuf,
+}
+
+impl Replayer {
+    pub fn new(dir: &Path) -> Self {
+        Replayer { dir: dir.to_path_buf() }
+    }
+
+    async fn load(&self, key: &str, url: &str) -> Result<HttpResponse, ZoomError> {
+        let failed = |reason: String| ZoomError::RequestFailed { url: url.to_string(), reason };
+        let stem = file_stem(&self.dir, key);
+        let json = fs::read(stem.with_extension(METADATA_EXTENSION)).await
+            .map_err(|_| failed(format!("no response was recorded for it in {:?}", self.dir)))?;
+        let recorded: RecordedResponse = serde_json::from_slice(&json)
+            .map_err(|e| failed(format!("invalid recorded response: {}", e)))?;
+        if let Some(error) = recorded.error {
+            return Err(failed(error));
+        }
+        let mut headers = HeaderMap::new();
+        for (name, value) in &recorded.headers {
+            headers.append(name.parse::<HeaderName>()?, value.parse::<HeaderValue>()?);
+        }
+        let body = fs::read(stem.with_extension(BODY_EXTENSION)).await?;
+        Ok((headers, body))
+    }
+}
+
+impl HttpClient for Replayer {
+    fn get<'a>(&'a self, url: &'a str, _headers: &'a HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(async move {
+            debug!("Replaying the recorded response to {}", url);
+            self.load(url, url).await
+        })
+    }
+
+    fn send<'a>(
+        &'a self,
+        method: &'a Method,
+        url: &'a str,
+        _headers: &'a HeaderMap,
+        body: &'a [u8],
+    ) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(async move {
+            debug!("Replaying the recorded response to the {} request to {}", method, url);
+            self.load(&request_key(method, url, body), url).await
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_record_and_replay() {
+    struct Server;
+    impl HttpClient for Server {
+        fn get<'a>(&'a self, url: &'a str, _headers: &'a HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+            Box::pin(async move {
+                match url {
+                    "http://example.com/info.json" => {
+                        let mut headers = HeaderMap::new();
+                        headers.insert("content-type", "application/json".parse().unwrap());
+                        Ok((headers, b"{}".to_vec()))
+                    }
+                    _ => Err(ZoomError::RequestFailed { url: url.to_string(), reason: "404 Not Found".into() }),
+                }
+            })
+        }
+
+        fn send<'a>(&'a self, _method: &'a Method, _url: &'a str, _headers: &'a HeaderMap, body: &'a [u8]) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+            Box::pin(async move { Ok((HeaderMap::new(), body.to_vec())) })
+        }
+    }
+    let dir = tempdir::TempDir::new("dezoomify-rs-record-http").unwrap();
+    let recorder = Recorder::new(Arc::new(Server), dir.path());
+    let headers = HeaderMap::new();
+    recorder.get("http://example.com/info.json", &headers).await.unwrap();
+    assert!(recorder.get("http://example.com/missing.jpg", &headers).await.is_err());
+    for body in &[&b"tile 1"[..], b"tile 2"] {
+        recorder.send(&Method::POST, "http://example.com/tiles", &headers, body).await.unwrap();
+    }
+
+    let replayer = Replayer::new(dir.path());
+    let (headers, body) = replayer.get("http://example.com/info.json", &headers).await.unwrap();
+    assert_eq!(body, b"{}");
+    assert_eq!(headers.get("content-type").unwrap(), "application/json");
+    let error = replayer.get("http://example.com/missing.jpg", &headers).await.unwrap_err();
+    assert!(error.to_string().contains("404 Not Found"), "{}", error);
+    let (_, body) = replayer.send(&Method::POST, "http://example.com/tiles", &headers, b"tile 2").await.unwrap();
+    assert_eq!(body, b"tile 2");
+    let error = replayer.get("http://example.com/other.jpg", &headers).await.unwrap_err();
+    assert!(error.to_string().contains("no response was recorded"), "{}", error);
+}
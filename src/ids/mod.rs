@@ -0,0 +1,207 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use custom_error::custom_error;
+use log::debug;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::dezoomer::*;
+use crate::json_utils::number_or_string;
+use crate::url_utils::remove_bom;
+
+/// A dezoomer for the Smithsonian's Illuminated Image Delivery Service (IDS), at ids.si.edu.
+/// The viewer and the download links of the Smithsonian's collections give the id of the image,
+/// and its deep zoom descriptor is at `https://ids.si.edu/ids/deepzoom/{id}.xml`.
+/// The descriptor resembles a DZI file, but it is a single element with lower case attributes:
+/// `<IDSImage width="6000" height="4000" tileSize="512" overlap="1" format="jpg" minLevel="8"/>`.
+/// The tiles are named like the tiles of a DZI file, and the server only generates the levels
+/// down to `minLevel`.
+#[derive(Default)]
+pub struct IdsDezoomer;
+
+const IDS_DEEPZOOM_PREFIX: &str = "https://ids.si.edu/ids/deepzoom/";
+
+/// The id of the image, in the URL of its descriptor or of any IDS service, such as `deliveryService?id=`
+fn parse_image_id(url: &str) -> Option<String> {
+    Regex::new(r"ids\.si\.edu/ids/(?:deepzoom/([^/?#]+)\.xml|\w+/?\?(?:[^#]*&)?id=([^&#]+))").unwrap()
+        .captures(url)
+        .and_then(|cap| cap.get(1).or_else(|| cap.get(2)))
+        .map(|m| m.as_str().to_string())
+}
+
+fn descriptor_url(image_id: &str) -> String {
+    format!("{}{}.xml", IDS_DEEPZOOM_PREFIX, image_id)
+}
+
+impl Dezoomer for IdsDezoomer {
+    fn name(&self) -> &'static str { "ids" }
+
+    fn description(&self) -> &str {
+        "Images from the Smithsonian Institution's image delivery service (ids.si.edu)"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["*ids.si.edu/ids/*"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec!["https://ids.si.edu/ids/deliveryService?id=SAAM-1929.6.125_1"]
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        self.assert(data.uri.contains("ids.si.edu/ids/"))?;
+        let image_id = parse_image_id(&data.uri)
+            .ok_or_else(|| DezoomerError::wrap(IdsError::NoIdInUrl { url: data.uri.clone() }))?;
+        let uri = descriptor_url(&image_id);
+        if data.uri != uri {
+            return Err(DezoomerError::NeedsData { uri });
+        }
+        let DezoomerInputWithContents { contents, .. } = data.with_contents()?;
+        let levels = load_from_descriptor(&image_id, contents).map_err(DezoomerError::wrap)?;
+        Ok(levels)
+    }
+}
+
+custom_error! {pub IdsError
+    XmlError{source: serde_xml_rs::Error} = "Unable to parse the IDS descriptor: {source}",
+    InvalidTileSize = "Invalid tile size. The tile size cannot be zero.",
+    NoIdInUrl{url: String} = "Unable to find the id of the image in {url}",
+}
+
+/// The deep zoom descriptor of an image
+#[derive(Debug, Deserialize, PartialEq)]
+struct Descriptor {
+    #[serde(deserialize_with = "number_or_string")]
+    width: u32,
+    #[serde(deserialize_with = "number_or_string")]
+    height: u32,
+    #[serde(rename = "tileSize", alias = "tilesize", deserialize_with = "number_or_string")]
+    tile_size: u32,
+    #[serde(deserialize_with = "number_or_string", default)]
+    overlap: u32,
+    /// The extension of the tile files
+    #[serde(default = "default_format")]
+    format: String,
+    /// The number of the smallest level generated by the server
+    #[serde(rename = "minLevel", alias = "minlevel", deserialize_with = "number_or_string", default)]
+    min_level: u32,
+    #[serde(default)]
+    title: Option<String>,
+}
+
+fn default_format() -> String { "jpg".into() }
+
+fn load_from_descriptor(image_id: &str, contents: &[u8]) -> Result<ZoomLevels, IdsError> {
+    let descriptor: Descriptor = serde_xml_rs::from_reader(remove_bom(contents))?;
+    debug!("Found the IDS descriptor: {:?}", descriptor);
+    if descriptor.tile_size == 0 {
+        return Err(IdsError::InvalidTileSize);
+    }
+    let size = Vec2d { x: descriptor.width, y: descriptor.height };
+    // Levels are numbered like in DZI files: the full image is at the level log2(max(width, height))
+    let max_level = 32 - (size.x.max(size.y).max(1) - 1).leading_zeros();
+    let image = Arc::new(Image {
+        base_url: format!("{}{}_files", IDS_DEEPZOOM_PREFIX, image_id),
+        title: descriptor.title.clone().unwrap_or_else(|| image_id.to_string()),
+        format: descriptor.format.trim().trim_start_matches('.').to_string(),
+        tile_size: Vec2d::square(descriptor.tile_size),
+        overlap: descriptor.overlap,
+    });
+    let levels = (descriptor.min_level.min(max_level)..=max_level).rev()
+        .map(|level| Level {
+            image: Arc::clone(&image),
+            size: size.ceil_div(Vec2d::square(1 << (max_level - level))),
+            level,
+        })
+        .into_zoom_levels();
+    Ok(levels)
+}
+
+/// The properties shared by all the levels of an image
+struct Image {
+    base_url: String,
+    title: String,
+    format: String,
+    tile_size: Vec2d,
+    overlap: u32,
+}
+
+struct Level {
+    image: Arc<Image>,
+    size: Vec2d,
+    level: u32,
+}
+
+impl TilesRect for Level {
+    fn size(&self) -> Vec2d { self.size }
+
+    fn tile_size(&self) -> Vec2d { self.image.tile_size }
+
+    fn tile_url(&self, pos: Vec2d) -> String {
+        format!("{}/{}/{}_{}.{}", self.image.base_url, self.level, pos.x, pos.y, self.image.format)
+    }
+
+    fn tile_ref(&self, pos: Vec2d) -> TileReference {
+        let overlap = self.image.overlap;
+        let delta = Vec2d {
+            x: if pos.x == 0 { 0 } else { overlap },
+            y: if pos.y == 0 { 0 } else { overlap },
+        };
+        TileReference {
+            url: self.tile_url(pos),
+            position: self.tile_size() * pos - delta,
+            size: None,
+            request: None,
+        }
+    }
+
+    fn tile_crop(&self) -> TileCrop { TileCrop::uniform(self.image.overlap) }
+
+    fn title(&self) -> Option<String> { Some(self.image.title.clone()) }
+}
+
+impl Debug for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Smithsonian IDS")
+    }
+}
+
+#[test]
+fn test_parse_image_id() {
+    let id = Some("SAAM-1929.6.125_1".to_string());
+    assert_eq!(parse_image_id("https://ids.si.edu/ids/deliveryService?id=SAAM-1929.6.125_1"), id);
+    assert_eq!(parse_image_id("https://ids.si.edu/ids/dynamic?container=fullpage&id=SAAM-1929.6.125_1&max=800"), id);
+    assert_eq!(parse_image_id("https://ids.si.edu/ids/deepzoom/SAAM-1929.6.125_1.xml"), id);
+    assert_eq!(parse_image_id("https://ids.si.edu/ids/"), None);
+}
+
+#[test]
+fn test_ids_levels() {
+    let contents = br#"<?xml version="1.0" encoding="UTF-8"?>
+        <IDSImage id="NPG-1" width="1000" height="600" tileSize="256" overlap="1" format="jpg" minLevel="8"/>"#;
+    let mut levels = load_from_descriptor("NPG-1", contents).unwrap();
+    // Levels 10 (1000x600), 9 (500x300) and 8 (250x150)
+    assert_eq!(levels.len(), 3);
+    assert_eq!(levels[1].size_hint(), Some(Vec2d { x: 500, y: 300 }));
+    assert_eq!(levels[2].size_hint(), Some(Vec2d { x: 250, y: 150 }));
+    assert_eq!(levels[0].title().as_deref(), Some("NPG-1"));
+    let tiles: Vec<(String, Vec2d)> = levels[1].next_tiles(None).into_iter().map(|t| (t.url, t.position)).collect();
+    assert_eq!(tiles, vec![
+        ("https://ids.si.edu/ids/deepzoom/NPG-1_files/9/0_0.jpg".to_string(), Vec2d { x: 0, y: 0 }),
+        ("https://ids.si.edu/ids/deepzoom/NPG-1_files/9/1_0.jpg".to_string(), Vec2d { x: 255, y: 0 }),
+        ("https://ids.si.edu/ids/deepzoom/NPG-1_files/9/0_1.jpg".to_string(), Vec2d { x: 0, y: 255 }),
+        ("https://ids.si.edu/ids/deepzoom/NPG-1_files/9/1_1.jpg".to_string(), Vec2d { x: 255, y: 255 }),
+    ]);
+    assert!(load_from_descriptor("NPG-1", br#"<IDSImage width="10" height="10" tileSize="0"/>"#).is_err());
+}
+
+#[test]
+fn test_ids_dezoomer() {
+    let mut dezoomer = IdsDezoomer;
+    let input = DezoomerInput { uri: "https://ids.si.edu/ids/deliveryService?id=NPG-1".into(), contents: PageContents::Unknown };
+    match dezoomer.zoom_levels(&input) {
+        Err(DezoomerError::NeedsData { uri }) => assert_eq!(uri, "https://ids.si.edu/ids/deepzoom/NPG-1.xml"),
+        other => panic!("Unexpected result: {:?}", other.map(|levels| levels.len())),
+    }
+}
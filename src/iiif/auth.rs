@@ -0,0 +1,181 @@
+//! Support for the IIIF Authentication API.
+//! See https://iiif.io/api/auth/
+//!
+//! When a server refuses to send an info.json file without authentication, the file it sends instead
+//! describes a login service, where the user can log in with a web browser,
+//! and a token service, that gives the access token to send with the next requests.
+
+use log::{info, warn};
+use serde::Deserialize;
+use url::Url;
+
+use crate::arguments::Arguments;
+use crate::network::set_bearer_token;
+use crate::{stdin_line, ZoomError};
+
+#[derive(Deserialize, Debug, Default)]
+struct Service {
+    #[serde(rename = "@id", alias = "id")]
+    id: Option<String>,
+    #[serde(rename = "type", alias = "@type")]
+    service_type: Option<String>,
+    profile: Option<String>,
+    label: Option<serde_json::Value>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    service: Vec<Service>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ServiceHolder {
+    #[serde(default, deserialize_with = "one_or_many")]
+    service: Vec<Service>,
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<Service>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Box<Service>),
+        Many(Vec<Service>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(service) => vec![*service],
+        OneOrMany::Many(services) => services,
+    })
+}
+
+impl Service {
+    /// The profile (IIIF auth 1) and type (IIIF auth 2) of the service
+    fn kinds(&self) -> impl Iterator<Item=&str> {
+        self.profile.as_deref().into_iter().chain(self.service_type.as_deref())
+    }
+
+    fn is_login(&self) -> bool {
+        self.kinds().any(|kind| {
+            ["/login", "/clickthrough", "/kiosk", "/external"].iter().any(|k| kind.ends_with(k)) ||
+                kind == "AuthAccessService2"
+        })
+    }
+
+    fn is_token(&self) -> bool {
+        self.kinds().any(|kind| kind.ends_with("/token") || kind == "AuthAccessTokenService2")
+    }
+
+    fn label(&self) -> Option<String> {
+        match self.label.as_ref()? {
+            serde_json::Value::String(s) => Some(s.clone()),
+            // IIIF 3 language maps: { "en": ["Log in"] }
+            serde_json::Value::Object(map) => map.values()
+                .flat_map(|v| v.as_array()).flatten()
+                .find_map(|v| v.as_str().map(String::from)),
+            _ => None,
+        }
+    }
+}
+
+/// The services described in the response of a server that requires authentication
+#[derive(Debug, PartialEq)]
+pub struct AuthServices {
+    pub label: Option<String>,
+    pub login: Option<String>,
+    pub token: Option<String>,
+}
+
+impl AuthServices {
+    pub fn parse(contents: &[u8]) -> Option<AuthServices> {
+        let holder: ServiceHolder = serde_json::from_slice(contents).ok()?;
+        // IIIF auth 2 nests the access service inside a probe service
+        let login = holder.service.iter()
+            .flat_map(|s| std::iter::once(s).chain(s.service.iter()))
+            .find(|s| s.is_login())?;
+        let token = login.service.iter().find(|s| s.is_token());
+        Some(AuthServices {
+            label: login.label(),
+            login: login.id.clone(),
+            token: token.and_then(|t| t.id.clone()),
+        })
+    }
+}
+
+/// Handle a response indicating that authentication is required to access the given URL.
+/// Returns whether an access token was set, in which case the request should be made again.
+pub fn authenticate(uri: &str, contents: &[u8], args: &Arguments) -> Result<bool, ZoomError> {
+    let services = match AuthServices::parse(contents) {
+        Some(services) => services,
+        None => return Ok(false),
+    };
+    let host = match Url::parse(uri).ok().and_then(|u| u.host_str().map(String::from)) {
+        Some(host) => host,
+        None => return Ok(false),
+    };
+    warn!("{} requires authentication{}", uri,
+          services.label.as_ref().map(|l| format!(": {}", l)).unwrap_or_default());
+    if let Some(login) = &services.login { info!("Login service: {}", login) }
+    if let Some(token) = &services.token { info!("Access token service: {}", token) }
+    let token = if let Some(token) = &args.iiif_token {
+        token.clone()
+    } else if args.is_interactive() {
+        eprintln!("This image requires authentication.");
+        if let Some(login) = &services.login {
+            eprintln!("Log in with your web browser at {}", login);
+        }
+        if let Some(token) = &services.token {
+            eprintln!("Then open {} in the same browser, and copy the value of 'accessToken'.", token);
+        }
+        eprintln!("Access token (leave empty to give up): ");
+        stdin_line()?.trim().to_string()
+    } else {
+        return Ok(false);
+    };
+    if token.is_empty() { return Ok(false); }
+    set_bearer_token(&host, &token);
+    Ok(true)
+}
+
+#[test]
+fn test_auth1_services() {
+    let contents = br#"{
+      "@context": "http://iiif.io/api/image/2/context.json",
+      "@id": "https://example.org/iiif/book1-page1",
+      "protocol": "http://iiif.io/api/image",
+      "width": 600, "height": 400,
+      "service": {
+        "@context": "http://iiif.io/api/auth/1/context.json",
+        "@id": "https://authentication.example.org/login",
+        "profile": "http://iiif.io/api/auth/1/login",
+        "label": "Login to Example Institution",
+        "service": [
+          { "@id": "https://authentication.example.org/token", "profile": "http://iiif.io/api/auth/1/token" },
+          { "@id": "https://authentication.example.org/logout", "profile": "http://iiif.io/api/auth/1/logout" }
+        ]
+      }
+    }"#;
+    assert_eq!(AuthServices::parse(contents), Some(AuthServices {
+        label: Some("Login to Example Institution".into()),
+        login: Some("https://authentication.example.org/login".into()),
+        token: Some("https://authentication.example.org/token".into()),
+    }));
+    assert_eq!(AuthServices::parse(br#"{"width": 600, "height": 400}"#), None);
+}
+
+#[test]
+fn test_auth2_services() {
+    let contents = br#"{
+      "id": "https://example.org/iiif/image1",
+      "service": [{
+        "id": "https://auth.example.org/probe/image1",
+        "type": "AuthProbeService2",
+        "service": [{
+          "id": "https://auth.example.org/login",
+          "type": "AuthAccessService2",
+          "profile": "active",
+          "label": { "en": ["Log in"] },
+          "service": [{ "id": "https://auth.example.org/token", "type": "AuthAccessTokenService2" }]
+        }]
+      }]
+    }"#;
+    let services = AuthServices::parse(contents).unwrap();
+    assert_eq!(services.login.as_deref(), Some("https://auth.example.org/login"));
+    assert_eq!(services.token.as_deref(), Some("https://auth.example.org/token"));
+    assert_eq!(services.label.as_deref(), Some("Log in"));
+}
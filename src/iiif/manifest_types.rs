@@ -0,0 +1,263 @@
+//! The parts of IIIF presentation manifests that list the images of a document:
+//! its canvases, the image service of each canvas, and the ranges of its table of contents.
+//! Both version 2 and version 3 of the presentation API are supported.
+//! See https://iiif.io/api/presentation/3.0/
+
+use std::collections::HashSet;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::tile_info::text_of;
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Manifest {
+    #[serde(default)]
+    pub label: Value,
+    /// Version 2: the canvases are listed in sequences
+    #[serde(default)]
+    sequences: Vec<Sequence>,
+    /// Version 3: the canvases are listed directly in the manifest
+    #[serde(default)]
+    items: Vec<Canvas>,
+    /// The table of contents
+    #[serde(default)]
+    structures: Vec<Range>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Sequence {
+    #[serde(default)]
+    canvases: Vec<Canvas>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Canvas {
+    #[serde(alias = "@id")]
+    pub id: String,
+    #[serde(default)]
+    pub label: Value,
+    /// Version 2: image annotations
+    #[serde(default)]
+    images: Vec<Annotation>,
+    /// Version 3: annotation pages
+    #[serde(default)]
+    items: Vec<AnnotationPage>,
+}
+
+#[derive(Deserialize, Debug)]
+struct AnnotationPage {
+    #[serde(default)]
+    items: Vec<Annotation>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Annotation {
+    /// Version 2
+    resource: Option<Resource>,
+    /// Version 3
+    body: Option<Resource>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Resource {
+    /// A single service in version 2, a list of services in version 3
+    #[serde(default)]
+    service: Value,
+    /// Version 2: the preferred image of a choice
+    default: Option<Box<Resource>>,
+    /// Version 3: the alternative images of a choice
+    #[serde(default)]
+    items: Vec<Resource>,
+}
+
+/// A section of the table of contents
+#[derive(Deserialize, Debug, Default)]
+pub struct Range {
+    #[serde(alias = "@id", default)]
+    pub id: String,
+    #[serde(default)]
+    pub label: Value,
+    /// Version 2: the ids of the canvases in the range
+    #[serde(default)]
+    canvases: Vec<String>,
+    /// Version 2: the ids of the sub-ranges
+    #[serde(default)]
+    ranges: Vec<String>,
+    /// Version 2.1 members, and version 3 items: canvases, and ranges or references to ranges
+    #[serde(default, alias = "members")]
+    items: Vec<RangeItem>,
+    /// Version 3: a part of a canvas
+    #[serde(default)]
+    source: Value,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum RangeItem {
+    Id(String),
+    Object(Range),
+}
+
+impl Manifest {
+    pub fn canvases(&self) -> impl Iterator<Item=&Canvas> {
+        self.sequences.iter().flat_map(|s| s.canvases.iter()).chain(self.items.iter())
+    }
+
+    pub fn range_labels(&self) -> impl Iterator<Item=String> + '_ {
+        self.structures.iter().filter_map(|range| text_of(&range.label))
+    }
+
+    /// The range whose label or id is the given name. Labels are compared without regard to case.
+    pub fn find_range(&self, name: &str) -> Option<&Range> {
+        let name = name.trim();
+        self.structures.iter().find(|range| {
+            range.id == name || text_of(&range.label).is_some_and(|label| label.eq_ignore_ascii_case(name))
+        })
+    }
+
+    /// The ids of all the canvases in a range and in its sub-ranges
+    pub fn range_canvases(&self, range: &Range) -> HashSet<String> {
+        let mut canvases = HashSet::new();
+        let mut visited = HashSet::new();
+        self.collect_canvases(range, &mut canvases, &mut visited);
+        canvases
+    }
+
+    fn collect_canvases<'a>(&'a self, range: &'a Range, canvases: &mut HashSet<String>, visited: &mut HashSet<&'a str>) {
+        if !range.id.is_empty() && !visited.insert(&range.id) { return; }
+        canvases.extend(range.canvases.iter().map(|id| without_fragment(id)));
+        for id in &range.ranges {
+            if let Some(sub_range) = self.structures.iter().find(|r| &r.id == id) {
+                self.collect_canvases(sub_range, canvases, visited);
+            }
+        }
+        for item in &range.items {
+            let id = match item {
+                RangeItem::Id(id) => id,
+                RangeItem::Object(sub_range) if sub_range.is_reference() => {
+                    match sub_range.source.as_str().or_else(|| sub_range.source["id"].as_str()) {
+                        Some(source) => source,
+                        None => &sub_range.id,
+                    }
+                }
+                RangeItem::Object(sub_range) => {
+                    self.collect_canvases(sub_range, canvases, visited);
+                    continue;
+                }
+            };
+            // References to other ranges of the table of contents, or to canvases
+            match self.structures.iter().find(|r| r.id == *id) {
+                Some(sub_range) => self.collect_canvases(sub_range, canvases, visited),
+                None => { canvases.insert(without_fragment(id)); }
+            }
+        }
+    }
+}
+
+impl Range {
+    /// Whether this is only a reference to a canvas or to another range
+    fn is_reference(&self) -> bool {
+        self.canvases.is_empty() && self.ranges.is_empty() && self.items.is_empty()
+    }
+}
+
+impl Canvas {
+    /// The URL of the info.json file of the image of this canvas
+    pub fn image_info_url(&self) -> Option<String> {
+        self.images.iter()
+            .chain(self.items.iter().flat_map(|page| page.items.iter()))
+            .filter_map(|annotation| annotation.resource.as_ref().or(annotation.body.as_ref()))
+            .find_map(Resource::service_url)
+    }
+}
+
+impl Resource {
+    fn service_url(&self) -> Option<String> {
+        let services = match &self.service {
+            Value::Array(services) => services.iter().collect(),
+            service => vec![service],
+        };
+        services.into_iter()
+            .find_map(|service| service["@id"].as_str().or_else(|| service["id"].as_str()))
+            .map(|id| format!("{}/info.json", id.trim_end_matches("/info.json").trim_end_matches('/')))
+            .or_else(|| self.default.iter().map(Box::as_ref).chain(self.items.iter()).find_map(Resource::service_url))
+    }
+}
+
+/// Canvases can be referenced with a fragment selecting a region of them, such as `#xywh=0,0,10,10`
+fn without_fragment(id: &str) -> String {
+    id.split('#').next().unwrap_or_default().to_string()
+}
+
+#[test]
+fn test_manifest_v2() {
+    let manifest: Manifest = serde_json::from_str(r##"{
+        "@id": "https://example.com/iiif/book/manifest",
+        "label": "A book",
+        "sequences": [{"canvases": [
+            {"@id": "https://example.com/iiif/book/canvas/1", "label": "1r",
+             "images": [{"resource": {"service": {"@id": "https://example.com/iiif/image/1"}}}]},
+            {"@id": "https://example.com/iiif/book/canvas/2", "label": "1v",
+             "images": [{"resource": {"service": {"@id": "https://example.com/iiif/image/2/"}}}]},
+            {"@id": "https://example.com/iiif/book/canvas/3", "label": "2r",
+             "images": [{"resource": {"@type": "oa:Choice",
+                "default": {"service": {"@id": "https://example.com/iiif/image/3"}}}}]}
+        ]}],
+        "structures": [
+            {"@id": "https://example.com/iiif/book/range/r0", "label": "Table of contents",
+             "ranges": ["https://example.com/iiif/book/range/r1", "https://example.com/iiif/book/range/r2"]},
+            {"@id": "https://example.com/iiif/book/range/r1", "label": "Chapter 1",
+             "canvases": ["https://example.com/iiif/book/canvas/1", "https://example.com/iiif/book/canvas/2#xywh=0,0,10,10"]},
+            {"@id": "https://example.com/iiif/book/range/r2", "label": "Chapter 2",
+             "members": [{"@id": "https://example.com/iiif/book/canvas/3", "@type": "sc:Canvas"}]}
+        ]
+    }"##).unwrap();
+    let urls: Vec<_> = manifest.canvases().filter_map(Canvas::image_info_url).collect();
+    assert_eq!(urls, vec![
+        "https://example.com/iiif/image/1/info.json",
+        "https://example.com/iiif/image/2/info.json",
+        "https://example.com/iiif/image/3/info.json",
+    ]);
+    let chapter = manifest.find_range("chapter 1").unwrap();
+    let expected: HashSet<String> = vec!["https://example.com/iiif/book/canvas/1", "https://example.com/iiif/book/canvas/2"]
+        .into_iter().map(String::from).collect();
+    assert_eq!(manifest.range_canvases(chapter), expected);
+    let all = manifest.find_range("Table of contents").unwrap();
+    assert_eq!(manifest.range_canvases(all).len(), 3);
+    assert!(manifest.find_range("Chapter 3").is_none());
+}
+
+#[test]
+fn test_manifest_v3() {
+    let manifest: Manifest = serde_json::from_str(r#"{
+        "id": "https://example.com/iiif/book/manifest",
+        "type": "Manifest",
+        "label": {"en": ["A book"]},
+        "items": [
+            {"id": "https://example.com/iiif/book/canvas/1", "type": "Canvas", "label": {"none": ["1r"]},
+             "items": [{"type": "AnnotationPage", "items": [{"type": "Annotation", "body": {
+                "id": "https://example.com/iiif/image/1/full/max/0/default.jpg",
+                "service": [{"id": "https://example.com/iiif/image/1", "type": "ImageService3"}]}}]}]},
+            {"id": "https://example.com/iiif/book/canvas/2", "type": "Canvas",
+             "items": [{"type": "AnnotationPage", "items": [{"type": "Annotation", "body": {
+                "service": [{"@id": "https://example.com/iiif/image/2", "@type": "ImageService2"}]}}]}]}
+        ],
+        "structures": [
+            {"id": "https://example.com/iiif/book/range/1", "type": "Range", "label": {"en": ["Chapter 1"]},
+             "items": [
+                {"id": "https://example.com/iiif/book/range/1.1", "type": "Range", "label": {"en": ["Section 1"]},
+                 "items": [{"id": "https://example.com/iiif/book/canvas/1", "type": "Canvas"}]}
+             ]},
+            {"id": "https://example.com/iiif/book/range/2", "type": "Range", "label": {"en": ["Chapter 2"]},
+             "items": [{"type": "SpecificResource", "source": "https://example.com/iiif/book/canvas/2"}]}
+        ]
+    }"#).unwrap();
+    let urls: Vec<_> = manifest.canvases().filter_map(Canvas::image_info_url).collect();
+    assert_eq!(urls, vec!["https://example.com/iiif/image/1/info.json", "https://example.com/iiif/image/2/info.json"]);
+    let chapter = manifest.find_range("Chapter 1").unwrap();
+    assert!(manifest.range_canvases(chapter).contains("https://example.com/iiif/book/canvas/1"));
+    let chapter = manifest.find_range("https://example.com/iiif/book/range/2").unwrap();
+    assert!(manifest.range_canvases(chapter).contains("https://example.com/iiif/book/canvas/2"));
+    assert_eq!(manifest.range_labels().collect::<Vec<_>>(), vec!["Chapter 1", "Chapter 2"]);
+}
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use custom_error::custom_error;
 use log::{info, debug};
 
-use tile_info::ImageInfo;
+use tile_info::{ImageInfo, SizeInfo};
 
 use crate::dezoomer::*;
 use crate::iiif::tile_info::TileSizeFormat;
@@ -11,6 +11,9 @@ use crate::json_utils::all_json;
 use crate::max_size_in_rect;
 
 pub mod tile_info;
+#[cfg(feature = "cli")]
+pub mod auth;
+pub mod manifest_types;
 
 /// Dezoomer for the International Image Interoperability Framework.
 /// See https://iiif.io/
@@ -32,6 +35,18 @@ impl Dezoomer for IIIF {
         "iiif"
     }
 
+    fn description(&self) -> &str {
+        "IIIF images, from their info.json file, and the images of IIIF manifests"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["*/info.json", "*/manifest.json"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec!["https://iiif.example.com/iiif/2/image-id/info.json"]
+    }
+
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
         let with_contents = data.with_contents()?;
         let contents = with_contents.contents;
@@ -76,19 +91,26 @@ fn zoom_levels_from_info(url: &str, mut image_info: ImageInfo) -> ZoomLevels {
     let img = Arc::new(image_info);
     let tiles = img.tiles();
     let base_url = &Arc::from(url.replace("/info.json", ""));
-    let levels = tiles
+    let limits = img.size_limits();
+    let quality: Arc<str> = Arc::from(img.best_quality());
+    let format: Arc<str> = Arc::from(img.best_format());
+    let size_format = img.preferred_size_format();
+    let mut levels: Vec<IIIFZoomLevel> = tiles
         .iter()
         .flat_map(|tile_info| {
-            let tile_size = Vec2d {
+            let declared_tile_size = Vec2d {
                 x: tile_info.width,
                 y: tile_info.height.unwrap_or(tile_info.width),
             };
-            let quality = Arc::from(img.best_quality());
-            let format = Arc::from(img.best_format());
-            let size_format = img.preferred_size_format();
+            let tile_size = limits.clamp(declared_tile_size);
+            if tile_size != declared_tile_size {
+                info!("The server does not allow {} tiles. Using {} tiles instead.", declared_tile_size, tile_size);
+            }
             info!("Chose the following image parameters: tile_size=({}) quality={} format={}",
                   tile_size, quality, format);
             let page_info = &img; // Required to allow the move
+            let quality = &quality;
+            let format = &format;
             tile_info
                 .scale_factors
                 .iter()
@@ -97,13 +119,35 @@ fn zoom_levels_from_info(url: &str, mut image_info: ImageInfo) -> ZoomLevels {
                     tile_size,
                     page_info: Arc::clone(page_info),
                     base_url: Arc::clone(base_url),
-                    quality: Arc::clone(&quality),
-                    format: Arc::clone(&format),
+                    quality: Arc::clone(quality),
+                    format: Arc::clone(format),
                     size_format,
                 })
         })
-        .into_zoom_levels();
-    levels
+        .collect();
+    // The sizes listed by the server can be downloaded in a single request,
+    // which is faster than downloading all the tiles of a level of the same size
+    let full_levels: Vec<IIIFFullLevel> = img.sizes.iter().flatten()
+        .map(|&SizeInfo { width, height }| Vec2d { x: width, y: height })
+        .filter(|&size| size.x > 0 && size.y > 0 && limits.allows(size))
+        .map(|size| IIIFFullLevel {
+            size,
+            page_info: Arc::clone(&img),
+            base_url: Arc::clone(base_url),
+            quality: Arc::clone(&quality),
+            format: Arc::clone(&format),
+            size_format,
+        })
+        .collect();
+    levels.retain(|level| !full_levels.iter().any(|full| same_size(full.size, level.size())));
+    levels.into_iter().into_zoom_levels().into_iter()
+        .chain(full_levels.into_iter().into_zoom_levels())
+        .collect()
+}
+
+/// Whether two level sizes are the same, up to rounding errors
+fn same_size(a: Vec2d, b: Vec2d) -> bool {
+    a.x.abs_diff(b.x) <= 1 && a.y.abs_diff(b.y) <= 1
 }
 
 struct IIIFZoomLevel {
@@ -117,8 +161,9 @@ struct IIIFZoomLevel {
 }
 
 impl TilesRect for IIIFZoomLevel {
+    // As in the IIIF specification, sizes are rounded up when the image is scaled down
     fn size(&self) -> Vec2d {
-        self.page_info.size() / self.scale_factor
+        self.page_info.size().ceil_div(Vec2d::square(self.scale_factor))
     }
 
     fn tile_size(&self) -> Vec2d {
@@ -126,10 +171,7 @@ impl TilesRect for IIIFZoomLevel {
     }
 
     fn tile_url(&self, col_and_row_pos: Vec2d) -> String {
-        let scaled_tile_size = self.tile_size * self.scale_factor;
-        let xy_pos = col_and_row_pos * scaled_tile_size;
-        let scaled_tile_size = max_size_in_rect(xy_pos, scaled_tile_size, self.page_info.size());
-        let tile_size = scaled_tile_size / self.scale_factor;
+        let (xy_pos, scaled_tile_size, tile_size) = self.tile_region(col_and_row_pos);
         format!(
             "{base}/{x},{y},{img_w},{img_h}/{tile_size}/{rotation}/{quality}.{format}",
             base = self.page_info.id.as_deref().unwrap_or_else(|| self.base_url.as_ref()),
@@ -143,6 +185,30 @@ impl TilesRect for IIIFZoomLevel {
             format = self.format,
         )
     }
+
+    fn tile_ref(&self, pos: Vec2d) -> TileReference {
+        TileReference {
+            url: self.tile_url(pos),
+            position: self.tile_size() * pos,
+            // Edge tiles are smaller, and their size is rounded by the server
+            size: Some(self.tile_region(pos).2),
+            request: None,
+        }
+    }
+
+    fn rights(&self) -> Option<Rights> { self.page_info.rights() }
+
+    fn physical_size(&self) -> Option<PhysicalSize> { self.page_info.physical_size() }
+}
+
+impl IIIFZoomLevel {
+    /// The position and size of a tile in the full-size image, and the size of the tile at this level
+    fn tile_region(&self, col_and_row_pos: Vec2d) -> (Vec2d, Vec2d, Vec2d) {
+        let scaled_tile_size = self.tile_size * self.scale_factor;
+        let xy_pos = col_and_row_pos * scaled_tile_size;
+        let scaled_tile_size = max_size_in_rect(xy_pos, scaled_tile_size, self.page_info.size());
+        (xy_pos, scaled_tile_size, scaled_tile_size.ceil_div(Vec2d::square(self.scale_factor)))
+    }
 }
 
 struct TileSizeFormatter { w: u32, h: u32, format: TileSizeFormat }
@@ -152,10 +218,51 @@ impl std::fmt::Display for TileSizeFormatter {
         match self.format {
             TileSizeFormat::WidthHeight => write!(f, "{},{}", self.w, self.h),
             TileSizeFormat::Width => write!(f, "{},", self.w),
+            TileSizeFormat::Height => write!(f, ",{}", self.h),
         }
     }
 }
 
+/// A level downloaded as a single image, at one of the sizes listed by the server
+struct IIIFFullLevel {
+    size: Vec2d,
+    page_info: Arc<ImageInfo>,
+    base_url: Arc<str>,
+    quality: Arc<str>,
+    format: Arc<str>,
+    size_format: TileSizeFormat,
+}
+
+impl TilesRect for IIIFFullLevel {
+    fn size(&self) -> Vec2d {
+        self.size
+    }
+
+    fn tile_size(&self) -> Vec2d {
+        self.size
+    }
+
+    fn tile_url(&self, _pos: Vec2d) -> String {
+        format!(
+            "{base}/full/{size}/0/{quality}.{format}",
+            base = self.page_info.id.as_deref().unwrap_or_else(|| self.base_url.as_ref()),
+            size = TileSizeFormatter { w: self.size.x, h: self.size.y, format: self.size_format },
+            quality = self.quality,
+            format = self.format,
+        )
+    }
+
+    fn rights(&self) -> Option<Rights> { self.page_info.rights() }
+
+    fn physical_size(&self) -> Option<PhysicalSize> { self.page_info.physical_size() }
+}
+
+impl std::fmt::Debug for IIIFFullLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "IIIF Image (single request)")
+    }
+}
+
 impl std::fmt::Debug for IIIFZoomLevel {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let name = self
@@ -200,8 +307,8 @@ fn test_tiles() {
         .map(|t| t.url)
         .collect();
     assert_eq!(tiles, vec![
-        "http://www.asmilano.it/fast/iipsrv.fcgi?IIIF=/opt/divenire/files/./tifs/05/36/536765.tif/0,0,15001,32768/234,512/0/default.jpg",
-        "http://www.asmilano.it/fast/iipsrv.fcgi?IIIF=/opt/divenire/files/./tifs/05/36/536765.tif/0,32768,15001,15234/234,238/0/default.jpg",
+        "http://www.asmilano.it/fast/iipsrv.fcgi?IIIF=/opt/divenire/files/./tifs/05/36/536765.tif/0,0,15001,32768/235,512/0/default.jpg",
+        "http://www.asmilano.it/fast/iipsrv.fcgi?IIIF=/opt/divenire/files/./tifs/05/36/536765.tif/0,32768,15001,15234/235,239/0/default.jpg",
     ])
 }
 
@@ -256,13 +363,60 @@ fn test_qualities() {
     }"#;
     let mut levels = zoom_levels("test.com", data).unwrap();
     let level = &mut levels[0];
-    assert_eq!(level.size_hint(), Some(Vec2d { x: 515, y: 381 }));
+    assert_eq!(level.size_hint(), Some(Vec2d { x: 516, y: 382 }));
     let tiles: Vec<String> = level
         .next_tiles(None)
         .into_iter()
         .map(|t| t.url)
         .collect();
     assert_eq!(tiles, vec![
-        "https://images.britishart.yale.edu/iiif/fd470c3e-ead0-4878-ac97-d63295753f82/0,0,5156,3816/515,381/0/native.png",
+        "https://images.britishart.yale.edu/iiif/fd470c3e-ead0-4878-ac97-d63295753f82/0,0,5156,3816/516,/0/native.png",
     ])
 }
+
+#[test]
+fn test_size_limits_and_sizes() {
+    let data = br#"{
+      "@context": "http://iiif.io/api/image/3/context.json",
+      "id": "http://test.com/img",
+      "width": 4000,
+      "height": 3000,
+      "maxWidth": 2000,
+      "sizes": [ { "width": 500, "height": 375 }, { "width": 250, "height": 188 }, { "width": 4000, "height": 3000 } ],
+      "tiles": [ { "width": 1024, "scaleFactors": [ 1, 2, 4, 8 ] } ]
+    }"#;
+    let mut levels = zoom_levels("http://test.com/img/info.json", data).unwrap();
+    let sizes: Vec<Option<Vec2d>> = levels.iter().map(|l| l.size_hint()).collect();
+    // The level of size 500x375 is replaced by a single request, and the full size is not allowed
+    assert_eq!(sizes, vec![
+        Some(Vec2d { x: 4000, y: 3000 }),
+        Some(Vec2d { x: 2000, y: 1500 }),
+        Some(Vec2d { x: 1000, y: 750 }),
+        Some(Vec2d { x: 500, y: 375 }),
+        Some(Vec2d { x: 250, y: 188 }),
+    ]);
+    let urls: Vec<String> = levels[3].next_tiles(None).into_iter().map(|t| t.url).collect();
+    assert_eq!(urls, vec!["http://test.com/img/full/500,375/0/default.jpg"]);
+    assert_eq!(levels[0].next_tiles(None).len(), 4 * 3);
+}
+
+#[test]
+fn test_scaled_edge_tiles() {
+    let data = br#"{
+      "@context": "http://iiif.io/api/image/2/context.json",
+      "@id": "http://test.com/img",
+      "width": 1001,
+      "height": 501,
+      "profile": "http://iiif.io/api/image/2/level0.json",
+      "tiles": [ { "width": 256, "scaleFactors": [ 2 ] } ]
+    }"#;
+    let mut levels = zoom_levels("http://test.com/img/info.json", data).unwrap();
+    let level = &mut levels[0];
+    // ceil(1001 / 2) x ceil(501 / 2)
+    assert_eq!(level.size_hint(), Some(Vec2d { x: 501, y: 251 }));
+    let urls: Vec<String> = level.next_tiles(None).into_iter().map(|t| t.url).collect();
+    assert_eq!(urls, vec![
+        "http://test.com/img/0,0,512,501/256,/0/default.jpg",
+        "http://test.com/img/512,0,489,501/245,/0/default.jpg",
+    ]);
+}
@@ -5,7 +5,9 @@ use lazy_static::lazy_static;
 use log::info;
 use log::warn;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
+use crate::dezoomer::{PhysicalSize, Rights};
 use crate::Vec2d;
 use regex::Regex;
 
@@ -42,6 +44,91 @@ pub struct ImageInfo {
     pub tile_width: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tile_height: Option<u32>,
+
+    /// Sizes at which the full image can be requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sizes: Option<Vec<SizeInfo>>,
+
+    // Size limits, at the top level in IIIF version 3, and in the profile in version 2
+    #[serde(rename = "maxWidth", skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<u32>,
+    #[serde(rename = "maxHeight", skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<u32>,
+    #[serde(rename = "maxArea", skip_serializing_if = "Option::is_none")]
+    pub max_area: Option<u64>,
+
+    // Rights statements: "attribution" and "license" in IIIF version 2, "rights" in version 3
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub attribution: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub license: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rights: Option<String>,
+    #[serde(rename = "requiredStatement", skip_serializing_if = "Option::is_none")]
+    pub required_statement: Option<Value>,
+
+    /// Services attached to the image, such as its physical dimensions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub service: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Clone, Copy)]
+pub struct SizeInfo {
+    pub width: u32,
+    pub height: u32,
+}
+
+/// The largest images the server accepts to return
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct SizeLimits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_area: Option<u64>,
+}
+
+impl SizeLimits {
+    pub fn allows(&self, size: Vec2d) -> bool {
+        self.max_width.is_none_or(|w| size.x <= w) &&
+            self.max_height.is_none_or(|h| size.y <= h) &&
+            self.max_area.is_none_or(|a| u64::from(size.x) * u64::from(size.y) <= a)
+    }
+
+    /// Shrink a tile size until it is allowed by the limits
+    pub fn clamp(&self, size: Vec2d) -> Vec2d {
+        let mut size = Vec2d {
+            x: self.max_width.map_or(size.x, |w| size.x.min(w)),
+            y: self.max_height.map_or(size.y, |h| size.y.min(h)),
+        };
+        while !self.allows(size) && (size.x > 1 || size.y > 1) {
+            size = size.ceil_div(Vec2d::square(2));
+        }
+        size
+    }
+}
+
+/// The text of a property that can be a string, a list of strings, a language map
+/// (`{"en": ["text"]}`), or a list of values with their language (`{"@value": "text", "@language": "en"}`).
+/// English is preferred, and HTML tags are removed.
+pub(crate) fn text_of(value: &Value) -> Option<String> {
+    lazy_static! {
+        static ref HTML_TAG: Regex = Regex::new(r"<[^>]*>").unwrap();
+    }
+    let text = match value {
+        Value::String(s) => HTML_TAG.replace_all(s, "").trim().to_string(),
+        Value::Array(values) if values.iter().all(Value::is_string) => {
+            values.iter().filter_map(text_of).collect::<Vec<_>>().join("; ")
+        }
+        Value::Array(values) => {
+            let english = values.iter().find(|v| v["@language"].as_str() == Some("en"));
+            text_of(english.or_else(|| values.first())?)?
+        }
+        Value::Object(map) => match map.get("@value") {
+            Some(value) => text_of(value)?,
+            None => text_of(map.get("en").or_else(|| map.get("none")).or_else(|| map.values().next())?)?,
+        },
+        _ => return None,
+    };
+    Some(text).filter(|t| !t.is_empty())
 }
 
 // Image qualities, from least favorite to favorite
@@ -52,7 +139,7 @@ static QUALITY_ORDER: [&str; 5] = ["bitonal", "gray", "color", "native", "defaul
 static FORMAT_ORDER: [&str; 7] = ["webp", "gif", "bmp", "tif", "png", "jpg", "jpeg"];
 
 #[derive(Clone, Copy, PartialEq, Debug)]
-pub enum TileSizeFormat { WidthHeight, Width }
+pub enum TileSizeFormat { WidthHeight, Width, Height }
 
 impl ImageInfo {
     pub fn size(&self) -> Vec2d {
@@ -90,19 +177,93 @@ impl ImageInfo {
             })
     }
 
+    /// The syntax of the size of the tiles in their URLs. Servers of static tiles only have the tiles
+    /// at their canonical URL, which uses `w,h` since version 3 of the API, and `w,` before.
     pub fn preferred_size_format(&self) -> TileSizeFormat {
         let pinfo = self.profile_info();
         let s: HashSet<&str> = pinfo.supports.iter()
             .flat_map(|x| x.iter())
             .map(|s| s.as_str())
             .collect();
-        if s.contains("sizeByW") && !s.contains("sizeByWh") {
+        let version = self.api_version();
+        if s.contains("sizeByWh") || version == Some(3) {
+            TileSizeFormat::WidthHeight
+        } else if s.contains("sizeByW") || version.is_some() {
             TileSizeFormat::Width
+        } else if s.contains("sizeByH") {
+            TileSizeFormat::Height
         } else {
             TileSizeFormat::WidthHeight
         }
     }
 
+    /// The major version of the IIIF Image API that the server implements, from its context or its profile
+    fn api_version(&self) -> Option<u32> {
+        let profiles: Vec<&str> = match &self.profile {
+            Some(Profile::Reference(s)) => vec![s],
+            Some(Profile::Multiple(Some(profiles))) => profiles.iter()
+                .filter_map(|p| if let Profile::Reference(s) = p { Some(s.as_str()) } else { None })
+                .collect(),
+            _ => vec![],
+        };
+        self.context.iter().map(String::as_str).chain(profiles).find_map(|s| {
+            if s.contains("/image/3/") {
+                Some(3)
+            } else if s.contains("/image/2/") {
+                Some(2)
+            } else if s.contains("/image-api/1") {
+                Some(1)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// The size limits of the server. As specified by IIIF,
+    /// the maximal height defaults to the maximal width when only the latter is given.
+    pub fn size_limits(&self) -> SizeLimits {
+        let pinfo = self.profile_info();
+        let max_width = self.max_width.or(pinfo.max_width);
+        SizeLimits {
+            max_width,
+            max_height: self.max_height.or(pinfo.max_height).or(max_width),
+            max_area: self.max_area.or(pinfo.max_area),
+        }
+    }
+
+    /// The license and the attribution text of the image, if the server gives any
+    pub fn rights(&self) -> Option<Rights> {
+        let license = self.rights.clone().or_else(|| self.license.as_ref().and_then(text_of));
+        let attribution = self.required_statement.as_ref().and_then(|s| text_of(&s["value"]))
+            .or_else(|| self.attribution.as_ref().and_then(text_of));
+        if license.is_none() && attribution.is_none() { return None; }
+        Some(Rights { license, attribution })
+    }
+
+    /// The dimensions of the original, from the physical dimensions service.
+    /// See https://iiif.io/api/annex/services/#physical-dimensions
+    pub fn physical_size(&self) -> Option<PhysicalSize> {
+        let services = match self.service.as_ref()? {
+            Value::Array(services) => services.iter().collect(),
+            service => vec![service],
+        };
+        let service = services.into_iter().find(|s| s["physicalScale"].is_number())?;
+        let scale = service["physicalScale"].as_f64().filter(|&s| s > 0.)?;
+        let inches_per_unit = match service["physicalUnits"].as_str()? {
+            "in" => 1.,
+            "cm" => 1. / 2.54,
+            "mm" => 1. / 25.4,
+            units => {
+                warn!("Unknown physical units: '{}'", units);
+                return None;
+            }
+        };
+        Some(PhysicalSize {
+            width_inches: f64::from(self.width) * scale * inches_per_unit,
+            height_inches: f64::from(self.height) * scale * inches_per_unit,
+        })
+    }
+
     pub fn tiles(&self) -> Vec<TileInfo> {
         self.tiles.as_ref()
             .and_then(|v|
@@ -177,6 +338,12 @@ pub struct ProfileInfo {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(alias = "extraFeatures")]
     supports: Option<Vec<String>>,
+    #[serde(rename = "maxWidth", skip_serializing_if = "Option::is_none")]
+    max_width: Option<u32>,
+    #[serde(rename = "maxHeight", skip_serializing_if = "Option::is_none")]
+    max_height: Option<u32>,
+    #[serde(rename = "maxArea", skip_serializing_if = "Option::is_none")]
+    max_area: Option<u64>,
 }
 
 lazy_static! {
@@ -202,16 +369,23 @@ impl Profile {
                 let mut formats = vec![];
                 let mut qualities = vec![];
                 let mut supports = vec![];
+                let (mut max_width, mut max_height, mut max_area) = (None, None, None);
                 for profile in profiles.iter().flat_map(|x| x.iter()) {
                     let p = profile.profile_info();
                     if let Some(x) = &p.formats { formats.extend_from_slice(x) }
                     if let Some(x) = &p.qualities { qualities.extend_from_slice(x) }
                     if let Some(x) = &p.supports { supports.extend_from_slice(x) }
+                    max_width = max_width.or(p.max_width);
+                    max_height = max_height.or(p.max_height);
+                    max_area = max_area.or(p.max_area);
                 }
                 Cow::Owned(ProfileInfo {
                     formats: Some(formats),
                     qualities: Some(qualities),
                     supports: Some(supports),
+                    max_width,
+                    max_height,
+                    max_area,
                 })
             },
         }
@@ -257,9 +431,8 @@ fn test_profile_info() {
     let profiles = Profile::Multiple(Some(vec![
         Profile::Reference("http://iiif.io/api/image/2/level0.json".into()),
         Profile::Info(ProfileInfo {
-            formats: None,
-            qualities: None,
             supports: Some(vec!["sizeByWh".into()]),
+            ..ProfileInfo::default()
         })
     ]));
     use std::ops::Deref;
@@ -270,6 +443,7 @@ fn test_profile_info() {
             "sizeByWhListed".into(), // from level0
             "sizeByWh".into(), // from the second profile
         ]),
+        ..ProfileInfo::default()
     })
 }
 
@@ -291,3 +465,74 @@ fn test_best_quality() {
         assert_eq!(info.best_quality(), expected_best_quality);
     }
 }
+
+#[test]
+fn test_size_limits() {
+    let info: ImageInfo = serde_json::from_str(r#"{
+        "width": 6000, "height": 4000,
+        "profile": ["http://iiif.io/api/image/2/level2.json", { "maxWidth": 1000, "maxArea": 500000 }]
+    }"#).unwrap();
+    let limits = info.size_limits();
+    assert_eq!(limits, SizeLimits { max_width: Some(1000), max_height: Some(1000), max_area: Some(500_000) });
+    assert!(limits.allows(Vec2d { x: 1000, y: 500 }));
+    assert!(!limits.allows(Vec2d { x: 1000, y: 501 }));
+    assert!(!limits.allows(Vec2d { x: 200, y: 1001 }));
+    assert_eq!(limits.clamp(Vec2d::square(1024)), Vec2d::square(500));
+    assert_eq!(limits.clamp(Vec2d::square(512)), Vec2d::square(512));
+}
+
+#[test]
+fn test_rights() {
+    let v2: ImageInfo = serde_json::from_str(r#"{
+        "width": 10, "height": 10,
+        "attribution": [{"@value": "Pr\u00eat", "@language": "fr"}, {"@value": "Lent by <b>the Museum</b>", "@language": "en"}],
+        "license": "https://creativecommons.org/licenses/by/4.0/"
+    }"#).unwrap();
+    assert_eq!(v2.rights(), Some(Rights {
+        license: Some("https://creativecommons.org/licenses/by/4.0/".into()),
+        attribution: Some("Lent by the Museum".into()),
+    }));
+    let v3: ImageInfo = serde_json::from_str(r#"{
+        "width": 10, "height": 10,
+        "rights": "http://rightsstatements.org/vocab/NoC-NC/1.0/",
+        "requiredStatement": {"label": {"en": ["Attribution"]}, "value": {"none": ["The Library"]}}
+    }"#).unwrap();
+    assert_eq!(v3.rights().unwrap().attribution.as_deref(), Some("The Library"));
+    assert_eq!(ImageInfo::default().rights(), None);
+}
+
+#[test]
+fn test_physical_size() {
+    let info: ImageInfo = serde_json::from_str(r#"{
+        "width": 6000, "height": 4000,
+        "service": {
+            "@context": "http://iiif.io/api/annex/services/physdim/1/context.json",
+            "profile": "http://iiif.io/api/annex/services/physdim",
+            "physicalScale": 0.0025,
+            "physicalUnits": "cm"
+        }
+    }"#).unwrap();
+    let size = info.physical_size().unwrap();
+    assert!((size.width_inches - 15. / 2.54).abs() < 1e-9);
+    assert!((size.height_inches - 10. / 2.54).abs() < 1e-9);
+    let v3: ImageInfo = serde_json::from_str(r#"{
+        "width": 3000, "height": 3000,
+        "service": [{"id": "https://example.com/auth", "type": "AuthCookieService1"},
+                    {"type": "PhysicalDimensions", "physicalScale": 0.001, "physicalUnits": "in"}]
+    }"#).unwrap();
+    assert_eq!(v3.physical_size(), Some(PhysicalSize { width_inches: 3., height_inches: 3. }));
+    assert_eq!(ImageInfo::default().physical_size(), None);
+}
+
+#[test]
+fn test_preferred_size_format() {
+    let format = |fields: &str| {
+        let json = format!(r#"{{"width": 100, "height": 100 {}}}"#, fields);
+        serde_json::from_str::<ImageInfo>(&json).unwrap().preferred_size_format()
+    };
+    assert_eq!(format(r#", "@context": "http://iiif.io/api/image/2/context.json", "profile": "http://iiif.io/api/image/2/level0.json""#), TileSizeFormat::Width);
+    assert_eq!(format(r#", "@context": "http://iiif.io/api/image/3/context.json", "profile": "level0""#), TileSizeFormat::WidthHeight);
+    assert_eq!(format(r#", "profile": [{"supports": ["sizeByH"]}]"#), TileSizeFormat::Height);
+    assert_eq!(format(r#", "profile": "http://iiif.io/api/image/2/level2.json""#), TileSizeFormat::WidthHeight);
+    assert_eq!(format(""), TileSizeFormat::WidthHeight);
+}
@@ -2,34 +2,95 @@ use crate::Vec2d;
 use std::str::FromStr;
 use custom_error::custom_error;
 use std::sync::Arc;
-use crate::dezoomer::{TilesRect, Dezoomer, DezoomerInput, ZoomLevels, DezoomerError, IntoZoomLevels, DezoomerInputWithContents};
+use crate::dezoomer::{TilesRect, Dezoomer, DezoomerInput, ZoomLevels, DezoomerError, IntoZoomLevels, DezoomerInputWithContents, PageContents};
+use crate::dzi::DziDezoomer;
+use crate::iiif::IIIF;
 use std::convert::TryFrom;
 use std::iter::successors;
 use std::fmt::Debug;
+use log::debug;
 use regex::Regex;
 
-/// A dezoomer for krpano images
+/// A dezoomer for IIPImage servers.
+/// iipsrv can also serve its images with the IIIF and DeepZoom protocols, which are tried first,
+/// before falling back to the IIP protocol.
 /// See https://iipimage.sourceforge.io/documentation/protocol/
 #[derive(Default)]
 pub struct IIPImage;
 
 const META_REQUEST_PARAMS: &str = "&OBJ=Max-size&OBJ=Tile-size&OBJ=Resolution-number";
 
+/// iipsrv refuses to convert images larger than this (its default MAX_CVT setting)
+const MAX_CVT_SIZE: u32 = 5000;
+
+/// The protocols in which iipsrv can serve an image, from the most preferred to the least preferred
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    Iiif,
+    DeepZoom,
+    Iip,
+}
+
+impl Protocol {
+    /// The address of the metadata of the image at `path` in this protocol
+    fn metadata_uri(self, base: &str, path: &str) -> String {
+        match self {
+            Protocol::Iiif => format!("{}?IIIF={}/info.json", base, path),
+            Protocol::DeepZoom => format!("{}?DeepZoom={}.dzi", base, path),
+            Protocol::Iip => format!("{}?FIF={}{}", base, path, META_REQUEST_PARAMS),
+        }
+    }
+
+    /// Recognizes a metadata address generated by `metadata_uri`, and returns the base and path in it
+    fn parse_metadata_uri(self, uri: &str) -> Option<(&str, &str)> {
+        let (param, suffix) = match self {
+            Protocol::Iiif => ("?IIIF=", "/info.json"),
+            Protocol::DeepZoom => ("?DeepZoom=", ".dzi"),
+            Protocol::Iip => return None,
+        };
+        let (base, rest) = uri.split_at(uri.find(param)?);
+        Some((base, rest[param.len()..].strip_suffix(suffix)?))
+    }
+}
+
+/// Splits an IIP address such as `http://example.com/iipsrv.fcgi?FIF=image.tif&JTL=1,2`
+/// into the address of the server and the path of the image
+fn parse_fif_uri(uri: &str) -> Option<(&str, &str)> {
+    let re = Regex::new("(?i)\\?FIF=([^&]*)").unwrap();
+    let captures = re.captures(uri)?;
+    let start = captures.get(0)?.start();
+    Some((&uri[..start], captures.get(1)?.as_str()))
+}
+
 impl Dezoomer for IIPImage {
     fn name(&self) -> &'static str { "IIPImage" }
+    fn description(&self) -> &str { "Images served by IIPImage with the Internet Imaging Protocol" }
+    fn url_patterns(&self) -> Vec<&str> { vec!["*?FIF=*"] }
+    fn examples(&self) -> Vec<&str> { vec!["http://example.com/fcgi-bin/iipsrv.fcgi?FIF=/images/image.tif"] }
 
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
         if data.uri.ends_with(META_REQUEST_PARAMS) {
             let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
             let iter = iter_levels(uri, contents).map_err(DezoomerError::wrap)?;
-            Ok(iter.into_zoom_levels())
-        } else {
-            let re = Regex::new("(?i)\\?FIF").unwrap();
-            self.assert(re.is_match(&data.uri))?;
-            let mut meta_uri: String = data.uri.chars().take_while(|&c| c != '&').collect();
-            meta_uri += META_REQUEST_PARAMS;
-            Err(DezoomerError::NeedsData { uri: meta_uri })
+            return Ok(iter.into_zoom_levels());
         }
+        for &(protocol, next) in &[(Protocol::Iiif, Protocol::DeepZoom), (Protocol::DeepZoom, Protocol::Iip)] {
+            if let Some((base, path)) = protocol.parse_metadata_uri(&data.uri) {
+                if let PageContents::Success(_) = &data.contents {
+                    let levels = match protocol {
+                        Protocol::Iiif => IIIF.zoom_levels(data),
+                        _ => DziDezoomer.zoom_levels(data),
+                    };
+                    match levels {
+                        Ok(levels) => return Ok(levels),
+                        Err(e) => debug!("The {:?} endpoint of iipsrv did not work: {}", protocol, e),
+                    }
+                }
+                return Err(DezoomerError::NeedsData { uri: next.metadata_uri(base, path) });
+            }
+        }
+        let (base, path) = parse_fif_uri(&data.uri).ok_or_else(|| self.wrong_dezoomer())?;
+        Err(DezoomerError::NeedsData { uri: Protocol::Iiif.metadata_uri(base, path) })
     }
 }
 
@@ -62,15 +123,27 @@ impl Debug for Level {
     }
 }
 
+impl Level {
+    /// Small levels are downloaded at once, with a request to convert the whole image to jpeg
+    fn single_request(&self) -> bool {
+        self.size().fits_inside(Vec2d::square(MAX_CVT_SIZE))
+    }
+}
+
 impl TilesRect for Level {
     fn size(&self) -> Vec2d {
         let reverse_level = self.metadata.levels - self.level - 1;
         self.metadata.size / 2_u32.pow(reverse_level)
     }
 
-    fn tile_size(&self) -> Vec2d { self.metadata.tile_size }
+    fn tile_size(&self) -> Vec2d {
+        if self.single_request() { self.size() } else { self.metadata.tile_size }
+    }
 
     fn tile_url(&self, Vec2d { x, y }: Vec2d) -> String {
+        if self.single_request() {
+            return format!("{base}&WID={width}&CVT=jpeg", base = self.base, width = self.size().x);
+        }
         let Vec2d { x: width, .. } = self.size().ceil_div(self.tile_size());
         format!("{base}&JTL={level},{tile_index}",
                 base = self.base,
@@ -140,23 +213,53 @@ mod tests {
     #[test]
     fn test_lowercase() {
         let uri = "https://publications-images.artic.edu/fcgi-bin/iipsrv.fcgi?fif=osci/Renoir_11/Color_Corrected/G39094sm2.ptif&jtl=4,11".to_string();
-        let metadata_uri = "https://publications-images.artic.edu/fcgi-bin/iipsrv.fcgi?fif=osci/Renoir_11/Color_Corrected/G39094sm2.ptif&OBJ=Max-size&OBJ=Tile-size&OBJ=Resolution-number";
+        let metadata_uri = "https://publications-images.artic.edu/fcgi-bin/iipsrv.fcgi?IIIF=osci/Renoir_11/Color_Corrected/G39094sm2.ptif/info.json";
         let data = DezoomerInput { uri, contents: PageContents::Unknown };
-        match IIPImage::default().zoom_levels(&data) {
+        match IIPImage.zoom_levels(&data) {
             Err(DezoomerError::NeedsData { uri }) => assert_eq!(uri, metadata_uri),
             _ => panic!("Unexpected result")
         }
     }
 
+    fn next_uri(uri: &str, contents: PageContents) -> String {
+        let data = DezoomerInput { uri: uri.to_string(), contents };
+        match IIPImage.zoom_levels(&data) {
+            Err(DezoomerError::NeedsData { uri }) => uri,
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Unexpected levels"),
+        }
+    }
+
+    #[test]
+    fn test_protocol_fallbacks() {
+        let base = "http://example.com/iipsrv.fcgi";
+        let iiif = next_uri(&format!("{}?FIF=/images/a.tif&JTL=1,2", base), PageContents::Unknown);
+        assert_eq!(iiif, "http://example.com/iipsrv.fcgi?IIIF=/images/a.tif/info.json");
+        let not_found = || PageContents::Error(std::rc::Rc::new(crate::ZoomError::NoLevels));
+        let dzi = next_uri(&iiif, not_found());
+        assert_eq!(dzi, "http://example.com/iipsrv.fcgi?DeepZoom=/images/a.tif.dzi");
+        let invalid = PageContents::Success(b"not a dzi file".to_vec());
+        let iip = next_uri(&dzi, invalid);
+        assert_eq!(iip, "http://example.com/iipsrv.fcgi?FIF=/images/a.tif&OBJ=Max-size&OBJ=Tile-size&OBJ=Resolution-number");
+
+        let info = br#"{"@context": "http://iiif.io/api/image/2/context.json",
+            "@id": "http://example.com/iipsrv.fcgi?IIIF=/images/a.tif",
+            "width": 1000, "height": 800, "tiles": [{"width": 256, "scaleFactors": [1, 2, 4]}]}"#;
+        let data = DezoomerInput { uri: iiif, contents: PageContents::Success(info.to_vec()) };
+        let mut levels = IIPImage.zoom_levels(&data).unwrap();
+        let tile = levels[0].next_tiles(None).remove(0);
+        assert!(tile.url.starts_with("http://example.com/iipsrv.fcgi?IIIF=/images/a.tif/0,0,"), "{}", tile.url);
+    }
+
     #[test]
     fn test_parse_metadata() {
-        let contents = &b"Max-size:512 512\nTile-size:256 256\nResolution-number:2"[..];
+        let contents = &b"Max-size:10240 10240\nTile-size:256 256\nResolution-number:2"[..];
         let base: Arc<str> = Arc::from("http://test.com/");
         let levels: Vec<Level> = iter_levels(&base, contents).unwrap().collect();
         assert_eq!(&levels, &[
             Level {
                 metadata: Arc::from(Metadata {
-                    size: Vec2d { x: 512, y: 512 },
+                    size: Vec2d { x: 10240, y: 10240 },
                     tile_size: Vec2d { x: 256, y: 256 },
                     levels: 2,
                 }),
@@ -165,7 +268,7 @@ mod tests {
             },
             Level {
                 metadata: Arc::from(Metadata {
-                    size: Vec2d { x: 512, y: 512 },
+                    size: Vec2d { x: 10240, y: 10240 },
                     tile_size: Vec2d { x: 256, y: 256 },
                     levels: 2,
                 }),
@@ -174,7 +277,16 @@ mod tests {
             }
         ]);
         assert_eq!(levels[0].tile_url(Vec2d { x: 0, y: 0 }), "http://test.com/&JTL=0,0");
-        assert_eq!(levels[1].tile_url(Vec2d { x: 0, y: 1 }), "http://test.com/&JTL=1,2");
+        assert_eq!(levels[1].tile_url(Vec2d { x: 0, y: 1 }), "http://test.com/&JTL=1,40");
+    }
+
+    #[test]
+    fn test_small_image_in_one_request() {
+        let contents = &b"Max-size:3000 2000\nTile-size:256 256\nResolution-number:3"[..];
+        let levels: Vec<Level> = iter_levels("http://test.com/?FIF=a.tif", contents).unwrap().collect();
+        let largest = &levels[2];
+        assert_eq!(largest.tile_size(), Vec2d { x: 3000, y: 2000 });
+        assert_eq!(largest.tile_url(Vec2d { x: 0, y: 0 }), "http://test.com/?FIF=a.tif&WID=3000&CVT=jpeg");
     }
 
     #[test]
@@ -136,22 +136,35 @@ pub enum KrpanoLevel {
     Down(ShapeDesc),
 }
 
+/// Describes all the levels of an image.
+/// Like krpano, `<level>` elements, which are listed from the largest to the smallest,
+/// are numbered from the smallest, as the sizes of a multires attribute are.
+pub fn image_level_descriptions(levels: Vec<KrpanoLevel>) -> Vec<Result<LevelDesc, &'static str>> {
+    let level_count = levels.iter().filter(|l| matches!(l, KrpanoLevel::Level(_))).count();
+    let mut level_elements = 0;
+    levels.into_iter().flat_map(|level| {
+        let level_index = level_count.saturating_sub(level_elements + 1);
+        if let KrpanoLevel::Level(_) = level { level_elements += 1 }
+        level.level_descriptions(None, level_index)
+    }).collect()
+}
+
 impl KrpanoLevel {
-    pub fn level_descriptions(self, size: Option<Vec2d>) -> Vec<Result<LevelDesc, &'static str>> {
+    fn level_descriptions(self, size: Option<Vec2d>, level_index: usize) -> Vec<Result<LevelDesc, &'static str>> {
         match self {
             Self::Level(LevelAttributes { tiledimagewidth, tiledimageheight, shape }) => {
                 let size = Vec2d { x: tiledimagewidth, y: tiledimageheight };
-                shape.into_iter().flat_map(|level| level.level_descriptions(Some(size))).collect()
+                shape.into_iter().flat_map(|level| level.level_descriptions(Some(size), level_index)).collect()
             }
-            Self::Cube(d) => shape_descriptions("Cube", d, size),
-            Self::Cylinder(d) => shape_descriptions("Cylinder", d, size),
-            Self::Flat(d) => shape_descriptions("Flat", d, size),
-            Self::Left(d) => shape_descriptions("Left", d, size),
-            Self::Right(d) => shape_descriptions("Right", d, size),
-            Self::Front(d) => shape_descriptions("Front", d, size),
-            Self::Back(d) => shape_descriptions("Back", d, size),
-            Self::Up(d) => shape_descriptions("Up", d, size),
-            Self::Down(d) => shape_descriptions("Down", d, size),
+            Self::Cube(d) => shape_descriptions("Cube", d, size, level_index),
+            Self::Cylinder(d) => shape_descriptions("Cylinder", d, size, level_index),
+            Self::Flat(d) => shape_descriptions("Flat", d, size, level_index),
+            Self::Left(d) => shape_descriptions("Left", d, size, level_index),
+            Self::Right(d) => shape_descriptions("Right", d, size, level_index),
+            Self::Front(d) => shape_descriptions("Front", d, size, level_index),
+            Self::Back(d) => shape_descriptions("Back", d, size, level_index),
+            Self::Up(d) => shape_descriptions("Up", d, size, level_index),
+            Self::Down(d) => shape_descriptions("Down", d, size, level_index),
             Self::Mobile(_) | Self::Tablet(_) => vec![], // Ignore
         }
     }
@@ -161,6 +174,7 @@ fn shape_descriptions(
     name: &'static str,
     desc: ShapeDesc,
     size: Option<Vec2d>,
+    level_index: usize,
 ) -> Vec<Result<LevelDesc, &'static str>> {
     let ShapeDesc { multires, url } = desc;
     if let Some(multires) = multires {
@@ -175,7 +189,7 @@ fn shape_descriptions(
         ).collect()
     } else if let Some(size) = size {
         let tilesize = None;
-        vec![Ok(LevelDesc { name, size, tilesize, url, level_index: 0 })]
+        vec![Ok(LevelDesc { name, size, tilesize, url, level_index })]
     } else {
         vec![Err("missing multires attribute")]
     }
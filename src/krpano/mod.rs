@@ -7,8 +7,9 @@ use log::warn;
 use krpano_metadata::{KrpanoMetadata, TemplateString, TemplateStringPart, XY};
 
 use crate::dezoomer::*;
-use crate::krpano::krpano_metadata::{ImageInfo, LevelDesc};
-use crate::network::{remove_bom, resolve_relative};
+use crate::krpano::krpano_metadata::{image_level_descriptions, ImageInfo, LevelDesc};
+use crate::max_size_in_rect;
+use crate::url_utils::{remove_bom, resolve_relative};
 
 mod krpano_metadata;
 
@@ -22,6 +23,18 @@ impl Dezoomer for KrpanoDezoomer {
         "krpano"
     }
 
+    fn description(&self) -> &str {
+        "Planes of krpano panoramas and virtual tours, from their xml file"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["*.xml"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec!["https://example.com/tour/pano.xml"]
+    }
+
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
         let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
         let levels = load_from_properties(uri, contents)?;
@@ -47,41 +60,38 @@ fn load_from_properties(url: &str, contents: &[u8])
     Ok(image_properties.into_image_iter().flat_map(move |ImageInfo { image, name }| {
         let root_tile_size = image.tilesize.map(Vec2d::square);
         let base_index = image.baseindex;
-        image.level.into_iter().flat_map(move |level| {
+        image_level_descriptions(image.level).into_iter().flat_map(move |level_desc| {
             let name = Arc::clone(&name);
-            level.level_descriptions(None).into_iter().flat_map(move |level_desc| {
-                let name = Arc::clone(&name);
-                level_desc
-                    .map_err(|err| warn!("bad krpano level: {}", err))
-                    .into_iter()
-                    .flat_map(move |LevelDesc {
-                                        name: shape_name,
-                                        size,
-                                        tilesize,
-                                        url,
-                                        level_index,
-                                    }| {
-                        let level = level_index + base_index as usize;
-                        let name = Arc::clone(&name);
-                        url.all_sides(level).flat_map(move |(side_name, template)| {
-                            let base_url = Arc::clone(base_url);
-                            let title = Arc::clone(title);
-                            let name = Arc::clone(&name);
-                            tilesize.or(root_tile_size).map(|tile_size|
-                                Level {
-                                    base_url,
-                                    base_index,
+            level_desc
+                .map_err(|err| warn!("bad krpano level: {}", err))
+                .into_iter()
+                .flat_map(move |LevelDesc {
+                                    name: shape_name,
                                     size,
-                                    tile_size,
-                                    template,
-                                    shape_name,
-                                    side_name,
-                                    name,
-                                    title,
-                                })
-                        })
+                                    tilesize,
+                                    url,
+                                    level_index,
+                                }| {
+                    let level = level_index + base_index as usize;
+                    let name = Arc::clone(&name);
+                    url.all_sides(level).flat_map(move |(side_name, template)| {
+                        let base_url = Arc::clone(base_url);
+                        let title = Arc::clone(title);
+                        let name = Arc::clone(&name);
+                        tilesize.or(root_tile_size).map(|tile_size|
+                            Level {
+                                base_url,
+                                base_index,
+                                size,
+                                tile_size,
+                                template,
+                                shape_name,
+                                side_name,
+                                name,
+                                title,
+                            })
                     })
-            })
+                })
         })
     }).into_zoom_levels())
 }
@@ -134,9 +144,13 @@ impl TilesRect for Level {
     }
 
     fn tile_ref(&self, pos: Vec2d) -> TileReference {
+        let position = self.tile_size() * pos;
         TileReference {
             url: self.tile_url(pos),
-            position: self.tile_size() * pos,
+            position,
+            // Partial levels can have smaller tiles on their edges
+            size: Some(max_size_in_rect(position, self.tile_size(), self.size())),
+            request: None,
         }
     }
 }
@@ -164,8 +178,8 @@ fn test_cube() {
     assert_eq!(levels[0].size_hint(), Some(Vec2d { x: 1000, y: 100 }));
     assert_eq!(format!("{:?}", levels[0]), "Krpano Cube forward");
     assert_eq!(levels[0].next_tiles(None), vec![
-        TileReference { url: "http://example.com/f/1/1.jpg".to_string(), position: Vec2d { x: 0, y: 0 } },
-        TileReference { url: "http://example.com/f/1/2.jpg".to_string(), position: Vec2d { x: 512, y: 0 } }]);
+        TileReference { url: "http://example.com/f/1/1.jpg".to_string(), position: Vec2d { x: 0, y: 0 }, size: Some(Vec2d { x: 512, y: 100 }), request: None },
+        TileReference { url: "http://example.com/f/1/2.jpg".to_string(), position: Vec2d { x: 512, y: 0 }, size: Some(Vec2d { x: 488, y: 100 }), request: None }]);
 }
 
 #[test]
@@ -182,6 +196,75 @@ fn test_flat_multires() {
     assert_eq!(levels[1].size_hint(), Some(Vec2d { x: 3, y: 4 }));
     assert_eq!(format!("{:?}", levels[0]), "Krpano Flat");
     assert_eq!(levels[1].next_tiles(None), vec![
-        TileReference { url: "http://test.com/level=2%20x=01%20y=01".to_string(), position: Vec2d { x: 0, y: 0 } },
-        TileReference { url: "http://test.com/level=2%20x=01%20y=02".to_string(), position: Vec2d { x: 0, y: 3 } }]);
-}
\ No newline at end of file
+        TileReference { url: "http://test.com/level=2%20x=01%20y=01".to_string(), position: Vec2d { x: 0, y: 0 }, size: Some(Vec2d { x: 3, y: 3 }), request: None },
+        TileReference { url: "http://test.com/level=2%20x=01%20y=02".to_string(), position: Vec2d { x: 0, y: 3 }, size: Some(Vec2d { x: 3, y: 1 }), request: None }]);
+}
+#[test]
+fn test_cube_multires_partial_tiles() {
+    let mut levels = load_from_properties(
+        "http://test.com",
+        r#"<krpano>
+        <image type="cube" multires="true" tilesize="512">
+            <cube url="%s/l%l/%v/%h.jpg" multires="512,640,1282"/>
+        </image>
+        </krpano>"#.as_bytes(),
+    ).unwrap();
+    assert_eq!(levels.len(), 12);
+    let largest_front = &mut levels[6];
+    assert_eq!(format!("{:?}", largest_front), "Krpano Cube forward");
+    let tiles = largest_front.next_tiles(None);
+    assert_eq!(tiles.len(), 9);
+    let last = tiles.last().unwrap();
+    assert_eq!(last.url, "http://test.com/f/l2/3/3.jpg");
+    assert_eq!(last.position, Vec2d::square(1024));
+    assert_eq!(last.size, Some(Vec2d::square(258)));
+}
+
+#[test]
+fn test_cylinder_levels() {
+    let mut levels = load_from_properties(
+        "http://test.com",
+        r#"<krpano>
+        <image type="CYLINDER" multires="true" tilesize="500" baseindex="0">
+            <level tiledimagewidth="1501" tiledimageheight="999">
+                <cylinder url="l%l/%v_%h.jpg"/>
+            </level>
+            <level tiledimagewidth="751" tiledimageheight="500">
+                <cylinder url="l%l/%v_%h.jpg"/>
+            </level>
+        </image>
+        </krpano>"#.as_bytes(),
+    ).unwrap();
+    assert_eq!(levels.len(), 2);
+    // Levels are numbered from the smallest one
+    let tiles = levels[0].next_tiles(None);
+    assert_eq!(tiles.len(), 8);
+    assert_eq!(tiles[7].url, "http://test.com/l1/1_3.jpg");
+    assert_eq!(tiles[7].size, Some(Vec2d { x: 1, y: 499 }));
+    let tiles = levels[1].next_tiles(None);
+    assert_eq!(tiles.iter().map(|t| t.url.as_str()).collect::<Vec<_>>(),
+               vec!["http://test.com/l0/0_0.jpg", "http://test.com/l0/0_1.jpg"]);
+    assert_eq!(tiles[1].size, Some(Vec2d { x: 251, y: 500 }));
+}
+
+#[test]
+fn test_flat_multires_partial_tiles() {
+    let mut levels = load_from_properties(
+        "http://test.com",
+        r#"<krpano>
+        <image type="flat" multires="true">
+            <flat url="%l/%0v/%0h.jpg" multires="256,300x157,599x313,1197x625"/>
+        </image>
+        </krpano>"#.as_bytes(),
+    ).unwrap();
+    assert_eq!(levels.len(), 3);
+    let sizes: Vec<_> = levels.iter_mut().map(|l| {
+        let tiles = l.next_tiles(None);
+        (tiles.len(), tiles.last().unwrap().size.unwrap())
+    }).collect();
+    assert_eq!(sizes, vec![
+        (2, Vec2d { x: 44, y: 157 }),
+        (6, Vec2d { x: 87, y: 57 }),
+        (15, Vec2d { x: 173, y: 113 }),
+    ]);
+}
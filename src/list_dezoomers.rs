@@ -0,0 +1,53 @@
+//! The `--list-dezoomers` mode: describe the available dezoomers and the inputs they accept.
+
+use crate::arguments::Arguments;
+use crate::auto::all_dezoomers;
+use crate::dezoomer::Dezoomer;
+use crate::ZoomError;
+
+/// Prints all the dezoomers, or only the one chosen with --dezoomer
+pub fn print_dezoomers(args: &Arguments) -> Result<(), ZoomError> {
+    let dezoomers: Vec<_> = all_dezoomers(true).into_iter()
+        .filter(|d| args.dezoomer == "auto" || d.name() == args.dezoomer)
+        .collect();
+    if dezoomers.is_empty() {
+        return Err(ZoomError::NoSuchDezoomer { name: args.dezoomer.clone() });
+    }
+    for dezoomer in dezoomers {
+        println!("{}", describe(dezoomer.as_ref()));
+    }
+    Ok(())
+}
+
+/// The name of a dezoomer, followed by its description, the inputs it accepts, and examples
+fn describe(dezoomer: &dyn Dezoomer) -> String {
+    let mut text = dezoomer.name().to_string();
+    if !dezoomer.description().is_empty() {
+        text += &format!("\n    {}", dezoomer.description());
+    }
+    for pattern in dezoomer.url_patterns() {
+        text += &format!("\n    input:   {}", pattern);
+    }
+    for example in dezoomer.examples() {
+        text += &format!("\n    example: {}", example);
+    }
+    text
+}
+
+#[test]
+fn test_describe() {
+    let text = describe(&crate::dzi::DziDezoomer);
+    assert!(text.starts_with("deepzoom\n    DeepZoom images"), "{}", text);
+    assert!(text.contains("\n    input:   *.dzi\n"), "{}", text);
+    assert!(text.ends_with("\n    example: http://test.com/y/xy.dzi"), "{}", text);
+}
+
+#[test]
+fn test_all_dezoomers_are_described() {
+    // Descriptors loaded from the configuration directory of the user may not have a description
+    let descriptors: Vec<&str> = crate::custom_yaml::descriptor::descriptor_dezoomers().iter()
+        .map(|d| d.name()).collect();
+    for dezoomer in all_dezoomers(true).iter().filter(|d| !descriptors.contains(&d.name())) {
+        assert!(!dezoomer.description().is_empty(), "{} has no description", dezoomer.name());
+    }
+}
@@ -1,49 +1,141 @@
-use colour::{green_ln, red_ln};
+use colour::{e_red_ln, green_ln};
+use log::warn;
 use human_panic::setup_panic;
-use structopt::StructOpt;
 
-use dezoomify_rs::{Arguments, dezoomify, ZoomError};
+use dezoomify_rs::{Arguments, BuildInfo, CancellationToken, CommandLine, dezoomify, ZoomError};
+use dezoomify_rs::bulk::{BulkOutputs, BulkStats, read_items};
 
 #[tokio::main]
 async fn main() {
     setup_panic!();
     let has_args = std::env::args_os().count() > 1;
-    let mut has_errors = false;
-    let args: Arguments = Arguments::from_args();
+    if wants_build_info() {
+        print!("{}", BuildInfo::current());
+        return;
+    }
+    let mut exit_code = 0;
+    let mut args: Arguments = CommandLine::parse(std::env::args_os())
+        .unwrap_or_else(|err| err.exit());
     init_log(&args);
+    if args.range.is_some() && args.bulk.is_none() {
+        log::warn!("--range is ignored without --bulk");
+    }
+    if args.archive.is_some() && args.bulk.is_none() {
+        log::warn!("--archive is ignored without --bulk");
+    }
+    if args.reading_copy.is_some() && args.bulk.is_none() {
+        log::warn!("--reading-copy is ignored without --bulk");
+    }
+    let cancel = CancellationToken::new();
+    cancel_on_ctrl_c(cancel.clone());
+    args.cancel = Some(cancel.clone());
 
-    loop {
-        match dezoomify(&args).await {
+    if let Some(path) = &args.bulk {
+        match read_items(path, args.range.as_deref()) {
+            Ok(items) => {
+                let mut stats = BulkStats::default();
+                let mut outputs = BulkOutputs::create(&args, items.len())
+                    .unwrap_or_else(|err| {
+                        e_red_ln!("ERROR {}", err);
+                        std::process::exit(err.exit_code())
+                    });
+                for (i, item) in items.into_iter().enumerate() {
+                    let item_args = item.arguments(&args, i + 1);
+                    let result = dezoomify(&item_args).await.and_then(|saved_as| outputs.add(i + 1, saved_as));
+                    let result = report(&item_args, result);
+                    stats.record(&result);
+                    match result {
+                        // Skipping small images is what the user asked for, not a failure of the bulk download
+                        Err(ZoomError::ImageTooSmall { .. }) | Ok(()) => {}
+                        Err(err) => exit_code = err.exit_code(),
+                    }
+                    if cancel.is_cancelled() { break; }
+                }
+                for result in outputs.finish() {
+                    match result {
+                        Ok(path) if !args.quiet => { green_ln!("Saved '{}'", path.to_string_lossy()) }
+                        Ok(_) => {}
+                        Err(err) => {
+                            e_red_ln!("ERROR {}", err);
+                            exit_code = err.exit_code();
+                        }
+                    }
+                }
+                if !args.quiet { println!("Bulk download finished: {}", stats); }
+            }
             Err(err) => {
-                red_ln!("ERROR {}", err);
-                has_errors = true;
+                e_red_ln!("ERROR {}", err);
+                exit_code = err.exit_code();
+            }
+        }
+    } else {
+        loop {
+            if let Err(err) = report(&args, dezoomify(&args).await) {
+                exit_code = err.exit_code();
                 // If we have reached the end of stdin, we exit
                 if let ZoomError::Io { source } = err {
                     if source.kind() == std::io::ErrorKind::UnexpectedEof {
                         break
                     }
                 }
-            },
-            Ok(saved_as) => {
-                green_ln!("Image successfully saved to '{}' (current working directory: {})",
-                         saved_as.to_string_lossy(),
-                         std::env::current_dir()
-                             .map(|p| p.to_string_lossy().to_string())
-                             .unwrap_or_else(|_e| "unknown".into())
-                );
             }
-        }
-        if has_args {
-            // Command-line invocation
-            break;
+            if has_args || cancel.is_cancelled() {
+                // Command-line invocation
+                break;
+            }
         }
     }
-    if has_errors {
-        std::process::exit(1);
+    if cancel.is_cancelled() && exit_code != 0 {
+        exit_code = ZoomError::Interrupted.exit_code();
     }
+    if exit_code != 0 {
+        std::process::exit(exit_code);
+    }
+}
+
+/// Displays the result of a download, and returns the error if it failed
+fn report(args: &Arguments, result: Result<std::path::PathBuf, ZoomError>) -> Result<(), ZoomError> {
+    match result {
+        Err(err @ ZoomError::ImageTooSmall { .. }) => {
+            warn!("{}", err);
+            Err(err)
+        }
+        Err(err) => {
+            e_red_ln!("ERROR {}", err);
+            Err(err)
+        }
+        Ok(_) if !args.saves_image() || args.quiet => Ok(()),
+        Ok(saved_as) => {
+            green_ln!("Image successfully saved to '{}' (current working directory: {})",
+                     saved_as.to_string_lossy(),
+                     std::env::current_dir()
+                         .map(|p| p.to_string_lossy().to_string())
+                         .unwrap_or_else(|_e| "unknown".into())
+            );
+            Ok(())
+        }
+    }
+}
+
+/// The first Ctrl-C stops the download and saves the tiles that were received, the second one quits immediately
+fn cancel_on_ctrl_c(cancel: CancellationToken) {
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() { return; }
+        e_red_ln!("Stopping the download. Press Ctrl-C again to quit immediately.");
+        cancel.cancel();
+        if tokio::signal::ctrl_c().await.is_ok() { std::process::exit(ZoomError::Interrupted.exit_code()); }
+    });
+}
+
+/// --version makes the argument parser print the version and exit,
+/// so --version --verbose has to be recognized before parsing the arguments
+fn wants_build_info() -> bool {
+    let args: Vec<_> = std::env::args_os().skip(1).collect();
+    let has = |names: &[&str]| args.iter().any(|arg| names.iter().any(|name| arg == name));
+    has(&["--version", "-V"]) && has(&["--verbose", "-v"])
 }
 
 fn init_log(args: &Arguments) {
-    let env = env_logger::Env::new().default_filter_or(&args.logging);
+    let env = env_logger::Env::new().default_filter_or(args.log_level());
     env_logger::init_from_env(env);
-}
\ No newline at end of file
+}
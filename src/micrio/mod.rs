@@ -0,0 +1,159 @@
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+use custom_error::custom_error;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::dezoomer::*;
+use crate::json_utils::number_or_string;
+
+/// A dezoomer for images hosted on Micrio
+/// See https://micr.io/
+#[derive(Default)]
+pub struct Micrio;
+
+const MICRIO_BASE_URL: &str = "https://b.micr.io";
+
+/// Extracts the id of a micrio image from the URL of a viewer page or of an image
+fn parse_image_id(url: &str) -> Option<&str> {
+    lazy_static! {
+        static ref ID_RE: Regex = Regex::new(
+            r"^https?://(?:[a-z]\.)?micr\.io/(?:i/)?([a-zA-Z0-9]+)"
+        ).unwrap();
+    }
+    ID_RE.captures(url)
+        .and_then(|cap| cap.get(1))
+        .map(|m| m.as_str())
+}
+
+fn info_url(image_id: &str) -> String {
+    format!("{}/{}/info.json", MICRIO_BASE_URL, image_id)
+}
+
+impl Dezoomer for Micrio {
+    fn name(&self) -> &'static str { "micrio" }
+    fn description(&self) -> &str { "Images hosted on Micrio, from their viewer page or their metadata file" }
+    fn url_patterns(&self) -> Vec<&str> { vec!["https://micr.io/i/*", "https://b.micr.io/*/info.json"] }
+    fn examples(&self) -> Vec<&str> { vec!["https://micr.io/i/abcde/", "https://b.micr.io/abcde/info.json"] }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let image_id = parse_image_id(&data.uri);
+        self.assert(image_id.is_some())?;
+        let image_id = image_id.unwrap_or_default();
+        let info_uri = info_url(image_id);
+        if data.uri != info_uri {
+            return Err(DezoomerError::NeedsData { uri: info_uri });
+        }
+        let DezoomerInputWithContents { contents, .. } = data.with_contents()?;
+        let levels = load_from_info(image_id, contents).map_err(DezoomerError::wrap)?;
+        Ok(levels)
+    }
+}
+
+custom_error! {pub MicrioError
+    BadMetadata{source: serde_json::Error} = "Invalid micrio metadata: {source}",
+    InvalidTileSize = "Invalid tile size. The tile size cannot be zero.",
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct MicrioInfo {
+    #[serde(deserialize_with = "number_or_string")]
+    width: u32,
+    #[serde(deserialize_with = "number_or_string")]
+    height: u32,
+    #[serde(rename = "tileSize", alias = "tilesize", default = "default_tile_size", deserialize_with = "number_or_string")]
+    tile_size: u32,
+    #[serde(default = "default_format")]
+    format: String,
+    #[serde(default)]
+    title: Option<String>,
+}
+
+fn default_tile_size() -> u32 { 1024 }
+
+fn default_format() -> String { "jpg".into() }
+
+fn load_from_info(image_id: &str, contents: &[u8]) -> Result<ZoomLevels, MicrioError> {
+    let info: MicrioInfo = serde_json::from_slice(contents)?;
+    if info.tile_size == 0 {
+        return Err(MicrioError::InvalidTileSize);
+    }
+    let size = Vec2d { x: info.width, y: info.height };
+    let info = Arc::new(info);
+    let image_id: Arc<str> = Arc::from(image_id);
+    // Level 0 is the full-resolution image, and each subsequent level is twice smaller
+    let levels = std::iter::successors(Some(size), |&size| {
+        if size.x > 1 || size.y > 1 {
+            Some(size.ceil_div(Vec2d::square(2)))
+        } else {
+            None
+        }
+    })
+        .enumerate()
+        .map(|(level, size)| MicrioLevel {
+            image_id: Arc::clone(&image_id),
+            info: Arc::clone(&info),
+            size,
+            level: level as u32,
+        })
+        .into_zoom_levels();
+    Ok(levels)
+}
+
+struct MicrioLevel {
+    image_id: Arc<str>,
+    info: Arc<MicrioInfo>,
+    size: Vec2d,
+    level: u32,
+}
+
+impl TilesRect for MicrioLevel {
+    fn size(&self) -> Vec2d { self.size }
+
+    fn tile_size(&self) -> Vec2d { Vec2d::square(self.info.tile_size) }
+
+    fn tile_url(&self, Vec2d { x, y }: Vec2d) -> String {
+        format!(
+            "{base}/{id}/{level}/{x}-{y}.{format}",
+            base = MICRIO_BASE_URL,
+            id = self.image_id,
+            level = self.level,
+            x = x,
+            y = y,
+            format = self.info.format,
+        )
+    }
+
+    fn title(&self) -> Option<String> {
+        self.info.title.clone()
+    }
+}
+
+impl Debug for MicrioLevel {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Micrio image {}", self.image_id)
+    }
+}
+
+#[test]
+fn test_parse_image_id() {
+    assert_eq!(parse_image_id("https://micr.io/i/dnIzS/le-jardin"), Some("dnIzS"));
+    assert_eq!(parse_image_id("https://i.micr.io/dnIzS"), Some("dnIzS"));
+    assert_eq!(parse_image_id("https://b.micr.io/dnIzS/info.json"), Some("dnIzS"));
+    assert_eq!(parse_image_id("https://example.com/micr.io/dnIzS"), None);
+}
+
+#[test]
+fn test_load_from_info() {
+    let contents = br#"{"id":"dnIzS","width":3000,"height":2000,"tileSize":1024,"format":"webp"}"#;
+    let mut levels = load_from_info("dnIzS", contents).unwrap();
+    assert_eq!(levels.len(), 13);
+    let level = &mut levels[0];
+    assert_eq!(level.size_hint(), Some(Vec2d { x: 3000, y: 2000 }));
+    let tiles: Vec<String> = level.next_tiles(None).into_iter().map(|t| t.url).collect();
+    assert_eq!(tiles.len(), 6);
+    assert_eq!(tiles[0], "https://b.micr.io/dnIzS/0/0-0.webp");
+    assert_eq!(tiles[5], "https://b.micr.io/dnIzS/0/2-1.webp");
+}
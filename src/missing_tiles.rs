@@ -0,0 +1,209 @@
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+
+use futures::stream::StreamExt;
+use image::{DynamicImage, imageops, RgbaImage};
+use log::{debug, warn};
+
+use crate::{Arguments, Tile, Vec2d, ZoomError};
+use crate::download::download_tile;
+use crate::dezoomer::{TileReference, ZoomLevel};
+use crate::network::tile_client;
+
+/// What to do when a tile cannot be downloaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingTilesPolicy {
+    /// Stop and do not save an image with holes
+    Abort,
+    /// Leave an empty area in place of the missing tile
+    Blank,
+    /// Fill the missing tile by scaling up the same region from a lower zoom level
+    Interpolate,
+}
+
+impl FromStr for MissingTilesPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(MissingTilesPolicy::Abort),
+            "blank" => Ok(MissingTilesPolicy::Blank),
+            "interpolate" => Ok(MissingTilesPolicy::Interpolate),
+            _ => Err("Invalid missing tiles policy. Expected 'abort', 'blank' or 'interpolate'"),
+        }
+    }
+}
+
+/// A rectangular area of the image, in pixels
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hole {
+    pub position: Vec2d,
+    pub size: Vec2d,
+}
+
+/// Returns the largest level that is smaller than the given size,
+/// to be used to fill the holes left by missing tiles
+pub fn fallback_level(levels: Vec<ZoomLevel>, size: Option<Vec2d>) -> Option<ZoomLevel> {
+    let size = size?;
+    levels.into_iter()
+        .filter(|l| l.size_hint().is_some_and(|s| s.area() < size.area()))
+        .max_by_key(|l| l.size_hint().map(|s| s.area()))
+}
+
+/// Create tiles that fill the given holes in an image of size `canvas_size`,
+/// using the tiles of a lower zoom level.
+/// Holes that cannot be filled are returned as empty tiles.
+pub async fn fill_holes(
+    mut level: ZoomLevel,
+    holes: &[Hole],
+    canvas_size: Vec2d,
+    args: &Arguments,
+) -> Result<Vec<Tile>, ZoomError> {
+    let empty_tiles = || holes.iter().map(|h| Tile::empty(h.position, h.size)).collect();
+    let lower_size = match level.size_hint() {
+        Some(size) => size,
+        None => return Ok(empty_tiles()),
+    };
+    let scale = Scale::new(canvas_size, lower_size);
+    let refs = level.next_tiles(None);
+    let extents = tile_extents(&refs, lower_size);
+
+    let regions: Vec<(Vec2d, Vec2d)> = holes.iter().map(|h| scale.region(h)).collect();
+    let needed: Vec<TileReference> = refs.into_iter()
+        .filter(|r| {
+            let end = r.position + extents[&r.position];
+            regions.iter().any(|&(start, size)| intersects(r.position, end, start, start + size))
+        })
+        .collect();
+    debug!("Downloading {} tiles from {:?} to fill {} holes", needed.len(), level, holes.len());
+
+    let level_headers = level.http_headers();
+    let http_client = tile_client(level_headers.iter().chain(args.headers()), args)?;
+    let post_process_fn = level.post_process_fn();
+    let lower_tiles: Vec<Tile> = futures::stream::iter(needed)
+        .map(|tile_ref| download_tile(post_process_fn.clone(), tile_ref, None, None, &http_client, args))
+        .buffer_unordered(args.parallelism)
+        .filter_map(|res| async move {
+            res.map(|(tile, _)| tile).map_err(|e| warn!("Unable to fill a missing tile: {}", e)).ok()
+        })
+        .collect().await;
+
+    Ok(holes.iter().zip(regions).map(|(hole, (start, size))| {
+        let mut region = RgbaImage::new(size.x, size.y);
+        for tile in lower_tiles.iter() {
+            copy_intersection(tile, &mut region, start);
+        }
+        let scaled_size = scale.up(size);
+        let scaled = imageops::resize(&region, scaled_size.x, scaled_size.y, imageops::FilterType::Triangle);
+        let offset = hole.position - scale.up(start).min(hole.position);
+        let cropped = DynamicImage::ImageRgba8(scaled).crop_imm(offset.x, offset.y, hole.size.x, hole.size.y);
+        Tile { image: cropped, position: hole.position, encoded: None }
+    }).collect())
+}
+
+struct Scale {
+    canvas_size: Vec2d,
+    lower_size: Vec2d,
+}
+
+impl Scale {
+    fn new(canvas_size: Vec2d, lower_size: Vec2d) -> Self {
+        Scale { canvas_size, lower_size }
+    }
+
+    /// The region of the lower level that covers the given hole
+    fn region(&self, hole: &Hole) -> (Vec2d, Vec2d) {
+        let down = |v: u32, lower: u32, canvas: u32| (u64::from(v) * u64::from(lower) / u64::from(canvas)) as u32;
+        let down_ceil = |v: u32, lower: u32, canvas: u32| {
+            (u64::from(v) * u64::from(lower)).div_ceil(u64::from(canvas)) as u32
+        };
+        let end = hole.position + hole.size;
+        let start = Vec2d {
+            x: down(hole.position.x, self.lower_size.x, self.canvas_size.x),
+            y: down(hole.position.y, self.lower_size.y, self.canvas_size.y),
+        };
+        let end = Vec2d {
+            x: down_ceil(end.x, self.lower_size.x, self.canvas_size.x).min(self.lower_size.x),
+            y: down_ceil(end.y, self.lower_size.y, self.canvas_size.y).min(self.lower_size.y),
+        };
+        (start, end.max(start + Vec2d::square(1)) - start)
+    }
+
+    /// Converts a size or a position in the lower level to the size of the canvas
+    fn up(&self, v: Vec2d) -> Vec2d {
+        let up = |v: u32, lower: u32, canvas: u32| (u64::from(v) * u64::from(canvas) / u64::from(lower)) as u32;
+        Vec2d {
+            x: up(v.x, self.lower_size.x, self.canvas_size.x),
+            y: up(v.y, self.lower_size.y, self.canvas_size.y),
+        }
+    }
+}
+
+/// Computes the size of each tile in a grid from the positions of the tiles
+fn tile_extents(refs: &[TileReference], size: Vec2d) -> HashMap<Vec2d, Vec2d> {
+    let xs: BTreeSet<u32> = refs.iter().map(|r| r.position.x).collect();
+    let ys: BTreeSet<u32> = refs.iter().map(|r| r.position.y).collect();
+    let next = |set: &BTreeSet<u32>, v: u32, max: u32| set.range(v + 1..).next().copied().unwrap_or(max);
+    refs.iter().map(|r| {
+        let Vec2d { x, y } = r.position;
+        let end = Vec2d { x: next(&xs, x, size.x), y: next(&ys, y, size.y) };
+        (r.position, end.max(r.position) - r.position)
+    }).collect()
+}
+
+fn intersects(a_start: Vec2d, a_end: Vec2d, b_start: Vec2d, b_end: Vec2d) -> bool {
+    a_start.x < b_end.x && b_start.x < a_end.x && a_start.y < b_end.y && b_start.y < a_end.y
+}
+
+/// Copy the part of the tile that is inside the region starting at `region_start`
+fn copy_intersection(tile: &Tile, region: &mut RgbaImage, region_start: Vec2d) {
+    let region_end = region_start + Vec2d::from(region.dimensions());
+    let start = tile.position.max(region_start);
+    let end = tile.bottom_right().min(region_end);
+    if start.x >= end.x || start.y >= end.y { return; }
+    let size = end - start;
+    let from = start - tile.position;
+    let part = tile.image.crop_imm(from.x, from.y, size.x, size.y).to_rgba8();
+    let to = start - region_start;
+    imageops::replace(region, &part, to.x, to.y);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        assert_eq!("abort".parse(), Ok(MissingTilesPolicy::Abort));
+        assert_eq!("interpolate".parse(), Ok(MissingTilesPolicy::Interpolate));
+        assert!("nope".parse::<MissingTilesPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_region() {
+        let scale = Scale::new(Vec2d { x: 1000, y: 800 }, Vec2d { x: 500, y: 400 });
+        let hole = Hole { position: Vec2d { x: 256, y: 512 }, size: Vec2d { x: 256, y: 256 } };
+        assert_eq!(scale.region(&hole), (Vec2d { x: 128, y: 256 }, Vec2d { x: 128, y: 128 }));
+        assert_eq!(scale.up(Vec2d { x: 128, y: 256 }), Vec2d { x: 256, y: 512 });
+    }
+
+    #[test]
+    fn test_tile_extents() {
+        let refs: Vec<TileReference> = vec!["0 0 a", "256 0 b", "0 256 c", "256 256 d"]
+            .into_iter().map(|s| s.parse().unwrap()).collect();
+        let extents = tile_extents(&refs, Vec2d { x: 300, y: 400 });
+        assert_eq!(extents[&Vec2d { x: 0, y: 0 }], Vec2d { x: 256, y: 256 });
+        assert_eq!(extents[&Vec2d { x: 256, y: 256 }], Vec2d { x: 44, y: 144 });
+    }
+
+    #[test]
+    fn test_copy_intersection() {
+        let mut image = RgbaImage::new(4, 4);
+        image.put_pixel(3, 3, image::Rgba([255, 0, 0, 255]));
+        let tile = Tile { image: DynamicImage::ImageRgba8(image), position: Vec2d { x: 10, y: 10 }, encoded: None };
+        let mut region = RgbaImage::new(2, 2);
+        copy_intersection(&tile, &mut region, Vec2d { x: 12, y: 12 });
+        assert_eq!(region.get_pixel(1, 1), &image::Rgba([255, 0, 0, 255]));
+        assert_eq!(region.get_pixel(0, 0), &image::Rgba([0, 0, 0, 0]));
+    }
+}
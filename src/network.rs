@@ -1,94 +1,579 @@
+use futures::future::BoxFuture;
 use log::debug;
-use reqwest::{Client, header};
+use regex::bytes::Regex;
+use lazy_static::lazy_static;
+use reqwest::{Client, header, Method, RequestBuilder, StatusCode};
 use std::collections::HashMap;
-use std::iter::once;
-use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::fs;
 use url::Url;
 
 use crate::arguments::Arguments;
+use crate::dezoomer::{BootstrapRequest, TileReference, TileRequest};
+use crate::audit_log::{AuditEntry, record};
+use crate::throttler;
+use crate::default_headers::default_headers;
+use crate::cookies::cookie_jar;
 use crate::ZoomError;
+use crate::errors::PartialData;
+use crate::http_client::{HttpClient, HttpResponse};
+use crate::http_fixtures::{Recorder, Replayer};
 
 /// Fetch data, either from an URL or a path to a local file.
 /// If uri doesnt start with "http(s)://", it is considered to be a path
 /// to a local file
 // TODO: return Bytes
-pub async fn fetch_uri(uri: &str, http: &Client) -> Result<Vec<u8>, ZoomError> {
+pub async fn fetch_uri(uri: &str, http: &Fetcher) -> Result<Vec<u8>, ZoomError> {
+    fetch_uri_with_headers(uri, http).await.map(|(_, contents)| contents)
+}
+
+/// Fetch an URL or read a local file, and return the headers of the response with its body.
+/// Local files have no headers.
+pub async fn fetch_uri_with_headers(uri: &str, http: &Fetcher) -> Result<(header::HeaderMap, Vec<u8>), ZoomError> {
     if uri.starts_with("http://") || uri.starts_with("https://") {
         debug!("Loading url: '{}'", uri);
-        let response = http.get(uri).send()
-            .await?.error_for_status()?;
-        let mut contents = Vec::new();
-        let bytes = response.bytes().await?;
-        contents.extend(bytes);
+        let result = fetch_url_with_headers(uri, None, http).await;
         debug!("Loaded url: '{}'", uri);
-        Ok(contents)
+        result
     } else {
         debug!("Loading file: '{}'", uri);
         let result = fs::read(uri).await?;
         debug!("Loaded file: '{}'", uri);
-        Ok(result)
+        Ok((header::HeaderMap::new(), result))
+    }
+}
+
+/// Fetch a tile, with the method, the body and the headers of its request when it has one.
+/// Local files are read whatever their request.
+pub async fn fetch_tile(tile_reference: &TileReference, http: &Fetcher) -> Result<(header::HeaderMap, Vec<u8>), ZoomError> {
+    let uri = &tile_reference.url;
+    match &tile_reference.request {
+        Some(request) if uri.starts_with("http://") || uri.starts_with("https://") => {
+            debug!("Loading url: '{}' with a {} request", uri, request.method);
+            fetch_url_with_headers(uri, Some(request), http).await
+        }
+        _ => fetch_uri_with_headers(uri, http).await,
+    }
+}
+
+/// Fetch an URL, and return both the headers and the body of the response
+async fn fetch_url_with_headers(
+    uri: &str,
+    request: Option<&TileRequest>,
+    http: &Fetcher,
+) -> Result<(header::HeaderMap, Vec<u8>), ZoomError> {
+    let host = Url::parse(uri).ok()
+        .and_then(|url| url.host_str().map(String::from))
+        .unwrap_or_default();
+    throttler::wait_for(&host).await;
+    let mut headers = http.headers.clone();
+    if let Some(token) = BEARER_TOKENS.read().unwrap().get(&host) {
+        headers.insert(header::AUTHORIZATION, format!("Bearer {}", token).parse()?);
+    }
+    if http.origin_referer {
+        if let Some(origin) = Url::parse(uri).ok().map(|url| url.origin()).filter(|o| o.is_tuple()) {
+            headers.insert(header::REFERER, format!("{}/", origin.ascii_serialization()).parse()?);
+        }
+    }
+    let result = match request {
+        None => http.client.get(uri, &headers).await,
+        Some(request) => {
+            for (name, value) in &request.headers {
+                headers.insert(name.parse::<header::HeaderName>()?, value.parse()?);
+            }
+            let method = Method::from_bytes(request.method.as_bytes()).map_err(|e| ZoomError::RequestFailed {
+                url: uri.to_string(),
+                reason: format!("invalid method '{}': {}", request.method, e),
+            })?;
+            match (&method, &request.body) {
+                (&Method::GET, None) => http.client.get(uri, &headers).await,
+                (_, body) => http.client.send(&method, uri, &headers, body.as_deref().unwrap_or("").as_bytes()).await,
+            }
+        }
+    };
+    report(uri, &host, &result);
+    result
+}
+
+/// Report the response to a request to the throttler and to the audit log, whatever the client that made it
+fn report(uri: &str, host: &str, result: &Result<HttpResponse, ZoomError>) {
+    match result {
+        Ok((headers, contents)) => {
+            // Clients only return the responses with a success status
+            throttler::report(host, StatusCode::OK, None);
+            record(|| AuditEntry::request(uri, Some(200), Some(headers)).with_bytes(contents.len() as u64));
+        }
+        Err(e) => {
+            let status = e.http_status();
+            if let Some(status) = status.and_then(|status| StatusCode::from_u16(status).ok()) {
+                throttler::report(host, status, e.retry_after());
+            }
+            let entry = AuditEntry::request(uri, status, None).with_error(e);
+            record(|| match e {
+                ZoomError::PartialBody { contents, .. } => entry.with_bytes(contents.0.len() as u64),
+                _ => entry,
+            });
+        }
+    }
+}
+
+/// The client that makes the requests of a download, with the headers to send with them
+#[derive(Clone)]
+pub struct Fetcher {
+    client: Arc<dyn HttpClient>,
+    headers: header::HeaderMap,
+    /// Whether to send the address of the site as the Referer, when no other Referer is known
+    origin_referer: bool,
+}
+
+/// The default http client
+impl HttpClient for Client {
+    fn get<'a>(&'a self, uri: &'a str, headers: &'a header::HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(send_request(Client::get(self, uri), uri, headers))
+    }
+
+    fn send<'a>(
+        &'a self,
+        method: &'a Method,
+        uri: &'a str,
+        headers: &'a header::HeaderMap,
+        body: &'a [u8],
+    ) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(send_request(self.request(method.clone(), uri).body(body.to_vec()), uri, headers))
+    }
+}
+
+/// A client that sends all its requests with HTTP/3, over QUIC
+#[cfg(feature = "http3")]
+struct Http3Client(Client);
+
+#[cfg(feature = "http3")]
+impl HttpClient for Http3Client {
+    fn get<'a>(&'a self, uri: &'a str, headers: &'a header::HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(send_request(self.0.get(uri).version(reqwest::Version::HTTP_3), uri, headers))
     }
+
+    fn send<'a>(
+        &'a self,
+        method: &'a Method,
+        uri: &'a str,
+        headers: &'a header::HeaderMap,
+        body: &'a [u8],
+    ) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        let request = self.0.request(method.clone(), uri).version(reqwest::Version::HTTP_3);
+        Box::pin(send_request(request.body(body.to_vec()), uri, headers))
+    }
+}
+
+/// The default client, or a client that only uses HTTP/3 with --http3
+#[cfg(feature = "http3")]
+fn reqwest_http_client(client: Client, http3: bool) -> Arc<dyn HttpClient> {
+    if http3 { Arc::new(Http3Client(client)) } else { Arc::new(client) }
+}
+
+#[cfg(not(feature = "http3"))]
+fn reqwest_http_client(client: Client, _http3: bool) -> Arc<dyn HttpClient> {
+    Arc::new(client)
+}
+
+/// Sends a request with the given headers, and reads the whole response
+async fn send_request(request: RequestBuilder, uri: &str, headers: &header::HeaderMap) -> Result<HttpResponse, ZoomError> {
+    let request = request.headers(headers.clone());
+    let response = match request.send().await {
+        Ok(response) => response,
+        Err(e) => {
+            return Err(match login_redirect(&e) {
+                Some(location) => ZoomError::LoginRedirect { url: uri.to_string(), location },
+                None => e.into(),
+            });
+        }
+    };
+    let headers = response.headers().clone();
+    if response.status() == StatusCode::UNAUTHORIZED {
+        let e = response.error_for_status_ref().expect_err("401 is an error status");
+        // The body can describe how to authenticate
+        let contents = response.bytes().await.map(|b| b.to_vec()).unwrap_or_default();
+        return Err(ZoomError::Unauthorized { contents: PartialData(contents), source: e });
+    }
+    let response = match response.error_for_status() {
+        Ok(response) => response,
+        Err(e) => {
+            return Err(match throttler::retry_after(&headers) {
+                Some(delay) => ZoomError::RetryLater { retry_after_secs: delay.as_secs(), source: e },
+                None => e.into(),
+            });
+        }
+    };
+    let mut response = response;
+    let mut contents = Vec::new();
+    let read_timeout = *READ_TIMEOUT.read().unwrap();
+    loop {
+        let chunk = match read_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, response.chunk()).await {
+                Ok(chunk) => chunk,
+                Err(_) => return Err(ZoomError::ReadTimeout { timeout: format!("{:?}", timeout) }),
+            },
+            None => response.chunk().await,
+        };
+        match chunk {
+            Ok(Some(chunk)) => contents.extend_from_slice(&chunk),
+            Ok(None) => break,
+            Err(e) => {
+                if contents.is_empty() { return Err(e.into()); }
+                // Keep what was received, in case the caller can make use of it
+                return Err(ZoomError::PartialBody { contents: PartialData(contents), source: e });
+            }
+        }
+    }
+    Ok((headers, contents))
+}
+
+/// Make the bootstrap request of an image, and return the headers to send with its tile requests.
+/// The cookies set by the response are stored in the shared cookie jar.
+pub async fn bootstrap(request: &BootstrapRequest, http: &Fetcher) -> Result<HashMap<String, String>, ZoomError> {
+    debug!("Making the bootstrap request {:?}", request);
+    let (response_headers, contents) = fetch_url_with_headers(&request.url, None, http).await?;
+    bootstrap_headers(request, &response_headers, &contents)
+}
+
+/// Extract the headers to send with the tile requests from the response to a bootstrap request
+fn bootstrap_headers(
+    request: &BootstrapRequest,
+    response_headers: &header::HeaderMap,
+    contents: &[u8],
+) -> Result<HashMap<String, String>, ZoomError> {
+    let failed = |reason: String| ZoomError::Bootstrap { url: request.url.clone(), reason };
+    let mut headers = HashMap::new();
+    for name in &request.forward_headers {
+        let value = response_headers.get(name.as_str())
+            .ok_or_else(|| failed(format!("the response has no '{}' header", name)))?;
+        let value = value.to_str().map_err(|e| failed(e.to_string()))?;
+        headers.insert(name.clone(), value.to_string());
+    }
+    if let Some(token) = &request.token {
+        let re = Regex::new(&token.pattern).map_err(|e| failed(e.to_string()))?;
+        let value = re.captures(contents)
+            .and_then(|c| c.get(1))
+            .ok_or_else(|| failed(format!("no token matching '{}' in the response", token.pattern)))?;
+        let value = String::from_utf8_lossy(value.as_bytes());
+        headers.insert(token.header.clone(), format!("{}{}", token.prefix, value));
+    }
+    Ok(headers)
+}
+
+lazy_static! {
+    static ref BEARER_TOKENS: RwLock<HashMap<String, String>> = RwLock::new(HashMap::new());
+    static ref READ_TIMEOUT: RwLock<Option<Duration>> = RwLock::new(None);
 }
 
+/// Set the maximum time to wait for new data while receiving a response
+pub fn set_read_timeout(timeout: Option<Duration>) {
+    *READ_TIMEOUT.write().unwrap() = timeout;
+}
+
+/// Send the given access token with all the following requests to the given host
+pub fn set_bearer_token(host: &str, token: &str) {
+    BEARER_TOKENS.write().unwrap().insert(host.to_string(), token.to_string());
+}
 
+/// A client for the requests made to find the image
 pub fn client<'a, I: Iterator<Item=(&'a String, &'a String)>>(
     headers: I,
     args: &Arguments,
     uri: Option<&str>,
-) -> Result<reqwest::Client, ZoomError> {
-    let referer = uri.or_else(|| args.input_uri.as_deref()).unwrap_or("").to_string();
+) -> Result<Fetcher, ZoomError> {
+    build_client(headers, args, uri, args.metadata_timeout.unwrap_or(args.timeout), reqwest::redirect::Policy::default())
+}
+
+/// A client for the tile requests. It refuses to follow redirections to login pages.
+pub fn tile_client<'a, I: Iterator<Item=(&'a String, &'a String)>>(
+    headers: I,
+    args: &Arguments,
+) -> Result<Fetcher, ZoomError> {
+    let policy = reqwest::redirect::Policy::custom(|attempt| {
+        let previous = attempt.previous();
+        if previous.len() >= MAX_REDIRECTS {
+            attempt.error("too many redirects")
+        } else if is_login_page(attempt.url()) && !previous.iter().any(is_login_page) {
+            let location = attempt.url().to_string();
+            attempt.error(LoginRedirect { location })
+        } else {
+            attempt.follow()
+        }
+    });
+    build_client(headers, args, None, args.tile_timeout.unwrap_or(args.timeout), policy)
+}
+
+const MAX_REDIRECTS: usize = 10;
+
+/// Whether a URL looks like the login page of a website or of an identity provider
+fn is_login_page(url: &Url) -> bool {
+    lazy_static! {
+        static ref LOGIN: regex::Regex = regex::Regex::new(
+            r"(?i)(^|[/._?&=-])(login|log-in|logon|signin|sign-in|sso|cas|auth|oauth2?|saml|idp|accounts)([/._?&=-]|$)"
+        ).unwrap();
+    }
+    let host = url.host_str().unwrap_or_default();
+    LOGIN.is_match(host) || LOGIN.is_match(url.path()) || url.query().is_some_and(|q| LOGIN.is_match(q))
+}
+
+/// The error returned by the redirection policy of tile clients
+#[derive(Debug)]
+struct LoginRedirect {
+    location: String,
+}
+
+impl std::fmt::Display for LoginRedirect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "redirected to the login page {}", self.location)
+    }
+}
+
+impl std::error::Error for LoginRedirect {}
+
+/// The login page to which a request was redirected, if it failed because of that
+fn login_redirect(error: &reqwest::Error) -> Option<String> {
+    let source = std::error::Error::source(error)?;
+    source.downcast_ref::<LoginRedirect>().map(|redirect| redirect.location.clone())
+}
+
+fn build_client<'a, I: Iterator<Item=(&'a String, &'a String)>>(
+    headers: I,
+    args: &Arguments,
+    uri: Option<&str>,
+    timeout: Duration,
+    redirect: reqwest::redirect::Policy,
+) -> Result<Fetcher, ZoomError> {
+    let referer_name = "Referer".to_string();
+    // The page the user opened, as a browser would send it
+    let referer = uri.or(args.input_uri.as_deref())
+        .filter(|uri| !args.no_auto_referer && (uri.starts_with("http://") || uri.starts_with("https://")))
+        .map(String::from);
     let header_map = default_headers()
         .iter()
-        .chain(once((&"Referer".to_string(), &referer)))
+        .chain(referer.iter().map(|referer| (&referer_name, referer)))
         .chain(headers.map(|(k, v)| (k, v)))
         .map(|(name, value)| Ok((name.parse()?, value.parse()?)))
         .collect::<Result<header::HeaderMap, ZoomError>>()?;
+    let origin_referer = !args.no_auto_referer && !header_map.contains_key(header::REFERER);
+    if let Some(dir) = &args.replay_http {
+        return Ok(Fetcher { client: Arc::new(Replayer::new(dir)), headers: header_map, origin_referer });
+    }
+    let (client, headers): (Arc<dyn HttpClient>, _) = match &args.http_client {
+        Some(client) => (Arc::clone(&client.0), header_map),
+        None => {
+            let client = build_reqwest_client(header_map, args, timeout, redirect)?;
+            (reqwest_http_client(client, args.http3), header::HeaderMap::new())
+        }
+    };
+    let client = match &args.record_http {
+        Some(dir) => Arc::new(Recorder::new(client, dir)),
+        None => client,
+    };
+    Ok(Fetcher { client, headers, origin_referer })
+}
+
+/// The version of the HTTP protocol used to talk to the servers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    /// HTTP/2 when the server offers it over HTTPS, and HTTP/1.1 otherwise
+    Auto,
+    /// HTTP/1.1 only, the default
+    Http1,
+    /// HTTP/2 only, including with servers that do not use HTTPS
+    Http2,
+}
+
+impl FromStr for HttpVersion {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(HttpVersion::Auto),
+            "1.1" | "1" => Ok(HttpVersion::Http1),
+            "2" => Ok(HttpVersion::Http2),
+            _ => Err("Invalid HTTP version. Expected 'auto', '1.1' or '2'"),
+        }
+    }
+}
+
+/// HTTP/3 is only available when dezoomify-rs is built with the http3 feature
+#[cfg(feature = "http3")]
+fn http3(builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder, ZoomError> {
+    Ok(builder.http3_prior_knowledge())
+}
+
+#[cfg(not(feature = "http3"))]
+fn http3(_builder: reqwest::ClientBuilder) -> Result<reqwest::ClientBuilder, ZoomError> {
+    Err(ZoomError::Http3Unsupported)
+}
+
+#[cfg(not(feature = "http3"))]
+fn http1(builder: reqwest::ClientBuilder, args: &Arguments) -> Result<reqwest::ClientBuilder, ZoomError> {
+    // Without ALPN, servers cannot offer HTTP/2
+    let tls = native_tls::TlsConnector::builder()
+        .danger_accept_invalid_certs(args.accept_invalid_certs)
+        .build()?;
+    Ok(builder.use_preconfigured_tls(tls))
+}
+
+/// rustls only offers HTTP/1.1 during the TLS handshake when the client only uses HTTP/1.1
+#[cfg(feature = "http3")]
+fn http1(builder: reqwest::ClientBuilder, _args: &Arguments) -> Result<reqwest::ClientBuilder, ZoomError> {
+    Ok(builder.http1_only())
+}
+
+fn build_reqwest_client(
+    header_map: header::HeaderMap,
+    args: &Arguments,
+    timeout: Duration,
+    redirect: reqwest::redirect::Policy,
+) -> Result<Client, ZoomError> {
     debug!("Creating an http client with the following headers: {:?}", header_map);
-    let client = reqwest::Client::builder()
+    let mut builder = reqwest::Client::builder();
+    // With the http3 feature, reqwest requires all the connections to use rustls
+    #[cfg(feature = "http3")]
+    {
+        builder = builder.use_rustls_tls();
+    }
+    if let Some(proxy) = &args.proxy {
+        builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+    }
+    match args.http_version {
+        _ if args.http3 => builder = http3(builder)?,
+        // ALPN lets servers offer HTTP/2 during the TLS handshake
+        HttpVersion::Auto => {}
+        HttpVersion::Http1 => builder = http1(builder, args)?,
+        HttpVersion::Http2 => builder = builder.http2_prior_knowledge().http2_adaptive_window(true),
+    }
+    let client = builder
         .default_headers(header_map)
+        .cookie_provider(cookie_jar())
         .referer(false)
+        .redirect(redirect)
         .pool_max_idle_per_host(args.max_idle_per_host)
         .danger_accept_invalid_certs(args.accept_invalid_certs)
-        .timeout(args.timeout)
+        .connect_timeout(args.connect_timeout)
+        .tcp_keepalive(args.tcp_keepalive)
+        .timeout(timeout)
         .build()?;
     Ok(client)
 }
 
-pub fn default_headers() -> HashMap<String, String> {
-    serde_yaml::from_str(include_str!("default_headers.yaml")).unwrap()
+#[test]
+fn test_is_login_page() {
+    let login = |url: &str| is_login_page(&Url::parse(url).unwrap());
+    assert!(login("https://example.com/login?next=/iiif/tile.jpg"));
+    assert!(login("https://example.com/users/sign-in"));
+    assert!(login("https://sso.example.org/idp/profile/SAML2/Redirect/SSO"));
+    assert!(login("https://example.com/cas/login"));
+    assert!(login("https://accounts.example.com/"));
+    assert!(!login("https://example.com/iiif/2/catalog%2Fimage/0,0,512,512/512,/0/default.jpg"));
+    assert!(!login("https://cdn.example.com/tiles/author/3/1_2.jpg"));
+    assert!(!login("https://example.com/blogin/tile.jpg"));
 }
 
-pub fn resolve_relative(base: &str, path: &str) -> String {
-    if Url::parse(path).is_ok() {
-        return path.to_string()
-    } else if let Ok(url) = Url::parse(base) {
-        if let Ok(r) = url.join(path) {
-            return r.to_string()
+#[test]
+fn test_bootstrap_headers() {
+    use crate::dezoomer::TokenCapture;
+    let request = BootstrapRequest {
+        forward_headers: vec!["X-Session".into()],
+        token: Some(TokenCapture {
+            pattern: r#""token":\s*"(\w+)""#.into(),
+            header: "Authorization".into(),
+            prefix: "Bearer ".into(),
+        }),
+        ..BootstrapRequest::new("http://example.com/session".into())
+    };
+    let mut response_headers = header::HeaderMap::new();
+    response_headers.insert("x-session", "abc".parse().unwrap());
+    let headers = bootstrap_headers(&request, &response_headers, br#"{"token": "xyz"}"#).unwrap();
+    assert_eq!(headers["X-Session"], "abc");
+    assert_eq!(headers["Authorization"], "Bearer xyz");
+    let err = bootstrap_headers(&request, &response_headers, b"{}").unwrap_err();
+    assert!(matches!(err, ZoomError::Bootstrap { .. }));
+}
+
+#[tokio::test]
+#[allow(clippy::field_reassign_with_default)]
+async fn test_auto_referer() {
+    use std::sync::Mutex;
+    use crate::http_client::SharedHttpClient;
+    #[derive(Default)]
+    struct RefererLog(Mutex<Vec<Option<String>>>);
+    impl HttpClient for RefererLog {
+        fn get<'a>(&'a self, _url: &'a str, headers: &'a header::HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+            let referer = headers.get(header::REFERER).and_then(|v| v.to_str().ok()).map(String::from);
+            self.0.lock().unwrap().push(referer);
+            Box::pin(async { Ok((header::HeaderMap::new(), vec![])) })
         }
     }
-    let mut res = PathBuf::from(base.rsplitn(2, '/').last().unwrap_or_default());
-    res.push(path);
-    res.to_string_lossy().to_string()
+    let log = Arc::new(RefererLog::default());
+    let fetch_tile_with = |input_uri: &str, no_auto_referer: bool| {
+        let mut args = Arguments::default();
+        args.input_uri = Some(input_uri.into());
+        args.no_auto_referer = no_auto_referer;
+        args.http_client = Some(SharedHttpClient(log.clone()));
+        async move {
+            let http = tile_client(std::iter::empty(), &args).unwrap();
+            fetch_uri("https://tiles.example.com/a/1.jpg", &http).await.unwrap();
+        }
+    };
+    fetch_tile_with("https://museum.example.com/viewer?id=1", false).await;
+    fetch_tile_with("saved/info.json", false).await;
+    fetch_tile_with("https://museum.example.com/viewer?id=1", true).await;
+    assert_eq!(*log.0.lock().unwrap(), vec![
+        Some("https://museum.example.com/viewer?id=1".to_string()),
+        Some("https://tiles.example.com/".to_string()),
+        None,
+    ]);
 }
 
-pub fn remove_bom(contents: &[u8]) -> &[u8] {
-    // Workaround for https://github.com/netvl/xml-rs/issues/155
-    // which the original author seems unwilling to fix
-    const BOM: &[u8] = &[0xEF, 0xBB, 0xBF]; // UTF8 byte order mark
-    if contents.starts_with(BOM) {
-        &contents[BOM.len()..]
-    } else { contents }
+#[tokio::test]
+#[allow(clippy::field_reassign_with_default)]
+async fn test_custom_client_status() {
+    use crate::http_client::SharedHttpClient;
+    struct Server;
+    impl HttpClient for Server {
+        fn get<'a>(&'a self, url: &'a str, _headers: &'a header::HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+            let (status, retry_after_secs) = if url.ends_with("private.json") { (401, 0) } else { (429, 5) };
+            let contents = PartialData(b"log in first".to_vec());
+            Box::pin(async move { Err(ZoomError::HttpStatus { url: url.to_string(), status, retry_after_secs, contents }) })
+        }
+    }
+    let mut args = Arguments::default();
+    args.http_client = Some(SharedHttpClient(Arc::new(Server)));
+    let http = client(std::iter::empty(), &args, None).unwrap();
+    let err = fetch_uri("https://example.com/private.json", &http).await.unwrap_err();
+    assert_eq!((err.http_status(), err.exit_code()), (Some(401), 4));
+    assert_eq!(err.unauthorized_contents(), Some(&b"log in first"[..]));
+    let err = fetch_uri("https://example.com/busy.json", &http).await.unwrap_err();
+    assert_eq!((err.http_status(), err.exit_code()), (Some(429), 3));
+    assert_eq!(err.retry_after(), Some(Duration::from_secs(5)));
+    assert_eq!(err.unauthorized_contents(), None);
 }
 
-#[test]
-fn test_resolve_relative() {
-    use std::path::MAIN_SEPARATOR;
-    assert_eq!(resolve_relative("/a/b", "c/d"), format!("/a{}c/d", MAIN_SEPARATOR));
-    assert_eq!(resolve_relative("C:\\X", "c/d"), format!("C:\\X{}c/d", MAIN_SEPARATOR));
-    assert_eq!(resolve_relative("/a/b", "http://example.com/x"), "http://example.com/x");
-    assert_eq!(resolve_relative("http://a.b", "http://example.com/x"), "http://example.com/x");
-    assert_eq!(resolve_relative("http://a.b", "c/d"), "http://a.b/c/d");
-    assert_eq!(resolve_relative("http://a.b/x", "c/d"), "http://a.b/c/d");
-    assert_eq!(resolve_relative("http://a.b/x/", "c/d"), "http://a.b/x/c/d");
-}
\ No newline at end of file
+#[allow(clippy::field_reassign_with_default)]
+#[tokio::test]
+async fn test_http_versions() {
+    assert_eq!("1.1".parse(), Ok(HttpVersion::Http1));
+    assert_eq!("2".parse(), Ok(HttpVersion::Http2));
+    assert!("3".parse::<HttpVersion>().is_err());
+    let mut args = Arguments::default();
+    assert_eq!(args.http_version, HttpVersion::Http1);
+    args.tcp_keepalive = Some(Duration::from_secs(30));
+    for &version in &[HttpVersion::Auto, HttpVersion::Http1, HttpVersion::Http2] {
+        args.http_version = version;
+        let client = build_reqwest_client(header::HeaderMap::new(), &args, args.timeout, reqwest::redirect::Policy::default());
+        assert!(client.is_ok(), "{:?}: {:?}", version, client.err());
+    }
+    args.http3 = true;
+    let client = build_reqwest_client(header::HeaderMap::new(), &args, args.timeout, reqwest::redirect::Policy::default());
+    if cfg!(feature = "http3") {
+        assert!(client.is_ok(), "HTTP/3: {:?}", client.err());
+    } else {
+        assert!(matches!(client, Err(ZoomError::Http3Unsupported)));
+    }
+}
@@ -7,17 +7,33 @@ use custom_error::custom_error;
 use regex::Regex;
 use serde::Deserialize;
 
-use crate::dezoomer::{TilesRect, Dezoomer, DezoomerInput, ZoomLevels, DezoomerError, IntoZoomLevels, DezoomerInputWithContents, TileReference};
+use crate::dezoomer::{BootstrapRequest, TilesRect, Dezoomer, DezoomerInput, ZoomLevels, DezoomerError, IntoZoomLevels, DezoomerInputWithContents, TileReference, PageContents};
+use crate::iiif::IIIF;
 use crate::json_utils::number_or_string;
 use crate::Vec2d;
 
-/// A dezoomer for NYPL images
+/// A dezoomer for NYPL images.
+/// The digital collections website now serves its images with IIIF,
+/// but the older tile server is still used when an item page does not reference any IIIF image.
 #[derive(Default)]
 pub struct NYPLImage;
 
 const NYPL_IMAGE_VIEW_PREFIX: &str = "https://digitalcollections.nypl.org/items/";
 const NYPL_META_PREFIX: &str = "https://access.nypl.org/image.php/";
 const NYPL_META_POSTFIX: &str = "/tiles/config.js";
+const NYPL_IIIF_PREFIX: &str = "https://iiif.nypl.org/iiif/3/";
+
+/// The id of an image on the IIIF server, in its URLs, or in the old images.nypl.org links
+fn parse_iiif_image_id(text: &str) -> Option<String> {
+    Regex::new(r"(?:iiif\.nypl\.org/iiif/[23]/|images\.nypl\.org/index\.php\?id=)([\w\-]+)").unwrap()
+        .captures(text)
+        .and_then(|cap| cap.get(1))
+        .map(|m| m.as_str().to_string())
+}
+
+fn iiif_info_url(image_id: &str) -> String {
+    format!("{}{}/info.json", NYPL_IIIF_PREFIX, image_id)
+}
 
 fn get_image_id_from_meta_url(meta_url: &str) -> String {
     meta_url.replace(NYPL_META_PREFIX, "")
@@ -33,14 +49,42 @@ fn parse_image_id(image_view_url: &str) -> Option<String> {
 
 impl Dezoomer for NYPLImage {
     fn name(&self) -> &'static str { "nypl" }
+    fn description(&self) -> &str { "Images from the digital collections of the New York Public Library" }
+    fn url_patterns(&self) -> Vec<&str> {
+        vec![
+            "https://digitalcollections.nypl.org/items/*",
+            "https://iiif.nypl.org/iiif/*",
+            "https://images.nypl.org/index.php?id=*",
+        ]
+    }
+    fn examples(&self) -> Vec<&str> {
+        vec![
+            "https://digitalcollections.nypl.org/items/a28d6e6b-b317-f008-e040-e00a1806635d",
+        ]
+    }
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
         if data.uri.starts_with(NYPL_IMAGE_VIEW_PREFIX) {
             let image_view_url = data.uri.as_str();
             let image_id = parse_image_id(image_view_url).ok_or_else(||
                 DezoomerError::wrap(NYPLError::NoIdInUrl { url: image_view_url.to_string() })
             )?;
-            let meta_uri = format!("{}{}{}", NYPL_META_PREFIX, image_id, NYPL_META_POSTFIX);
-            Err(DezoomerError::NeedsData { uri: meta_uri })
+            // The item page references the images of the item on the IIIF server
+            let iiif_image_id = match &data.contents {
+                PageContents::Unknown => return Err(DezoomerError::NeedsData { uri: data.uri.clone() }),
+                PageContents::Success(page) => parse_iiif_image_id(&String::from_utf8_lossy(page)),
+                PageContents::Error(_) => None,
+            };
+            let uri = match iiif_image_id {
+                Some(id) => iiif_info_url(&id),
+                None => format!("{}{}{}", NYPL_META_PREFIX, image_id, NYPL_META_POSTFIX),
+            };
+            Err(DezoomerError::NeedsData { uri })
+        } else if let Some(image_id) = parse_iiif_image_id(&data.uri) {
+            let info_url = iiif_info_url(&image_id);
+            if data.uri != info_url {
+                return Err(DezoomerError::NeedsData { uri: info_url });
+            }
+            IIIF.zoom_levels(data)
         } else {
             self.assert(data.uri.contains(NYPL_META_PREFIX))?;
             let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
@@ -113,8 +157,15 @@ impl TilesRect for Level {
         TileReference {
             url: self.tile_url(pos),
             position: self.tile_size() * pos - delta,
+            size: None,
+            request: None,
         }
     }
+
+    /// The tile server only answers to clients that have a session on the collections website
+    fn bootstrap_request(&self) -> Option<BootstrapRequest> {
+        Some(BootstrapRequest::new(format!("{}{}", NYPL_IMAGE_VIEW_PREFIX, self.base)))
+    }
 }
 
 #[derive(Debug, PartialEq, Deserialize)]
@@ -224,6 +275,10 @@ mod tests {
             a28d6e6b-b317-f008-e040-e00a1806635d\
             /tiles/0/12/0_0.png";
         assert_eq!(level.tile_url(Vec2d { x: 0, y: 0 }), expected_url);
+        assert_eq!(
+            TilesRect::bootstrap_request(&level).unwrap().url,
+            "https://digitalcollections.nypl.org/items/a28d6e6b-b317-f008-e040-e00a1806635d"
+        );
         assert_eq!(
             parse_image_id(
                 "https://digitalcollections.nypl.org/items/a14f3200-fac1-012f-f7a4-58d385a7bbd0#item-data"
@@ -231,4 +286,41 @@ mod tests {
             "a14f3200-fac1-012f-f7a4-58d385a7bbd0",
         )
     }
+
+    fn next_uri(uri: &str, contents: PageContents) -> String {
+        let data = DezoomerInput { uri: uri.to_string(), contents };
+        match NYPLImage.zoom_levels(&data) {
+            Err(DezoomerError::NeedsData { uri }) => uri,
+            Err(e) => panic!("Unexpected error: {}", e),
+            Ok(_) => panic!("Unexpected levels"),
+        }
+    }
+
+    #[test]
+    fn test_item_page() {
+        let item = "https://digitalcollections.nypl.org/items/510d47e2-8e1a-a3d9-e040-e00a18064a99";
+        assert_eq!(next_uri(item, PageContents::Unknown), item);
+        let page = r#"<html><script id="__NEXT_DATA__" type="application/json">{"props":{"pageProps":{
+            "item":{"uuid":"510d47e2-8e1a-a3d9-e040-e00a18064a99","imageIds":["1260237"],
+            "thumbnail":"https://iiif.nypl.org/iiif/3/1260237/full/!288,288/0/default.jpg"}}}}</script></html>"#;
+        assert_eq!(next_uri(item, PageContents::Success(page.as_bytes().to_vec())),
+                   "https://iiif.nypl.org/iiif/3/1260237/info.json");
+        // Items that are not on the IIIF server use the old tile server
+        let page = b"<html><body>No image</body></html>".to_vec();
+        assert_eq!(next_uri(item, PageContents::Success(page)),
+                   "https://access.nypl.org/image.php/510d47e2-8e1a-a3d9-e040-e00a18064a99/tiles/config.js");
+    }
+
+    #[test]
+    fn test_iiif_urls() {
+        let expected = "https://iiif.nypl.org/iiif/3/1260237/info.json";
+        assert_eq!(next_uri("https://iiif.nypl.org/iiif/2/1260237/full/full/0/default.jpg", PageContents::Unknown), expected);
+        assert_eq!(next_uri("http://images.nypl.org/index.php?id=1260237&t=w", PageContents::Unknown), expected);
+        let info = br#"{"@context": "http://iiif.io/api/image/3/context.json", "type": "ImageService3",
+            "id": "https://iiif.nypl.org/iiif/3/1260237", "profile": "level2",
+            "width": 2560, "height": 3200, "tiles": [{"width": 512, "scaleFactors": [1, 2, 4, 8]}]}"#;
+        let data = DezoomerInput { uri: expected.into(), contents: PageContents::Success(info.to_vec()) };
+        let levels = NYPLImage.zoom_levels(&data).unwrap();
+        assert_eq!(levels[0].size_hint(), Some(Vec2d { x: 2560, y: 3200 }));
+    }
 }
\ No newline at end of file
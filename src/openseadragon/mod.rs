@@ -0,0 +1,560 @@
+use std::fmt::Write as _;
+use std::sync::Arc;
+
+use custom_error::custom_error;
+use evalexpr::{Context, HashMapContext, IntType};
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::dezoomer::*;
+use crate::html_probe::page_title;
+use crate::url_utils::resolve_relative;
+
+/// A dezoomer for the tile sources defined inline in the configuration of OpenSeadragon viewers:
+/// legacy image pyramids, and custom tile sources that compute their tile URLs with a simple
+/// `getTileUrl` function.
+/// Tile sources given as URLs are found by the html_probe dezoomer,
+/// and inline DZI and IIIF descriptors are read by the deepzoom and IIIF dezoomers.
+/// See https://openseadragon.github.io/examples/tilesource-custom/
+#[derive(Default)]
+pub struct OpenSeadragon;
+
+impl Dezoomer for OpenSeadragon {
+    fn name(&self) -> &'static str {
+        "openseadragon"
+    }
+
+    fn description(&self) -> &str {
+        "Images described in the tileSources option of an OpenSeadragon viewer page"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["web pages with an OpenSeadragon viewer"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec!["https://example.com/viewer.html"]
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
+        let title = page_title(contents);
+        let contents = String::from_utf8_lossy(contents);
+        let sources = tile_sources(&contents);
+        self.assert(!sources.is_empty())?;
+        let levels: ZoomLevels = sources.iter()
+            .flat_map(|source| source.zoom_levels(uri))
+            .collect();
+        if levels.is_empty() {
+            return Err(DezoomerError::wrap(OpenSeadragonError::NoInlineSource));
+        }
+        Ok(with_default_title(levels, title))
+    }
+}
+
+custom_error! {pub OpenSeadragonError
+    NoInlineSource = "The OpenSeadragon tile sources in the page cannot be read directly",
+}
+
+lazy_static! {
+    static ref TILE_SOURCES_RE: Regex = Regex::new(r"\btileSources\s*[:=]\s*").unwrap();
+    static ref FUNCTION_RE: Regex = Regex::new(
+        r"(\w+)\s*(?::\s*function\s*\w*\s*)?\(([^()]*)\)\s*\{"
+    ).unwrap();
+    static ref RETURN_RE: Regex = Regex::new(r"(?s)^\s*return\b(.*?);?\s*$").unwrap();
+    static ref TEMPLATE_LITERAL_RE: Regex = Regex::new(r"\$\{([^}]*)\}").unwrap();
+}
+
+/// The value of a `tileSources` option: a single tile source, or an array of them
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum TileSources {
+    Many(Vec<TileSource>),
+    One(TileSource),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum TileSource {
+    Url(String),
+    Options(Box<TileSourceOptions>),
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+struct TileSourceOptions {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    /// Entry of a collection, with a position in the viewer: `{ tileSource: ..., x: 0, y: 0 }`
+    tile_source: Option<TileSource>,
+    #[serde(default)]
+    levels: Vec<LegacyLevel>,
+    width: Option<u32>,
+    height: Option<u32>,
+    tile_size: Option<u32>,
+    tile_width: Option<u32>,
+    tile_height: Option<u32>,
+    #[serde(default)]
+    tile_overlap: u32,
+    min_level: Option<u32>,
+    max_level: Option<u32>,
+    get_tile_url: Option<JsFunction>,
+    /// Base URL of a zoomify image, for the `zoomifytileservice` type
+    tiles_url: Option<String>,
+    #[serde(rename = "@id")]
+    iiif_id: Option<String>,
+    #[serde(rename = "@context")]
+    iiif_context: Option<serde_json::Value>,
+}
+
+/// A level of a legacy image pyramid: a single image at a given resolution
+#[derive(Deserialize, Debug)]
+struct LegacyLevel {
+    url: String,
+    width: u32,
+    height: u32,
+}
+
+/// The parameters and the source code of the body of a javascript function
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct JsFunction {
+    params: Vec<String>,
+    body: String,
+}
+
+impl TileSource {
+    fn options(&self) -> Option<&TileSourceOptions> {
+        match self {
+            TileSource::Url(_) => None,
+            TileSource::Options(options) => match &options.tile_source {
+                Some(inner) => inner.options(),
+                None => Some(options),
+            },
+        }
+    }
+
+    /// The address of a descriptor file for the image, to be opened by another dezoomer
+    fn descriptor_url(&self) -> Option<String> {
+        if let TileSource::Url(url) = self {
+            return Some(url.clone());
+        }
+        if let TileSource::Options(options) = self {
+            if let Some(inner) = &options.tile_source {
+                return inner.descriptor_url();
+            }
+        }
+        let options = self.options()?;
+        if options.kind.as_deref() == Some("zoomifytileservice") {
+            let base = options.tiles_url.as_deref()?.trim_end_matches('/');
+            return Some(format!("{}/ImageProperties.xml", base));
+        }
+        let is_iiif = options.iiif_context.as_ref()
+            .is_some_and(|context| context.to_string().contains("iiif.io"));
+        if is_iiif && (options.width.is_none() || options.height.is_none()) {
+            let id = options.iiif_id.as_deref()?.trim_end_matches('/');
+            return Some(format!("{}/info.json", id));
+        }
+        None
+    }
+
+    fn zoom_levels(&self, page_uri: &str) -> ZoomLevels {
+        let options = match self.options() { Some(options) => options, None => return vec![] };
+        if !options.levels.is_empty() {
+            return options.levels.iter()
+                .map(|level| PyramidLevel {
+                    url: resolve_relative(page_uri, &level.url),
+                    size: Vec2d { x: level.width, y: level.height },
+                })
+                .into_zoom_levels();
+        }
+        match (&options.get_tile_url, options.width, options.height) {
+            (Some(function), Some(width), Some(height)) => {
+                let template = match UrlTemplate::parse(function) {
+                    Some(template) => Arc::new(template),
+                    None => {
+                        warn!("Unable to understand the getTileUrl function of an OpenSeadragon tile source: {}",
+                              function.body.trim());
+                        return vec![];
+                    }
+                };
+                custom_levels(options, template, Vec2d { x: width, y: height }, page_uri)
+            }
+            _ => vec![],
+        }
+    }
+}
+
+fn custom_levels(options: &TileSourceOptions, template: Arc<UrlTemplate>, size: Vec2d, page_uri: &str) -> ZoomLevels {
+    let tile_size = Vec2d {
+        x: options.tile_width.or(options.tile_size).unwrap_or(256),
+        y: options.tile_height.or(options.tile_size).unwrap_or(256),
+    };
+    if tile_size.x == 0 || tile_size.y == 0 { return vec![]; }
+    // As in OpenSeadragon, the image has its full size at the highest level,
+    // and each level is twice as small as the next one.
+    let default_max_level = 32 - (size.x.max(size.y).max(1) - 1).leading_zeros();
+    let max_level = options.max_level.unwrap_or(default_max_level);
+    let min_level = options.min_level.unwrap_or(0).min(max_level);
+    let page_uri: Arc<str> = Arc::from(page_uri);
+    (min_level..=max_level).map(|level| {
+        let scale = 1u64 << (max_level - level).min(32);
+        let level_size = Vec2d {
+            x: u64::from(size.x).div_ceil(scale) as u32,
+            y: u64::from(size.y).div_ceil(scale) as u32,
+        };
+        CustomLevel {
+            template: Arc::clone(&template),
+            page_uri: Arc::clone(&page_uri),
+            level,
+            size: level_size,
+            tile_size,
+            overlap: options.tile_overlap,
+        }
+    }).into_zoom_levels()
+}
+
+/// Finds and parses the values of all the `tileSources` options in a page
+fn tile_sources(contents: &str) -> Vec<TileSource> {
+    TILE_SOURCES_RE.find_iter(contents)
+        .filter_map(|m| {
+            let code = &contents[m.end()..];
+            let code = &code[..value_len(code)?];
+            match json5::from_str::<TileSources>(&replace_functions(code)) {
+                Ok(sources) => Some(sources),
+                Err(e) => {
+                    debug!("Unable to parse the OpenSeadragon tile sources {}: {}", code, e);
+                    None
+                }
+            }
+        })
+        .flat_map(|sources| match sources {
+            TileSources::Many(sources) => sources,
+            TileSources::One(source) => vec![source],
+        })
+        .collect()
+}
+
+/// The addresses of the image descriptors (DZI, IIIF, Zoomify...) referenced by the
+/// OpenSeadragon tile sources of a page
+pub fn descriptor_urls(contents: &str) -> Vec<String> {
+    tile_sources(contents).iter().filter_map(TileSource::descriptor_url).collect()
+}
+
+/// Returns the length of the javascript array, object or string at the start of `code`,
+/// by looking for the matching closing bracket or quote
+fn value_len(code: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in code.char_indices() {
+        if let Some(q) = quote {
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == q {
+                quote = None;
+                if depth == 0 { return Some(i + 1); }
+            }
+            continue;
+        }
+        match c {
+            '"' | '\'' | '`' => quote = Some(c),
+            '[' | '{' | '(' => depth += 1,
+            ']' | '}' | ')' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 { return Some(i + 1); }
+            }
+            _ if depth == 0 => return None,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Replaces the functions defined in a javascript object by JSON objects
+/// containing their parameters and body, so that the object can be parsed as JSON5
+fn replace_functions(code: &str) -> String {
+    let mut out = String::with_capacity(code.len());
+    let mut rest = code;
+    while let Some(caps) = FUNCTION_RE.captures(rest) {
+        let m = caps.get(0).expect("a match");
+        let body_start = m.end() - 1;
+        let body_len = match value_len(&rest[body_start..]) {
+            Some(len) => len,
+            None => break,
+        };
+        let function = JsFunction {
+            params: caps[2].split(',')
+                .map(|p| p.trim().to_string())
+                .filter(|p| !p.is_empty())
+                .collect(),
+            body: rest[body_start + 1..body_start + body_len - 1].to_string(),
+        };
+        let json = serde_json::to_string(&function).expect("functions can be serialized");
+        write!(out, "{}{}: {}", &rest[..m.start()], &caps[1], json).unwrap();
+        rest = &rest[body_start + body_len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// A tile URL template extracted from a `getTileUrl(level, x, y)` function
+/// that returns a concatenation of strings and arithmetic expressions of its parameters
+#[derive(Debug, PartialEq)]
+struct UrlTemplate {
+    params: Vec<String>,
+    parts: Vec<TemplatePart>,
+}
+
+#[derive(Debug, PartialEq)]
+enum TemplatePart {
+    Constant(String),
+    Expression(String),
+}
+
+impl UrlTemplate {
+    fn parse(function: &JsFunction) -> Option<UrlTemplate> {
+        let caps = RETURN_RE.captures(&function.body)?;
+        let mut parts = vec![];
+        for term in split_concatenation(&caps[1]) {
+            let term = term.trim();
+            if term.starts_with('`') && term.ends_with('`') && term.len() > 1 {
+                let literal = &term[1..term.len() - 1];
+                let mut cursor = 0;
+                for caps in TEMPLATE_LITERAL_RE.captures_iter(literal) {
+                    let m = caps.get(0).expect("a match");
+                    parts.push(TemplatePart::Constant(literal[cursor..m.start()].to_string()));
+                    parts.push(TemplatePart::Expression(caps[1].trim().to_string()));
+                    cursor = m.end();
+                }
+                parts.push(TemplatePart::Constant(literal[cursor..].to_string()));
+            } else if term.starts_with('"') || term.starts_with('\'') {
+                parts.push(TemplatePart::Constant(json5::from_str(term).ok()?));
+            } else {
+                parts.push(TemplatePart::Expression(term.to_string()));
+            }
+        }
+        let template = UrlTemplate { params: function.params.clone(), parts };
+        // Only keep templates in which all the expressions can be evaluated
+        let ctx = template.variables(0, 0, 0);
+        let valid = template.parts.iter().all(|part| match part {
+            TemplatePart::Constant(_) => true,
+            TemplatePart::Expression(expr) => evalexpr::eval_int_with_context(expr, &ctx).is_ok(),
+        });
+        if valid { Some(template) } else { None }
+    }
+
+    fn variables(&self, level: u32, x: u32, y: u32) -> HashMapContext {
+        let mut ctx = HashMapContext::new();
+        for (name, &value) in self.params.iter().zip(&[level, x, y]) {
+            ctx.set_value(name.clone(), IntType::from(value).into())
+                .expect("integer variables can always be set");
+        }
+        ctx
+    }
+
+    fn url(&self, level: u32, x: u32, y: u32) -> String {
+        let ctx = self.variables(level, x, y);
+        self.parts.iter().map(|part| match part {
+            TemplatePart::Constant(s) => s.clone(),
+            TemplatePart::Expression(expr) => evalexpr::eval_int_with_context(expr, &ctx)
+                .map(|n| n.to_string())
+                .unwrap_or_default(),
+        }).collect()
+    }
+}
+
+/// Splits a javascript expression on the `+` operators that are not inside strings or parentheses
+fn split_concatenation(expr: &str) -> Vec<&str> {
+    let mut terms = vec![];
+    let mut start = 0;
+    let mut depth = 0usize;
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    for (i, c) in expr.char_indices() {
+        if let Some(q) = quote {
+            if escaped { escaped = false } else if c == '\\' { escaped = true } else if c == q { quote = None }
+            continue;
+        }
+        match c {
+            '"' | '\'' | '`' => quote = Some(c),
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            '+' if depth == 0 => {
+                terms.push(&expr[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    terms.push(&expr[start..]);
+    terms
+}
+
+/// A level of a legacy image pyramid, made of a single image
+#[derive(Debug)]
+struct PyramidLevel {
+    url: String,
+    size: Vec2d,
+}
+
+impl TilesRect for PyramidLevel {
+    fn size(&self) -> Vec2d { self.size }
+
+    fn tile_size(&self) -> Vec2d { self.size }
+
+    fn tile_url(&self, _pos: Vec2d) -> String { self.url.clone() }
+}
+
+struct CustomLevel {
+    template: Arc<UrlTemplate>,
+    page_uri: Arc<str>,
+    level: u32,
+    size: Vec2d,
+    tile_size: Vec2d,
+    overlap: u32,
+}
+
+impl TilesRect for CustomLevel {
+    fn size(&self) -> Vec2d { self.size }
+
+    fn tile_size(&self) -> Vec2d { self.tile_size }
+
+    fn tile_url(&self, pos: Vec2d) -> String {
+        resolve_relative(&self.page_uri, &self.template.url(self.level, pos.x, pos.y))
+    }
+
+    fn tile_ref(&self, pos: Vec2d) -> TileReference {
+        let delta = Vec2d {
+            x: if pos.x == 0 { 0 } else { self.overlap },
+            y: if pos.y == 0 { 0 } else { self.overlap },
+        };
+        TileReference {
+            url: self.tile_url(pos),
+            position: self.tile_size() * pos - delta,
+            size: None,
+            request: None,
+        }
+    }
+}
+
+impl std::fmt::Debug for CustomLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "OpenSeadragon custom tile source (level {})", self.level)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn levels(page: &str) -> ZoomLevels {
+        let data = DezoomerInput {
+            uri: "http://example.com/viewer/page.html".into(),
+            contents: PageContents::Success(page.as_bytes().to_vec()),
+        };
+        OpenSeadragon.zoom_levels(&data).unwrap()
+    }
+
+    fn urls(level: &mut ZoomLevel) -> Vec<String> {
+        level.next_tiles(None).into_iter().map(|t| t.url).collect()
+    }
+
+    #[test]
+    fn test_custom_tile_source() {
+        let mut levels = levels(r#"
+            OpenSeadragon({
+                id: "viewer",
+                tileSources: {
+                    height: 1000, width: 1500, tileSize: 512, tileOverlap: 1,
+                    getTileUrl: function( level, x, y ){
+                        return "/tiles/" + (level - 8) + '/' + x + "-" + y + ".jpg";
+                    }
+                }
+            });"#);
+        // ceil(log2(1500)) = 11 levels above the level 0
+        assert_eq!(levels.len(), 12);
+        let top = levels.last_mut().unwrap();
+        assert_eq!(top.size_hint(), Some(Vec2d { x: 1500, y: 1000 }));
+        let tiles = top.next_tiles(None);
+        assert_eq!(tiles.len(), 6);
+        assert_eq!(tiles[0].url, "http://example.com/tiles/3/0-0.jpg");
+        assert_eq!(tiles[4].url, "http://example.com/tiles/3/1-1.jpg");
+        assert_eq!(tiles[4].position, Vec2d { x: 511, y: 511 });
+        assert_eq!(levels[10].size_hint(), Some(Vec2d { x: 750, y: 500 }));
+    }
+
+    #[test]
+    fn test_template_literal() {
+        let mut levels = levels(r#"
+            const viewer = OpenSeadragon({
+                tileSources: [{
+                    width: 300, height: 200, tileWidth: 256, tileHeight: 128, minLevel: 9,
+                    getTileUrl(z, col, row) { return `https://tiles.example.com/${z}/${row}/${col}.png`; },
+                }]
+            });"#);
+        assert_eq!(levels.len(), 1);
+        assert_eq!(urls(&mut levels[0]), vec![
+            "https://tiles.example.com/9/0/0.png",
+            "https://tiles.example.com/9/0/1.png",
+            "https://tiles.example.com/9/1/0.png",
+            "https://tiles.example.com/9/1/1.png",
+        ]);
+    }
+
+    #[test]
+    fn test_legacy_image_pyramid() {
+        let mut levels = levels(r#"
+            <title>A painting</title>
+            OpenSeadragon({
+                tileSources: {
+                    type: 'legacy-image-pyramid',
+                    levels: [
+                        { url: 'images/small.jpg', height: 200, width: 300 },
+                        { url: 'images/large.jpg', height: 2000, width: 3000 }
+                    ]
+                }
+            });"#);
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[1].size_hint(), Some(Vec2d { x: 3000, y: 2000 }));
+        assert_eq!(urls(&mut levels[1]), vec!["http://example.com/viewer/images/large.jpg"]);
+        assert_eq!(levels[1].title().as_deref(), Some("A painting"));
+    }
+
+    #[test]
+    fn test_descriptor_urls() {
+        let page = r#"
+            OpenSeadragon({
+                sequenceMode: true,
+                tileSources: [
+                    "/images/a.dzi",
+                    { tileSource: "/images/b.dzi", x: 1, y: 0 },
+                    { type: "zoomifytileservice", width: 100, height: 100, tilesUrl: "/zoomify/c/" },
+                    { "@context": "http://iiif.io/api/image/2/context.json", "@id": "https://example.com/iiif/d" },
+                ]
+            });"#;
+        assert_eq!(descriptor_urls(page), vec![
+            "/images/a.dzi",
+            "/images/b.dzi",
+            "/zoomify/c/ImageProperties.xml",
+            "https://example.com/iiif/d/info.json",
+        ]);
+        let data = DezoomerInput {
+            uri: "http://example.com/viewer/page.html".into(),
+            contents: PageContents::Success(page.as_bytes().to_vec()),
+        };
+        assert!(OpenSeadragon.zoom_levels(&data).is_err());
+    }
+
+    #[test]
+    fn test_unsupported_function() {
+        let function = JsFunction {
+            params: vec!["level".into(), "x".into(), "y".into()],
+            body: "var s = this.server; return s + level;".into(),
+        };
+        assert_eq!(UrlTemplate::parse(&function), None);
+    }
+}
@@ -1,9 +1,9 @@
 use std::convert::TryFrom;
 use std::ffi::OsString;
-use std::fs::OpenOptions;
+use std::fs::{OpenOptions, remove_file};
 use std::path::{Path, PathBuf};
 
-use log::info;
+use log::{info, warn};
 use sanitize_filename_reader_friendly::sanitize;
 
 use crate::{Vec2d, ZoomError};
@@ -13,21 +13,80 @@ pub fn reserve_output_file(path: &PathBuf) -> Result<(), ZoomError> {
     Ok(())
 }
 
+/// Remove a file created by `reserve_output_file` if nothing has been written to it,
+/// so that failed runs do not leave empty placeholder files behind.
+pub fn remove_reserved_file(path: &Path) {
+    let is_empty = path.metadata().map(|m| m.is_file() && m.len() == 0).unwrap_or(false);
+    if is_empty {
+        info!("Removing the empty output file {:?}", path);
+        if let Err(e) = remove_file(path) {
+            warn!("Unable to remove the empty output file {:?}: {}", path, e);
+        }
+    }
+}
+
+/// A template for the names of the output files, such as `{title}_{width}x{height}`
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputTemplate(String);
+
+impl OutputTemplate {
+    /// The variables that can be used in templates
+    pub const VARIABLES: [&'static str; 4] = ["title", "width", "height", "host"];
+
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..].find('}')
+                .ok_or_else(|| format!("Unclosed '{{' in the output template '{}'", template))?;
+            let variable = &rest[start + 1..start + end];
+            if !Self::VARIABLES.contains(&variable) {
+                return Err(format!(
+                    "Unknown variable '{{{}}}' in the output template. Available variables: {}",
+                    variable, Self::VARIABLES.iter().map(|v| format!("{{{}}}", v)).collect::<Vec<_>>().join(", ")
+                ));
+            }
+            rest = &rest[start + end + 1..];
+        }
+        if rest.contains('}') {
+            return Err(format!("Unmatched '}}' in the output template '{}'", template));
+        }
+        Ok(OutputTemplate(template.to_string()))
+    }
+
+    /// The file name for an image. Unknown values are replaced by an empty string.
+    pub fn render(&self, title: Option<&str>, size: Option<Vec2d>, input_uri: Option<&str>) -> String {
+        let host = input_uri
+            .and_then(|uri| url::Url::parse(uri).ok())
+            .and_then(|url| url.host_str().map(String::from));
+        Self::VARIABLES.iter().fold(self.0.clone(), |name, &variable| {
+            let value = match variable {
+                "title" => title.map(String::from),
+                "width" => size.map(|s| s.x.to_string()),
+                "height" => size.map(|s| s.y.to_string()),
+                "host" => host.clone(),
+                _ => None,
+            };
+            name.replace(&format!("{{{}}}", variable), &value.unwrap_or_default())
+        })
+    }
+}
+
+/// Chooses where to save the image. `export_extension` replaces the extension of the output file,
+/// to save the tiles in a format such as a DeepZoom or IIIF directory.
 pub fn get_outname(
     outfile: &Option<PathBuf>,
     zoom_name: &Option<String>,
     base_dir: &Path,
     size: Option<Vec2d>,
+    export_extension: Option<&str>,
 ) -> PathBuf {
     // An image can be encoded as JPEG only if both its dimensions can be encoded as u16
     let fits_in_jpg = size
         .map(|Vec2d { x, y }| u16::try_from(x.max(y)).is_ok());
-    let extension = if fits_in_jpg == Some(true) { "jpg" } else { "png" };
+    let extension = export_extension
+        .unwrap_or(if fits_in_jpg == Some(true) { "jpg" } else { "png" });
     if let Some(path) = outfile {
-        if let Some(forced_extension) = path.extension() {
-            if fits_in_jpg == Some(false) && (forced_extension == "jpg" || forced_extension == "jpeg") {
-                log::error!("This file is too large to be saved as JPEG")
-            }
+        if path.extension().is_some() && export_extension.is_none() {
             path.into()
         } else {
             path.with_extension(extension)
@@ -37,23 +96,25 @@ pub fn get_outname(
             .map(|s| sanitize(s))
             .filter(|s| !s.is_empty())
             .unwrap_or_else(|| "dezoomified".into());
-        let mut path = base_dir.join(base).with_extension(extension);
-
-        // append a suffix (_1,_2,..) to `outname` if  the file already exists
-        let filename = path.file_stem().map(OsString::from).unwrap_or_default();
-        let ext = path.extension().map(OsString::from).unwrap_or_default();
-        for i in 1.. {
-            if !path.exists() { break; }
-            info!("File {:?} already exists. Trying another file name...", &path);
-            let mut name = OsString::from(&filename);
-            name.push(&format!("_{:04}.", i));
-            name.push(&ext);
-            path.set_file_name(name);
-        }
-        path
+        available_path(base_dir.join(base).with_extension(extension))
     }
 }
 
+/// The path, with a suffix (_0001, _0002...) appended to its file name if the file already exists
+pub fn available_path(mut path: PathBuf) -> PathBuf {
+    let filename = path.file_stem().map(OsString::from).unwrap_or_default();
+    let ext = path.extension().map(OsString::from).unwrap_or_default();
+    for i in 1.. {
+        if !path.exists() { break; }
+        info!("File {:?} already exists. Trying another file name...", &path);
+        let mut name = OsString::from(&filename);
+        name.push(&format!("_{:04}.", i));
+        name.push(&ext);
+        path.set_file_name(name);
+    }
+    path
+}
+
 #[allow(clippy::expect_fun_call)]
 #[cfg(test)]
 mod tests {
@@ -84,7 +145,7 @@ mod tests {
 
     fn assert_filename_ok(filename: &str) -> Result<(), Box<dyn Error>> {
         let base_dir = TempDir::new("dezoomify-rs-test-filename")?;
-        let outname = get_outname(&None, &Some(filename.to_string()), base_dir.as_ref(), None);
+        let outname = get_outname(&None, &Some(filename.to_string()), base_dir.as_ref(), None, None);
         assert_eq!(false, outname.exists(), "get_outname cannot overwrite {:?}", outname);
         File::create(&outname)
             .expect(&format!("Could not to create a file named {:?} for input {:?}", outname, filename));
@@ -115,6 +176,33 @@ mod tests {
         })
     }
 
+    #[test]
+    fn test_remove_reserved_file() {
+        in_tmp_dir(|cwd| {
+            let reserved = cwd.join("reserved.png");
+            reserve_output_file(&reserved).unwrap();
+            remove_reserved_file(&reserved);
+            assert!(!reserved.exists(), "empty reserved files should be removed");
+
+            let written = cwd.join("written.png");
+            std::fs::write(&written, b"data").unwrap();
+            remove_reserved_file(&written);
+            assert!(written.exists(), "files with contents should be kept");
+        })
+    }
+
+    #[test]
+    fn test_output_template() {
+        let template = OutputTemplate::parse("{host} - {title} ({width}x{height})").unwrap();
+        let size = Some(Vec2d { x: 2000, y: 1000 });
+        assert_eq!(template.render(Some("Mona Lisa"), size, Some("https://example.com/info.json")),
+                   "example.com - Mona Lisa (2000x1000)");
+        assert_eq!(template.render(None, None, None), " -  (x)");
+        assert!(OutputTemplate::parse("{name}").unwrap_err().contains("{title}, {width}, {height}, {host}"));
+        assert!(OutputTemplate::parse("{title").is_err());
+        assert!(OutputTemplate::parse("title}").is_err());
+    }
+
     #[test]
     fn switch_to_png_for_large_files() {
         let base_dir = TempDir::new("dezoomify-rs-test-png").unwrap();
@@ -129,8 +217,12 @@ mod tests {
             (Some("test.tiff".into()), Some("hello".to_string()), Some(Vec2d { x: 1000, y: 1000 }), "test.tiff".into()),
         ];
         for (outfile, zoom_name, size, expected_result) in tests.into_iter() {
-            let outname = get_outname(&outfile, &zoom_name, base_dir.as_ref(), size);
+            let outname = get_outname(&outfile, &zoom_name, base_dir.as_ref(), size, None);
             assert_eq!(outname, expected_result);
         }
+        let exported = get_outname(&Some("test.jpg".into()), &None, base_dir.as_ref(), None, Some("dzi"));
+        assert_eq!(exported, PathBuf::from("test.dzi"));
+        let exported = get_outname(&None, &Some("hello".to_string()), base_dir.as_ref(), None, Some("iiif"));
+        assert_eq!(exported, base("hello.iiif"));
     }
 }
\ No newline at end of file
@@ -1,4 +1,5 @@
 use std::num::ParseIntError;
+use std::ops::Range;
 use std::str::FromStr;
 
 use serde::{Serialize, Deserialize, Deserializer};
@@ -18,20 +19,56 @@ fn deserialize_from_string<'d, D: Deserializer<'d>, T: FromStr>(de: D) -> Result
         .map_err(|e: <T as FromStr>::Err| serde::de::Error::custom(e.to_string()))
 }
 
+/// The attributes of the header of a pff file.
+/// Newer servers write them in lower case, and can obfuscate the tiles.
 #[derive(Debug, Deserialize, PartialEq, Clone)]
 pub struct PffHeader {
-    #[serde(rename = "WIDTH", default)]
+    #[serde(rename = "WIDTH", alias = "width", default)]
     pub width: u32,
-    #[serde(rename = "HEIGHT", default)]
+    #[serde(rename = "HEIGHT", alias = "height", default)]
     pub height: u32,
-    #[serde(rename = "TILESIZE", default)]
+    #[serde(rename = "TILESIZE", alias = "tilesize", default)]
     pub tile_size: u32,
-    #[serde(rename = "NUMTILES", default)]
+    #[serde(rename = "NUMTILES", alias = "numtiles", default)]
     pub num_tiles: u32,
-    #[serde(rename = "HEADERSIZE", default)]
+    #[serde(rename = "HEADERSIZE", alias = "headersize", default)]
     pub header_size: u64,
-    #[serde(rename = "VERSION", default)]
-    pub version: u32,
+    /// Sent back to the server as-is: it is a number such as 106 in the first versions,
+    /// and a version string such as 3.0 in PFFv3 headers
+    #[serde(rename = "VERSION", alias = "version", default)]
+    pub version: String,
+    /// Whether the tiles are obfuscated with `key`
+    #[serde(rename = "ENCRYPTED", alias = "encrypted", default)]
+    pub encrypted: u8,
+    /// The XOR key of obfuscated tiles, in hexadecimal
+    #[serde(rename = "KEY", alias = "key", default, deserialize_with = "deserialize_hex")]
+    pub key: Vec<u8>,
+}
+
+impl PffHeader {
+    /// Removes the obfuscation of a tile. Tiles that are already valid JPEG images are left untouched,
+    /// since some servers undo the obfuscation themselves.
+    pub fn deobfuscate(&self, data: Vec<u8>) -> Vec<u8> {
+        if self.encrypted == 0 || self.key.is_empty() || data.starts_with(&JPEG_START) {
+            return data;
+        }
+        data.into_iter().zip(self.key.iter().cycle()).map(|(b, k)| b ^ k).collect()
+    }
+}
+
+/// The first bytes of every JPEG image
+const JPEG_START: [u8; 2] = [0xFF, 0xD8];
+
+fn deserialize_hex<'d, D: Deserializer<'d>>(de: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(de)?;
+    let hex = hex.trim().trim_start_matches("0x");
+    if hex.len() % 2 != 0 {
+        return Err(serde::de::Error::custom(format!("Invalid pff key: {}", hex)));
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .map_err(|e| serde::de::Error::custom(format!("Invalid pff key {}: {}", hex, e)))
 }
 
 
@@ -62,7 +99,7 @@ impl HeaderInfo {
         let begin = 0x424 + header.header_size;
         let end = begin + 8 * u64::from(header.num_tiles);
         self.request_url(ServletRequestParams {
-            vers: header.version,
+            vers: header.version.clone(),
             head: header.header_size,
             begin,
             end,
@@ -78,7 +115,8 @@ pub struct ImageInfo {
 }
 
 impl ImageInfo {
-    pub fn tile_url(&self, tile_number: usize) -> String {
+    /// The offsets of the beginning and of the end of a tile in the pff file
+    pub fn tile_range(&self, tile_number: usize) -> Range<u64> {
         let header = &self.header_info.header;
         let tiles = &self.tiles;
         let begin = if let Some(i) = tile_number.checked_sub(1) {
@@ -86,14 +124,25 @@ impl ImageInfo {
         } else {
             0x424 + header.header_size + 8 * u64::from(header.num_tiles)
         };
+        begin..tiles.indices[tile_number]
+    }
+
+    /// The URL of a part of the pff file. The tiles are stored one after the other,
+    /// so the part that spans several consecutive tiles contains all of them.
+    pub fn range_url(&self, range: Range<u64>) -> String {
+        let header = &self.header_info.header;
         self.header_info.request_url(ServletRequestParams {
-            vers: header.version,
+            vers: header.version.clone(),
             head: header.header_size,
-            begin,
-            end: tiles.indices[tile_number],
+            begin: range.start,
+            end: range.end,
             request_type: RequestType::TileImage as u8,
         })
     }
+
+    pub fn tile_url(&self, tile_number: usize) -> String {
+        self.range_url(self.tile_range(tile_number))
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -133,7 +182,7 @@ pub enum RequestType {
 #[serde(rename_all = "camelCase")]
 pub struct ServletRequestParams {
     //pff file version number
-    vers: u32,
+    vers: String,
     //the size of the JFIF headers table
     head: u64,
     // a beginning offset in the pff file (in bytes)
@@ -195,7 +244,8 @@ mod test {
         assert_eq!(header.tile_size, 256);
         assert_eq!(header.num_tiles, 5541);
         assert_eq!(header.header_size, 15331);
-        assert_eq!(header.version, 106);
+        assert_eq!(header.version, "106");
+        assert_eq!(header.encrypted, 0);
         let header_info = HeaderInfo { header, file: "x".into(), base_url: "http://x.com/".into() };
         assert_eq!(
             header_info.tiles_index_url(),
@@ -224,4 +274,33 @@ mod test {
         let reply: Reply<PffHeader> = serde_urlencoded::from_str(src).unwrap();
         assert_eq!(reply.reply_data.width, 38843);
     }
+
+    #[test]
+    fn test_deserialize_pff_v3_header() {
+        let src = r#"
+        <PFFHEADER
+            width="6000" height="4000"
+            numtiles="510"
+            numimages="1"
+            headersize="4018"
+            version="3.0"
+            tilesize="256"
+            encrypted="1"
+            key="5AC3"
+        />"#;
+        let header: PffHeader = serde_xml_rs::from_str(src).unwrap();
+        assert_eq!((header.width, header.height, header.num_tiles), (6000, 4000, 510));
+        assert_eq!(header.version, "3.0");
+        assert_eq!(header.key, vec![0x5A, 0xC3]);
+        let header_info = HeaderInfo { header: header.clone(), file: "x".into(), base_url: "http://x.com/".into() };
+        assert!(header_info.tiles_index_url().contains("vers=3.0&"));
+
+        let jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00];
+        let obfuscated: Vec<u8> = jpeg.iter().zip([0x5A, 0xC3].iter().cycle()).map(|(b, k)| b ^ k).collect();
+        assert_eq!(header.deobfuscate(obfuscated), jpeg);
+        assert_eq!(header.deobfuscate(jpeg.clone()), jpeg, "plain tiles should be left untouched");
+
+        let invalid = r#"<PFFHEADER WIDTH="1" HEIGHT="1" KEY="5A3"/>"#;
+        assert!(serde_xml_rs::from_str::<PffHeader>(invalid).is_err());
+    }
 }
\ No newline at end of file
@@ -26,6 +26,7 @@ impl Default for PFF { fn default() -> Self { PFF::Init } }
 custom_error! {pub PffError
     DecodeError{source: serde_urlencoded::de::Error} = "Invalid meta information file: {source}",
     EncodeError{source: serde_urlencoded::ser::Error} = "Unable to generate URL: {source}",
+    MissingKey = "The tiles of this image are obfuscated, but the server did not give their key",
 }
 
 impl From<PffError> for DezoomerError {
@@ -39,6 +40,18 @@ impl Dezoomer for PFF {
         "pff"
     }
 
+    fn description(&self) -> &str {
+        "Images in the Zoomify PFF format, from their meta-information URL"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["*requestType=1*"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec!["http://example.com/zoomifyDynamicViewer.php?file=image.pff&requestType=1"]
+    }
+
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
         let mut parts = data.uri.splitn(2, '?');
         let base_url = parts.next().ok_or_else(|| self.wrong_dezoomer())?.to_string();
@@ -55,7 +68,11 @@ impl Dezoomer for PFF {
                 let DezoomerInputWithContents { contents, .. } = data.with_contents()?;
                 let reply: Reply<PffHeader> =
                     serde_urlencoded::from_bytes(contents).map_err(PffError::from)?;
-                let header_info = HeaderInfo { base_url, file, header: reply.reply_data };
+                let header = reply.reply_data;
+                if header.encrypted != 0 && header.key.is_empty() {
+                    return Err(PffError::MissingKey.into());
+                }
+                let header_info = HeaderInfo { base_url, file, header };
                 let uri = header_info.tiles_index_url();
                 *self = PFF::WithHeader(header_info);
                 Err(DezoomerError::NeedsData { uri })
@@ -107,9 +124,51 @@ impl TilesRect for PffZoomLevel {
     }
 
     fn tile_url(&self, pos: Vec2d) -> String {
+        self.image_info.tile_url(self.tile_number(pos))
+    }
+
+    /// Tiles with consecutive numbers are stored one after the other, so they are requested together
+    fn fetch_plan(&self, tiles: &[TileReference], max_tiles: usize) -> Vec<TileBatch> {
+        let mut numbered: Vec<(usize, &TileReference)> = tiles.iter()
+            .map(|tile| (self.tile_number(tile.position / self.tile_size()), tile))
+            .collect();
+        numbered.sort_by_key(|&(number, _)| number);
+        let mut runs: Vec<Vec<(usize, &TileReference)>> = vec![];
+        for (number, tile) in numbered {
+            match runs.last_mut() {
+                Some(run) if run.len() < max_tiles && run.last().is_some_and(|&(last, _)| last + 1 == number) => {
+                    run.push((number, tile))
+                }
+                _ => runs.push(vec![(number, tile)]),
+            }
+        }
+        runs.into_iter().filter(|run| run.len() > 1).map(|run| {
+            let start = self.image_info.tile_range(run[0].0).start;
+            let end = self.image_info.tile_range(run[run.len() - 1].0).end;
+            let tiles = run.iter().map(|&(number, tile)| {
+                let range = self.image_info.tile_range(number);
+                (tile.key(), (range.start - start) as usize..(range.end - start) as usize)
+            }).collect();
+            TileBatch { url: self.image_info.range_url(start..end), tiles }
+        }).collect()
+    }
+
+    fn post_process_fn(&self) -> PostProcessFn {
+        if self.image_info.header_info.header.encrypted == 0 {
+            return PostProcessFn::None;
+        }
+        let info = Arc::clone(&self.image_info);
+        PostProcessFn::Closure(Arc::new(move |_tile: &TileReference, data: Vec<u8>| {
+            Ok(info.header_info.header.deobfuscate(data))
+        }))
+    }
+}
+
+impl PffZoomLevel {
+    /// The number of the tile in the pff file
+    fn tile_number(&self, pos: Vec2d) -> usize {
         let num_tiles_x = (self.size().ceil_div(self.tile_size())).x;
-        let i = self.tiles_before + pos.x + pos.y * num_tiles_x;
-        self.image_info.tile_url(i as usize)
+        (self.tiles_before + pos.x + pos.y * num_tiles_x) as usize
     }
 }
 
@@ -118,3 +177,28 @@ impl std::fmt::Debug for PffZoomLevel {
         f.write_str("Zoomify PFF")
     }
 }
+
+#[test]
+fn test_fetch_plan() {
+    let header: PffHeader = serde_xml_rs::from_str(
+        r#"<PFFHEADER WIDTH="600" HEIGHT="300" NUMTILES="8" HEADERSIZE="10" VERSION="106" TILESIZE="256"/>"#
+    ).unwrap();
+    let header_info = HeaderInfo { base_url: "http://x.com/".into(), file: "x".into(), header };
+    // The tiles start after the header, at 0x424 + 10 + 8 * 8 = 1134
+    let tiles: TileIndices = "1134, 10 20 30 40 50 60 70 80".parse().unwrap();
+    let levels = zoom_levels(ImageInfo { header_info, tiles });
+    let level = &levels[0];
+    let tile = |x: u32, y: u32| TileReference { url: format!("{},{}", x, y), position: Vec2d { x: x * 256, y: y * 256 }, size: None, request: None };
+    // The second tile of the first row is missing, and the batches have at most 2 tiles
+    let plan = level.fetch_plan(&[tile(0, 0), tile(2, 0), tile(0, 1), tile(1, 1), tile(2, 1)], 2);
+    assert_eq!(plan, vec![
+        TileBatch {
+            url: "http://x.com/?file=x&vers=106&head=10&begin=1154&end=1174&requestType=0".into(),
+            tiles: vec![("2,0".into(), 0..10), ("0,1".into(), 10..20)],
+        },
+        TileBatch {
+            url: "http://x.com/?file=x&vers=106&head=10&begin=1174&end=1194&requestType=0".into(),
+            tiles: vec![("1,1".into(), 0..10), ("2,1".into(), 10..20)],
+        },
+    ]);
+}
@@ -0,0 +1,281 @@
+//! Detecting the placeholder images that some servers send instead of the tiles,
+//! for instance an "access denied" image once they have banned the client.
+//! The server still answers successfully, so without detection the image would be silently
+//! filled with copies of the placeholder.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::time::Duration;
+
+use image::GenericImageView;
+use log::warn;
+
+use crate::{Tile, ZoomError};
+use crate::dezoomer::TileReference;
+
+/// Number of identical tiles in a row after which they are considered placeholders
+const REPEATS: usize = 8;
+/// Identical uniform tiles are common in the margins of images, so more of them are required
+const UNIFORM_REPEATS: usize = 4 * REPEATS;
+/// Standard deviation of the brightness under which a tile is considered uniform
+const UNIFORM_MAX_DEVIATION: f64 = 2.;
+
+/// What to do when the server seems to send placeholders instead of the tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaceholderPolicy {
+    /// Do not look for placeholders
+    Ignore,
+    /// Warn, and wait for --placeholder-pause before requesting more tiles
+    Pause,
+    /// Stop the download
+    Abort,
+}
+
+impl FromStr for PlaceholderPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(PlaceholderPolicy::Ignore),
+            "pause" => Ok(PlaceholderPolicy::Pause),
+            "abort" => Ok(PlaceholderPolicy::Abort),
+            _ => Err("Invalid placeholder tiles policy. Expected 'ignore', 'pause' or 'abort'"),
+        }
+    }
+}
+
+/// The result of checking a tile
+#[derive(Debug, PartialEq)]
+pub struct Check<T> {
+    /// Tiles that are not placeholders. Each tile is held back until a different tile is received,
+    /// so these are the tiles of the series that the new tile ended, if any.
+    pub tiles: Vec<T>,
+    /// Tiles that are copies of a placeholder
+    pub placeholders: Vec<T>,
+    /// Whether the tile revealed a new placeholder
+    pub detected: bool,
+    /// Number of identical tiles received in a row, including this one
+    pub copies: usize,
+}
+
+/// Identical tiles received in a row
+#[derive(Debug)]
+struct Series<T> {
+    hash: u64,
+    uniform: bool,
+    copies: usize,
+    /// The tiles that are held back
+    tiles: Vec<T>,
+}
+
+/// Follows the tiles as they are received, and detects series of identical tiles
+#[derive(Debug)]
+pub struct PlaceholderDetector<T> {
+    series: Option<Series<T>>,
+    /// The hashes of the tiles that were detected as placeholders
+    placeholders: HashSet<u64>,
+    /// The columns and rows in which detailed tiles were received, by position
+    detailed_columns: HashSet<u32>,
+    detailed_rows: HashSet<u32>,
+    /// The tiles that were replaced by placeholders, to be requested again
+    replaced: Vec<TileReference>,
+    /// The URL of the last placeholder, and the number of its copies received in a row
+    last_placeholder: Option<(String, usize)>,
+}
+
+impl<T> Default for PlaceholderDetector<T> {
+    fn default() -> Self {
+        PlaceholderDetector {
+            series: None,
+            placeholders: HashSet::new(),
+            detailed_columns: HashSet::new(),
+            detailed_rows: HashSet::new(),
+            replaced: vec![],
+            last_placeholder: None,
+        }
+    }
+}
+
+impl<T: AsRef<Tile>> PlaceholderDetector<T> {
+    pub fn check(&mut self, item: T) -> Check<T> {
+        let tile = item.as_ref();
+        let hash = tile_hash(tile);
+        let mut check = Check { tiles: vec![], placeholders: vec![], detected: false, copies: 0 };
+        if !matches!(&self.series, Some(series) if series.hash == hash) {
+            // A different tile ends the series, whose tiles were not placeholders
+            check.tiles = self.series.take().map(|series| series.tiles).unwrap_or_default();
+            let uniform = is_uniform(tile);
+            if !uniform && !self.placeholders.contains(&hash) {
+                self.detailed_columns.insert(tile.position.x);
+                self.detailed_rows.insert(tile.position.y);
+            }
+            self.series = Some(Series { hash, uniform, copies: 0, tiles: vec![] });
+        }
+        let (columns, rows) = (&self.detailed_columns, &self.detailed_rows);
+        let series = self.series.as_mut().expect("the series was just started");
+        series.copies += 1;
+        check.copies = series.copies;
+        if self.placeholders.contains(&hash) {
+            check.placeholders.push(item);
+            return check;
+        }
+        // Uniform tiles are common in the margins of images, and in skies or backgrounds.
+        // They are only suspicious in place of the detailed tiles of the same rows and columns.
+        let in_detailed_area = |tile: &T| {
+            let position = tile.as_ref().position;
+            columns.contains(&position.x) && rows.contains(&position.y)
+        };
+        let suspicious = !series.uniform || (in_detailed_area(&item) && series.tiles.iter().all(in_detailed_area));
+        let required = if series.uniform { UNIFORM_REPEATS } else { REPEATS };
+        series.tiles.push(item);
+        if suspicious && series.copies >= required {
+            self.placeholders.insert(hash);
+            check.placeholders = std::mem::take(&mut series.tiles);
+            check.detected = true;
+        }
+        check
+    }
+
+    /// Releases the tiles that were held back, when no more tile will be received for now.
+    /// The series goes on: the identical tiles received afterwards are counted with the released ones.
+    pub fn flush(&mut self) -> Vec<T> {
+        self.series.as_mut().map(|series| std::mem::take(&mut series.tiles)).unwrap_or_default()
+    }
+}
+
+impl<T: AsRef<Tile> + AsRef<TileReference>> PlaceholderDetector<T> {
+    /// Checks a downloaded tile, following the `policy` set with --placeholder-tiles,
+    /// and returns the tiles that can be saved. The tiles replaced by placeholders are kept,
+    /// to be requested again. When placeholders are detected, waits for `pause`,
+    /// or returns an error if the download has to stop.
+    pub async fn receive(&mut self, item: T, policy: PlaceholderPolicy, pause: Duration) -> Result<Vec<T>, ZoomError> {
+        if policy == PlaceholderPolicy::Ignore { return Ok(vec![item]); }
+        let check = self.check(item);
+        if let Some(first) = check.placeholders.first() {
+            let reference: &TileReference = first.as_ref();
+            self.last_placeholder = Some((reference.url.clone(), check.copies));
+        }
+        self.replaced.extend(check.placeholders.iter().map(|t| AsRef::<TileReference>::as_ref(t).clone()));
+        if check.detected {
+            let error = self.error().expect("placeholders were just received");
+            if policy == PlaceholderPolicy::Abort { return Err(error); }
+            warn!("{} Pausing for {:?}.", error, pause);
+            tokio::time::sleep(pause).await;
+        }
+        Ok(check.tiles)
+    }
+
+    /// Returns the tiles that were replaced by placeholders since the last call
+    pub fn take_replaced(&mut self) -> Vec<TileReference> {
+        std::mem::take(&mut self.replaced)
+    }
+
+    /// Describes the last placeholders that were received, if any
+    pub fn error(&self) -> Option<ZoomError> {
+        let (url, count) = self.last_placeholder.clone()?;
+        Some(ZoomError::PlaceholderTiles { url, count })
+    }
+}
+
+/// Identifies the contents of a tile, from its bytes as received when they are available
+fn tile_hash(tile: &Tile) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    match &tile.encoded {
+        Some(bytes) => bytes.hash(&mut hasher),
+        None => {
+            tile.image.dimensions().hash(&mut hasher);
+            tile.image.as_bytes().hash(&mut hasher);
+        }
+    }
+    hasher.finish()
+}
+
+/// Whether the tile has a single color, or almost
+fn is_uniform(tile: &Tile) -> bool {
+    let luma = tile.image.thumbnail(16, 16).to_luma8();
+    let n = luma.pixels().len() as f64;
+    if n == 0. { return true; }
+    let mean = luma.pixels().map(|p| f64::from(p.0[0])).sum::<f64>() / n;
+    let variance = luma.pixels().map(|p| (f64::from(p.0[0]) - mean).powi(2)).sum::<f64>() / n;
+    variance.sqrt() < UNIFORM_MAX_DEVIATION
+}
+
+#[test]
+fn test_placeholder_detection() {
+    use image::{DynamicImage, Luma};
+    use crate::Vec2d;
+    let tile = |seed: u32, uniform: bool, x: u32, y: u32| Tile {
+        image: DynamicImage::ImageLuma8(image::GrayImage::from_fn(8, 8, |px, py| {
+            Luma([if uniform { seed as u8 } else { ((px * 31 + py * 17 + seed * 101) % 251) as u8 }])
+        })),
+        position: Vec2d { x, y },
+        encoded: None,
+    };
+    let released = |check: Check<Tile>| {
+        assert!(check.placeholders.is_empty() && !check.detected, "unexpected placeholders: {:?}", check);
+        check.tiles.len()
+    };
+    let mut detector = PlaceholderDetector::default();
+    // Each tile is held back until the next one is received
+    assert_eq!(released(detector.check(tile(0, false, 0, 0))), 0);
+    for i in 1..REPEATS as u32 {
+        assert_eq!(released(detector.check(tile(i, false, i, 0))), 1);
+    }
+    // Margins: identical uniform tiles, in a row without detailed tiles
+    assert_eq!(released(detector.check(tile(255, true, 0, 1))), 1);
+    for i in 1..UNIFORM_REPEATS as u32 {
+        assert_eq!(released(detector.check(tile(255, true, i, 1))), 0);
+    }
+    assert_eq!(released(detector.check(tile(1000, false, 0, 2))), UNIFORM_REPEATS);
+    for i in 1..REPEATS as u32 - 1 {
+        assert_eq!(released(detector.check(tile(1000, false, i, 2))), 0);
+    }
+    let check = detector.check(tile(1000, false, 7, 2));
+    assert!(check.detected, "placeholders were not detected");
+    assert_eq!(check.placeholders.len(), REPEATS);
+    let check = detector.check(tile(1000, false, 8, 2));
+    assert_eq!((check.placeholders.len(), check.detected, check.copies), (1, false, REPEATS + 1));
+    assert_eq!(released(detector.check(tile(5, false, 9, 0))), 0);
+    // A known placeholder is recognized at once, even after the end of the download
+    assert_eq!(detector.flush().len(), 1);
+    let check = detector.check(tile(1000, false, 3, 2));
+    assert_eq!((check.placeholders.len(), check.detected), (1, false));
+    // Uniform placeholders, in place of the detailed tiles of the first row
+    let checks: Vec<Check<Tile>> = (0..UNIFORM_REPEATS as u32)
+        .map(|i| detector.check(tile(128, true, i % REPEATS as u32, 0)))
+        .collect();
+    assert!(checks.last().unwrap().detected);
+    assert!(checks[..UNIFORM_REPEATS - 1].iter().all(|check| !check.detected));
+}
+
+#[tokio::test]
+async fn test_placeholder_policies() {
+    use image::DynamicImage;
+    use crate::Vec2d;
+    #[derive(Debug)]
+    struct Received(Tile, TileReference);
+    impl AsRef<Tile> for Received { fn as_ref(&self) -> &Tile { &self.0 } }
+    impl AsRef<TileReference> for Received { fn as_ref(&self) -> &TileReference { &self.1 } }
+    let received = |x: u32| {
+        let position = Vec2d { x, y: 0 };
+        let image = DynamicImage::ImageLuma8(image::GrayImage::from_fn(8, 8, |px, py| image::Luma([(px * 30 + py) as u8])));
+        let tile = Tile { image, position, encoded: Some(b"denied".to_vec().into()) };
+        Received(tile, TileReference { url: format!("http://example.com/{}.jpg", x), position, size: None, request: None })
+    };
+    let mut detector = PlaceholderDetector::default();
+    for x in 0..REPEATS as u32 - 1 {
+        let saved = detector.receive(received(x), PlaceholderPolicy::Abort, Duration::default()).await.unwrap();
+        assert!(saved.is_empty());
+    }
+    assert_eq!(detector.error().map(|e| e.to_string()), None);
+    let err = detector.receive(received(REPEATS as u32), PlaceholderPolicy::Abort, Duration::default()).await.unwrap_err();
+    assert!(matches!(&err, ZoomError::PlaceholderTiles { url, count: REPEATS } if url == "http://example.com/0.jpg"), "{}", err);
+    assert_eq!(detector.take_replaced().len(), REPEATS);
+    assert!(detector.take_replaced().is_empty());
+
+    let mut ignoring = PlaceholderDetector::default();
+    let saved = ignoring.receive(received(0), PlaceholderPolicy::Ignore, Duration::default()).await.unwrap();
+    assert_eq!(saved.len(), 1);
+}
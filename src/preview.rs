@@ -0,0 +1,191 @@
+//! Checking the stitched image against a low resolution preview of it, with `--verify-with-preview`.
+//! Many sites show a small version of their zoomable images, and comparing it with the result
+//! detects tiles that were placed at the wrong position, or a level whose size was computed wrongly.
+//! A thumbnail of the image is built from scaled down copies of the tiles kept while they are added
+//! to the canvas, so the whole image never has to be read again.
+
+use image::{GrayImage, imageops};
+use image::imageops::FilterType;
+use log::{info, warn};
+
+use crate::{Vec2d, ZoomError};
+use crate::network::{fetch_uri, Fetcher};
+use crate::tile::Tile;
+
+/// Largest dimension of the images that are compared. Larger previews are scaled down.
+const COMPARISON_SIZE: u32 = 256;
+/// Difference between the proportions of the preview and of the image above which they cannot be compared
+const MAX_ASPECT_RATIO_DIFFERENCE: f64 = 0.05;
+/// Largest dimension of the copies of the tiles received before the size of the image is known
+const PENDING_TILE_SIZE: u32 = 32;
+/// Similarity under which the image is considered different from the preview
+const MIN_SIMILARITY: f64 = 0.5;
+
+pub struct PreviewCheck {
+    url: String,
+    preview: GrayImage,
+    /// The size of the stitched image, once it is known
+    image_size: Option<Vec2d>,
+    /// Scaled down copies of the tiles, with their position and size in the image
+    tiles: Vec<(Vec2d, Vec2d, GrayImage)>,
+}
+
+impl PreviewCheck {
+    pub async fn download(url: &str, http: &Fetcher) -> Result<Self, ZoomError> {
+        let preview = image::load_from_memory(&fetch_uri(url, http).await?)?.to_luma8();
+        let (width, height) = preview.dimensions();
+        let factor = f64::from(COMPARISON_SIZE) / f64::from(width.max(height));
+        let preview = if factor < 1. {
+            let scale = |d: u32| ((f64::from(d) * factor).round() as u32).max(1);
+            imageops::resize(&preview, scale(width), scale(height), FilterType::Triangle)
+        } else {
+            preview
+        };
+        info!("Downloaded a preview of {}x{} pixels from {}", width, height, url);
+        Ok(PreviewCheck { url: url.to_string(), preview, image_size: None, tiles: vec![] })
+    }
+
+    /// Sets the size of the stitched image. Some dezoomers only find it after downloading the first tiles.
+    pub fn set_image_size(&mut self, size: Vec2d) {
+        self.image_size = Some(size);
+    }
+
+    /// The area of the thumbnail covered by a part of the image
+    fn thumbnail_rect(&self, image_size: Vec2d, position: Vec2d, size: Vec2d) -> (Vec2d, Vec2d) {
+        let (width, height) = self.preview.dimensions();
+        let scale = |v: u32, image_size: u32, thumbnail_size: u32, round: fn(f64) -> f64| {
+            (round(f64::from(v) * f64::from(thumbnail_size) / f64::from(image_size)) as u32).min(thumbnail_size)
+        };
+        let end = position + size;
+        let start = Vec2d { x: scale(position.x, image_size.x, width, f64::floor), y: scale(position.y, image_size.y, height, f64::floor) };
+        let end = Vec2d { x: scale(end.x, image_size.x, width, f64::ceil), y: scale(end.y, image_size.y, height, f64::ceil) };
+        (start, end.max(start) - start)
+    }
+
+    /// Keeps a scaled down copy of a tile. Until the size of the image is known,
+    /// the copies have a fixed size, and they are scaled again when the thumbnail is built.
+    pub fn add_tile(&mut self, tile: &Tile) {
+        let size = tile.size();
+        let scaled_size = match self.image_size {
+            Some(image_size) => self.thumbnail_rect(image_size, tile.position, size).1,
+            None => {
+                let factor = (f64::from(PENDING_TILE_SIZE) / f64::from(size.x.max(size.y))).min(1.);
+                let scale = |d: u32| ((f64::from(d) * factor).round() as u32).max(1);
+                Vec2d { x: scale(size.x), y: scale(size.y) }
+            }
+        };
+        if scaled_size.area() == 0 { return; }
+        let scaled = imageops::resize(&tile.image.to_luma8(), scaled_size.x, scaled_size.y, FilterType::Triangle);
+        self.tiles.push((tile.position, size, scaled));
+    }
+
+    /// Compares the image with the preview, warns if they differ,
+    /// and returns an error if they are too different
+    pub fn verify(&self) -> Result<(), ZoomError> {
+        let image_size = match self.image_size {
+            Some(size) => size,
+            None => {
+                warn!("The size of the image is unknown, so it cannot be compared with the preview");
+                return Ok(());
+            }
+        };
+        let (width, height) = self.preview.dimensions();
+        let preview_ratio = f64::from(width) / f64::from(height);
+        let image_ratio = f64::from(image_size.x) / f64::from(image_size.y);
+        if (preview_ratio / image_ratio - 1.).abs() > MAX_ASPECT_RATIO_DIFFERENCE {
+            warn!("The preview ({}x{}) and the image ({}) do not have the same proportions. \
+                   They were not compared.", width, height, image_size);
+            return Ok(());
+        }
+        let mut thumbnail = GrayImage::new(width, height);
+        for (position, size, scaled) in &self.tiles {
+            let (start, size) = self.thumbnail_rect(image_size, *position, *size);
+            if size.area() == 0 { continue; }
+            if scaled.dimensions() == (size.x, size.y) {
+                imageops::replace(&mut thumbnail, scaled, start.x, start.y);
+            } else {
+                let scaled = imageops::resize(scaled, size.x, size.y, FilterType::Triangle);
+                imageops::replace(&mut thumbnail, &scaled, start.x, start.y);
+            }
+        }
+        let similarity = ssim(&thumbnail, &self.preview);
+        if similarity < MIN_SIMILARITY {
+            warn!("The image does not look like the preview at {}: \
+                   the tiles may have been assembled in the wrong order", self.url);
+            Err(ZoomError::PreviewMismatch { similarity, min_similarity: MIN_SIMILARITY })
+        } else {
+            info!("The image looks like the preview (similarity: {:.2})", similarity);
+            Ok(())
+        }
+    }
+}
+
+/// The mean structural similarity (SSIM) of two grayscale images of the same size,
+/// computed on windows of 8x8 pixels. It is 1 for identical images, and close to 0 for unrelated images.
+fn ssim(a: &GrayImage, b: &GrayImage) -> f64 {
+    const WINDOW: u32 = 8;
+    const C1: f64 = (0.01 * 255.) * (0.01 * 255.);
+    const C2: f64 = (0.03 * 255.) * (0.03 * 255.);
+    let (width, height) = a.dimensions();
+    let window_starts = |size: u32| (0..size.saturating_sub(WINDOW) + 1).step_by(WINDOW as usize / 2);
+    let mut total = 0.;
+    let mut count = 0;
+    for y in window_starts(height) {
+        for x in window_starts(width) {
+            let pixels: Vec<(f64, f64)> = (y..(y + WINDOW).min(height))
+                .flat_map(|y| (x..(x + WINDOW).min(width)).map(move |x| (x, y)))
+                .map(|(x, y)| (f64::from(a.get_pixel(x, y).0[0]), f64::from(b.get_pixel(x, y).0[0])))
+                .collect();
+            let n = pixels.len() as f64;
+            let mean_a = pixels.iter().map(|p| p.0).sum::<f64>() / n;
+            let mean_b = pixels.iter().map(|p| p.1).sum::<f64>() / n;
+            let var_a = pixels.iter().map(|p| (p.0 - mean_a).powi(2)).sum::<f64>() / n;
+            let var_b = pixels.iter().map(|p| (p.1 - mean_b).powi(2)).sum::<f64>() / n;
+            let covariance = pixels.iter().map(|p| (p.0 - mean_a) * (p.1 - mean_b)).sum::<f64>() / n;
+            total += ((2. * mean_a * mean_b + C1) * (2. * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            count += 1;
+        }
+    }
+    total / f64::from(count)
+}
+
+#[test]
+fn test_preview_check() {
+    use image::{DynamicImage, Luma};
+    let image = GrayImage::from_fn(64, 32, |x, y| Luma([((x * 7919 + y * 104_729) % 251) as u8]));
+    let mut check = PreviewCheck { url: "preview.jpg".into(), preview: image.clone(), image_size: None, tiles: vec![] };
+    let quadrants = [(0, 0), (32, 0), (0, 16), (32, 16)];
+    check.set_image_size(Vec2d { x: 64, y: 32 });
+    let add_quadrants = |check: &mut PreviewCheck, sources: &[(u32, u32)]| {
+        for (&(x, y), &(source_x, source_y)) in quadrants.iter().zip(sources) {
+            let quadrant = imageops::crop_imm(&image, source_x, source_y, 32, 16).to_image();
+            let tile = Tile { image: DynamicImage::ImageLuma8(quadrant), position: Vec2d { x, y }, encoded: None };
+            check.add_tile(&tile);
+        }
+    };
+    add_quadrants(&mut check, &quadrants);
+    assert!(check.verify().is_ok());
+    check.tiles.clear();
+    add_quadrants(&mut check, &[(32, 0), (0, 0), (32, 16), (0, 16)]);
+    assert!(matches!(check.verify(), Err(ZoomError::PreviewMismatch { .. })));
+    // The tiles received before the size of the image is known are scaled when it is found
+    check.tiles.clear();
+    check.image_size = None;
+    add_quadrants(&mut check, &quadrants);
+    assert_eq!(check.tiles[0].2.dimensions(), (32, 16));
+    check.set_image_size(Vec2d { x: 64, y: 32 });
+    assert!(check.verify().is_ok());
+    // A preview that does not have the proportions of the image is not compared
+    check.set_image_size(Vec2d { x: 100, y: 100 });
+    assert!(check.verify().is_ok());
+}
+
+#[test]
+fn test_ssim() {
+    use image::Luma;
+    let a = GrayImage::from_fn(32, 32, |x, y| Luma([((x * 7 + y * 3) % 256) as u8]));
+    assert!((ssim(&a, &a) - 1.).abs() < 1e-9);
+    let noise = GrayImage::from_fn(32, 32, |x, y| Luma([((x * 7919 + y * 104_729) % 251) as u8]));
+    assert!(ssim(&a, &noise) < 0.2);
+}
@@ -0,0 +1,295 @@
+//! Reporting of the progress of a download. The command-line interface displays it with
+//! a progress bar, and programs that use dezoomify-rs as a library can receive it
+//! by implementing [`ProgressSink`] and setting it with [`crate::DezoomRequest::progress`].
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use indicatif::{HumanBytes, HumanDuration, ProgressBar, ProgressStyle};
+
+/// The steps of the download of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Asking the dezoomer for the tiles of the image
+    ListingTiles,
+    /// Opening connections before the download, with `--warmup`
+    WarmingUp,
+    DownloadingTiles,
+    /// Filling the given number of missing tiles from a lower zoom level
+    Interpolating(usize),
+    /// Writing the end of the image file
+    Finalizing,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::ListingTiles => write!(f, "Computing the URLs of the image tiles..."),
+            Phase::WarmingUp => write!(f, "Warming up..."),
+            Phase::DownloadingTiles => write!(f, "Requesting the tiles..."),
+            Phase::Interpolating(count) => write!(f, "Interpolating {} missing tiles", count),
+            Phase::Finalizing => write!(f, "Downloaded all tiles. Finalizing the image file."),
+        }
+    }
+}
+
+/// Receives the progress of the download of an image.
+/// All the methods do nothing by default.
+pub trait ProgressSink: Send + Sync {
+    fn set_phase(&self, _phase: Phase) {}
+
+    /// The number of tiles found so far. Some dezoomers find the tiles of an image progressively,
+    /// so the total can grow during the download.
+    fn set_total_tiles(&self, _total: u64) {}
+
+    /// A tile was received, with the size of its data, or failed, in which case `bytes` is `None`
+    fn tile_completed(&self, _bytes: Option<u64>) {}
+
+    /// Describes the last event, such as the position of the last tile
+    fn message(&self, _message: &str) {}
+
+    /// Information that should be displayed to the user, such as the estimate made with `--warmup`
+    fn println(&self, _message: &str) {}
+
+    /// The download ended, successfully or not
+    fn finish(&self, _message: &str) {}
+}
+
+/// The progress bar of the command-line interface
+impl ProgressSink for ProgressBar {
+    fn set_phase(&self, phase: Phase) { self.set_message(&phase.to_string()) }
+    fn set_total_tiles(&self, total: u64) { self.set_length(total) }
+    fn tile_completed(&self, _bytes: Option<u64>) { self.inc(1) }
+    fn message(&self, message: &str) { self.set_message(message) }
+    fn println(&self, message: &str) { ProgressBar::println(self, message) }
+    fn finish(&self, message: &str) { self.finish_with_message(message) }
+}
+
+/// Weight of the last tile in the estimation of the speed of the download.
+/// Lower values make the speed and the remaining time steadier, but slower to follow real changes.
+const SMOOTHING: f64 = 0.1;
+
+/// The time after which a download without new tiles is displayed as stalled
+const STALL_DISPLAY_DELAY: Duration = Duration::from_secs(5);
+
+/// The amount of data received, and the speed of the download
+#[derive(Debug)]
+struct TransferStats {
+    start: Instant,
+    total_tiles: u64,
+    completed: u64,
+    failed: u64,
+    bytes: u64,
+    /// When the last tile was completed, or when the download of the tiles started
+    last_event: Instant,
+    /// Exponentially weighted moving averages of the time between two tiles, in seconds,
+    /// and of the size of the tiles, in bytes. Tiles completed at the same time are averaged
+    /// with the ones that took longer, so the estimation stays steady when tiles arrive in bursts.
+    average_interval: Option<f64>,
+    average_size: f64,
+}
+
+impl TransferStats {
+    fn new(start: Instant) -> Self {
+        TransferStats {
+            start,
+            total_tiles: 0,
+            completed: 0,
+            failed: 0,
+            bytes: 0,
+            last_event: start,
+            average_interval: None,
+            average_size: 0.,
+        }
+    }
+
+    fn tile_completed(&mut self, now: Instant, bytes: Option<u64>) {
+        self.completed += 1;
+        match bytes {
+            Some(bytes) => self.bytes += bytes,
+            None => self.failed += 1,
+        }
+        let interval = now.duration_since(self.last_event).as_secs_f64();
+        let size = bytes.unwrap_or(0) as f64;
+        self.last_event = now;
+        match &mut self.average_interval {
+            Some(average) => {
+                *average += SMOOTHING * (interval - *average);
+                self.average_size += SMOOTHING * (size - self.average_size);
+            }
+            None => {
+                self.average_interval = Some(interval);
+                self.average_size = size;
+            }
+        }
+    }
+
+    /// The number of tiles and of bytes received per second
+    fn rates(&self) -> Option<(f64, f64)> {
+        let interval = self.average_interval.filter(|&i| i > 0.)?;
+        Some((1. / interval, self.average_size / interval))
+    }
+
+    /// The time needed to receive the remaining tiles at the current speed
+    fn eta(&self) -> Option<Duration> {
+        let (tiles_per_second, _) = self.rates()?;
+        let remaining = self.total_tiles.saturating_sub(self.completed);
+        Some(Duration::from_secs_f64(remaining as f64 / tiles_per_second))
+    }
+
+    /// How long it has been since the last tile was received, if that is unusually long
+    fn stalled(&self, now: Instant) -> Option<Duration> {
+        let waiting = now.duration_since(self.last_event);
+        let expected = self.average_interval.map_or(Duration::default(), |i| Duration::from_secs_f64(4. * i));
+        Some(waiting).filter(|&w| self.completed < self.total_tiles && w >= STALL_DISPLAY_DELAY.max(expected))
+    }
+
+    fn status(&self, now: Instant, stall_timeout: Option<Duration>) -> String {
+        let mut status = format!("{}", HumanBytes(self.bytes));
+        if let Some(stalled) = self.stalled(now) {
+            status.push_str(&format!(", stalled for {}s", stalled.as_secs()));
+            if let Some(timeout) = stall_timeout {
+                status.push_str(&format!(", restarting after {}s", timeout.as_secs()));
+            }
+            return status;
+        }
+        if let Some((tiles_per_second, bytes_per_second)) = self.rates() {
+            status.push_str(&format!(", {:.1} tiles/s, {}/s", tiles_per_second, HumanBytes(bytes_per_second as u64)));
+        }
+        if let Some(eta) = self.eta() {
+            status.push_str(&format!(", ETA {}", HumanDuration(eta)));
+        }
+        status
+    }
+
+    fn summary(&self, now: Instant) -> String {
+        let elapsed = now.duration_since(self.start);
+        let speed = self.bytes as f64 / elapsed.as_secs_f64().max(1e-3);
+        let mut summary = format!(
+            "{} tiles, {} in {}, {}/s",
+            self.completed - self.failed, HumanBytes(self.bytes), HumanDuration(elapsed), HumanBytes(speed as u64)
+        );
+        if self.failed > 0 { summary.push_str(&format!(", {} failed", self.failed)); }
+        summary
+    }
+}
+
+/// The progress bar of the command-line interface, with the amount of data received,
+/// the current speed of the download, and the estimated remaining time.
+/// When no tile is received for a while, the download is displayed as stalled.
+/// A summary of the download is displayed when it ends.
+pub struct ProgressDisplay {
+    bar: ProgressBar,
+    stats: Arc<Mutex<TransferStats>>,
+    /// The delay after which the download is restarted when it stalls, set with `--stall-timeout`
+    stall_timeout: Option<Duration>,
+}
+
+impl ProgressDisplay {
+    pub fn new(stall_timeout: Option<Duration>) -> Self {
+        let bar = ProgressBar::new(0);
+        bar.set_style(
+            ProgressStyle::default_bar()
+                .template("{bar:40.cyan/blue} {pos:>4}/{len:4} [{prefix}] {msg}")
+                .progress_chars("##-"),
+        );
+        let stats = Arc::new(Mutex::new(TransferStats::new(Instant::now())));
+        // The stalled state has to be displayed while nothing else happens
+        let ticker_bar = bar.clone();
+        let ticker_stats = Arc::downgrade(&stats);
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(1));
+            let stats = match ticker_stats.upgrade() {
+                Some(stats) if !ticker_bar.is_finished() => stats,
+                _ => break,
+            };
+            let stats = stats.lock().unwrap();
+            if stats.stalled(Instant::now()).is_some() {
+                ticker_bar.set_prefix(&stats.status(Instant::now(), stall_timeout));
+            }
+        });
+        ProgressDisplay { bar, stats, stall_timeout }
+    }
+}
+
+impl ProgressSink for ProgressDisplay {
+    fn set_phase(&self, phase: Phase) {
+        if phase == Phase::DownloadingTiles {
+            let mut stats = self.stats.lock().unwrap();
+            if stats.completed == 0 { stats.last_event = Instant::now(); }
+        }
+        self.bar.set_phase(phase)
+    }
+
+    fn set_total_tiles(&self, total: u64) {
+        self.stats.lock().unwrap().total_tiles = total;
+        self.bar.set_length(total)
+    }
+
+    fn tile_completed(&self, bytes: Option<u64>) {
+        let mut stats = self.stats.lock().unwrap();
+        let now = Instant::now();
+        stats.tile_completed(now, bytes);
+        self.bar.set_prefix(&stats.status(now, self.stall_timeout));
+        self.bar.inc(1)
+    }
+
+    fn message(&self, message: &str) { self.bar.set_message(message) }
+    fn println(&self, message: &str) { self.bar.println(message) }
+
+    fn finish(&self, message: &str) {
+        let summary = self.stats.lock().unwrap().summary(Instant::now());
+        self.bar.finish_with_message(&format!("{} ({})", message, summary))
+    }
+}
+
+#[test]
+fn test_transfer_stats() {
+    let start = Instant::now();
+    let at = |seconds: f64| start + Duration::from_secs_f64(seconds);
+    let mut stats = TransferStats::new(start);
+    stats.total_tiles = 100;
+    assert_eq!(stats.eta(), None);
+    for i in 1..=10 {
+        stats.tile_completed(at(i as f64), Some(1024));
+    }
+    let (tiles_per_second, bytes_per_second) = stats.rates().unwrap();
+    assert!((tiles_per_second - 1.).abs() < 1e-9);
+    assert!((bytes_per_second - 1024.).abs() < 1e-9);
+    assert_eq!(stats.eta(), Some(Duration::from_secs(90)));
+
+    // A burst of tiles received at the same time only changes the estimation progressively
+    for _ in 0..4 {
+        stats.tile_completed(at(10.), Some(1024));
+    }
+    let (tiles_per_second, _) = stats.rates().unwrap();
+    assert!(tiles_per_second > 1.5 && tiles_per_second < 2., "{}", tiles_per_second);
+
+    assert_eq!(stats.stalled(at(12.)), None);
+    assert_eq!(stats.stalled(at(20.)), Some(Duration::from_secs(10)));
+    assert!(stats.status(at(20.), Some(Duration::from_secs(30))).ends_with("stalled for 10s, restarting after 30s"));
+
+    stats.tile_completed(at(20.), None);
+    assert_eq!(stats.summary(at(40.)), "14 tiles, 14.00KB in 40 seconds, 358B/s, 1 failed");
+}
+
+/// Allows keeping a reference to a sink, to read what it received after the download
+impl<T: ProgressSink + ?Sized> ProgressSink for Arc<T> {
+    fn set_phase(&self, phase: Phase) { (**self).set_phase(phase) }
+    fn set_total_tiles(&self, total: u64) { (**self).set_total_tiles(total) }
+    fn tile_completed(&self, bytes: Option<u64>) { (**self).tile_completed(bytes) }
+    fn message(&self, message: &str) { (**self).message(message) }
+    fn println(&self, message: &str) { (**self).println(message) }
+    fn finish(&self, message: &str) { (**self).finish(message) }
+}
+
+/// A progress sink that can be stored in the arguments of a download
+#[derive(Clone)]
+pub struct SharedProgressSink(pub Arc<dyn ProgressSink>);
+
+impl fmt::Debug for SharedProgressSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SharedProgressSink")
+    }
+}
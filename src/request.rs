@@ -0,0 +1,140 @@
+//! A programmatic interface to download a single image, for programs that embed dezoomify-rs.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), dezoomify_rs::ZoomError> {
+//! use dezoomify_rs::{DezoomRequest, LevelSelection};
+//!
+//! let saved_as = DezoomRequest::new("https://example.com/ImageProperties.xml")
+//!     .level(LevelSelection::Largest)
+//!     .output("image.jpg")
+//!     .run()
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests never read from the standard input and never display a progress bar:
+//! their progress can be followed with a [`ProgressSink`].
+//! When several zoom levels are available, the largest one is downloaded unless another one is selected.
+//!
+//! The settings of a download, such as its cookies and its tile cache, are shared by the whole process,
+//! so requests started at the same time are downloaded one after the other.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use crate::{Arguments, Region, ZoomError};
+use crate::download::dezoomify_exclusive;
+use crate::bulk::LevelSelection;
+use crate::http_client::{HttpClient, SharedHttpClient};
+use crate::progress_sink::{ProgressSink, SharedProgressSink};
+use tokio_util::sync::CancellationToken;
+
+/// The download of a single image
+#[derive(Debug, Clone)]
+pub struct DezoomRequest {
+    args: Arguments,
+}
+
+impl DezoomRequest {
+    /// A request for the zoomable image at the given URL, or in the given local file
+    pub fn new(url: impl Into<String>) -> Self {
+        let mut args = Arguments {
+            input_uri: Some(url.into()),
+            non_interactive: true,
+            quiet: true,
+            ..Arguments::default()
+        };
+        args.select_level(LevelSelection::Largest);
+        DezoomRequest { args }
+    }
+
+    /// Which zoom level to download
+    pub fn level(mut self, selection: LevelSelection) -> Self {
+        self.args.select_level(selection);
+        self
+    }
+
+    /// The file to which the image is saved. Its extension sets the format of the image.
+    /// By default, the image is saved in the current directory, with a name based on its title.
+    pub fn output(mut self, path: impl Into<PathBuf>) -> Self {
+        self.args.outfile = Some(path.into());
+        self
+    }
+
+    /// Adds an HTTP header to all the requests
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.args.headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// The name of the dezoomer to use, instead of trying all of them
+    pub fn dezoomer(mut self, name: impl Into<String>) -> Self {
+        self.args.dezoomer = name.into();
+        self
+    }
+
+    /// Only download the given region of the image
+    pub fn crop(mut self, region: Region) -> Self {
+        self.args.crop = Some(region);
+        self
+    }
+
+    /// The number of tiles downloaded at the same time
+    pub fn parallelism(mut self, parallelism: usize) -> Self {
+        self.args.parallelism = parallelism;
+        self
+    }
+
+    /// The number of new attempts to download a tile that failed
+    pub fn retries(mut self, retries: usize) -> Self {
+        self.args.retries = retries;
+        self
+    }
+
+    /// Receives the progress of the download
+    pub fn progress(mut self, sink: impl ProgressSink + 'static) -> Self {
+        self.args.progress = Some(SharedProgressSink(Arc::new(sink)));
+        self
+    }
+
+    /// Allows stopping the download from another task. The image is then saved with the tiles
+    /// that were received, and the request fails with [`ZoomError::PartialDownload`].
+    pub fn cancellation(mut self, token: CancellationToken) -> Self {
+        self.args.cancel = Some(token);
+        self
+    }
+
+    /// Makes the http requests with the given client, instead of the built-in one.
+    /// The requests sent to it already carry all their headers.
+    pub fn http_client(mut self, client: impl HttpClient + 'static) -> Self {
+        self.args.http_client = Some(SharedHttpClient(Arc::new(client)));
+        self
+    }
+
+    /// The arguments equivalent to this request on the command line
+    pub fn arguments(&self) -> &Arguments {
+        &self.args
+    }
+
+    /// Downloads the image, and returns the path of the file it was saved to.
+    /// Requests run one at a time: if another request is running, this one waits for it to end.
+    pub async fn run(self) -> Result<PathBuf, ZoomError> {
+        dezoomify_exclusive(&self.args).await
+    }
+}
+
+#[test]
+fn test_request_arguments() {
+    let request = DezoomRequest::new("http://example.com/info.json")
+        .level(LevelSelection::BelowMax(1))
+        .header("Referer", "http://example.com/")
+        .dezoomer("iiif");
+    let args = request.arguments();
+    assert_eq!(args.input_uri.as_deref(), Some("http://example.com/info.json"));
+    assert!(!args.is_interactive());
+    assert!(!args.largest);
+    assert_eq!(args.levels_below_max, Some(1));
+    assert_eq!(args.headers().collect::<Vec<_>>(), vec![(&"Referer".to_string(), &"http://example.com/".to_string())]);
+    assert_eq!(args.find_dezoomer().unwrap().name(), "iiif");
+}
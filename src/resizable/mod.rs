@@ -0,0 +1,301 @@
+use std::convert::TryFrom;
+use std::fmt::{Debug, Formatter};
+use std::sync::RwLock;
+
+use custom_error::custom_error;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use regex::Regex;
+
+use crate::dezoomer::*;
+use crate::Vec2d;
+
+/// Height of the strips requested when no strip size is given with --strip-size
+const DEFAULT_STRIP_HEIGHT: u32 = 1024;
+
+/// Number of times the first strip is requested again with a smaller size when it fails
+const MAX_PROBES: u32 = 4;
+
+lazy_static! {
+    static ref STRIP_SIZE: RwLock<Option<Vec2d>> = RwLock::new(None);
+}
+
+/// Set the size of the parts of the image requested from resize-on-demand servers
+pub fn set_strip_size(size: Option<Vec2d>) {
+    *STRIP_SIZE.write().unwrap() = size;
+}
+
+/// A dezoomer for images served by resize-on-demand servers such as thumbor and imgproxy.
+/// These servers limit the size of the images they generate, but can crop the source image:
+/// the full image is requested in strips, and the limits of the server are detected on the first one.
+/// Only unsigned URLs (`/unsafe/` for thumbor, `/insecure/` or `/_/` for imgproxy) can be used.
+#[derive(Default)]
+pub struct ResizableDezoomer {
+    endpoint: Option<Endpoint>,
+}
+
+impl Dezoomer for ResizableDezoomer {
+    fn name(&self) -> &'static str { "resizable" }
+    fn description(&self) -> &str { "Images served by resize-on-demand servers such as thumbor and imgproxy" }
+    fn url_patterns(&self) -> Vec<&str> { vec!["*/unsafe/*", "*/insecure/*", "*/_/*"] }
+    fn examples(&self) -> Vec<&str> {
+        vec![
+            "https://example.com/unsafe/300x200/images/painting.jpg",
+            "https://example.com/insecure/rs:fit:300:300/plain/images/painting.jpg",
+        ]
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        if self.endpoint.is_none() {
+            self.endpoint = Endpoint::parse(&data.uri);
+            self.assert(self.endpoint.is_some())?;
+        }
+        let endpoint = self.endpoint.as_ref().expect("the endpoint was parsed");
+        let info_url = endpoint.info_url();
+        if data.uri != info_url {
+            return Err(DezoomerError::NeedsData { uri: info_url });
+        }
+        let DezoomerInputWithContents { contents, .. } = data.with_contents()?;
+        let size = endpoint.parse_info(contents).map_err(DezoomerError::wrap)?;
+        info!("The source image of {:?} is {}x{} pixels", endpoint.server, size.x, size.y);
+        let strip_size = STRIP_SIZE.read().unwrap()
+            .unwrap_or(Vec2d { x: size.x, y: DEFAULT_STRIP_HEIGHT });
+        single_level(ResizableImage::new(endpoint.clone(), size, strip_size))
+    }
+}
+
+custom_error! {pub ResizableError
+    BadInfo{source: serde_json::Error} = "Invalid image information: {source}",
+    NoSize = "The image information does not contain the size of the image",
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Server {
+    Thumbor,
+    Imgproxy,
+}
+
+/// An image URL on a resize-on-demand server, split into its parts
+#[derive(Debug, Clone, PartialEq)]
+struct Endpoint {
+    server: Server,
+    /// The beginning of the URL, before the signature
+    base: String,
+    /// The URL signature: servers that do not check it accept any options
+    signature: String,
+    /// Processing options that do not change the geometry of the image, kept in the strip URLs
+    options: Vec<String>,
+    /// The source image, as it appears at the end of the URL
+    source: String,
+}
+
+impl Endpoint {
+    fn parse(url: &str) -> Option<Endpoint> {
+        lazy_static! {
+            static ref URL_RE: Regex = Regex::new(
+                r"^(https?://[^?#]+?)/(unsafe|insecure|_)/([^?#]+)$"
+            ).unwrap();
+            // Path segments of thumbor URLs that come before the image
+            static ref THUMBOR_OPTION_RE: Regex = Regex::new(
+                r"^(meta|debug|trim(:[^/]*)?|\d+x\d+:\d+x\d+|(adaptive-|full-)?fit-in|-?\d*x-?\d*|left|right|center|top|middle|bottom|smart|filters:.*)$"
+            ).unwrap();
+        }
+        let caps = URL_RE.captures(url)?;
+        let signature = caps[2].to_string();
+        let server = if signature == "unsafe" { Server::Thumbor } else { Server::Imgproxy };
+        let segments: Vec<&str> = caps[3].split('/').collect();
+        let (source_start, options) = match server {
+            Server::Thumbor => {
+                let start = segments.iter().position(|s| !THUMBOR_OPTION_RE.is_match(s))?;
+                let options = segments[..start].iter()
+                    .filter(|s| s.starts_with("filters:"))
+                    .map(|s| s.to_string())
+                    .collect();
+                (start, options)
+            }
+            Server::Imgproxy => {
+                let start = segments.iter().position(|s| !s.contains(':'))?;
+                let options = segments[..start].iter()
+                    .filter(|s| !is_imgproxy_geometry_option(s))
+                    .map(|s| s.to_string())
+                    .collect();
+                (start, options)
+            }
+        };
+        let source = segments[source_start..].join("/");
+        if source.is_empty() || source == "plain" { return None; }
+        Some(Endpoint { server, base: caps[1].to_string(), signature, options, source })
+    }
+
+    /// The URL of a description of the source image, that contains its size
+    fn info_url(&self) -> String {
+        match self.server {
+            Server::Thumbor => format!("{}/{}/meta/{}", self.base, self.signature, self.source),
+            Server::Imgproxy => format!("{}/info/{}/{}", self.base, self.signature, self.source),
+        }
+    }
+
+    fn parse_info(&self, contents: &[u8]) -> Result<Vec2d, ResizableError> {
+        let info: serde_json::Value = serde_json::from_slice(contents)?;
+        let size_info = match self.server {
+            Server::Thumbor => &info["thumbor"]["source"],
+            Server::Imgproxy => &info,
+        };
+        let dimension = |name: &str| size_info[name].as_u64().and_then(|d| u32::try_from(d).ok());
+        match (dimension("width"), dimension("height")) {
+            (Some(x), Some(y)) if x > 0 && y > 0 => Ok(Vec2d { x, y }),
+            _ => Err(ResizableError::NoSize),
+        }
+    }
+
+    /// The URL of the given rectangle of the source image, at its original resolution
+    fn crop_url(&self, position: Vec2d, size: Vec2d) -> String {
+        let crop = match self.server {
+            Server::Thumbor => {
+                let end = position + size;
+                format!("{}x{}:{}x{}", position.x, position.y, end.x, end.y)
+            }
+            Server::Imgproxy => format!("crop:{}:{}:nowe:{}:{}", size.x, size.y, position.x, position.y),
+        };
+        std::iter::once(self.base.as_str())
+            .chain(std::iter::once(self.signature.as_str()))
+            .chain(std::iter::once(crop.as_str()))
+            .chain(self.options.iter().map(String::as_str))
+            .chain(std::iter::once(self.source.as_str()))
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+}
+
+/// Whether an imgproxy processing option changes the size or the position of the image
+fn is_imgproxy_geometry_option(option: &str) -> bool {
+    let name = option.split(':').next().unwrap_or_default();
+    [
+        "resize", "rs", "size", "s", "width", "w", "height", "h", "min-width", "mw", "min-height", "mh",
+        "zoom", "z", "dpr", "enlarge", "el", "extend", "ex", "gravity", "g", "crop", "c", "trim", "t",
+        "padding", "pd", "rotate", "rot",
+    ].contains(&name)
+}
+
+struct ResizableImage {
+    endpoint: Endpoint,
+    size: Vec2d,
+    strip_size: Vec2d,
+    probes: u32,
+    done: bool,
+}
+
+impl ResizableImage {
+    fn new(endpoint: Endpoint, size: Vec2d, strip_size: Vec2d) -> Self {
+        let strip_size = strip_size.max(Vec2d { x: 1, y: 1 }).min(size);
+        ResizableImage { endpoint, size, strip_size, probes: 0, done: false }
+    }
+
+    fn strip(&self, position: Vec2d) -> TileReference {
+        let size = (position + self.strip_size).min(self.size) - position;
+        TileReference { url: self.endpoint.crop_url(position, size), position, size: None, request: None }
+    }
+
+    fn strips(&self) -> impl Iterator<Item=TileReference> + '_ {
+        let Vec2d { x: w, y: h } = self.size.ceil_div(self.strip_size);
+        (0..h).flat_map(move |y| (0..w).map(move |x| self.strip(self.strip_size * Vec2d { x, y })))
+    }
+}
+
+impl TileProvider for ResizableImage {
+    fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
+        if self.done { return vec![]; }
+        let previous = match previous {
+            // Request a single strip first, to find out how large the images generated by the server can be
+            None => return vec![self.strip(Vec2d::default())],
+            Some(previous) => previous,
+        };
+        match previous.tile_size.filter(|_| previous.is_success()) {
+            Some(received) => {
+                self.done = true;
+                if received == self.strip_size {
+                    return self.strips().skip(1).collect();
+                }
+                warn!("The server returned a {}x{} image instead of {}x{}. Requesting smaller strips.",
+                      received.x, received.y, self.strip_size.x, self.strip_size.y);
+                self.strip_size = received.min(self.strip_size);
+                self.strips().collect()
+            }
+            None if self.probes < MAX_PROBES && self.strip_size.y > 1 => {
+                self.probes += 1;
+                self.strip_size.y = self.strip_size.y.div_ceil(2);
+                info!("Trying again with strips of {}x{} pixels", self.strip_size.x, self.strip_size.y);
+                vec![self.strip(Vec2d::default())]
+            }
+            None => {
+                self.done = true;
+                vec![]
+            }
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("{:?} ({:>5} x {:>5} pixels)", self, self.size.x, self.size.y)
+    }
+
+    fn title(&self) -> Option<String> {
+        let file_name = self.endpoint.source.rsplit('/').next()?;
+        let title = file_name.split('.').next().unwrap_or(file_name);
+        Some(title.to_string()).filter(|t| !t.is_empty())
+    }
+
+    fn size_hint(&self) -> Option<Vec2d> { Some(self.size) }
+}
+
+impl Debug for ResizableImage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} image", self.endpoint.server)
+    }
+}
+
+#[test]
+fn test_thumbor() {
+    let url = "https://thumbs.example.com/unsafe/fit-in/300x200/filters:quality(90)/images.example.com/painting.jpg";
+    let endpoint = Endpoint::parse(url).unwrap();
+    assert_eq!(endpoint.server, Server::Thumbor);
+    assert_eq!(endpoint.source, "images.example.com/painting.jpg");
+    assert_eq!(endpoint.info_url(), "https://thumbs.example.com/unsafe/meta/images.example.com/painting.jpg");
+    let info = br#"{"thumbor": {"source": {"url": "painting.jpg", "width": 3000, "height": 2000}}}"#;
+    assert_eq!(endpoint.parse_info(info).unwrap(), Vec2d { x: 3000, y: 2000 });
+    assert_eq!(
+        endpoint.crop_url(Vec2d { x: 0, y: 1024 }, Vec2d { x: 3000, y: 976 }),
+        "https://thumbs.example.com/unsafe/0x1024:3000x2000/filters:quality(90)/images.example.com/painting.jpg"
+    );
+}
+
+#[test]
+fn test_imgproxy() {
+    let url = "http://imgproxy.example.com/insecure/rs:fit:300:300/q:80/plain/http://example.com/images/painting.jpg";
+    let endpoint = Endpoint::parse(url).unwrap();
+    assert_eq!(endpoint.server, Server::Imgproxy);
+    assert_eq!(endpoint.options, vec!["q:80"]);
+    assert_eq!(endpoint.info_url(), "http://imgproxy.example.com/info/insecure/plain/http://example.com/images/painting.jpg");
+    assert_eq!(endpoint.parse_info(br#"{"width": 640, "height": 480}"#).unwrap(), Vec2d { x: 640, y: 480 });
+    assert_eq!(
+        endpoint.crop_url(Vec2d { x: 0, y: 256 }, Vec2d { x: 640, y: 224 }),
+        "http://imgproxy.example.com/insecure/crop:640:224:nowe:0:256/q:80/plain/http://example.com/images/painting.jpg"
+    );
+    assert_eq!(Endpoint::parse("http://example.com/images/painting.jpg"), None);
+}
+
+#[test]
+fn test_strip_size_probing() {
+    let endpoint = Endpoint::parse("http://example.com/unsafe/a.jpg").unwrap();
+    let mut image = ResizableImage::new(endpoint, Vec2d { x: 3000, y: 2000 }, Vec2d { x: 3000, y: 1024 });
+    let first = image.next_tiles(None);
+    assert_eq!(first.len(), 1);
+    assert_eq!(first[0].url, "http://example.com/unsafe/0x0:3000x1024/a.jpg");
+    // The server scales the strips down to 1500 pixels wide
+    let tiles = image.next_tiles(Some(TileFetchResult {
+        count: 1, successes: 1, tile_size: Some(Vec2d { x: 1500, y: 512 }), ..Default::default()
+    }));
+    assert_eq!(tiles.len(), 8);
+    assert_eq!(tiles[7].position, Vec2d { x: 1500, y: 1536 });
+    assert_eq!(tiles[7].url, "http://example.com/unsafe/1500x1536:3000x2000/a.jpg");
+    assert!(image.next_tiles(Some(TileFetchResult { count: 8, successes: 8, tile_size: None, ..Default::default() })).is_empty());
+}
@@ -0,0 +1,118 @@
+//! Downloading again the tiles listed with `--failed-tiles-log`, with `--retry-failed`,
+//! and patching them into the image that was saved without them.
+//! The image is decoded and encoded again, so it has to fit in memory.
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use futures::stream::StreamExt;
+use image::DynamicImage;
+use log::{info, warn};
+
+use crate::{Arguments, ZoomError};
+use crate::download::{download_tile, list_tiles, TileDownloadError};
+use crate::dezoomer::{PostProcessFn, TileReference, ZoomLevelIter};
+use crate::failure_report::{FailedTile, FailureReport};
+use crate::network::{client, tile_client};
+use crate::Vec2d;
+
+/// Downloads the tiles listed in a failure report, adds them to the image it refers to,
+/// and replaces the list with the tiles that failed again
+pub async fn retry_failed(report_path: &Path, args: &Arguments) -> Result<PathBuf, ZoomError> {
+    let mut report = FailureReport::load(report_path)?;
+    let total_tiles = report.failed_tiles.len() as u64;
+    if total_tiles == 0 {
+        info!("No tile is listed in {:?}", report_path);
+        return Ok(report.output);
+    }
+    let (post_process_fn, level_headers) = match &report.input_uri {
+        Some(uri) => find_level(uri, &report.failed_tiles, args).await,
+        None => None,
+    }.unwrap_or_else(|| {
+        warn!("The zoom level of the failed tiles was not found. Requesting them without its headers.");
+        (PostProcessFn::None, HashMap::new())
+    });
+    let http_client = tile_client(level_headers.iter().chain(args.headers()), args)?;
+    let tile_refs: Vec<TileReference> = report.failed_tiles.iter().map(|failed| TileReference {
+        url: failed.url.clone(),
+        position: Vec2d { x: failed.x, y: failed.y },
+        size: None,
+        request: failed.request.clone().map(Box::new),
+    }).collect();
+    let results: Vec<_> = futures::stream::iter(tile_refs)
+        .map(|tile_ref| download_tile(post_process_fn.clone(), tile_ref, None, None, &http_client, args))
+        .buffer_unordered(args.parallelism)
+        .collect().await;
+
+    let mut image = image::open(&report.output)?;
+    report.failed_tiles.clear();
+    for result in results {
+        match result {
+            Ok((tile, _)) => patch(&mut image, &tile.image, tile.position),
+            Err(TileDownloadError { tile_reference, cause }) => {
+                warn!("Unable to download the tile '{}' again: {}", tile_reference.url, cause);
+                report.failed_tiles.push(FailedTile::new(&tile_reference, &cause));
+            }
+        }
+    }
+    let successful_tiles = total_tiles - report.failed_tiles.len() as u64;
+    if successful_tiles > 0 {
+        image.save(&report.output)?;
+    }
+    report.save(report_path)?;
+    if successful_tiles < total_tiles {
+        Err(ZoomError::PartialDownload { successful_tiles, total_tiles })
+    } else {
+        Ok(report.output)
+    }
+}
+
+/// Finds the zoom level the failed tiles belong to, and returns how to post-process its tiles
+/// and the headers to request them with
+async fn find_level(
+    uri: &str,
+    failed_tiles: &[FailedTile],
+    args: &Arguments,
+) -> Option<(PostProcessFn, HashMap<String, String>)> {
+    let urls: HashSet<&str> = failed_tiles.iter().map(|t| t.url.as_str()).collect();
+    let mut dezoomer = args.find_dezoomer().ok()?;
+    let http_client = client(args.headers(), args, Some(uri)).ok()?;
+    let levels = list_tiles(dezoomer.as_mut(), &http_client, uri, args).await
+        .map_err(|e| warn!("Unable to list the tiles of {}: {}", uri, e)).ok()?;
+    for mut level in levels {
+        let mut iter = ZoomLevelIter::new(&mut level);
+        let contains_failed = iter.next_tile_references()
+            .is_some_and(|refs| refs.iter().any(|r| urls.contains(r.url.as_str())));
+        if contains_failed {
+            return Some((level.post_process_fn(), level.http_headers()));
+        }
+    }
+    None
+}
+
+/// Copies a tile into the image, in the color type of the image
+pub(crate) fn patch(image: &mut DynamicImage, tile: &DynamicImage, position: Vec2d) {
+    let (x, y) = (position.x, position.y);
+    match image {
+        DynamicImage::ImageLuma8(img) => image::imageops::replace(img, &tile.to_luma8(), x, y),
+        DynamicImage::ImageLumaA8(img) => image::imageops::replace(img, &tile.to_luma_alpha8(), x, y),
+        DynamicImage::ImageRgb8(img) => image::imageops::replace(img, &tile.to_rgb8(), x, y),
+        DynamicImage::ImageRgba8(img) => image::imageops::replace(img, &tile.to_rgba8(), x, y),
+        other => {
+            let mut img = other.to_rgba8();
+            image::imageops::replace(&mut img, &tile.to_rgba8(), x, y);
+            *other = DynamicImage::ImageRgba8(img);
+        }
+    }
+}
+
+#[test]
+fn test_patch() {
+    use image::GenericImageView;
+    let mut image = DynamicImage::new_rgb8(4, 4);
+    let tile = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(2, 2, image::Rgba([255, 0, 0, 255])));
+    patch(&mut image, &tile, Vec2d { x: 2, y: 2 });
+    assert!(matches!(image, DynamicImage::ImageRgb8(_)));
+    assert_eq!(image.get_pixel(3, 3), image::Rgba([255, 0, 0, 255]));
+    assert_eq!(image.get_pixel(1, 1), image::Rgba([0, 0, 0, 255]));
+}
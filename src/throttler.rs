@@ -0,0 +1,162 @@
+//! Limits the rate at which requests are sent to each server.
+//!
+//! Requests to a same host are spaced by at least `--min-interval`.
+//! With `--auto-throttle`, the interval grows when a server answers with
+//! 429 (Too Many Requests) or 503 (Service Unavailable), and shrinks back slowly
+//! after a series of successful requests.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use log::{debug, info};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::StatusCode;
+use tokio::time::Instant;
+
+lazy_static! {
+    static ref THROTTLER: RwLock<Option<Arc<Throttler>>> = RwLock::new(None);
+}
+
+/// Interval used for the first slowdown when no minimal interval is set
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+/// The interval never grows larger than this
+const MAX_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of consecutive successful requests after which the interval is reduced
+const SUCCESS_STREAK: u32 = 10;
+
+pub struct Throttler {
+    min_interval: Duration,
+    adaptive: bool,
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+#[derive(Debug)]
+struct HostState {
+    interval: Duration,
+    next_slot: Instant,
+    successes: u32,
+}
+
+impl Throttler {
+    pub fn new(min_interval: Duration, adaptive: bool) -> Self {
+        Throttler { min_interval, adaptive, hosts: Mutex::new(HashMap::new()) }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.adaptive || self.min_interval > Duration::default()
+    }
+
+    /// Reserve the next time at which a request can be sent to the host
+    fn reserve(&self, host: &str, now: Instant) -> Instant {
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host.to_string()).or_insert_with(|| HostState {
+            interval: self.min_interval,
+            next_slot: now,
+            successes: 0,
+        });
+        let slot = state.next_slot.max(now);
+        state.next_slot = slot + state.interval;
+        slot
+    }
+
+    /// Wait until a request can be sent to the host
+    pub async fn wait(&self, host: &str) {
+        let slot = self.reserve(host, Instant::now());
+        tokio::time::sleep_until(slot).await;
+    }
+
+    /// Adapt the interval between requests to the host to the status of a response
+    fn report(&self, host: &str, status: StatusCode, retry_after: Option<Duration>, now: Instant) {
+        if !self.adaptive { return; }
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = match hosts.get_mut(host) { Some(state) => state, None => return };
+        if status == StatusCode::TOO_MANY_REQUESTS || status == StatusCode::SERVICE_UNAVAILABLE {
+            let interval = (state.interval * 2)
+                .max(INITIAL_BACKOFF)
+                .max(retry_after.unwrap_or_default())
+                .min(MAX_INTERVAL);
+            info!("{} answered with '{}'. Waiting {:?} between requests.", host, status, interval);
+            state.interval = interval;
+            state.successes = 0;
+            if let Some(retry_after) = retry_after {
+                state.next_slot = state.next_slot.max(now + retry_after);
+            }
+        } else if status.is_success() {
+            state.successes += 1;
+            if state.successes >= SUCCESS_STREAK && state.interval > self.min_interval {
+                state.interval = (state.interval * 3 / 4).max(self.min_interval);
+                state.successes = 0;
+                debug!("Reducing the interval between requests to {} to {:?}", host, state.interval);
+            }
+        }
+    }
+}
+
+/// Set the throttler used for all requests. `None` disables throttling.
+pub fn set_throttler(throttler: Option<Throttler>) {
+    *THROTTLER.write().unwrap() = throttler.filter(Throttler::is_enabled).map(Arc::new);
+}
+
+fn throttler() -> Option<Arc<Throttler>> {
+    THROTTLER.read().unwrap().clone()
+}
+
+/// Wait until a request can be sent to the given host
+pub async fn wait_for(host: &str) {
+    if let Some(throttler) = throttler() {
+        throttler.wait(host).await;
+    }
+}
+
+/// Inform the throttler of the status of the response to a request to the given host,
+/// and of the time after which the server asked to make requests again
+pub fn report(host: &str, status: StatusCode, retry_after: Option<Duration>) {
+    if let Some(throttler) = throttler() {
+        throttler.report(host, status, retry_after, Instant::now());
+    }
+}
+
+/// Parses a Retry-After header expressed in seconds
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?;
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+#[test]
+fn test_min_interval() {
+    let throttler = Throttler::new(Duration::from_secs(1), false);
+    let now = Instant::now();
+    assert_eq!(throttler.reserve("a", now), now);
+    assert_eq!(throttler.reserve("a", now), now + Duration::from_secs(1));
+    assert_eq!(throttler.reserve("b", now), now);
+}
+
+#[test]
+fn test_backoff() {
+    let throttler = Throttler::new(Duration::default(), true);
+    let now = Instant::now();
+    throttler.reserve("a", now);
+    throttler.report("a", StatusCode::TOO_MANY_REQUESTS, None, now);
+    assert_eq!(throttler.reserve("a", now), now);
+    assert_eq!(throttler.reserve("a", now), now + INITIAL_BACKOFF);
+    throttler.report("a", StatusCode::SERVICE_UNAVAILABLE, Some(Duration::from_secs(5)), now);
+    assert_eq!(throttler.hosts.lock().unwrap()["a"].interval, Duration::from_secs(5));
+    assert_eq!(throttler.reserve("a", now), now + Duration::from_secs(5));
+    for _ in 0..SUCCESS_STREAK {
+        throttler.report("a", StatusCode::OK, None, now);
+    }
+    let interval = throttler.hosts.lock().unwrap()["a"].interval;
+    assert_eq!(interval, Duration::from_millis(3750));
+}
+
+#[test]
+fn test_retry_after() {
+    let mut headers = HeaderMap::new();
+    assert_eq!(retry_after(&headers), None);
+    headers.insert(RETRY_AFTER, "120".parse().unwrap());
+    assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+    headers.insert(RETRY_AFTER, "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+    assert_eq!(retry_after(&headers), None);
+}
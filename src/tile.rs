@@ -1,14 +1,24 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Arc;
+
 use image::{GenericImageView, DynamicImage};
+use log::debug;
 
-use crate::{Vec2d, ZoomError};
+use crate::{HeaderMap, Vec2d, ZoomError};
 use crate::dezoomer::{PostProcessFn, TileReference};
 use crate::errors::BufferToImageError;
-use crate::network::fetch_uri;
+#[cfg(feature = "cli")]
+use crate::network::{fetch_tile, Fetcher};
+use crate::decode_cache;
+use crate::tile_cache;
 
 #[derive(Clone)]
 pub struct Tile {
     pub image: image::DynamicImage,
     pub position: Vec2d,
+    /// The tile as it was received from the server (after post-processing),
+    /// if the image was not modified since then
+    pub encoded: Option<Arc<[u8]>>,
 }
 
 impl Tile {
@@ -16,42 +26,166 @@ impl Tile {
     pub fn bottom_right(&self) -> Vec2d {
         self.size() + self.position
     }
+    /// Downloads and decodes a tile. The size of the tile as it was received is returned with it,
+    /// because tiles whose dezoomer knows their size are resized to fit it.
+    #[cfg(feature = "cli")]
     pub async fn download(
         post_process_fn: PostProcessFn,
         tile_reference: &TileReference,
-        client: &reqwest::Client,
-    ) -> Result<Tile, ZoomError> {
-        let bytes = fetch_uri(&tile_reference.url, client).await?;
-        let tile_reference = tile_reference.clone();
-
-        let tile: Result<Tile, BufferToImageError> = tokio::spawn(async move {
-            tokio::task::block_in_place(move || {
-                let transformed_bytes =
-                    if let PostProcessFn::Fn(post_process) = post_process_fn {
-                        post_process(&tile_reference, bytes)
-                            .map_err(|e|
-                                BufferToImageError::PostProcessing { e }
-                            )?
-                    } else {
-                        bytes
-                    };
-
-                Ok(Tile {
-                    image: image::load_from_memory(&transformed_bytes)?,
-                    position: tile_reference.position,
-                })
-            })
+        client: &Fetcher,
+    ) -> Result<(Tile, Vec2d), ZoomError> {
+        // Tiles are stored in the cache after post-processing
+        let (headers, bytes, post_process_fn) = match tile_cache::get(&tile_reference.key()) {
+            Some(bytes) => (None, bytes, PostProcessFn::None),
+            None => {
+                let (headers, bytes) = fetch_tile(tile_reference, client).await?;
+                (Some(headers), bytes, post_process_fn)
+            }
+        };
+        Tile::decode(post_process_fn, tile_reference, bytes, headers).await
+    }
+    /// Post-processes and decodes the bytes of a tile. Tiles that were received from the server,
+    /// with the given response `headers`, are stored in the tile cache.
+    pub async fn decode(
+        post_process_fn: PostProcessFn,
+        tile_reference: &TileReference,
+        bytes: Vec<u8>,
+        headers: Option<HeaderMap>,
+    ) -> Result<(Tile, Vec2d), ZoomError> {
+        let reference = tile_reference.clone();
+        #[cfg(feature = "cli")]
+        let decoded = tokio::spawn(async move {
+            tokio::task::block_in_place(move || decode_tile(post_process_fn, &reference, bytes, headers))
         }).await?;
-        Ok(tile?)
+        // Without a tokio runtime, such as in a browser, the tile is decoded in the current task
+        #[cfg(not(feature = "cli"))]
+        let decoded = decode_tile(post_process_fn, &reference, bytes, headers);
+        Ok(decoded?)
     }
     pub fn empty(position: Vec2d, size: Vec2d) -> Tile {
-        Tile { image: DynamicImage::new_rgba8(size.x, size.y), position }
+        Tile { image: DynamicImage::new_rgba8(size.x, size.y), position, encoded: None }
     }
     pub fn position(&self) -> Vec2d {
         self.position
     }
 }
 
+fn decode_tile(
+    post_process_fn: PostProcessFn,
+    reference: &TileReference,
+    bytes: Vec<u8>,
+    headers: Option<HeaderMap>,
+) -> Result<(Tile, Vec2d), BufferToImageError> {
+    let transformed_bytes = match post_process_fn {
+        PostProcessFn::Fn(post_process) => post_process(reference, bytes),
+        PostProcessFn::Closure(post_process) => post_process(reference, bytes),
+        PostProcessFn::None => Ok(bytes),
+    }.map_err(|e| BufferToImageError::PostProcessing { e })?;
+
+    let image = decode_cache::decode(&transformed_bytes)?;
+    let transformed_bytes: Arc<[u8]> = transformed_bytes.into();
+    let received = Vec2d::from(image.dimensions());
+    let tile = match reference.size {
+        Some(size) if received != size => Tile {
+            image: fit_to_size(image, size),
+            position: reference.position,
+            encoded: None,
+        },
+        _ => Tile { image, position: reference.position, encoded: Some(Arc::clone(&transformed_bytes)) },
+    };
+    if let Some(headers) = headers {
+        tile_cache::put(&reference.key(), &headers, &transformed_bytes);
+    }
+    Ok((tile, received))
+}
+
+impl AsRef<Tile> for Tile {
+    fn as_ref(&self) -> &Tile { self }
+}
+
+/// The distance between two successive columns and between two successive rows of a grid of tiles.
+/// The most common distance is used, so that a few missing tiles do not change it,
+/// and the smallest one when there is no most common distance.
+pub fn grid_step(tile_refs: &[TileReference]) -> Option<Vec2d> {
+    let step = |coordinates: BTreeSet<u32>| -> Option<u32> {
+        let mut counts = HashMap::new();
+        for (a, b) in coordinates.iter().zip(coordinates.iter().skip(1)) {
+            *counts.entry(b - a).or_insert(0) += 1;
+        }
+        counts.into_iter().max_by_key(|&(step, count)| (count, std::cmp::Reverse(step))).map(|(step, _)| step)
+    };
+    let x = step(tile_refs.iter().map(|t| t.position.x).collect())?;
+    let y = step(tile_refs.iter().map(|t| t.position.y).collect())?;
+    Some(Vec2d { x, y })
+}
+
+/// The size of the area a tile should cover: its size when the dezoomer knows it,
+/// or its cell in the grid of tiles, cut at the edges of the image
+pub fn expected_size(tile_reference: &TileReference, step: Option<Vec2d>, image_size: Option<Vec2d>) -> Option<Vec2d> {
+    tile_reference.size.or_else(|| Some(step?.min(image_size? - tile_reference.position)))
+}
+
+/// Whether a tile is smaller than the area it should cover in both dimensions.
+/// This happens when a server (or a cache in front of it) sends a downscaled version of a tile,
+/// which leaves a blurry patch or a hole in the image.
+pub fn is_low_resolution(received: Vec2d, expected: Vec2d) -> bool {
+    received.x < expected.x && received.y < expected.y
+}
+
+/// Make a tile the size its dezoomer expected.
+/// A tile that was sent at another resolution is scaled, and a tile that has another shape
+/// is cropped, or padded with transparent pixels.
+pub fn fit_to_size(image: DynamicImage, expected: Vec2d) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    if (width, height) == (expected.x, expected.y) || expected.x == 0 || expected.y == 0 {
+        return image;
+    }
+    // Allow for the rounding of the dimensions when comparing the aspect ratios.
+    // The tolerance is relative to the compared products, so that an edge tile that was padded to the full tile size
+    // along its long side is cropped instead of being squeezed with its padding into the image.
+    let (received_shape, expected_shape) = (u64::from(width) * u64::from(expected.y), u64::from(height) * u64::from(expected.x));
+    if received_shape.abs_diff(expected_shape) <= received_shape.max(expected_shape) / 100 {
+        debug!("Scaling a {}x{} tile to {}x{}", width, height, expected.x, expected.y);
+        return image.resize_exact(expected.x, expected.y, image::imageops::FilterType::Triangle);
+    }
+    debug!("Cropping a {}x{} tile to {}x{}", width, height, expected.x, expected.y);
+    let mut fitted = image::RgbaImage::new(expected.x, expected.y);
+    image::imageops::replace(&mut fitted, &image.to_rgba8(), 0, 0);
+    DynamicImage::ImageRgba8(fitted)
+}
+
+/// Number of bytes added to a truncated JPEG file before decoding it
+const SALVAGE_PADDING: usize = 64;
+
+/// Decode the beginning of a JPEG image whose download was interrupted.
+/// The rows of pixels that could not be recovered are left transparent, like missing tiles.
+/// Returns None if no complete row could be recovered.
+pub fn salvage_jpeg(data: &[u8]) -> Option<DynamicImage> {
+    if !data.starts_with(&[0xFF, 0xD8]) { return None; }
+    // A trailing 0xFF would be interpreted as the beginning of a marker
+    let data = &data[..data.iter().rposition(|&b| b != 0xFF)? + 1];
+    // The decoder fills the truncated data with zeros; decoding it with two different paddings
+    // shows where the pixels stop depending on the received data
+    let decode = |padding: u8| {
+        let mut padded = data.to_vec();
+        padded.resize(data.len() + SALVAGE_PADDING, padding);
+        padded.extend(&[0xFF, 0xD9]);
+        image::load_from_memory_with_format(&padded, image::ImageFormat::Jpeg).ok().map(|i| i.to_rgba8())
+    };
+    let (mut image, other) = (decode(0x00)?, decode(0x55)?);
+    let (width, height) = image.dimensions();
+    let first_invalid_row = (0..height)
+        .find(|&y| (0..width).any(|x| image.get_pixel(x, y) != other.get_pixel(x, y)))
+        .unwrap_or(height);
+    // Rows are decoded by blocks of up to 16 pixels
+    let valid_rows = if first_invalid_row == height { height } else { first_invalid_row / 16 * 16 };
+    if valid_rows == 0 { return None; }
+    for y in valid_rows..height {
+        for x in 0..width { image.put_pixel(x, y, image::Rgba([0, 0, 0, 0])); }
+    }
+    Some(DynamicImage::ImageRgba8(image))
+}
+
 impl std::fmt::Debug for Tile {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_struct("Tile")
@@ -71,4 +205,63 @@ impl PartialEq for Tile {
                 other.image.get_pixel(x, y) == pix
             })
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_salvage_jpeg() {
+    let data = std::fs::read("testdata/zoomify/test_custom_size/TileGroup0/3-0-0.jpg").unwrap();
+    let full = image::load_from_memory(&data).unwrap().to_rgba8();
+    let salvaged = salvage_jpeg(&data[..data.len() * 2 / 3]).unwrap();
+    let (width, height) = salvaged.dimensions();
+    assert_eq!((width, height), full.dimensions());
+    let valid_rows = (0..height).take_while(|&y| salvaged.get_pixel(0, y)[3] == 255).count() as u32;
+    assert!(valid_rows > 0 && valid_rows < height, "{} valid rows out of {}", valid_rows, height);
+    for y in 0..valid_rows {
+        for x in 0..width {
+            assert_eq!(&salvaged.get_pixel(x, y), full.get_pixel(x, y), "pixel {},{}", x, y);
+        }
+    }
+    assert!(salvage_jpeg(&data[..100]).is_none());
+    assert!(salvage_jpeg(b"not a jpeg").is_none());
+}
+
+#[test]
+fn test_fit_to_size() {
+    let tile = |w, h| DynamicImage::new_rgb8(w, h);
+    let expected = Vec2d { x: 256, y: 100 };
+    // A tile with the same shape is scaled
+    let scaled = fit_to_size(tile(512, 200), expected);
+    assert_eq!(scaled.dimensions(), (256, 100));
+    assert_eq!(scaled.color(), image::ColorType::Rgb8);
+    // A tile with another shape is cropped and padded
+    let padded = fit_to_size(tile(300, 50), expected);
+    assert_eq!(padded.dimensions(), (256, 100));
+    assert_eq!(padded.to_rgba8().get_pixel(0, 0)[3], 255);
+    assert_eq!(padded.to_rgba8().get_pixel(0, 99)[3], 0);
+    // A right edge tile sent at the full tile width, with black padding, is cropped
+    let padded_edge = image::RgbImage::from_fn(512, 100, |x, _| image::Rgb([if x < 488 { 255 } else { 0 }, 0, 0]));
+    let cropped = fit_to_size(DynamicImage::ImageRgb8(padded_edge), Vec2d { x: 488, y: 100 });
+    assert_eq!(cropped.dimensions(), (488, 100));
+    assert_eq!(cropped.to_rgba8().get_pixel(487, 99)[0], 255);
+    // Rounded dimensions of a downscaled tile are tolerated
+    let rounded = fit_to_size(tile(244, 50), Vec2d { x: 487, y: 100 });
+    assert_eq!(rounded.to_rgba8().get_pixel(486, 99)[3], 255);
+}
+
+#[test]
+fn test_low_resolution() {
+    let image_size = Vec2d { x: 900, y: 300 };
+    let tile_ref = |x, y| TileReference { url: format!("{}_{}", x, y), position: Vec2d { x, y }, size: None, request: None };
+    let mut refs: Vec<_> = (0..4).flat_map(|x| (0..2).map(move |y| tile_ref(x * 256, y * 256))).collect();
+    // A missing column does not change the step of the grid
+    refs.retain(|t| t.position.x != 256);
+    let step = grid_step(&refs);
+    assert_eq!(step, Some(Vec2d::square(256)));
+    assert_eq!(expected_size(&refs[0], step, Some(image_size)), Some(Vec2d::square(256)));
+    let last = expected_size(&refs[5], step, Some(image_size)).unwrap();
+    assert_eq!(last, Vec2d { x: 132, y: 44 });
+    assert!(!is_low_resolution(last, last));
+    assert!(is_low_resolution(Vec2d::square(128), Vec2d::square(256)));
+    // Tiles that overlap their neighbours are larger than their cell
+    assert!(!is_low_resolution(Vec2d::square(258), Vec2d::square(256)));
+    assert_eq!(grid_step(&refs[..1]), None);
+}
@@ -0,0 +1,518 @@
+//! A directory in which downloaded tiles are kept, set with `--tile-cache`.
+//! The cache is consulted before making any network request, so tiles that are already in it
+//! are not downloaded again: an interrupted download can be resumed, the same image can be downloaded
+//! again almost instantly, and it can be assembled later without network access with `--from-tile-cache`.
+//!
+//! Each tile is stored after post-processing, in a file named after the hash of its URL,
+//! next to a JSON file with its metadata. When the cache grows over its maximum size,
+//! the least recently used tiles are evicted.
+//! A manifest records the size and title of the last downloaded image, and the position of each of its tiles.
+//! Query parameters that change at each visit of a page, such as timestamps and session ids,
+//! can be left out of the URLs the files are named after, with `--cache-ignore-param`.
+//!
+//! Images that are assembled on disk are assembled in the cache, in a checkpoint that lists the tiles
+//! already written to them, so that a crashed or aborted download resumes without compositing these tiles again.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use http::header::{self, HeaderMap};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::dezoomer::{TileFetchResult, TileProvider, TileReference};
+use url::Url;
+
+use crate::{Vec2d, ZoomError};
+
+const MANIFEST: &str = "manifest.jsonl";
+const METADATA_EXTENSION: &str = "json";
+/// Minimum time between two saves of a checkpoint, which require syncing the whole canvas to disk
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref TILE_CACHE: RwLock<Option<Arc<TileCache>>> = RwLock::new(None);
+}
+
+/// A line of the manifest
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum ManifestEntry {
+    Tile { x: u32, y: u32, file: String },
+    Image { width: Option<u32>, height: Option<u32>, title: Option<String> },
+}
+
+/// A line of the journal of a checkpoint: the position of a tile reference
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct JournalEntry { x: u32, y: u32 }
+
+/// Information about a cached tile, stored next to it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct TileMetadata {
+    url: String,
+    etag: Option<String>,
+    content_type: Option<String>,
+    /// Size of the stored tile, in bytes
+    size: u64,
+    /// Time of the last use of the tile, in milliseconds since the Unix epoch
+    last_used: u64,
+}
+
+/// The tiles in the cache, keyed by file name
+struct Index {
+    budget: u64,
+    used: u64,
+    entries: HashMap<String, TileMetadata>,
+}
+
+impl Index {
+    fn new(budget: u64) -> Self {
+        Index { budget, used: 0, entries: HashMap::new() }
+    }
+
+    /// Add a tile, and return the names of the files of the tiles to evict to stay within the budget
+    fn insert(&mut self, file: String, metadata: TileMetadata) -> Vec<String> {
+        self.used += metadata.size;
+        if let Some(old) = self.entries.insert(file, metadata) {
+            self.used -= old.size;
+        }
+        let mut evicted = vec![];
+        while self.used > self.budget {
+            let oldest = self.entries.iter()
+                .min_by_key(|(_, metadata)| metadata.last_used)
+                .map(|(file, _)| file.clone());
+            match oldest.and_then(|file| self.entries.remove_entry(&file)) {
+                Some((file, metadata)) => {
+                    self.used -= metadata.size;
+                    evicted.push(file);
+                }
+                None => break,
+            }
+        }
+        evicted
+    }
+
+    fn touch(&mut self, file: &str) -> Option<TileMetadata> {
+        let metadata = self.entries.get_mut(file)?;
+        metadata.last_used = now_millis().max(metadata.last_used + 1);
+        Some(metadata.clone())
+    }
+
+    fn remove(&mut self, file: &str) {
+        if let Some(metadata) = self.entries.remove(file) {
+            self.used -= metadata.size;
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+pub struct TileCache {
+    dir: PathBuf,
+    manifest: Mutex<File>,
+    index: Mutex<Index>,
+    /// Names of the query parameters that do not change the contents of a tile
+    ignored_params: Vec<String>,
+}
+
+impl TileCache {
+    /// Open a cache directory of at most `max_size` bytes, creating it if needed,
+    /// and start a new manifest in it
+    pub fn open(dir: &Path, max_size: u64) -> Result<Self, ZoomError> {
+        std::fs::create_dir_all(dir)?;
+        let manifest = File::create(dir.join(MANIFEST))?;
+        let mut entries = vec![];
+        for dir_entry in std::fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(METADATA_EXTENSION) { continue; }
+            let metadata = std::fs::read(&path).ok()
+                .and_then(|contents| serde_json::from_slice::<TileMetadata>(&contents).ok());
+            let file = path.file_stem().map(|f| f.to_string_lossy().into_owned());
+            match (metadata, file) {
+                (Some(metadata), Some(file)) if dir.join(&file).exists() => entries.push((file, metadata)),
+                _ => warn!("Ignoring invalid tile cache entry {:?}", path),
+            }
+        }
+        let cache = TileCache {
+            dir: dir.to_path_buf(),
+            manifest: Mutex::new(manifest),
+            index: Mutex::new(Index::new(max_size)),
+            ignored_params: vec![],
+        };
+        debug!("Found {} tiles in the tile cache", entries.len());
+        // Insert the most recently used tiles last, so that they are evicted last if the maximum size was reduced
+        entries.sort_by_key(|(_, metadata)| metadata.last_used);
+        for (file, metadata) in entries {
+            let evicted = cache.index.lock().unwrap().insert(file, metadata);
+            cache.remove_files(&evicted);
+        }
+        Ok(cache)
+    }
+
+    /// Do not take the given query parameters into account to recognize tiles
+    pub fn ignoring_params(mut self, params: Vec<String>) -> Self {
+        self.ignored_params = params;
+        self
+    }
+
+    fn file_name(url: &str) -> String {
+        Sha1::digest(url.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// The name of the file of a tile, which is the same for all the URLs that only differ by ignored parameters
+    fn key(&self, url: &str) -> String {
+        Self::file_name(&canonical_url(url, &self.ignored_params))
+    }
+
+    fn metadata_path(&self, file: &str) -> PathBuf {
+        self.dir.join(file).with_extension(METADATA_EXTENSION)
+    }
+
+    fn remove_files(&self, files: &[String]) {
+        for file in files {
+            debug!("Evicting {} from the tile cache", file);
+            let _ = std::fs::remove_file(self.dir.join(file));
+            let _ = std::fs::remove_file(self.metadata_path(file));
+        }
+    }
+
+    fn write_entry(&self, entry: &ManifestEntry) {
+        let mut line = serde_json::to_vec(entry).expect("manifest entries can be serialized");
+        line.push(b'\n');
+        if let Err(e) = self.manifest.lock().unwrap().write_all(&line) {
+            warn!("Unable to write to the tile cache manifest: {}", e);
+        }
+    }
+}
+
+/// The URL without the given query parameters
+fn canonical_url(url: &str, ignored_params: &[String]) -> String {
+    let mut parsed = match Url::parse(url) {
+        Ok(parsed) if !ignored_params.is_empty() && parsed.query().is_some() => parsed,
+        _ => return url.to_string(),
+    };
+    let kept: Vec<(String, String)> = parsed.query_pairs()
+        .filter(|(name, _)| !ignored_params.iter().any(|ignored| ignored == name))
+        .map(|(name, value)| (name.into_owned(), value.into_owned()))
+        .collect();
+    if kept.is_empty() {
+        parsed.set_query(None);
+    } else {
+        parsed.query_pairs_mut().clear().extend_pairs(kept);
+    }
+    parsed.to_string()
+}
+
+/// Start using the given tile cache, or stop using any if it is `None`
+pub fn set_tile_cache(cache: Option<TileCache>) {
+    *TILE_CACHE.write().unwrap() = cache.map(Arc::new);
+}
+
+fn tile_cache() -> Option<Arc<TileCache>> {
+    TILE_CACHE.read().unwrap().clone()
+}
+
+/// Write a file through a temporary file, so that an interrupted write never leaves a truncated file
+fn write_file(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    std::fs::write(&tmp_path, contents)?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// The post-processed contents of a tile, if it is in the cache
+pub fn get(url: &str) -> Option<Vec<u8>> {
+    let cache = tile_cache()?;
+    let file = cache.key(url);
+    let metadata = cache.index.lock().unwrap().touch(&file)?;
+    match std::fs::read(cache.dir.join(&file)) {
+        Ok(contents) => {
+            debug!("Found {} in the tile cache", url);
+            let metadata = serde_json::to_vec(&metadata).expect("metadata can be serialized");
+            if let Err(e) = write_file(&cache.metadata_path(&file), &metadata) {
+                debug!("Unable to update the metadata of {} in the tile cache: {}", url, e);
+            }
+            Some(contents)
+        }
+        Err(e) => {
+            warn!("Unable to read {} from the tile cache: {}", url, e);
+            cache.index.lock().unwrap().remove(&file);
+            None
+        }
+    }
+}
+
+/// Store the post-processed contents of a tile, with the headers of the response it was received in
+pub fn put(url: &str, headers: &HeaderMap, contents: &[u8]) {
+    let cache = match tile_cache() { Some(cache) => cache, None => return };
+    if contents.len() as u64 > cache.index.lock().unwrap().budget { return; }
+    let header_value = |name: header::HeaderName| {
+        headers.get(name).and_then(|v| v.to_str().ok()).map(String::from)
+    };
+    let metadata = TileMetadata {
+        url: url.to_string(),
+        etag: header_value(header::ETAG),
+        content_type: header_value(header::CONTENT_TYPE),
+        size: contents.len() as u64,
+        last_used: now_millis(),
+    };
+    let file = cache.key(url);
+    let serialized = serde_json::to_vec(&metadata).expect("metadata can be serialized");
+    let result = write_file(&cache.dir.join(&file), contents)
+        .and_then(|()| write_file(&cache.metadata_path(&file), &serialized));
+    if let Err(e) = result {
+        warn!("Unable to store the tile {} in the cache: {}", url, e);
+        return;
+    }
+    let evicted = cache.index.lock().unwrap().insert(file, metadata);
+    cache.remove_files(&evicted);
+}
+
+/// Remove a tile from the cache, so that it is downloaded again
+pub fn forget(url: &str) {
+    if let Some(cache) = tile_cache() {
+        let file = cache.key(url);
+        cache.index.lock().unwrap().remove(&file);
+        cache.remove_files(&[file]);
+    }
+}
+
+/// Record the size and title of the image that is being downloaded
+pub fn record_image(size: Option<Vec2d>, title: Option<String>) {
+    if let Some(cache) = tile_cache() {
+        cache.write_entry(&ManifestEntry::Image {
+            width: size.map(|s| s.x),
+            height: size.map(|s| s.y),
+            title,
+        });
+    }
+}
+
+/// Record the position in the image of a tile that was stored in the cache
+pub fn record_tile(url: &str, position: Vec2d) {
+    if let Some(cache) = tile_cache() {
+        cache.write_entry(&ManifestEntry::Tile {
+            x: position.x,
+            y: position.y,
+            file: cache.key(url),
+        });
+    }
+}
+
+/// An image being assembled on disk in the tile cache, that survives a crash of dezoomify-rs.
+/// Its pixels are in a canvas file, and a journal lists the positions of the tiles that were written to it.
+/// The journal is only appended to after the canvas is synced to disk, so all the tiles it lists are in the canvas.
+#[derive(Debug)]
+pub struct Checkpoint {
+    canvas: PathBuf,
+    journal: PathBuf,
+    tiles: HashSet<Vec2d>,
+    /// Tiles written to the canvas since the last save
+    pending: Vec<Vec2d>,
+    last_save: Instant,
+}
+
+impl Checkpoint {
+    /// Open the checkpoint stored at the given path, without extension
+    pub(crate) fn open(path: &Path) -> Self {
+        let canvas = path.with_extension("canvas");
+        let journal = path.with_extension("tiles");
+        let mut tiles = HashSet::new();
+        if canvas.exists() {
+            // The last line may be incomplete if dezoomify-rs was stopped while writing it
+            let contents = std::fs::read_to_string(&journal).unwrap_or_default();
+            tiles.extend(contents.lines()
+                .filter_map(|line| serde_json::from_str(line).ok())
+                .map(|JournalEntry { x, y }| Vec2d { x, y }));
+        } else {
+            let _ = std::fs::remove_file(&journal);
+        }
+        Checkpoint { canvas, journal, tiles, pending: vec![], last_save: Instant::now() }
+    }
+
+    /// The file in which the image is assembled
+    pub fn canvas(&self) -> &Path {
+        &self.canvas
+    }
+
+    /// The positions of the references of the tiles that are in the canvas
+    pub fn tiles(&self) -> &HashSet<Vec2d> {
+        &self.tiles
+    }
+
+    /// Record that the tiles were written to the canvas, and save the checkpoint if it was not saved recently
+    pub fn record(&mut self, canvas: &File, tiles: &[Vec2d]) -> std::io::Result<()> {
+        self.pending.extend_from_slice(tiles);
+        if self.last_save.elapsed() >= CHECKPOINT_INTERVAL {
+            self.save(canvas)?;
+        }
+        Ok(())
+    }
+
+    /// Sync the canvas to disk, and add the tiles that were written to it to the journal
+    pub fn save(&mut self, canvas: &File) -> std::io::Result<()> {
+        self.last_save = Instant::now();
+        if self.pending.is_empty() { return Ok(()); }
+        canvas.sync_data()?;
+        let mut lines = vec![];
+        for Vec2d { x, y } in &self.pending {
+            serde_json::to_writer(&mut lines, &JournalEntry { x: *x, y: *y }).expect("journal entries can be serialized");
+            lines.push(b'\n');
+        }
+        let mut journal = OpenOptions::new().create(true).append(true).open(&self.journal)?;
+        journal.write_all(&lines)?;
+        journal.sync_data()?;
+        debug!("Saved a checkpoint of {} new tiles in {:?}", self.pending.len(), self.journal);
+        self.tiles.extend(self.pending.drain(..));
+        Ok(())
+    }
+
+    /// Remove the checkpoint once the image was saved
+    pub fn remove(self) {
+        let _ = std::fs::remove_file(&self.canvas);
+        let _ = std::fs::remove_file(&self.journal);
+    }
+}
+
+/// The checkpoint in the tile cache of the image with the given description, if a tile cache is used.
+/// The description must change with everything that changes the pixels of the assembled image.
+pub fn checkpoint(image: &str) -> Option<Checkpoint> {
+    let cache = tile_cache()?;
+    let name = format!("checkpoint_{}", TileCache::file_name(image));
+    Some(Checkpoint::open(&cache.dir.join(name)))
+}
+
+/// An image assembled from the tiles stored in a cache directory
+#[derive(Debug)]
+pub struct CachedImage {
+    size: Option<Vec2d>,
+    title: Option<String>,
+    tiles: Vec<TileReference>,
+}
+
+impl CachedImage {
+    /// Read the manifest of a tile cache directory
+    pub fn load(dir: &Path) -> Result<Self, ZoomError> {
+        let manifest = std::fs::read_to_string(dir.join(MANIFEST))?;
+        let mut image = CachedImage { size: None, title: None, tiles: vec![] };
+        // A tile can be recorded several times: only keep the last file for each position
+        let mut tiles = BTreeMap::new();
+        for line in manifest.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str(line) {
+                Ok(ManifestEntry::Tile { x, y, file }) => {
+                    let path = dir.join(file);
+                    if path.exists() {
+                        tiles.insert((y, x), path.to_string_lossy().into_owned());
+                    } else {
+                        warn!("The tile at {},{} is missing from the cache", x, y);
+                    }
+                }
+                Ok(ManifestEntry::Image { width, height, title }) => {
+                    image.size = width.zip(height).map(|(x, y)| Vec2d { x, y }).or(image.size);
+                    image.title = title.or(image.title);
+                }
+                Err(e) => warn!("Invalid line in the tile cache manifest: {}", e),
+            }
+        }
+        image.tiles = tiles.into_iter()
+            .map(|((y, x), url)| TileReference { url, position: Vec2d { x, y }, size: None, request: None })
+            .collect();
+        Ok(image)
+    }
+}
+
+impl TileProvider for CachedImage {
+    fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
+        if previous.is_some() { vec![] } else { std::mem::take(&mut self.tiles) }
+    }
+
+    fn name(&self) -> String {
+        format!("Cached tiles ({} tiles)", self.tiles.len())
+    }
+
+    fn title(&self) -> Option<String> {
+        self.title.clone()
+    }
+
+    fn size_hint(&self) -> Option<Vec2d> {
+        self.size
+    }
+}
+
+#[test]
+fn test_manifest() {
+    let dir = tempdir::TempDir::new("dezoomify-rs-tile-cache").unwrap();
+    let cache = TileCache::open(dir.path(), 1 << 20).unwrap();
+    let url = "http://example.com/tile_1_0.jpg";
+    std::fs::write(dir.path().join(TileCache::file_name(url)), b"data").unwrap();
+    cache.write_entry(&ManifestEntry::Image { width: None, height: None, title: Some("Image".into()) });
+    cache.write_entry(&ManifestEntry::Tile { x: 256, y: 0, file: TileCache::file_name(url) });
+    cache.write_entry(&ManifestEntry::Tile { x: 0, y: 0, file: TileCache::file_name("missing") });
+    cache.write_entry(&ManifestEntry::Image { width: Some(300), height: Some(200), title: None });
+    drop(cache);
+    let mut image = CachedImage::load(dir.path()).unwrap();
+    assert_eq!(image.size_hint(), Some(Vec2d { x: 300, y: 200 }));
+    assert_eq!(image.title().as_deref(), Some("Image"));
+    let tiles = image.next_tiles(None);
+    assert_eq!(tiles.len(), 1);
+    assert_eq!(tiles[0].position, Vec2d { x: 256, y: 0 });
+    assert_eq!(std::fs::read(&tiles[0].url).unwrap(), b"data");
+}
+
+#[test]
+fn test_canonical_url() {
+    let ignored = vec!["t".to_string(), "session".to_string()];
+    assert_eq!(canonical_url("http://example.com/tile.jpg?t=1234&session=ab", &ignored), "http://example.com/tile.jpg");
+    assert_eq!(canonical_url("http://example.com/tile.jpg?x=1&t=1234&y=2", &ignored), "http://example.com/tile.jpg?x=1&y=2");
+    assert_eq!(canonical_url("http://example.com/tile.jpg?t=1", &[]), "http://example.com/tile.jpg?t=1");
+    assert_eq!(canonical_url("tiles/tile.jpg?t=1", &ignored), "tiles/tile.jpg?t=1");
+    let dir = tempdir::TempDir::new("dezoomify-rs-tile-cache-params").unwrap();
+    let cache = TileCache::open(dir.path(), 1 << 20).unwrap().ignoring_params(ignored);
+    assert_eq!(cache.key("http://example.com/a.jpg?t=1"), cache.key("http://example.com/a.jpg?t=2"));
+    assert_ne!(cache.key("http://example.com/a.jpg?t=1"), cache.key("http://example.com/b.jpg?t=1"));
+}
+
+#[test]
+fn test_checkpoint() {
+    let dir = tempdir::TempDir::new("dezoomify-rs-checkpoint").unwrap();
+    let path = dir.path().join("checkpoint");
+    let mut checkpoint = Checkpoint::open(&path);
+    assert!(checkpoint.tiles().is_empty());
+    let canvas = File::create(checkpoint.canvas()).unwrap();
+    checkpoint.record(&canvas, &[Vec2d { x: 0, y: 0 }, Vec2d { x: 256, y: 0 }]).unwrap();
+    checkpoint.save(&canvas).unwrap();
+    checkpoint.record(&canvas, &[Vec2d { x: 0, y: 256 }]).unwrap();
+    // The tiles recorded after the last save are not in the journal
+    drop(checkpoint);
+    let checkpoint = Checkpoint::open(&path);
+    let expected: HashSet<Vec2d> = [Vec2d { x: 0, y: 0 }, Vec2d { x: 256, y: 0 }].iter().copied().collect();
+    assert_eq!(checkpoint.tiles(), &expected);
+    checkpoint.remove();
+    assert!(Checkpoint::open(&path).tiles().is_empty());
+}
+
+#[test]
+fn test_eviction() {
+    let metadata = |last_used| TileMetadata {
+        url: String::new(), etag: None, content_type: None, size: 10, last_used,
+    };
+    let mut index = Index::new(30);
+    assert!(index.insert("a".into(), metadata(1)).is_empty());
+    assert!(index.insert("b".into(), metadata(2)).is_empty());
+    assert!(index.insert("c".into(), metadata(3)).is_empty());
+    // Using a tile makes it the most recently used one
+    assert!(index.touch("a").is_some());
+    assert_eq!(index.insert("d".into(), metadata(4)), vec!["b".to_string()]);
+    assert_eq!(index.used, 30);
+    // Storing the same tile again does not count it twice
+    assert!(index.insert("d".into(), metadata(5)).is_empty());
+    assert_eq!(index.used, 30);
+}
@@ -0,0 +1,121 @@
+//! Processing the tiles with an external command given with `--tile-filter`,
+//! for servers that obfuscate their tiles in ways dezoomify-rs does not know.
+
+use std::error::Error;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+
+use custom_error::custom_error;
+use lazy_static::lazy_static;
+
+use crate::dezoomer::{PostProcessFn, TileReference};
+
+custom_error! {pub TileFilterError
+    Spawn{command: String, source: std::io::Error} = "unable to run the tile filter '{command}': {source}",
+    Failed{command: String, status: String, stderr: String} = "the tile filter '{command}' failed ({status}): {stderr}",
+    Empty{command: String} = "the tile filter '{command}' did not output anything",
+}
+
+lazy_static! {
+    /// The number of filter processes running, and the condition signaled when one of them ends.
+    /// Tiles are downloaded with a high parallelism, but no more processes than processors are started.
+    static ref RUNNING: (Mutex<usize>, Condvar) = (Mutex::new(0), Condvar::new());
+    static ref MAX_RUNNING: usize = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4);
+}
+
+/// A place among the running filter processes, released when dropped
+struct Slot;
+
+impl Slot {
+    fn acquire() -> Slot {
+        let (running, ended) = &*RUNNING;
+        let mut running = ended.wait_while(running.lock().unwrap(), |n| *n >= *MAX_RUNNING).unwrap();
+        *running += 1;
+        Slot
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let (running, ended) = &*RUNNING;
+        *running.lock().unwrap() -= 1;
+        ended.notify_one();
+    }
+}
+
+/// Applies the filter command, if there is one, after the post-processing of the dezoomer
+pub fn with_tile_filter(post_process_fn: PostProcessFn, command: Option<&str>) -> PostProcessFn {
+    let command = match command {
+        Some(command) => command.to_string(),
+        None => return post_process_fn,
+    };
+    PostProcessFn::Closure(Arc::new(move |tile: &TileReference, data: Vec<u8>| {
+        let data = match &post_process_fn {
+            PostProcessFn::Fn(post_process) => post_process(tile, data)?,
+            PostProcessFn::Closure(post_process) => post_process(tile, data)?,
+            PostProcessFn::None => data,
+        };
+        run_filter(&command, &data).map_err(|e| Box::new(e) as Box<dyn Error + Send>)
+    }))
+}
+
+fn run_filter(command: &str, data: &[u8]) -> Result<Vec<u8>, TileFilterError> {
+    let _slot = Slot::acquire();
+    let spawn_error = |source| TileFilterError::Spawn { command: command.to_string(), source };
+    let mut child = shell(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(spawn_error)?;
+    let mut stdin = child.stdin.take().expect("the standard input of the filter is piped");
+    let output = std::thread::scope(|scope| {
+        // Written from another thread, because the filter may start writing before it has read everything.
+        // A filter that does not read its whole input is not an error.
+        scope.spawn(move || { let _ = stdin.write_all(data); });
+        child.wait_with_output()
+    }).map_err(spawn_error)?;
+    if !output.status.success() {
+        return Err(TileFilterError::Failed {
+            command: command.to_string(),
+            status: output.status.to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    if output.stdout.is_empty() {
+        return Err(TileFilterError::Empty { command: command.to_string() });
+    }
+    Ok(output.stdout)
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(unix)]
+#[test]
+fn test_tile_filter() {
+    let tile = TileReference { url: "http://example.com/0_0.jpg".into(), position: Default::default(), size: None, request: None };
+    let apply = |post_process_fn: PostProcessFn, data: &[u8]| match post_process_fn {
+        PostProcessFn::Closure(post_process) => post_process(&tile, data.to_vec()).map_err(|e| e.to_string()),
+        _ => panic!("the filter should be applied in a closure"),
+    };
+    let reverse = PostProcessFn::Fn(|_, data| Ok(data.into_iter().rev().collect()));
+    assert_eq!(apply(with_tile_filter(reverse, Some("tr a b")), b"abc"), Ok(b"cbb".to_vec()));
+    let error = apply(with_tile_filter(PostProcessFn::None, Some("echo oops >&2; exit 3")), b"abc").unwrap_err();
+    assert!(error.contains("failed") && error.contains("oops"), "{}", error);
+    let error = apply(with_tile_filter(PostProcessFn::None, Some("cat >/dev/null")), b"abc").unwrap_err();
+    assert!(error.contains("did not output anything"), "{}", error);
+    assert!(matches!(with_tile_filter(PostProcessFn::None, None), PostProcessFn::None));
+}
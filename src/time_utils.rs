@@ -0,0 +1,36 @@
+//! Formatting of dates, for the audit log and the metadata of the output images.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Format a time as an RFC 3339 UTC timestamp, such as 2021-03-04T05:06:07.890Z
+pub fn rfc3339(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+    // Convert a number of days since the epoch to a date in the proleptic gregorian calendar
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day,
+        secs_of_day / 3600, secs_of_day / 60 % 60, secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[test]
+fn test_rfc3339() {
+    use std::time::Duration;
+    assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    let t = UNIX_EPOCH + Duration::from_millis(1_614_834_367_890);
+    assert_eq!(rfc3339(t), "2021-03-04T05:06:07.890Z");
+    let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+    assert_eq!(rfc3339(leap), "2000-02-29T00:00:00.000Z");
+}
@@ -0,0 +1,41 @@
+//! Helpers used by the dezoomers to locate and read the metadata files they are given.
+//! They do not depend on the network stack, so that the parsing of metadata can be reused
+//! without it.
+
+use std::path::PathBuf;
+
+use url::Url;
+
+pub fn resolve_relative(base: &str, path: &str) -> String {
+    if Url::parse(path).is_ok() {
+        return path.to_string()
+    } else if let Ok(url) = Url::parse(base) {
+        if let Ok(r) = url.join(path) {
+            return r.to_string()
+        }
+    }
+    let mut res = PathBuf::from(base.rsplitn(2, '/').last().unwrap_or_default());
+    res.push(path);
+    res.to_string_lossy().to_string()
+}
+
+pub fn remove_bom(contents: &[u8]) -> &[u8] {
+    // Workaround for https://github.com/netvl/xml-rs/issues/155
+    // which the original author seems unwilling to fix
+    const BOM: &[u8] = &[0xEF, 0xBB, 0xBF]; // UTF8 byte order mark
+    if contents.starts_with(BOM) {
+        &contents[BOM.len()..]
+    } else { contents }
+}
+
+#[test]
+fn test_resolve_relative() {
+    use std::path::MAIN_SEPARATOR;
+    assert_eq!(resolve_relative("/a/b", "c/d"), format!("/a{}c/d", MAIN_SEPARATOR));
+    assert_eq!(resolve_relative("C:\\X", "c/d"), format!("C:\\X{}c/d", MAIN_SEPARATOR));
+    assert_eq!(resolve_relative("/a/b", "http://example.com/x"), "http://example.com/x");
+    assert_eq!(resolve_relative("http://a.b", "http://example.com/x"), "http://example.com/x");
+    assert_eq!(resolve_relative("http://a.b", "c/d"), "http://a.b/c/d");
+    assert_eq!(resolve_relative("http://a.b/x", "c/d"), "http://a.b/c/d");
+    assert_eq!(resolve_relative("http://a.b/x/", "c/d"), "http://a.b/x/c/d");
+}
@@ -0,0 +1,89 @@
+//! The warmup phase enabled by `--warmup`. Before the download of the tiles starts,
+//! a few of them are requested in parallel. This resolves the name of the server and opens
+//! connections that are then reused for the rest of the download, and measures the latency
+//! and the size of the tiles, in order to estimate the duration and size of the whole download.
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use futures::future::join_all;
+use indicatif::{HumanBytes, HumanDuration};
+use log::debug;
+
+use crate::dezoomer::TileReference;
+use crate::network::{fetch_uri, Fetcher};
+
+/// Maximum number of tiles requested during the warmup
+const WARMUP_TILES: usize = 4;
+
+/// An estimate of the resources needed to download a batch of tiles
+#[derive(Debug, PartialEq)]
+pub struct Plan {
+    pub tile_count: usize,
+    pub parallelism: usize,
+    /// Average size of the sample tiles, in bytes
+    pub tile_bytes: u64,
+    /// Average time it took to download a sample tile
+    pub latency: Duration,
+}
+
+impl Plan {
+    pub fn total_bytes(&self) -> u64 {
+        self.tile_bytes * self.tile_count as u64
+    }
+
+    pub fn duration(&self) -> Duration {
+        let rounds = self.tile_count.div_ceil(self.parallelism.max(1));
+        self.latency * u32::try_from(rounds).unwrap_or(u32::MAX)
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Downloading {} tiles: about {} in {} (average tile: {} in {} ms)",
+               self.tile_count, HumanBytes(self.total_bytes()), HumanDuration(self.duration()),
+               HumanBytes(self.tile_bytes), self.latency.as_millis())
+    }
+}
+
+/// Tiles spread evenly across the batch, because the tiles on the edges are often smaller
+fn sample(tile_refs: &[TileReference]) -> impl Iterator<Item=&TileReference> {
+    let step = tile_refs.len().div_ceil(WARMUP_TILES).max(1);
+    tile_refs.iter().step_by(step)
+}
+
+/// Downloads a few tiles of the batch in parallel, and returns an estimate of the resources needed
+/// to download all of them. Returns None if none of the sample tiles could be downloaded.
+pub async fn warmup(tile_refs: &[TileReference], http: &Fetcher, parallelism: usize) -> Option<Plan> {
+    let timed_fetches = sample(tile_refs).map(|tile_ref| async move {
+        let start = Instant::now();
+        let result = fetch_uri(&tile_ref.url, http).await;
+        if let Err(e) = &result { debug!("Warmup request to {} failed: {}", tile_ref.url, e) }
+        result.ok().map(|contents| (contents.len() as u64, start.elapsed()))
+    });
+    let samples: Vec<(u64, Duration)> = join_all(timed_fetches).await.into_iter().flatten().collect();
+    let count = u32::try_from(samples.len()).ok().filter(|&n| n > 0)?;
+    Some(Plan {
+        tile_count: tile_refs.len(),
+        parallelism,
+        tile_bytes: samples.iter().map(|&(bytes, _)| bytes).sum::<u64>() / u64::from(count),
+        latency: samples.iter().map(|&(_, latency)| latency).sum::<Duration>() / count,
+    })
+}
+
+#[test]
+fn test_plan() {
+    let tile_refs: Vec<TileReference> = (0..10).map(|x| TileReference {
+        url: format!("{}.jpg", x),
+        position: crate::Vec2d { x, y: 0 },
+        size: None,
+        request: None,
+    }).collect();
+    let urls: Vec<&str> = sample(&tile_refs).map(|t| t.url.as_str()).collect();
+    assert_eq!(urls, vec!["0.jpg", "3.jpg", "6.jpg", "9.jpg"]);
+
+    let plan = Plan { tile_count: 100, parallelism: 16, tile_bytes: 20_000, latency: Duration::from_millis(500) };
+    assert_eq!(plan.total_bytes(), 2_000_000);
+    assert_eq!(plan.duration(), Duration::from_millis(3500));
+}
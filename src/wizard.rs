@@ -0,0 +1,276 @@
+//! The `--wizard` mode: build a tiles.yaml file for the custom YAML dezoomer
+//! from the URL of a single example tile. The user tells which numbers of the URL
+//! are the column, the row and the zoom level of the tile, then the number of tiles
+//! of each zoom level is probed with the generic dezoomer.
+
+use std::ops::Range;
+use std::path::PathBuf;
+
+use image::GenericImageView;
+use lazy_static::lazy_static;
+use log::{info, warn};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::arguments::Arguments;
+use crate::dezoomer::{TileFetchResult, ZoomLevel};
+use crate::generic::GenericDezoomer;
+use crate::network::{client, fetch_uri, Fetcher};
+use crate::{stdin_line, Vec2d, ZoomError};
+use crate::download::list_tiles;
+
+lazy_static! {
+    static ref NUMBER_RE: Regex = Regex::new(r"\d+").unwrap();
+}
+
+/// What a number in the example tile URL stands for
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    X,
+    Y,
+    Z,
+}
+
+/// The numbers that appear in a URL
+fn numbers(url: &str) -> Vec<Range<usize>> {
+    NUMBER_RE.find_iter(url).map(|m| m.range()).collect()
+}
+
+/// The most common layout: `.../{z}/{x}/{y}.jpg` or `..._{x}_{y}.jpg`
+fn guess_roles(count: usize) -> Vec<(usize, Role)> {
+    match count {
+        0 | 1 => vec![],
+        2 => vec![(0, Role::X), (1, Role::Y)],
+        n => vec![(n - 3, Role::Z), (n - 2, Role::X), (n - 1, Role::Y)],
+    }
+}
+
+/// A tile URL template, in which the numbers with a role are replaced by placeholders.
+/// With `generic`, the syntax of the generic dezoomer is used, which supports zero-padding,
+/// otherwise the syntax of tiles.yaml files.
+fn template(url: &str, numbers: &[Range<usize>], roles: &[(usize, Role)], generic: bool) -> String {
+    let mut template = String::with_capacity(url.len());
+    let mut cursor = 0;
+    for (i, range) in numbers.iter().enumerate() {
+        let role = match roles.iter().find(|&&(n, _)| n == i) {
+            Some(&(_, role)) => role,
+            None => continue,
+        };
+        template.push_str(&url[cursor..range.start]);
+        let name = match (role, generic) {
+            (Role::X, true) => "X",
+            (Role::Y, true) => "Y",
+            (Role::Z, true) => "Z",
+            (Role::X, false) => "x",
+            (Role::Y, false) => "y",
+            (Role::Z, false) => "z",
+        };
+        let number = &url[range.clone()];
+        if generic && number.len() > 1 && number.starts_with('0') {
+            template.push_str(&format!("{{{{{}:0{}}}}}", name, number.len()));
+        } else {
+            template.push_str(&format!("{{{{{}}}}}", name));
+        }
+        cursor = range.end;
+    }
+    template.push_str(&url[cursor..]);
+    template
+}
+
+/// The number of tiles of a zoom level, as found by probing
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Grid {
+    tile_size: Vec2d,
+    tiles: Vec2d,
+}
+
+#[derive(Serialize, Debug)]
+struct YamlLevel {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    url_template: String,
+    x_template: String,
+    y_template: String,
+    variables: Vec<YamlVariable>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum YamlVariable {
+    Range { name: &'static str, from: u32, to: u32 },
+    Constant { name: &'static str, value: u32 },
+}
+
+#[derive(Serialize, Debug)]
+struct YamlLevels {
+    levels: Vec<YamlLevel>,
+}
+
+impl YamlLevel {
+    fn new(url_template: &str, z: Option<u32>, grid: Grid) -> Self {
+        let mut variables = vec![
+            YamlVariable::Range { name: "x", from: 0, to: grid.tiles.x.saturating_sub(1) },
+            YamlVariable::Range { name: "y", from: 0, to: grid.tiles.y.saturating_sub(1) },
+        ];
+        if let Some(value) = z {
+            variables.push(YamlVariable::Constant { name: "z", value });
+        }
+        YamlLevel {
+            name: z.map(|z| format!("zoom level {}", z)),
+            url_template: url_template.to_string(),
+            x_template: format!("x * {}", grid.tile_size.x),
+            y_template: format!("y * {}", grid.tile_size.y),
+            variables,
+        }
+    }
+}
+
+/// The contents of a tiles.yaml file describing the given levels
+fn tiles_yaml(example: &str, mut levels: Vec<YamlLevel>) -> Result<String, serde_yaml::Error> {
+    let yaml = if levels.len() == 1 {
+        serde_yaml::to_string(&levels.remove(0))?
+    } else {
+        serde_yaml::to_string(&YamlLevels { levels })?
+    };
+    Ok(format!("# Generated by dezoomify-rs --wizard from the example tile {}\n{}", example, yaml))
+}
+
+/// Asks for the position of the number that has the given role
+fn ask_role(question: &str, count: usize, default: Option<usize>) -> Result<Option<usize>, ZoomError> {
+    loop {
+        match default {
+            Some(d) => eprintln!("{} [{}] ", question, d + 1),
+            None => eprintln!("{} [none] ", question),
+        }
+        let line = stdin_line()?;
+        let line = line.trim();
+        if line.is_empty() { return Ok(default); }
+        if line == "none" { return Ok(None); }
+        match line.parse::<usize>() {
+            Ok(n) if (1..=count).contains(&n) => return Ok(Some(n - 1)),
+            _ => eprintln!("'{}' is not a number between 1 and {}", line, count),
+        }
+    }
+}
+
+fn choose_roles(url: &str, numbers: &[Range<usize>], args: &Arguments) -> Result<Vec<(usize, Role)>, ZoomError> {
+    let guess = guess_roles(numbers.len());
+    if !args.is_interactive() {
+        return Ok(guess);
+    }
+    eprintln!("Numbers found in the example tile URL:");
+    for (i, range) in numbers.iter().enumerate() {
+        eprintln!("{: >2}. {}[{}]{}", i + 1, &url[..range.start], &url[range.clone()], &url[range.end..]);
+    }
+    let default = |role| guess.iter().find(|&&(_, r)| r == role).map(|&(n, _)| n);
+    let mut roles = vec![];
+    for &(role, question) in &[
+        (Role::X, "Which number is the column of the tile (x)?"),
+        (Role::Y, "Which number is the row of the tile (y)?"),
+        (Role::Z, "Which number is the zoom level (type 'none' if there is none)?"),
+    ] {
+        if let Some(n) = ask_role(question, numbers.len(), default(role))? {
+            roles.retain(|&(m, _)| m != n);
+            roles.push((n, role));
+        }
+    }
+    Ok(roles)
+}
+
+/// Finds the number of tiles in a level, by requesting tiles until the last row and column are found.
+/// Only a few tiles are downloaded.
+async fn probe_level(mut level: ZoomLevel, http: &Fetcher) -> Option<Grid> {
+    let mut tile_size: Option<Vec2d> = None;
+    let mut previous = None;
+    loop {
+        let tile_refs = level.next_tiles(previous);
+        // The generic dezoomer knows the size of the image once it has found the last tile
+        if let (Some(size), Some(tile_size)) = (level.size_hint(), tile_size) {
+            return Some(Grid { tile_size, tiles: size / tile_size });
+        }
+        if tile_refs.is_empty() { return None; }
+        let mut successful_urls = vec![];
+        for tile_ref in &tile_refs {
+            let image = fetch_uri(&tile_ref.url, http).await.ok()
+                .and_then(|bytes| image::load_from_memory(&bytes).ok());
+            info!("Probing {}: {}", tile_ref.url, if image.is_some() { "found" } else { "not found" });
+            if let Some(image) = image {
+                successful_urls.push(tile_ref.url.clone());
+                tile_size = tile_size.or_else(|| Some(image.dimensions().into()));
+            }
+        }
+        let (count, successes) = (tile_refs.len() as u64, successful_urls.len() as u64);
+        previous = Some(TileFetchResult { count, successes, tile_size, successful_urls });
+    }
+}
+
+/// Asks which numbers of the example tile URL are the coordinates of the tile,
+/// probes the number of tiles of each level, and writes the corresponding tiles.yaml file
+pub async fn wizard(args: &Arguments) -> Result<(), ZoomError> {
+    let url = args.choose_input_uri()?;
+    let numbers = numbers(&url);
+    let roles = choose_roles(&url, &numbers, args)?;
+    if !roles.iter().any(|&(_, r)| r == Role::X) || !roles.iter().any(|&(_, r)| r == Role::Y) {
+        return Err(ZoomError::Wizard { reason: format!("unable to find the column and row of the tile in {}", url) });
+    }
+    let generic_template = template(&url, &numbers, &roles, true);
+    let yaml_template = template(&url, &numbers, &roles, false);
+    if generic_template.contains(":0") {
+        warn!("The numbers in the tile URLs are padded with zeroes, which tiles.yaml files cannot express. \
+               You can download the image directly with the generic dezoomer, using the template {}",
+              generic_template);
+    }
+    let http = client(args.headers(), args, Some(&url))?;
+    let mut dezoomer = GenericDezoomer::default();
+    let levels = list_tiles(&mut dezoomer, &http, &generic_template, args).await?;
+    let level_numbers = dezoomer.found_levels().to_vec();
+    let mut yaml_levels = Vec::with_capacity(levels.len());
+    for (i, level) in levels.into_iter().enumerate() {
+        let z = level_numbers.get(i).copied();
+        match probe_level(level, &http).await {
+            Some(grid) => {
+                println!("Level {}: {} x {} tiles of {} x {} pixels", z.unwrap_or(0),
+                         grid.tiles.x, grid.tiles.y, grid.tile_size.x, grid.tile_size.y);
+                yaml_levels.push(YamlLevel::new(&yaml_template, z, grid));
+            }
+            None => warn!("Unable to find the tiles of level {}", z.unwrap_or(0)),
+        }
+    }
+    if yaml_levels.is_empty() {
+        return Err(ZoomError::Wizard { reason: format!("no tile could be found with the template {}", generic_template) });
+    }
+    let destination = args.outfile.clone().unwrap_or_else(|| PathBuf::from("tiles.yaml"));
+    if !destination.to_string_lossy().ends_with("tiles.yaml") {
+        warn!("The custom YAML dezoomer only reads files whose name ends with tiles.yaml. \
+               Rename {} before using it.", destination.display());
+    }
+    std::fs::write(&destination, tiles_yaml(&url, yaml_levels)?)?;
+    println!("Saved the description of the image to {}. Download the image with: dezoomify-rs {}",
+             destination.display(), destination.display());
+    Ok(())
+}
+
+#[test]
+fn test_template() {
+    let url = "http://example.com/v2/tiles/12/003_5.jpg";
+    let numbers = numbers(url);
+    assert_eq!(numbers.len(), 4);
+    let roles = guess_roles(numbers.len());
+    assert_eq!(template(url, &numbers, &roles, true), "http://example.com/v2/tiles/{{Z}}/{{X:03}}_{{Y}}.jpg");
+    assert_eq!(template(url, &numbers, &roles, false), "http://example.com/v2/tiles/{{z}}/{{x}}_{{y}}.jpg");
+}
+
+#[test]
+fn test_tiles_yaml() {
+    let grid = Grid { tile_size: Vec2d::square(256), tiles: Vec2d { x: 3, y: 2 } };
+    let levels = vec![
+        YamlLevel::new("{{z}}/{{x}}_{{y}}.jpg", Some(1), grid),
+        YamlLevel::new("{{z}}/{{x}}_{{y}}.jpg", Some(2), grid),
+    ];
+    let yaml = tiles_yaml("1/0_0.jpg", levels).unwrap();
+    assert!(yaml.starts_with("# Generated by dezoomify-rs"), "{}", yaml);
+    let samples = crate::custom_yaml::validate_yaml(&yaml).unwrap();
+    assert_eq!(samples.len(), 2);
+    assert_eq!(samples[1].tile_count, 6);
+    assert_eq!(samples[1].first_tiles[1], (Vec2d { x: 0, y: 256 }, "2/0_1.jpg".into()));
+}
@@ -0,0 +1,118 @@
+//! XMP metadata of the output images, written with `--embed-metadata`, so that downloaded images
+//! keep their provenance: the title of the image, the URL it was downloaded from,
+//! and the license and attribution published by its server.
+//! The attribution is written to the IPTC credit line and to the copyright notice.
+//! XMP packets are embedded in JPEG, PNG and TIFF files.
+
+use std::convert::TryFrom;
+
+use crate::exif::{self, ImageMetadata};
+
+/// The identifier of the APP1 segments that contain XMP in JPEG files
+const JPEG_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+/// The keyword of the iTXt chunk that contains XMP in PNG files
+const PNG_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+/// The TIFF tag that contains XMP
+pub const TIFF_TAG: u16 = 700;
+
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// A property with a single value in each language. Only the default language is written.
+fn alternative(name: &str, value: &str) -> String {
+    format!("   <{0}><rdf:Alt><rdf:li xml:lang=\"x-default\">{1}</rdf:li></rdf:Alt></{0}>\n", name, escape(value))
+}
+
+fn simple(name: &str, value: &str) -> String {
+    format!("   <{0}>{1}</{0}>\n", name, escape(value))
+}
+
+/// The XMP packet describing the image
+pub fn packet(metadata: &ImageMetadata) -> String {
+    let mut properties = String::new();
+    if let Some(title) = &metadata.title { properties.push_str(&alternative("dc:title", title)); }
+    if let Some(source) = &metadata.source { properties.push_str(&simple("dc:source", source)); }
+    let rights = metadata.rights.clone().unwrap_or_default();
+    if let Some(attribution) = &rights.attribution {
+        properties.push_str(&alternative("dc:rights", attribution));
+        properties.push_str(&simple("photoshop:Credit", attribution));
+    }
+    if let Some(license) = &rights.license { properties.push_str(&simple("xmpRights:WebStatement", license)); }
+    properties.push_str(&simple("xmp:CreatorTool", &ImageMetadata::software()));
+    // The EXIF date is `YYYY:MM:DD HH:MM:SS`, and XMP dates are `YYYY-MM-DDTHH:MM:SS`
+    properties.push_str(&simple("xmp:MetadataDate", &metadata.date.replacen(':', "-", 2).replace(' ', "T")));
+    format!(
+        "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n\
+         <x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n\
+         \x20<rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n\
+         \x20 <rdf:Description rdf:about=\"\"\n\
+         \x20   xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n\
+         \x20   xmlns:photoshop=\"http://ns.adobe.com/photoshop/1.0/\"\n\
+         \x20   xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n\
+         \x20   xmlns:xmpRights=\"http://ns.adobe.com/xap/1.0/rights/\">\n\
+         {}\
+         \x20 </rdf:Description>\n\
+         \x20</rdf:RDF>\n\
+         </x:xmpmeta>\n\
+         <?xpacket end=\"w\"?>",
+        properties
+    )
+}
+
+/// Inserts an APP1 XMP segment in a JPEG file, after its JFIF and EXIF segments
+pub fn insert_into_jpeg(packet: &str, jpeg: &mut Vec<u8>) {
+    let length = match u16::try_from(JPEG_NAMESPACE.len() + packet.len() + 2) {
+        Ok(length) => length,
+        Err(_) => return,
+    };
+    if !jpeg.starts_with(&[0xFF, 0xD8]) { return; }
+    let mut segment = vec![0xFF, 0xE1];
+    segment.extend_from_slice(&length.to_be_bytes());
+    segment.extend_from_slice(JPEG_NAMESPACE);
+    segment.extend_from_slice(packet.as_bytes());
+    let position = exif::segments(jpeg)
+        .take_while(|&(marker, _, _)| marker == 0xE0 || marker == 0xE1)
+        .last()
+        .map_or(2, |(_, _, end)| end);
+    jpeg.splice(position..position, segment);
+}
+
+/// The data of an uncompressed PNG iTXt chunk containing the packet
+pub fn png_chunk(packet: &str) -> Vec<u8> {
+    let mut data = PNG_KEYWORD.to_vec();
+    // Null separator, no compression, compression method, empty language tag and translated keyword
+    data.extend_from_slice(&[0, 0, 0, 0, 0]);
+    data.extend_from_slice(packet.as_bytes());
+    data
+}
+
+#[test]
+fn test_xmp() {
+    use crate::dezoomer::Rights;
+
+    let mut metadata = ImageMetadata::new(Some("https://example.com/iiif/1/info.json".into()));
+    metadata.title = Some("Sunflowers & <roses>".into());
+    metadata.rights = Some(Rights {
+        license: Some("https://creativecommons.org/publicdomain/zero/1.0/".into()),
+        attribution: Some("The Museum".into()),
+    });
+    let packet = packet(&metadata);
+    assert!(packet.contains("<rdf:li xml:lang=\"x-default\">Sunflowers &amp; &lt;roses&gt;</rdf:li>"), "{}", packet);
+    assert!(packet.contains("<dc:source>https://example.com/iiif/1/info.json</dc:source>"), "{}", packet);
+    assert!(packet.contains("<photoshop:Credit>The Museum</photoshop:Credit>"), "{}", packet);
+    assert!(packet.contains("<xmpRights:WebStatement>https://creativecommons.org/publicdomain/zero/1.0/"), "{}", packet);
+    assert!(packet.contains(&format!("<xmp:MetadataDate>{}-", &metadata.date[..4])), "{}", packet);
+
+    let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x04, 0x4A, 0x46, 0xFF, 0xDA];
+    metadata.insert_into_jpeg(&mut jpeg);
+    insert_into_jpeg(&packet, &mut jpeg);
+    let markers: Vec<u8> = exif::segments(&jpeg).map(|(marker, _, _)| marker).collect();
+    assert_eq!(markers, vec![0xE0, 0xE1, 0xE1]);
+    let (_, start, end) = exif::segments(&jpeg).nth(2).unwrap();
+    assert!(jpeg[start..end].starts_with(JPEG_NAMESPACE));
+    assert!(jpeg[start..end].ends_with(b"<?xpacket end=\"w\"?>"));
+}
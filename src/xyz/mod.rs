@@ -0,0 +1,330 @@
+use std::collections::HashSet;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use custom_error::custom_error;
+use lazy_static::lazy_static;
+use log::debug;
+use regex::Regex;
+
+use crate::dezoomer::*;
+
+/// The highest zoom level that is probed for
+const MAX_ZOOM: u32 = 30;
+
+/// A dezoomer for XYZ tile pyramids, as displayed by Leaflet or OpenLayers.
+/// It takes a tile URL template like `http://example.com/tiles/{z}/{x}/{y}.png`,
+/// or `http://example.com/tiles/{z}/{x}/{-y}.png` for TMS servers, that number rows from the bottom,
+/// finds the available zoom levels by requesting the top-left tile of each level,
+/// and then finds the extent of the chosen level by probing the tiles on its edges.
+#[derive(Default)]
+pub struct XyzDezoomer {
+    template: Option<Arc<Template>>,
+    tile_size: Option<Vec2d>,
+    next_level: u32,
+}
+
+impl Dezoomer for XyzDezoomer {
+    fn name(&self) -> &'static str {
+        "xyz"
+    }
+
+    fn description(&self) -> &str {
+        "Map tiles, such as the ones displayed by Leaflet and OpenLayers, from a template of their URLs"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["*{z}*{x}*{y}*", "*{z}*{x}*{-y}*", "*{z}*{x}*{y}*#bounds=min_x,min_y,max_x,max_y"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec![
+            "https://example.com/tiles/{z}/{x}/{y}.png",
+            "https://example.com/tiles/{z}/{x}/{y}.png#bounds=0,0,40,25",
+        ]
+    }
+
+    fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
+        let template = match &self.template {
+            Some(template) => Arc::clone(template),
+            None => {
+                self.assert(Template::is_template(&data.uri))?;
+                let template = Arc::new(Template::parse(&data.uri).map_err(DezoomerError::wrap)?);
+                self.template = Some(Arc::clone(&template));
+                template
+            }
+        };
+        let probed_url = template.url(self.next_level, Vec2d::default());
+        if data.uri != probed_url {
+            return Err(DezoomerError::NeedsData { uri: probed_url });
+        }
+        let tile_size = match &data.contents {
+            PageContents::Unknown => return Err(DezoomerError::NeedsData { uri: probed_url }),
+            PageContents::Success(contents) => image_size(contents),
+            PageContents::Error(e) => {
+                debug!("No xyz tile at zoom level {}: {}", self.next_level, e);
+                None
+            }
+        };
+        if let Some(tile_size) = tile_size {
+            self.tile_size = Some(tile_size);
+            self.next_level += 1;
+            if self.next_level <= MAX_ZOOM {
+                let uri = template.url(self.next_level, Vec2d::default());
+                return Err(DezoomerError::NeedsData { uri });
+            }
+        }
+        let tile_size = self.tile_size.ok_or_else(||
+            DezoomerError::wrap(XyzError::NoTile { url: probed_url })
+        )?;
+        let max_level = self.next_level - 1;
+        Ok((0..=max_level).map(|z| XyzLevel::new(&template, z, max_level, tile_size))
+            .into_zoom_levels())
+    }
+}
+
+custom_error! {pub XyzError
+    NoTile{url: String} = "Unable to download the first tile of the image at {url}",
+    InvalidBounds{bounds: String} = "Invalid bounds: '{bounds}'. \
+        Expected '#bounds=min_x,min_y,max_x,max_y'",
+}
+
+fn image_size(contents: &[u8]) -> Option<Vec2d> {
+    image::io::Reader::new(Cursor::new(contents))
+        .with_guessed_format().ok()?
+        .into_dimensions().ok()
+        .map(Vec2d::from)
+}
+
+lazy_static! {
+    static ref TEMPLATE_RE: Regex = Regex::new(r"\{(?P<var>[xyz]|-y)\}").unwrap();
+}
+
+/// A tile URL template, with optional bounds expressed as tile indices at the highest zoom level.
+#[derive(Debug, PartialEq)]
+struct Template {
+    url_template: String,
+    bounds: Option<(Vec2d, Vec2d)>,
+}
+
+impl Template {
+    fn is_template(uri: &str) -> bool {
+        let vars: HashSet<&str> = TEMPLATE_RE.captures_iter(uri)
+            .filter_map(|c| c.name("var"))
+            .map(|m| m.as_str().trim_start_matches('-'))
+            .collect();
+        vars.len() == 3
+    }
+
+    fn parse(uri: &str) -> Result<Template, XyzError> {
+        let mut parts = uri.splitn(2, "#bounds=");
+        let url_template = parts.next().unwrap_or_default().to_string();
+        let bounds = parts.next().map(|bounds| {
+            let invalid = || XyzError::InvalidBounds { bounds: bounds.to_string() };
+            let nums = bounds.split(',')
+                .map(|n| n.trim().parse::<u32>())
+                .collect::<Result<Vec<u32>, _>>()
+                .map_err(|_| invalid())?;
+            match nums[..] {
+                [min_x, min_y, max_x, max_y] if min_x <= max_x && min_y <= max_y =>
+                    Ok((Vec2d { x: min_x, y: min_y }, Vec2d { x: max_x, y: max_y })),
+                _ => Err(invalid()),
+            }
+        }).transpose()?;
+        Ok(Template { url_template, bounds })
+    }
+
+    fn url(&self, z: u32, Vec2d { x, y }: Vec2d) -> String {
+        TEMPLATE_RE.replace_all(&self.url_template, |caps: &regex::Captures| {
+            match &caps["var"] {
+                "x" => x,
+                "y" => y,
+                // TMS numbering: the first row is at the bottom of the map
+                "-y" => ((1u32 << z) - 1).saturating_sub(y),
+                _ => z,
+            }.to_string()
+        }).to_string()
+    }
+}
+
+/// A binary search for the last existing tile in a row or a column
+#[derive(Debug, Clone, Copy)]
+struct Bisect {
+    /// Index of a tile that exists
+    found: u32,
+    /// Index of a tile that does not exist
+    missing: u32,
+}
+
+impl Bisect {
+    fn new(missing: u32) -> Self {
+        Bisect { found: 0, missing }
+    }
+
+    fn probe(&self) -> Option<u32> {
+        if self.missing - self.found > 1 {
+            Some(self.found + (self.missing - self.found) / 2)
+        } else {
+            None
+        }
+    }
+
+    fn update(&mut self, probe: u32, exists: bool) {
+        if exists { self.found = probe } else { self.missing = probe }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Probe {
+    Columns(Bisect),
+    Rows { columns: u32, rows: Bisect },
+    Ready { grid: Vec2d },
+    Done,
+}
+
+struct XyzLevel {
+    template: Arc<Template>,
+    z: u32,
+    tile_size: Vec2d,
+    origin: Vec2d,
+    probe: Probe,
+    last_probe: Option<Vec2d>,
+    done: HashSet<Vec2d>,
+    size: Option<Vec2d>,
+}
+
+impl XyzLevel {
+    fn new(template: &Arc<Template>, z: u32, max_level: u32, tile_size: Vec2d) -> Self {
+        let (origin, probe) = if let Some((min, max)) = template.bounds {
+            let scale = Vec2d::square(1 << (max_level - z));
+            let (min, max) = (min / scale, max / scale);
+            (min, Probe::Ready { grid: max - min + Vec2d::square(1) })
+        } else {
+            (Vec2d::default(), Probe::Columns(Bisect::new(1 << z)))
+        };
+        let size = if let Probe::Ready { grid } = probe { Some(grid * tile_size) } else { None };
+        XyzLevel {
+            template: Arc::clone(template),
+            z,
+            tile_size,
+            origin,
+            probe,
+            last_probe: None,
+            done: HashSet::new(),
+            size,
+        }
+    }
+
+    fn tile_ref(&self, pos: Vec2d) -> TileReference {
+        TileReference {
+            url: self.template.url(self.z, self.origin + pos),
+            position: pos * self.tile_size,
+            size: None,
+            request: None,
+        }
+    }
+
+    fn probe_at(&mut self, pos: Vec2d) -> Vec<TileReference> {
+        self.last_probe = Some(pos);
+        vec![self.tile_ref(pos)]
+    }
+
+    fn all_tiles(&mut self, grid: Vec2d) -> Vec<TileReference> {
+        self.size = Some(grid * self.tile_size);
+        let tiles = (0..grid.y)
+            .flat_map(|y| (0..grid.x).map(move |x| Vec2d { x, y }))
+            .filter(|pos| !self.done.contains(pos))
+            .map(|pos| self.tile_ref(pos))
+            .collect();
+        self.done.clear();
+        tiles
+    }
+}
+
+impl TileProvider for XyzLevel {
+    fn next_tiles(&mut self, previous: Option<TileFetchResult>) -> Vec<TileReference> {
+        if let (Some(result), Some(pos)) = (previous, self.last_probe.take()) {
+            let exists = result.is_success();
+            if exists { self.done.insert(pos); }
+            match &mut self.probe {
+                Probe::Columns(columns) => columns.update(pos.x, exists),
+                Probe::Rows { rows, .. } => rows.update(pos.y, exists),
+                _ => {}
+            }
+        }
+        loop {
+            self.probe = match self.probe {
+                Probe::Columns(columns) => match columns.probe() {
+                    Some(x) => return self.probe_at(Vec2d { x, y: 0 }),
+                    None => Probe::Rows { columns: columns.found + 1, rows: Bisect::new(1 << self.z) },
+                },
+                Probe::Rows { columns, rows } => match rows.probe() {
+                    Some(y) => return self.probe_at(Vec2d { x: 0, y }),
+                    None => Probe::Ready { grid: Vec2d { x: columns, y: rows.found + 1 } },
+                },
+                Probe::Ready { grid } => {
+                    self.probe = Probe::Done;
+                    return self.all_tiles(grid);
+                }
+                Probe::Done => return vec![],
+            }
+        }
+    }
+
+    fn name(&self) -> String {
+        format!("XYZ tiles at zoom level {}", self.z)
+    }
+
+    fn size_hint(&self) -> Option<Vec2d> {
+        self.size
+    }
+}
+
+impl std::fmt::Debug for XyzLevel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "XYZ level {}", self.z)
+    }
+}
+
+#[test]
+fn test_template() {
+    assert!(Template::is_template("http://x.com/{z}/{x}/{y}.png"));
+    assert!(!Template::is_template("http://x.com/{x}/{y}.png"));
+    let t = Template::parse("http://x.com/{z}/{y}/{x}.png#bounds=1,2,30,40").unwrap();
+    assert_eq!(t.url(3, Vec2d { x: 4, y: 5 }), "http://x.com/3/5/4.png");
+    assert_eq!(t.bounds, Some((Vec2d { x: 1, y: 2 }, Vec2d { x: 30, y: 40 })));
+    assert!(Template::parse("http://x.com/{z}/{y}/{x}.png#bounds=1,2,3").is_err());
+    assert!(Template::is_template("http://x.com/{z}/{x}/{-y}.png"));
+    let tms = Template::parse("http://x.com/{z}/{x}/{-y}.png").unwrap();
+    assert_eq!(tms.url(3, Vec2d { x: 4, y: 0 }), "http://x.com/3/4/7.png");
+    assert_eq!(tms.url(0, Vec2d { x: 0, y: 0 }), "http://x.com/0/0/0.png");
+}
+
+#[test]
+fn test_probe_edges() {
+    let template = Arc::new(Template::parse("{z}/{x}/{y}").unwrap());
+    // At zoom level 3, the image is 5 tiles wide and 3 tiles high
+    let mut level: ZoomLevel = Box::new(XyzLevel::new(&template, 3, 3, Vec2d::square(256)));
+    let exists = |url: &str| {
+        let nums: Vec<u32> = url.split('/').map(|n| n.parse().unwrap()).collect();
+        nums[1] < 5 && nums[2] < 3
+    };
+    let mut all_tiles = HashSet::new();
+    let mut zoom_level_iter = ZoomLevelIter::new(&mut level);
+    let mut requests = 0;
+    while let Some(tiles) = zoom_level_iter.next_tile_references() {
+        requests += 1;
+        let count = tiles.len() as u64;
+        let successes: Vec<_> = tiles.into_iter().filter(|t| exists(&t.url)).collect();
+        zoom_level_iter.set_fetch_result(TileFetchResult {
+            count,
+            successes: successes.len() as u64,
+            tile_size: if successes.is_empty() { None } else { Some(Vec2d::square(256)) },
+            successful_urls: successes.iter().map(|t| t.url.clone()).collect(),
+        });
+        all_tiles.extend(successes.into_iter().map(|t| t.url));
+    }
+    assert_eq!(zoom_level_iter.size_hint(), Some(Vec2d { x: 5 * 256, y: 3 * 256 }));
+    assert_eq!(all_tiles.len(), 15);
+    assert!(requests <= 8, "too many requests: {}", requests);
+}
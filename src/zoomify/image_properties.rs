@@ -2,17 +2,19 @@ use serde::Deserialize;
 
 use log::{info, warn};
 use crate::dezoomer::Vec2d;
+use crate::json_utils::number_or_string;
 use std::convert::TryInto;
 
+/// The attributes of the IMAGE_PROPERTIES element, which are also found in JSON objects
 #[derive(Debug, Deserialize, PartialEq)]
 pub struct ImageProperties {
-    #[serde(rename = "WIDTH", default)]
+    #[serde(rename = "WIDTH", default, deserialize_with = "number_or_string")]
     pub width: u32,
-    #[serde(rename = "HEIGHT", default)]
+    #[serde(rename = "HEIGHT", default, deserialize_with = "number_or_string")]
     pub height: u32,
-    #[serde(rename = "TILESIZE", default)]
+    #[serde(rename = "TILESIZE", default, deserialize_with = "number_or_string")]
     pub tile_size: u32,
-    #[serde(rename = "NUMTILES", default)]
+    #[serde(rename = "NUMTILES", default, deserialize_with = "number_or_string")]
     pub num_tiles: u32,
 }
 
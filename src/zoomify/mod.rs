@@ -2,31 +2,130 @@ use std::sync::Arc;
 
 use custom_error::custom_error;
 use image_properties::{ImageProperties, ZoomLevelInfo};
+use lazy_static::lazy_static;
+use regex::Regex;
 
 use crate::dezoomer::*;
+use crate::html_probe::page_title;
+use crate::json_utils::all_json;
+use crate::url_utils::resolve_relative;
 
 mod image_properties;
 
 /// Dezoomer for the zoomify image format.
+/// It also finds the image displayed by a zoomify viewer page.
 /// See: http://zoomify.com/
 #[derive(Default)]
-pub struct ZoomifyDezoomer;
+pub struct ZoomifyDezoomer {
+    /// The title of the viewer page, when the image was found in one
+    title: Option<String>,
+    /// Whether a URL that ends with a slash is taken for the folder of an image.
+    /// This is only done when the zoomify dezoomer is chosen explicitly, so as not to make
+    /// an additional request for all the folder URLs given to the other dezoomers.
+    folders: bool,
+}
+
+impl ZoomifyDezoomer {
+    /// The dezoomer used when it is chosen with `--dezoomer zoomify`
+    pub fn chosen() -> Self {
+        ZoomifyDezoomer { folders: true, ..Default::default() }
+    }
+}
 
 impl Dezoomer for ZoomifyDezoomer {
     fn name(&self) -> &'static str {
         "zoomify"
     }
 
+    fn description(&self) -> &str {
+        "Zoomify images, from their ImageProperties.xml file, one of their tiles, their viewer page \
+        or their folder (with --dezoomer zoomify)"
+    }
+
+    fn url_patterns(&self) -> Vec<&str> {
+        vec!["*/ImageProperties.xml", "*/ImageProperties.js", "*/TileGroup*/*-*-*.jpg"]
+    }
+
+    fn examples(&self) -> Vec<&str> {
+        vec!["http://example.com/path/to/ImageProperties.xml", "http://example.com/path/to/"]
+    }
+
     fn zoom_levels(&mut self, data: &DezoomerInput) -> Result<ZoomLevels, DezoomerError> {
-        self.assert(data.uri.contains("/ImageProperties.xml"))?;
+        if let Some(uri) = image_properties_url(&data.uri, self.folders) {
+            return Err(DezoomerError::NeedsData { uri });
+        }
         let DezoomerInputWithContents { uri, contents } = data.with_contents()?;
+        if !is_image_properties(uri) {
+            let path = image_path_in_page(&String::from_utf8_lossy(contents));
+            self.assert(path.is_some())?;
+            self.title = page_title(contents);
+            let path = path.unwrap_or_default();
+            return Err(DezoomerError::NeedsData { uri: properties_url_for_path(uri, &path) });
+        }
         let levels = load_from_properties(uri, contents)?;
-        Ok(levels)
+        Ok(with_default_title(levels, self.title.clone()))
+    }
+}
+
+/// ImageProperties files are usually xml files, but they are sometimes served as JSONP scripts
+fn is_image_properties(uri: &str) -> bool {
+    lazy_static! {
+        static ref PROPERTIES_RE: Regex = Regex::new(r"(?i)/ImageProperties\.(xml|js|json)\b").unwrap();
+    }
+    PROPERTIES_RE.is_match(uri)
+}
+
+/// The folder of the image displayed by a zoomify viewer, given as `zoomifyImagePath`
+/// in its scripts or in the parameters of its Flash version, or as an argument of `Z.showImage`
+fn image_path_in_page(contents: &str) -> Option<String> {
+    lazy_static! {
+        static ref IMAGE_PATH_RE: Regex = Regex::new(
+            r#"zoomifyImagePath["']?\s*[:=]\s*["']?([^"'&;\s<>]+)"#
+        ).unwrap();
+        static ref SHOW_IMAGE_RE: Regex = Regex::new(
+            r#"Z\.showImage\(\s*["'][^"']*["']\s*,\s*["']([^"']+)["']"#
+        ).unwrap();
+    }
+    [&*IMAGE_PATH_RE, &*SHOW_IMAGE_RE].iter()
+        .find_map(|re| re.captures(contents))
+        .map(|caps| caps[1].replace("\\/", "/"))
+}
+
+/// The URL of the ImageProperties.xml file of an image folder given by a viewer page
+fn properties_url_for_path(page_uri: &str, path: &str) -> String {
+    let path = if is_image_properties(path) {
+        path.to_string()
+    } else {
+        format!("{}/ImageProperties.xml", path.trim_end_matches('/'))
+    };
+    resolve_relative(page_uri, &path)
+}
+
+/// When given the URL of one of the tiles of a zoomify image, or of the folder that contains it
+/// if `folders` is set, returns the URL of the corresponding ImageProperties.xml file
+fn image_properties_url(uri: &str, folders: bool) -> Option<String> {
+    lazy_static! {
+        static ref TILE_GROUP_RE: Regex = Regex::new(r"^(.*?)/TileGroup\d+(/|$)").unwrap();
+    }
+    if uri.contains("/ImageProperties.xml") {
+        return None;
+    }
+    let without_query = uri.split(&['?', '#'][..]).next().unwrap_or(uri);
+    if let Some(caps) = TILE_GROUP_RE.captures(without_query) {
+        Some(format!("{}/ImageProperties.xml", &caps[1]))
+    } else if folders && without_query.ends_with('/') {
+        Some(format!("{}ImageProperties.xml", without_query))
+    } else {
+        None
     }
 }
 
 custom_error! {pub ZoomifyError
-    XmlError{source: serde_xml_rs::Error} = "Unable to parse ImageProperties.xml: {source}"
+    XmlError{source: serde_xml_rs::Error} = "Unable to parse ImageProperties.xml: {source}",
+    InvalidProperties{width: u32, height: u32, tile_size: u32} =
+        "Invalid ImageProperties.xml: the image size ({width}x{height}) \
+        and tile size ({tile_size}) should not be zero",
+    NoProperties = "The response does not contain any zoomify image properties",
 }
 
 impl From<ZoomifyError> for DezoomerError {
@@ -35,9 +134,32 @@ impl From<ZoomifyError> for DezoomerError {
     }
 }
 
+/// Reads an ImageProperties.xml file, or a JSONP response that contains the same xml element
+/// as a string, or its attributes as an object
+fn parse_properties(contents: &[u8]) -> Result<ImageProperties, ZoomifyError> {
+    let text = String::from_utf8_lossy(contents);
+    if text.trim_start().starts_with('<') {
+        return Ok(serde_xml_rs::from_str(&text)?);
+    }
+    if let Some(start) = text.find("<IMAGE_PROPERTIES") {
+        let element = &text[start..];
+        let end = element.find('>').map_or(element.len(), |end| end + 1);
+        let element = element[..end].replace("\\\"", "\"").replace("\\'", "'");
+        let element = if element.ends_with("/>") { element } else { format!("{}</IMAGE_PROPERTIES>", element) };
+        return Ok(serde_xml_rs::from_str(&element)?);
+    }
+    all_json::<ImageProperties>(contents)
+        .find(|properties| properties.width > 0)
+        .ok_or(ZoomifyError::NoProperties)
+}
+
 fn load_from_properties(url: &str, contents: &[u8]) -> Result<ZoomLevels, ZoomifyError> {
-    let image_properties: ImageProperties = serde_xml_rs::from_reader(contents)?;
-    let base_url_string = url.split("/ImageProperties.xml").next().unwrap().to_string();
+    let image_properties = parse_properties(contents)?;
+    let ImageProperties { width, height, tile_size, .. } = image_properties;
+    if width == 0 || height == 0 || tile_size == 0 {
+        return Err(ZoomifyError::InvalidProperties { width, height, tile_size });
+    }
+    let base_url_string = url.split("/ImageProperties.").next().unwrap().to_string();
     let base_url = &Arc::from(base_url_string);
     let levels: Vec<ZoomLevelInfo> = image_properties.levels();
     let levels: ZoomLevels = levels.into_iter().enumerate()
@@ -75,6 +197,9 @@ impl TilesRect for ZoomifyLevel {
             z = self.level
         )
     }
+
+    // Image properties files sometimes announce more levels than the server has
+    fn probe_tile(&self) -> Option<TileReference> { Some(self.last_tile_ref()) }
 }
 
 impl std::fmt::Debug for ZoomifyLevel {
@@ -105,6 +230,9 @@ fn test_panorama() {
             "http://x.fr/y/TileGroup0/3-5-0.jpg"
         ]
     );
+    let probe = level.probe_tile().unwrap();
+    assert_eq!(probe.url, "http://x.fr/y/TileGroup0/3-5-0.jpg");
+    assert_eq!(probe.position, Vec2d { x: 5 * 256, y: 0 });
 }
 
 #[test]
@@ -119,3 +247,72 @@ fn test_tilegroups() {
     assert!(tiles.contains("http://x.fr/y/TileGroup1/5-0-14.jpg"));
     assert!(tiles.contains("http://x.fr/y/TileGroup2/5-0-15.jpg"));
 }
+
+#[test]
+fn test_image_properties_url() {
+    let props = |s: &str| Some(s.to_string());
+    assert_eq!(image_properties_url("http://x.fr/y/ImageProperties.xml", true), None);
+    assert_eq!(image_properties_url("http://x.fr/y/", true), props("http://x.fr/y/ImageProperties.xml"));
+    assert_eq!(image_properties_url("http://x.fr/y/?t=1", true), props("http://x.fr/y/ImageProperties.xml"));
+    // Folders are only guessed when the zoomify dezoomer is chosen explicitly
+    assert_eq!(image_properties_url("http://x.fr/y/", false), None);
+    assert_eq!(image_properties_url("http://x.fr/y/TileGroup0/", false), props("http://x.fr/y/ImageProperties.xml"));
+    assert_eq!(
+        image_properties_url("http://x.fr/y/TileGroup12/3-1-0.jpg", false),
+        props("http://x.fr/y/ImageProperties.xml")
+    );
+    assert_eq!(image_properties_url("http://x.fr/y/image.dzi", true), None);
+}
+
+#[test]
+fn test_jsonp_properties() {
+    let url = "http://x.fr/y/ImageProperties.js?callback=cb";
+    let contents = br#"cb("<IMAGE_PROPERTIES WIDTH=\"12000\" HEIGHT=\"9788\" NUMTILES=\"2477\" TILESIZE=\"256\" />");"#;
+    let levels = load_from_properties(url, contents).unwrap();
+    assert_eq!(levels[0].size_hint(), Some(Vec2d { x: 188, y: 152 }));
+    let contents = br#"cb({"WIDTH": "12000", "HEIGHT": 9788, "NUMTILES": 2477, "TILESIZE": "256"})"#;
+    let mut levels = load_from_properties(url, contents).unwrap();
+    assert_eq!(levels.len(), 7);
+    let tile = levels[0].next_tiles(None).remove(0);
+    assert_eq!(tile.url, "http://x.fr/y/TileGroup0/0-0-0.jpg");
+    assert!(load_from_properties(url, b"cb({})").is_err());
+}
+
+#[test]
+fn test_viewer_page() {
+    let page = r#"<script>var zoomifyImagePath = "images/painting/"; Z.showImage("viewer", other);</script>"#;
+    assert_eq!(image_path_in_page(page).as_deref(), Some("images/painting/"));
+    let page = r#"<param name="FlashVars" value="zoomifyImagePath=/zoom/painting&zoomifyNavigatorVisible=false">"#;
+    assert_eq!(image_path_in_page(page).as_deref(), Some("/zoom/painting"));
+    let page = r#"Z.showImage("myContainer", "https:\/\/x.fr\/tiles\/painting", "zInitialZoom=50");"#;
+    assert_eq!(image_path_in_page(page).as_deref(), Some("https://x.fr/tiles/painting"));
+    assert_eq!(image_path_in_page("<html><body>No image</body></html>"), None);
+
+    assert_eq!(properties_url_for_path("http://x.fr/a/page.html", "images/painting/"),
+               "http://x.fr/a/images/painting/ImageProperties.xml");
+    assert_eq!(properties_url_for_path("http://x.fr/a/page.html", "/z/ImageProperties.xml?v=2"),
+               "http://x.fr/z/ImageProperties.xml?v=2");
+
+    let mut dezoomer = ZoomifyDezoomer::default();
+    let page = br#"<html><head><title>A painting</title></head>
+        <script>zoomifyImagePath = "tiles/painting";</script></html>"#;
+    let input = DezoomerInput { uri: "http://x.fr/a/page.html".into(), contents: PageContents::Success(page.to_vec()) };
+    match dezoomer.zoom_levels(&input) {
+        Err(DezoomerError::NeedsData { uri }) => assert_eq!(uri, "http://x.fr/a/tiles/painting/ImageProperties.xml"),
+        other => panic!("unexpected result: {:?}", other.map(|l| l.len())),
+    }
+    let properties = br#"<IMAGE_PROPERTIES WIDTH="500" HEIGHT="400" NUMTILES="6" TILESIZE="256"/>"#;
+    let input = DezoomerInput {
+        uri: "http://x.fr/a/tiles/painting/ImageProperties.xml".into(),
+        contents: PageContents::Success(properties.to_vec()),
+    };
+    let levels = dezoomer.zoom_levels(&input).unwrap();
+    assert_eq!(levels[0].title().as_deref(), Some("A painting"));
+}
+
+#[test]
+fn test_invalid_properties() {
+    let url = "http://x.fr/y/ImageProperties.xml";
+    let contents = br#"<html><body>Not found</body></html>"#;
+    assert!(load_from_properties(url, contents).is_err());
+}
@@ -2,11 +2,15 @@ use std::collections::hash_map::DefaultHasher;
 use std::default::Default;
 use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 
 use image::{self, DynamicImage, GenericImageView};
 use img_hash::HasherConfig;
 
-use dezoomify_rs::{Arguments, dezoomify, ZoomError};
+use futures::future::BoxFuture;
+use dezoomify_rs::bulk::{BulkOutputs, read_items};
+use dezoomify_rs::{Arguments, CancellationToken, DezoomRequest, dezoomify, ExportFormat, HeaderMap, HttpClient, HttpResponse, Method, PlaceholderPolicy, ProgressSink, SharedHttpClient, ZoomError};
 
 /// Dezoom a file locally
 #[ignore] // Ignore this test by default because it's slow in debug mode
@@ -26,6 +30,382 @@ pub async fn local_generic_tiles() {
     ).await.unwrap()
 }
 
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn out_dir_is_created() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-out-dir").unwrap();
+    let out_dir = tmp.path().join("images");
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.out_dir = Some(out_dir.clone());
+    args.outfile = Some("map.png".into());
+    args.logging = "error".into();
+    let saved_as = dezoomify(&args).await.expect("Dezooming failed");
+    assert_eq!(saved_as, out_dir.join("map.png"));
+    assert!(saved_as.exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn exported_dzi() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-export").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.outfile = Some(tmp.path().join("map.png"));
+    args.export_format = Some(ExportFormat::Dzi);
+    args.logging = "error".into();
+    let saved_as = dezoomify(&args).await.expect("Exporting failed");
+    assert_eq!(saved_as, tmp.path().join("map.dzi"));
+    let descriptor = std::fs::read_to_string(&saved_as).unwrap();
+    assert!(descriptor.contains("<Image"), "invalid descriptor: {}", descriptor);
+    assert!(tmp.path().join("map_files").is_dir());
+}
+
+/// The tiles of this DeepZoom image have a border of 1 pixel on their sides that are inside the image.
+/// The border is painted red, so that it shows if it is not removed.
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn dzi_overlap() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-dzi-overlap").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/dzi/overlap.dzi".into());
+    args.outfile = Some(tmp.path().join("overlap.png"));
+    args.largest = true;
+    args.logging = "error".into();
+    let saved_as = dezoomify(&args).await.expect("Dezooming failed");
+    let image = image::open(saved_as).unwrap().to_rgb8();
+    assert_eq!(image.dimensions(), (10, 6));
+    for (x, y, pixel) in image.enumerate_pixels() {
+        assert_eq!(pixel.0, [x as u8 * 25, y as u8 * 40, 100], "wrong pixel at {},{}", x, y);
+    }
+}
+
+/// Two copies of the DeepZoom image above, side by side
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn composite_image() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-composite").unwrap();
+    let dzi = std::fs::canonicalize("testdata/dzi/overlap.dzi").unwrap();
+    let composite = tmp.path().join("composite.yaml");
+    let yaml = format!("parts:\n  - url: {0:?}\n  - url: {0:?}\n    x: 10\n    y: 2\n", dzi);
+    std::fs::write(&composite, yaml).unwrap();
+    let mut args: Arguments = Default::default();
+    args.composite = Some(composite);
+    args.outfile = Some(tmp.path().join("composite.png"));
+    args.largest = true;
+    args.logging = "error".into();
+    let saved_as = dezoomify(&args).await.expect("Dezooming failed");
+    let image = image::open(saved_as).unwrap().to_rgb8();
+    assert_eq!(image.dimensions(), (20, 8));
+    assert_eq!(image.get_pixel(3, 1).0, [75, 40, 100]);
+    assert_eq!(image.get_pixel(13, 3).0, [75, 40, 100]);
+    assert_eq!(image.get_pixel(15, 0).0, [0, 0, 0]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn verify_with_preview() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-preview").unwrap();
+    let expected = image::open("testdata/generic/map_expected.png").unwrap();
+    let preview = tmp.path().join("preview.png");
+    expected.thumbnail(100, 100).save(&preview).unwrap();
+    let flipped = tmp.path().join("flipped.png");
+    expected.thumbnail(100, 100).fliph().flipv().save(&flipped).unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/generic/map_{{X}}_{{Y}}.jpg".into());
+    args.outfile = Some(tmp.path().join("map.png"));
+    args.verify_with_preview = Some(preview.to_string_lossy().into());
+    args.logging = "error".into();
+    dezoomify(&args).await.expect("The image should look like its preview");
+    args.outfile = Some(tmp.path().join("map_flipped.png"));
+    args.verify_with_preview = Some(flipped.to_string_lossy().into());
+    let result = dezoomify(&args).await;
+    assert!(matches!(result, Err(ZoomError::PreviewMismatch { .. })), "{:?}", result);
+    assert!(tmp.path().join("map_flipped.png").exists());
+}
+
+/// Most tiles of this image are copies of the same tile, like the placeholders sent by a server that banned the client
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn placeholder_tiles() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-placeholders").unwrap();
+    for x in 0..4 {
+        for y in 0..4 {
+            let source = if x < 2 && y < 2 { format!("map_{}_{}.jpg", x, y) } else { "map_0_0.jpg".into() };
+            std::fs::copy(Path::new("testdata/generic").join(source), tmp.path().join(format!("tile_{}_{}.jpg", x, y))).unwrap();
+        }
+    }
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(tmp.path().join("tile_{{X}}_{{Y}}.jpg").to_string_lossy().into());
+    args.outfile = Some(tmp.path().join("placeholders.png"));
+    args.placeholder_tiles = PlaceholderPolicy::Abort;
+    args.parallelism = 1;
+    args.logging = "error".into();
+    let result = dezoomify(&args).await;
+    assert!(matches!(result, Err(ZoomError::PlaceholderTiles { count: 8, .. })), "{:?}", result);
+
+    // The placeholders are requested again after the pause, and are not saved
+    args.placeholder_tiles = PlaceholderPolicy::Pause;
+    args.placeholder_pause = std::time::Duration::from_millis(1);
+    args.failed_tiles_log = Some(tmp.path().join("failed.json"));
+    let result = dezoomify(&args).await;
+    assert!(matches!(result, Err(ZoomError::PartialDownload { .. })), "{:?}", result);
+    let report: serde_json::Value = serde_json::from_slice(&std::fs::read(tmp.path().join("failed.json")).unwrap()).unwrap();
+    assert!(!report["failed_tiles"].as_array().unwrap().is_empty(), "{}", report);
+}
+
+#[derive(Default)]
+struct TileCounter {
+    total: AtomicU64,
+    received: AtomicU64,
+    finished: AtomicBool,
+}
+
+impl ProgressSink for TileCounter {
+    fn set_total_tiles(&self, total: u64) { self.total.store(total, Ordering::SeqCst) }
+    fn tile_completed(&self, bytes: Option<u64>) {
+        if bytes.is_some() { self.received.fetch_add(1, Ordering::SeqCst); }
+    }
+    fn finish(&self, _message: &str) { self.finished.store(true, Ordering::SeqCst) }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn library_request() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-request").unwrap();
+    let counter = Arc::new(TileCounter::default());
+    let saved_as = DezoomRequest::new("testdata/generic/map_{{X}}_{{Y}}.jpg")
+        .output(tmp.path().join("map.png"))
+        .progress(Arc::clone(&counter))
+        .run().await
+        .expect("Dezooming failed");
+    let expected = image::open("testdata/generic/map_expected.png").unwrap();
+    assert_images_equal(image::open(saved_as).unwrap(), expected);
+    // The generic dezoomer also requests the tiles just outside of the image, which fail
+    assert!(counter.received.load(Ordering::SeqCst) > 0);
+    assert!(counter.received.load(Ordering::SeqCst) < counter.total.load(Ordering::SeqCst));
+    assert!(counter.finished.load(Ordering::SeqCst));
+}
+
+/// Cancels the download when the first tile is received
+struct CancelAfterFirstTile(CancellationToken);
+
+impl ProgressSink for CancelAfterFirstTile {
+    fn tile_completed(&self, _bytes: Option<u64>) { self.0.cancel() }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn cancelled_request() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-cancel").unwrap();
+    let outfile = tmp.path().join("cancelled.png");
+    let token = CancellationToken::new();
+    let result = DezoomRequest::new("testdata/zoomify/test_custom_size/ImageProperties.xml")
+        .output(&outfile)
+        .parallelism(1)
+        .progress(CancelAfterFirstTile(token.clone()))
+        .cancellation(token)
+        .run().await;
+    match result {
+        Err(ZoomError::PartialDownload { successful_tiles, total_tiles }) => {
+            assert!(successful_tiles < total_tiles, "{} tiles out of {}", successful_tiles, total_tiles);
+        }
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    // The image is still saved, with its full size
+    let image = image::open(outfile).unwrap();
+    assert_eq!((image.width(), image.height()), (1702, 2052));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn cancelled_before_any_tile() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-cancel").unwrap();
+    let outfile = tmp.path().join("cancelled.png");
+    let token = CancellationToken::new();
+    token.cancel();
+    let result = DezoomRequest::new("testdata/zoomify/test_custom_size/ImageProperties.xml")
+        .output(&outfile)
+        .cancellation(token)
+        .run().await;
+    assert!(matches!(result, Err(ZoomError::Interrupted)), "Unexpected result: {:?}", result);
+    assert!(!outfile.exists(), "no blank image should be saved");
+}
+
+/// Serves the files of the testdata directory, and checks the headers of the requests
+struct TestdataClient;
+
+impl HttpClient for TestdataClient {
+    fn get<'a>(&'a self, url: &'a str, headers: &'a HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(async move {
+            assert_eq!(headers.get("X-Test").and_then(|v| v.to_str().ok()), Some("yes"));
+            assert!(headers.contains_key("User-Agent"), "no default headers in {:?}", headers);
+            let path = url.replace("http://example.com/", "testdata/");
+            match std::fs::read(&path) {
+                Ok(contents) => Ok((HeaderMap::new(), contents)),
+                Err(e) => Err(ZoomError::RequestFailed { url: url.to_string(), reason: e.to_string() }),
+            }
+        })
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn custom_http_client() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-http-client").unwrap();
+    let saved_as = DezoomRequest::new("http://example.com/generic/map_{{X}}_{{Y}}.jpg")
+        .output(tmp.path().join("map.png"))
+        .header("X-Test", "yes")
+        .http_client(TestdataClient)
+        .run().await
+        .expect("Dezooming failed");
+    let expected = image::open("testdata/generic/map_expected.png").unwrap();
+    assert_images_equal(image::open(saved_as).unwrap(), expected);
+}
+
+/// Serves small IIIF images, made of a single tile
+struct IiifClient;
+
+impl HttpClient for IiifClient {
+    fn get<'a>(&'a self, url: &'a str, _headers: &'a HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(async move {
+            if let Some(id) = url.strip_suffix("/info.json") {
+                let info = format!(r#"{{"@id": "{}", "width": 300, "height": 200, "tiles": [{{"width": 512, "scaleFactors": [1]}}]}}"#, id);
+                return Ok((HeaderMap::new(), info.into_bytes()));
+            }
+            let mut tile = std::io::Cursor::new(Vec::new());
+            DynamicImage::new_rgb8(300, 200).write_to(&mut tile, image::ImageOutputFormat::Png).unwrap();
+            Ok((HeaderMap::new(), tile.into_inner()))
+        })
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn bulk_reading_copy() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-reading-copy").unwrap();
+    let manifest = tmp.path().join("manifest.json");
+    std::fs::write(&manifest, r#"{"sequences": [{"canvases": [
+        {"@id": "c1", "label": "Cover", "images": [{"resource": {"service": {"@id": "http://example.com/1"}}}]},
+        {"@id": "c2", "label": "Page", "images": [{"resource": {"service": {"@id": "http://example.com/2"}}}]}
+    ]}]}"#).unwrap();
+    let mut args: Arguments = Default::default();
+    args.bulk = Some(manifest.clone());
+    args.out_dir = Some(tmp.path().to_path_buf());
+    args.reading_copy = Some(tmp.path().join("book.pdf"));
+    args.http_client = Some(SharedHttpClient(Arc::new(IiifClient)));
+    args.logging = "error".into();
+
+    let items = read_items(&manifest, None).unwrap();
+    let mut outputs = BulkOutputs::create(&args, items.len()).unwrap();
+    for (i, item) in items.iter().enumerate() {
+        let saved_as = dezoomify(&item.arguments(&args, i + 1)).await.expect("Dezooming failed");
+        let saved_as = outputs.add(i + 1, saved_as).unwrap();
+        assert_eq!(image::open(saved_as).unwrap().dimensions(), (300, 200), "the page images are kept");
+    }
+    let saved: Vec<PathBuf> = outputs.finish().into_iter().map(Result::unwrap).collect();
+    assert_eq!(saved, vec![tmp.path().join("book.pdf")]);
+    let pdf = std::fs::read(&saved[0]).unwrap();
+    assert!(String::from_utf8_lossy(&pdf).contains("/Count 2"));
+}
+
+/// Serves the files of the testdata directory whose path is given in the body of POST requests
+struct PostClient;
+
+impl HttpClient for PostClient {
+    fn get<'a>(&'a self, url: &'a str, _headers: &'a HeaderMap) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(async move { Err(ZoomError::RequestFailed { url: url.to_string(), reason: "GET".into() }) })
+    }
+
+    fn send<'a>(&'a self, method: &'a Method, url: &'a str, headers: &'a HeaderMap, body: &'a [u8]) -> BoxFuture<'a, Result<HttpResponse, ZoomError>> {
+        Box::pin(async move {
+            assert_eq!(method, Method::POST);
+            let path = String::from_utf8(body.to_vec()).unwrap();
+            assert_eq!(headers.get("X-Tile").and_then(|v| v.to_str().ok()), Some(&path[path.len() - 7..path.len() - 4]));
+            match std::fs::read(&path) {
+                Ok(contents) => Ok((HeaderMap::new(), contents)),
+                Err(e) => Err(ZoomError::RequestFailed { url: url.to_string(), reason: e.to_string() }),
+            }
+        })
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+pub async fn post_tile_requests() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-post").unwrap();
+    let yaml = tmp.path().join("tiles.yaml");
+    std::fs::write(&yaml, r#"
+variables:
+  - { name: x, from: 0, to: 1 }
+  - { name: y, from: 0, to: 1 }
+url_template: "http://example.com/tiles"
+x_template: "x * 256"
+y_template: "y * 256"
+body_template: "testdata/generic/map_{{x}}_{{y}}.jpg"
+tile_headers:
+  X-Tile: "{{x}}_{{y}}"
+"#).unwrap();
+    let saved_as = DezoomRequest::new(yaml.to_string_lossy())
+        .output(tmp.path().join("map.png"))
+        .http_client(PostClient)
+        .run().await
+        .expect("Dezooming failed");
+    let expected = image::open("testdata/generic/map_expected.png").unwrap();
+    assert_images_equal(image::open(saved_as).unwrap(), expected);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn cropped_local_zoomify_tiles() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-crop").unwrap();
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some("testdata/zoomify/test_custom_size/ImageProperties.xml".into());
+    args.largest = true;
+    args.crop = Some("300,500,700,400".parse().unwrap());
+    args.outfile = Some(tmp.path().join("cropped.png"));
+    args.logging = "error".into();
+    let saved_as = dezoomify(&args).await.expect("Dezooming failed");
+    let actual = image::open(saved_as).unwrap();
+    let expected = image::open("testdata/zoomify/test_custom_size/expected_result.jpg").unwrap();
+    assert_images_equal(actual, expected.crop_imm(300, 500, 700, 400));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+#[allow(clippy::field_reassign_with_default)]
+pub async fn retried_failed_tiles() {
+    let tmp = tempdir::TempDir::new("dezoomify-rs-retry").unwrap();
+    let source = Path::new("testdata/zoomify/test_custom_size");
+    let copy = tmp.path().join("zoomify");
+    std::fs::create_dir_all(copy.join("TileGroup0")).unwrap();
+    std::fs::copy(source.join("ImageProperties.xml"), copy.join("ImageProperties.xml")).unwrap();
+    for entry in std::fs::read_dir(source.join("TileGroup0")).unwrap() {
+        let path = entry.unwrap().path();
+        if !path.ends_with("3-1-1.jpg") {
+            std::fs::copy(&path, copy.join("TileGroup0").join(path.file_name().unwrap())).unwrap();
+        }
+    }
+    let report = tmp.path().join("failed.json");
+    let mut args: Arguments = Default::default();
+    args.input_uri = Some(copy.join("ImageProperties.xml").to_string_lossy().into());
+    args.largest = true;
+    args.retries = 0;
+    args.outfile = Some(tmp.path().join("patched.png"));
+    args.failed_tiles_log = Some(report.clone());
+    args.logging = "error".into();
+    match dezoomify(&args).await {
+        Err(ZoomError::PartialDownload { successful_tiles: 62, total_tiles: 63 }) => {}
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    std::fs::copy(source.join("TileGroup0/3-1-1.jpg"), copy.join("TileGroup0/3-1-1.jpg")).unwrap();
+    let mut retry: Arguments = Default::default();
+    retry.retry_failed = Some(report.clone());
+    retry.logging = "error".into();
+    let patched = dezoomify(&retry).await.expect("Retrying failed");
+    assert!(std::fs::read_to_string(&report).unwrap().contains("\"failed_tiles\": []"));
+
+    args.outfile = Some(tmp.path().join("full.png"));
+    args.failed_tiles_log = None;
+    let full = dezoomify(&args).await.expect("Dezooming failed");
+    assert_eq!(image::open(patched).unwrap().to_rgb8(), image::open(full).unwrap().to_rgb8());
+}
+
 #[allow(clippy::needless_lifetimes)]
 #[allow(clippy::field_reassign_with_default)]
 pub async fn dezoom_image<'a>(input: &str, expected: &'a str) -> Result<TmpFile<'a>, ZoomError> {